@@ -0,0 +1,148 @@
+//! Sequential writes, reads and a final fsync through the `custom` engine and through the
+//! `passthrough` one, which caches nothing, to see what the cache costs or saves. Then writes to
+//! as many files as threads at once, through a `custom` engine with a single shard and one with
+//! a shard for each thread, to see what sharding buys. Run with `cargo bench --bench engines`.
+
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use lazyfs_rs::pagecache::cache::Cache;
+use lazyfs_rs::pagecache::config::Config;
+use lazyfs_rs::pagecache::engine::backends;
+
+/// Bytes written then read back in each round
+const FILE_SIZE: usize = 16 << 20;
+/// Bytes per write and read
+const CHUNK: usize = 64 << 10;
+const ROUNDS: u32 = 5;
+/// Files written at once, by a thread each, `FILE_SIZE` bytes between them
+const THREADS: usize = 8;
+
+struct Timings {
+    write: Duration,
+    read: Duration,
+    sync: Duration,
+}
+
+fn round(engine: &str, path: &Path) -> Timings {
+    let defaults = Config::default();
+    // Room for the whole file, nothing gets evicted or dropped
+    let config = Config {
+        engine: engine.to_string(),
+        cache_nr_pages: FILE_SIZE / defaults.cache_page_size,
+        ..defaults
+    };
+    let cache = Cache::with_boxed_engine(config.clone(), backends::from_config(&config).unwrap());
+    fs::write(path, b"").unwrap();
+    let owner = cache
+        .insert_inode_mapping(path.to_path_buf(), "bench".to_string(), false)
+        .unwrap();
+    let chunk = vec![0xa5; CHUNK];
+    let mut buf = vec![0; CHUNK];
+
+    let start = Instant::now();
+    for offset in (0..FILE_SIZE).step_by(CHUNK) {
+        cache
+            .write_at(owner.clone(), offset as u64, &chunk)
+            .unwrap();
+    }
+    let write = start.elapsed();
+
+    let start = Instant::now();
+    for offset in (0..FILE_SIZE).step_by(CHUNK) {
+        cache
+            .read_at(owner.clone(), offset as u64, &mut buf)
+            .unwrap();
+    }
+    let read = start.elapsed();
+
+    let start = Instant::now();
+    cache.sync_owner(owner, false, path.to_path_buf()).unwrap();
+    let sync = start.elapsed();
+
+    Timings { write, read, sync }
+}
+
+fn parallel_writes(shards: usize, dir: &Path) -> Duration {
+    let defaults = Config::default();
+    let config = Config {
+        cache_nr_pages: FILE_SIZE / defaults.cache_page_size,
+        engine_shards: shards,
+        ..defaults
+    };
+    let cache = Cache::with_boxed_engine(config.clone(), backends::from_config(&config).unwrap());
+    let owners: Vec<_> = (0..THREADS)
+        .map(|thread| {
+            let path = dir.join(format!("file-{}", thread));
+            fs::write(&path, b"").unwrap();
+            cache
+                .insert_inode_mapping(path, format!("bench-{}", thread), false)
+                .unwrap()
+        })
+        .collect();
+    let chunk = vec![0xa5; CHUNK];
+
+    let start = Instant::now();
+    thread::scope(|scope| {
+        for owner in &owners {
+            let (cache, chunk) = (&cache, &chunk);
+            scope.spawn(move || {
+                for offset in (0..FILE_SIZE / THREADS).step_by(CHUNK) {
+                    let written = cache.write_at(owner.clone(), offset as u64, chunk).unwrap();
+                    assert_eq!(written, CHUNK);
+                }
+            });
+        }
+    });
+    start.elapsed()
+}
+
+fn throughput(elapsed: Duration) -> f64 {
+    FILE_SIZE as f64 / (1 << 20) as f64 / elapsed.as_secs_f64()
+}
+
+fn main() {
+    let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-bench", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("file");
+
+    println!(
+        "{:<12} {:>12} {:>12} {:>10}",
+        "engine", "write MiB/s", "read MiB/s", "sync ms"
+    );
+    for engine in ["custom", "passthrough"] {
+        let mut best: Option<Timings> = None;
+        for _ in 0..ROUNDS {
+            let timings = round(engine, &path);
+            best = Some(match best {
+                Some(best) => Timings {
+                    write: best.write.min(timings.write),
+                    read: best.read.min(timings.read),
+                    sync: best.sync.min(timings.sync),
+                },
+                None => timings,
+            });
+        }
+        let best = best.unwrap();
+        println!(
+            "{:<12} {:>12.1} {:>12.1} {:>10.2}",
+            engine,
+            throughput(best.write),
+            throughput(best.read),
+            best.sync.as_secs_f64() * 1000.0
+        );
+    }
+
+    println!();
+    println!("{:<12} {:>12}", "shards", "write MiB/s");
+    for shards in [1, THREADS] {
+        let best = (0..ROUNDS)
+            .map(|_| parallel_writes(shards, &dir))
+            .min()
+            .unwrap();
+        println!("{:<12} {:>12.1}", shards, throughput(best));
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
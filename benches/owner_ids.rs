@@ -0,0 +1,91 @@
+//! Heap allocations made by `put_data_blocks` for 1000 blocks, once in a single call and once a
+//! block per call with the owner cloned each time, as a caller holding on to its owner does.
+//! Owner ids are shared rather than copied, so the count shouldn't grow with every hand-off.
+//! Run with `cargo bench --bench owner_ids`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use lazyfs_rs::pagecache::cache::Cache;
+use lazyfs_rs::pagecache::config::Config;
+use lazyfs_rs::pagecache::engine::{backends, AllocateOperationType};
+use lazyfs_rs::pagecache::OwnerId;
+
+const BLOCKS: i32 = 1000;
+
+/// The system allocator, counting the allocations made through it
+struct Counting;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn new_cache() -> Cache {
+    let config = Config {
+        cache_nr_pages: BLOCKS as usize,
+        ..Config::default()
+    };
+    Cache::with_boxed_engine(config.clone(), backends::from_config(&config).unwrap())
+}
+
+/// Allocations made while running `f`
+fn count(f: impl FnOnce()) -> u64 {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    f();
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+fn main() {
+    let block_size = Config::default().io_block_size;
+    let data = vec![0xa5; block_size];
+    let owner: OwnerId = "bench".into();
+
+    let cache = new_cache();
+    cache.insert_item(owner.clone()).unwrap();
+    let blocks: HashMap<_, _> = (0..BLOCKS)
+        .map(|block_id| (block_id, (&data, 0, block_size as i32 - 1)))
+        .collect();
+    let batched = count(|| {
+        cache
+            .put_data_blocks(owner.clone(), blocks, AllocateOperationType::OpWrite, None)
+            .unwrap();
+    });
+
+    let cache = new_cache();
+    cache.insert_item(owner.clone()).unwrap();
+    let single = count(|| {
+        for block_id in 0..BLOCKS {
+            let blocks = HashMap::from([(block_id, (&data, 0, block_size as i32 - 1))]);
+            cache
+                .put_data_blocks(owner.clone(), blocks, AllocateOperationType::OpWrite, None)
+                .unwrap();
+        }
+    });
+
+    println!(
+        "put_data_blocks, {} blocks: {} allocations in one call ({:.1} per block), {} a block per call ({:.1} per block)",
+        BLOCKS,
+        batched,
+        batched as f64 / BLOCKS as f64,
+        single,
+        single as f64 / BLOCKS as f64
+    );
+}
@@ -0,0 +1,31 @@
+fn main() {
+    #[cfg(feature = "ffi")]
+    ffi();
+}
+
+/// Generates the C header into `OUT_DIR` and compiles the C program the `ffi` integration test
+/// runs against it. The program is linked as a static library exposing `lazyfs_ffi_test_main`.
+/// The copy in `include/` is for users of the library, the `ffi` test checks it is up to date.
+#[cfg(feature = "ffi")]
+fn ffi() {
+    println!("cargo:rerun-if-changed=src/ffi.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    println!("cargo:rerun-if-changed=tests/ffi/ffi_test.c");
+
+    let include = std::path::PathBuf::from(std::env::var("OUT_DIR").unwrap()).join("include");
+    std::fs::create_dir_all(&include).expect("Unable to create the header directory");
+    let config = cbindgen::Config::from_file("cbindgen.toml").expect("Invalid cbindgen.toml");
+    cbindgen::Builder::new()
+        .with_config(config)
+        .with_src("src/ffi.rs")
+        .generate()
+        .expect("Unable to generate the C header")
+        .write_to_file(include.join("lazyfs.h"));
+
+    cc::Build::new()
+        .file("tests/ffi/ffi_test.c")
+        .include(&include)
+        .warnings(true)
+        .warnings_into_errors(true)
+        .compile("lazyfs_ffi_test");
+}
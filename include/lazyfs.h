@@ -0,0 +1,118 @@
+#ifndef LAZYFS_H
+#define LAZYFS_H
+
+/* Generated by cbindgen from src/ffi.rs, do not edit */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * A `LazyFS` and the cache under it, opaque to C
+ */
+typedef struct LazyFsCache LazyFsCache;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Sets up LazyFS with the config file at `config_path`, or the default config if it is NULL.
+ * Returns NULL on failure, see `lazyfs_last_error_message`.
+ *
+ * # Safety
+ *
+ * `config_path` must be NULL or a NUL-terminated string
+ */
+struct LazyFsCache *lazyfs_cache_new(const char *config_path);
+
+/**
+ * Shuts LazyFS down without syncing, what wasn't fsynced is lost
+ *
+ * # Safety
+ *
+ * `cache` must be NULL or come from `lazyfs_cache_new`, and is not to be used afterwards
+ */
+void lazyfs_cache_free(struct LazyFsCache *cache);
+
+/**
+ * Writes `len` bytes of `buf` at `offset` of `path` through the cache. Returns how many were
+ * accepted, which faults may make fewer than `len`.
+ *
+ * # Safety
+ *
+ * `cache` must come from `lazyfs_cache_new`, `path` be a NUL-terminated string and `buf`
+ * point to `len` readable bytes
+ */
+int64_t lazyfs_write(const struct LazyFsCache *cache,
+                     const char *path,
+                     const uint8_t *buf,
+                     size_t len,
+                     uint64_t offset);
+
+/**
+ * Reads up to `len` bytes at `offset` of `path` into `buf`, unsynced writes included. Returns
+ * how many were read.
+ *
+ * # Safety
+ *
+ * `cache` must come from `lazyfs_cache_new`, `path` be a NUL-terminated string and `buf`
+ * point to `len` writable bytes
+ */
+int64_t lazyfs_read(const struct LazyFsCache *cache,
+                    const char *path,
+                    uint8_t *buf,
+                    size_t len,
+                    uint64_t offset);
+
+/**
+ * Syncs what the cache holds of `path` to the backing file
+ *
+ * # Safety
+ *
+ * `cache` must come from `lazyfs_cache_new` and `path` be a NUL-terminated string
+ */
+int32_t lazyfs_fsync(const struct LazyFsCache *cache, const char *path);
+
+/**
+ * Drops everything the cache holds, as a crash would, losing what wasn't fsynced
+ *
+ * # Safety
+ *
+ * `cache` must come from `lazyfs_cache_new`
+ */
+int32_t lazyfs_drop_unsynced(const struct LazyFsCache *cache);
+
+/**
+ * The unsynced bytes of each owner as JSON, `{"unsynced":[{"owner":..,"dirty_bytes":..}],
+ * "omitted":..}`, heaviest first as in crash reports. Returns NULL on failure, the string is
+ * to be freed with `lazyfs_string_free`.
+ *
+ * # Safety
+ *
+ * `cache` must come from `lazyfs_cache_new`
+ */
+char *lazyfs_unsynced_report_json(const struct LazyFsCache *cache);
+
+/**
+ * Frees a string returned by LazyFS
+ *
+ * # Safety
+ *
+ * `s` must be NULL or come from LazyFS, and is not to be used afterwards
+ */
+void lazyfs_string_free(char *s);
+
+/**
+ * Message of the last failure on the calling thread, or NULL if there was none. Valid until
+ * the next failing call on the thread.
+ */
+const char *lazyfs_last_error_message(void);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* LAZYFS_H */
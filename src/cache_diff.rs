@@ -0,0 +1,262 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+use crate::pagecache::{BlockId, Offsets, PageId};
+
+/// Most marks kept at once, taking another drops the oldest
+pub const MAX_MARKS: usize = 16;
+
+/// Label that always stands for a mark taken on the spot
+pub const NOW: &str = "now";
+
+/// Where a cached block lives and what of it is dirty, without its data
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockMark {
+    pub page: PageId,
+    pub readable: Offsets,
+    pub dirty: Vec<Offsets>,
+    /// Op id of the last write to the block
+    pub write_op: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OwnerMark {
+    pub size: u64,
+    pub blocks: BTreeMap<BlockId, BlockMark>,
+}
+
+/// What the cache held at one moment. Costs a few words per cached block.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CacheMark {
+    pub owners: BTreeMap<String, OwnerMark>,
+}
+
+impl CacheMark {
+    pub fn blocks(&self) -> usize {
+        self.owners.values().map(|owner| owner.blocks.len()).sum()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FileChange {
+    Added,
+    Removed,
+    Changed,
+}
+
+/// How the cached blocks of one owner changed between two marks
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FileDiff {
+    pub owner: String,
+    pub change: FileChange,
+    /// Size before and after, if it changed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<(u64, u64)>,
+    /// Written to since the first mark
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub dirtied: Vec<BlockId>,
+    /// Dirty at the first mark and clean at the second
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub synced: Vec<BlockId>,
+    /// Cached clean since the first mark, such as by a read
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub cached: Vec<BlockId>,
+    /// Clean at the first mark and gone at the second
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub evicted: Vec<BlockId>,
+    /// Dirty at the first mark and gone at the second without being synced
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub dropped: Vec<BlockId>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CacheDiff {
+    pub from: String,
+    pub to: String,
+    /// Owners that changed, sorted by owner
+    pub files: Vec<FileDiff>,
+}
+
+impl FileDiff {
+    fn new(owner: &str, from: Option<&OwnerMark>, to: Option<&OwnerMark>) -> Self {
+        let change = match (from, to) {
+            (None, _) => FileChange::Added,
+            (_, None) => FileChange::Removed,
+            _ => FileChange::Changed,
+        };
+        let size_of = |owner: Option<&OwnerMark>| owner.map_or(0, |owner| owner.size);
+        let size = (size_of(from) != size_of(to)).then(|| (size_of(from), size_of(to)));
+        let mut diff = FileDiff {
+            owner: owner.to_string(),
+            change,
+            size,
+            dirtied: Vec::new(),
+            synced: Vec::new(),
+            cached: Vec::new(),
+            evicted: Vec::new(),
+            dropped: Vec::new(),
+        };
+
+        let empty = BTreeMap::new();
+        let from = from.map_or(&empty, |owner| &owner.blocks);
+        let to = to.map_or(&empty, |owner| &owner.blocks);
+        for &block_id in from
+            .keys()
+            .chain(to.keys().filter(|id| !from.contains_key(id)))
+        {
+            let list = match (from.get(&block_id), to.get(&block_id)) {
+                (Some(before), None) if before.dirty.is_empty() => &mut diff.evicted,
+                (Some(_), None) => &mut diff.dropped,
+                (before, Some(after)) if !after.dirty.is_empty() => match before {
+                    Some(before) if before == after => continue,
+                    _ => &mut diff.dirtied,
+                },
+                (Some(before), Some(_)) if !before.dirty.is_empty() => &mut diff.synced,
+                (Some(before), Some(after)) if before.page == after.page => continue,
+                _ => &mut diff.cached,
+            };
+            list.push(block_id);
+        }
+        for list in [
+            &mut diff.dirtied,
+            &mut diff.synced,
+            &mut diff.cached,
+            &mut diff.evicted,
+            &mut diff.dropped,
+        ] {
+            list.sort();
+        }
+        diff
+    }
+
+    fn is_empty(&self) -> bool {
+        self.change == FileChange::Changed
+            && self.size.is_none()
+            && self.dirtied.is_empty()
+            && self.synced.is_empty()
+            && self.cached.is_empty()
+            && self.evicted.is_empty()
+            && self.dropped.is_empty()
+    }
+}
+
+impl CacheDiff {
+    pub fn between(from_label: &str, from: &CacheMark, to_label: &str, to: &CacheMark) -> Self {
+        let owners = from
+            .owners
+            .keys()
+            .chain(to.owners.keys())
+            .collect::<std::collections::BTreeSet<_>>();
+        let files = owners
+            .into_iter()
+            .map(|owner| FileDiff::new(owner, from.owners.get(owner), to.owners.get(owner)))
+            .filter(|diff| !diff.is_empty())
+            .collect();
+        CacheDiff {
+            from: from_label.to_string(),
+            to: to_label.to_string(),
+            files,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("cache diffs always serialize")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pagecache::cache::Cache;
+    use crate::pagecache::config::Config;
+    use crate::pagecache::engine::backends::custom::CustomCacheEngine;
+    use crate::pagecache::engine::AllocateOperationType;
+    use crate::pagecache::item::metadata::{Metadata, MetadataField};
+    use std::collections::HashMap;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    static NEXT_OP: AtomicU64 = AtomicU64::new(0);
+
+    /// Caches a whole block, each put being its own op
+    fn put(cache: &Cache, owner: &str, block_id: BlockId, op: AllocateOperationType) {
+        let data = vec![1u8; 4096];
+        let op_id = NEXT_OP.fetch_add(1, Ordering::SeqCst);
+        cache
+            .put_data_blocks(
+                owner.to_string(),
+                HashMap::from([(block_id, (&data, 0, 4095))]),
+                op,
+                Some(op_id),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn diff_shows_block_changes() {
+        let config = Config {
+            cache_nr_pages: 8,
+            ..Default::default()
+        };
+        let cache = Cache::new(
+            config.clone(),
+            CustomCacheEngine::new(Box::new(config)).unwrap(),
+        );
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-diff", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let wal = dir.join("wal");
+        fs::write(&wal, b"").unwrap();
+        let owner = |path: &PathBuf| path.to_string_lossy().to_string();
+        cache
+            .insert_inode_mapping(wal.clone(), owner(&wal), false)
+            .unwrap();
+        put(&cache, &owner(&wal), 0, AllocateOperationType::OpWrite);
+        put(&cache, &owner(&wal), 1, AllocateOperationType::OpWrite);
+        put(&cache, "sst", 0, AllocateOperationType::OpRead);
+        put(&cache, "tmp", 0, AllocateOperationType::OpWrite);
+        let before = cache.mark().unwrap();
+        assert_eq!(before.blocks(), 4);
+
+        // Sync the wal, then dirty one of its blocks again and add one
+        let metadata = Metadata {
+            size: 8192,
+            ..Default::default()
+        };
+        cache
+            .update_content_metadata(owner(&wal), metadata, &[MetadataField::Size])
+            .unwrap();
+        cache.sync_file(wal.clone()).unwrap();
+        put(&cache, &owner(&wal), 1, AllocateOperationType::OpWrite);
+        put(&cache, &owner(&wal), 2, AllocateOperationType::OpWrite);
+        // Read more of the sst and throw away the unsynced tmp
+        put(&cache, "sst", 1, AllocateOperationType::OpRead);
+        cache
+            .remove_cached_item("tmp".to_string(), PathBuf::from("tmp"), true)
+            .unwrap();
+        put(&cache, "new", 0, AllocateOperationType::OpRead);
+
+        let diff = CacheDiff::between("before", &before, NOW, &cache.mark().unwrap());
+        let file = |owner: &str| diff.files.iter().find(|f| f.owner == owner).unwrap();
+        let wal_diff = file(&owner(&wal));
+        assert_eq!(wal_diff.change, FileChange::Changed);
+        assert_eq!(wal_diff.size, Some((0, 8192)));
+        assert_eq!(wal_diff.synced, vec![0]);
+        assert_eq!(wal_diff.dirtied, vec![1, 2]);
+        assert_eq!(file("sst").cached, vec![1]);
+        assert_eq!(file("tmp").change, FileChange::Removed);
+        assert_eq!(file("tmp").dropped, vec![0]);
+        assert_eq!(file("new").change, FileChange::Added);
+        assert_eq!(diff.files.len(), 4);
+
+        let json = diff.to_json();
+        assert!(json.starts_with(r#"{"from":"before","to":"now","files":["#));
+        assert!(json.contains(r#""owner":"tmp","change":"removed","dropped":[0]}"#));
+
+        // Nothing changes between two marks with nothing in between
+        let now = cache.mark().unwrap();
+        assert!(CacheDiff::between("a", &now, "b", &now).files.is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,230 @@
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+#[cfg(any(test, feature = "testing"))]
+use std::sync::Mutex;
+
+/// Source of time for everything LazyFS stamps or waits on, so tests can control it
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+
+    fn sleep(&self, duration: Duration);
+}
+
+/// Wall clock time
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+/// Signed offset between the time LazyFS stamps and the real time, written like `-90s`,
+/// `+1500ms` or `2h`
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct ClockSkew {
+    nanos: i64,
+}
+
+impl ClockSkew {
+    pub fn from_nanos(nanos: i64) -> Self {
+        ClockSkew { nanos }
+    }
+
+    pub fn as_nanos(&self) -> i64 {
+        self.nanos
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.nanos == 0
+    }
+
+    /// `time` moved by the skew, left as is if that would overflow
+    pub fn apply(&self, time: SystemTime) -> SystemTime {
+        let offset = Duration::from_nanos(self.nanos.unsigned_abs());
+        let skewed = if self.nanos < 0 {
+            time.checked_sub(offset)
+        } else {
+            time.checked_add(offset)
+        };
+        skewed.unwrap_or(time)
+    }
+}
+
+const SKEW_UNITS: [(&str, i64); 7] = [
+    ("ns", 1),
+    ("us", 1_000),
+    ("ms", 1_000_000),
+    ("s", 1_000_000_000),
+    ("m", 60_000_000_000),
+    ("h", 3_600_000_000_000),
+    ("d", 86_400_000_000_000),
+];
+
+impl FromStr for ClockSkew {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || anyhow!("Invalid clock skew '{}', expected e.g. -90s or +1500ms", s);
+        let (sign, rest) = match s.strip_prefix('-') {
+            Some(rest) => (-1, rest),
+            None => (1, s.strip_prefix('+').unwrap_or(s)),
+        };
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let (amount, unit) = rest.split_at(digits);
+        let amount: i64 = amount.parse().map_err(|_| invalid())?;
+        let scale = match unit {
+            "" if amount == 0 => 1,
+            _ => {
+                SKEW_UNITS
+                    .iter()
+                    .find(|(name, _)| *name == unit)
+                    .ok_or_else(invalid)?
+                    .1
+            }
+        };
+        let nanos = amount.checked_mul(scale).ok_or_else(invalid)?;
+        Ok(ClockSkew::from_nanos(sign * nanos))
+    }
+}
+
+impl fmt::Display for ClockSkew {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.nanos == 0 {
+            return write!(f, "0s");
+        }
+        let sign = if self.nanos < 0 { '-' } else { '+' };
+        let nanos = self.nanos.unsigned_abs();
+        // The largest unit up to seconds that shows the skew exactly
+        let (unit, scale) = SKEW_UNITS[..4]
+            .iter()
+            .rev()
+            .find(|(_, scale)| nanos.is_multiple_of(*scale as u64))
+            .unwrap();
+        write!(f, "{}{}{}", sign, nanos / *scale as u64, unit)
+    }
+}
+
+/// Another clock moved by a skew that can be changed while running, to stamp files with times
+/// that went backwards or jumped ahead. Sleeping is left to the other clock.
+pub struct SkewedClock {
+    clock: Arc<dyn Clock>,
+    skew: AtomicI64,
+}
+
+impl SkewedClock {
+    pub fn new(clock: Arc<dyn Clock>, skew: ClockSkew) -> Self {
+        SkewedClock {
+            clock,
+            skew: AtomicI64::new(skew.as_nanos()),
+        }
+    }
+
+    /// What the clock underneath says
+    pub fn real_now(&self) -> SystemTime {
+        self.clock.now()
+    }
+
+    pub fn skew(&self) -> ClockSkew {
+        ClockSkew::from_nanos(self.skew.load(Ordering::SeqCst))
+    }
+
+    /// Returns the previous skew
+    pub fn set_skew(&self, skew: ClockSkew) -> ClockSkew {
+        ClockSkew::from_nanos(self.skew.swap(skew.as_nanos(), Ordering::SeqCst))
+    }
+}
+
+impl Clock for SkewedClock {
+    fn now(&self) -> SystemTime {
+        self.skew().apply(self.clock.now())
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.clock.sleep(duration);
+    }
+}
+
+/// Clock that only moves when told to. Sleeping advances it instead of blocking.
+#[cfg(any(test, feature = "testing"))]
+#[derive(Debug)]
+pub struct ManualClock {
+    now: Mutex<SystemTime>,
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl ManualClock {
+    pub fn new(start: SystemTime) -> Self {
+        Self {
+            now: Mutex::new(start),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+
+    pub fn set(&self, now: SystemTime) {
+        *self.now.lock().unwrap() = now;
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new(SystemTime::UNIX_EPOCH)
+    }
+}
+
+#[cfg(any(test, feature = "testing"))]
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skew_moves_the_clock_underneath() {
+        let manual = Arc::new(ManualClock::new(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(100),
+        ));
+        let skewed = SkewedClock::new(manual.clone(), "-90s".parse().unwrap());
+        assert_eq!(
+            skewed.now(),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(10)
+        );
+
+        manual.advance(Duration::from_secs(5));
+        assert_eq!(skewed.real_now(), manual.now());
+        let previous = skewed.set_skew("+1500ms".parse().unwrap());
+        assert_eq!(previous.to_string(), "-90s");
+        assert_eq!(skewed.now(), manual.now() + Duration::from_millis(1500));
+        assert_eq!(skewed.skew().to_string(), "+1500ms");
+
+        assert_eq!("0".parse::<ClockSkew>().unwrap(), ClockSkew::default());
+        assert_eq!("2h".parse::<ClockSkew>().unwrap().to_string(), "+7200s");
+        for invalid in ["", "-", "5", "5y", "1.5s", "--5s"] {
+            assert!(invalid.parse::<ClockSkew>().is_err(), "{}", invalid);
+        }
+    }
+}
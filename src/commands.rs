@@ -0,0 +1,1567 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::thread::{self, JoinHandle};
+use tracing::{debug, info, warn};
+
+use crate::clock::ClockSkew;
+use crate::crash_faults::{CrashFaultSpec, FaultId, FsOperation};
+use crate::crash_report::CrashReport;
+use crate::lazyfs::LazyFS;
+use crate::pagecache::cache::NotCached;
+use crate::pagecache::config::{Fault, InjectionSpec, QuotaFault, QuotaMode};
+use crate::pagecache::item::stats::StatMetric;
+use crate::path_matcher::{MatchOptions, PathMatcher};
+use crate::self_test;
+use crate::startup;
+use crate::TRACING_TARGET;
+
+const COMMAND_PREFIX: &str = "lazyfs::";
+
+/// Names listed by `lazyfs::help`, batches included
+const COMMANDS: &[&str] = &[
+    "clear-cache",
+    "display-cache-usage",
+    "unsynced-data-report",
+    "help",
+    "sync-file",
+    "sync-prefix",
+    "unsynced",
+    "top",
+    "self-test",
+    "dry-run",
+    "snapshot",
+    "lock-stats",
+    "quota",
+    "crash",
+    "torn-op",
+    "torn-seq",
+    "test-match",
+    "fence-writes",
+    "unfence-writes",
+    "quarantine-retry",
+    "quarantine-drop",
+    "invalidate",
+    "op-limit",
+    "queue-stats",
+    "mark",
+    "diff",
+    "evictions",
+    "clock-skew",
+    "begin",
+    "commit",
+    "abort",
+    "batch",
+];
+
+/// Control commands accepted on the FIFO, one per line
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    /// `lazyfs::clear-cache`, drops everything cached as a crash would
+    ClearCache,
+    /// `lazyfs::display-cache-usage`, share of the cache pages in use
+    DisplayCacheUsage,
+    /// `lazyfs::unsynced-data-report`, dirty bytes of each owner
+    UnsyncedDataReport,
+    /// `lazyfs::help`, the commands understood
+    Help,
+    /// `lazyfs::sync-file:<path>`
+    SyncFile(PathBuf),
+    /// `lazyfs::sync-prefix:<dir>`
+    SyncPrefix(PathBuf),
+    /// `lazyfs::unsynced:<dir>`, dirty bytes per directory right below `dir`
+    Unsynced(PathBuf),
+    /// `lazyfs::top:<n>:<metric>`
+    Top(usize, StatMetric),
+    /// `lazyfs::self-test:<dir>`, runs the cache round trip with a temp file in `dir`
+    SelfTest(PathBuf),
+    /// `lazyfs::dry-run:on|off`
+    DryRun(bool),
+    /// `lazyfs::snapshot::path=<file>::target=<file>`, writes a consistent copy of `path` as
+    /// seen through the cache to `target`
+    Snapshot { path: PathBuf, target: PathBuf },
+    /// `lazyfs::lock-stats`, lock contention per site (needs the `lock-diagnostics` feature)
+    LockStats,
+    /// `lazyfs::quota::path=<regex>::budget=<bytes>[::mode=enospc|short-write]`, plus the
+    /// match options below
+    Quota {
+        path_regex: String,
+        budget: u64,
+        mode: QuotaMode,
+        options: MatchOptions,
+    },
+    /// `lazyfs::crash::op=<op>::timing=before|after::path=<regex>[::mode=kill|clear-cache|freeze]
+    /// [::occurrence=<n>]`, plus the match options. `from_rgx` is accepted for `path`.
+    Crash(CrashFaultSpec),
+    /// `lazyfs::torn-op::file=<path>::persist=<i,j,..>::parts=<n>|parts_bytes=<a,b,..>
+    /// [::occurrence=<n>]`, a split write, or
+    /// `lazyfs::torn-seq::op=<op>::file=<path>::persist=<i,j,..>[::occurrence=<n>]`, a reorder
+    Inject(InjectionSpec),
+    /// `lazyfs::test-match::path=<path>::pattern=<regex>[::case-insensitive=true]
+    /// [::normalize=nfc|nfd|none]`, shows how a fault pattern would see `path`
+    TestMatch {
+        path: PathBuf,
+        pattern: String,
+        options: MatchOptions,
+    },
+    /// `lazyfs::fence-writes`, holds writes, truncates and renames until `unfence-writes` or
+    /// `fence_max_ms`
+    FenceWrites,
+    /// `lazyfs::unfence-writes`
+    UnfenceWrites,
+    /// `lazyfs::quarantine-retry:<path>`, syncs an owner whose sync failed without waiting out
+    /// its backoff
+    QuarantineRetry(PathBuf),
+    /// `lazyfs::quarantine-drop:<path>`, discards what an owner whose sync failed never synced
+    QuarantineDrop(PathBuf),
+    /// `lazyfs::invalidate:<path>`, drops the clean cached blocks of `path` so they are read
+    /// from the backing file again, as if it had changed outside the mount
+    Invalidate(PathBuf),
+    /// `lazyfs::op-limit::op=<op>|all::limit=<n>`, queue depth of one kind of operation or of
+    /// all of them, 0 for no limit
+    OpLimit {
+        op: Option<FsOperation>,
+        limit: usize,
+    },
+    /// `lazyfs::queue-stats`, time operations spent queued behind the op limits
+    QueueStats,
+    /// `lazyfs::mark:<label>`, records which blocks are cached and dirty under `label`
+    Mark(String),
+    /// `lazyfs::diff:<label>:<label>`, block changes between two marks as JSON, either label
+    /// can be `now`
+    Diff(String, String),
+    /// `lazyfs::evictions`, the latest pages taken from their owners, oldest first
+    Evictions,
+    /// `lazyfs::clock-skew:<+/-duration>`, offset of the times stamped into files from now on,
+    /// like `-90s`, `+1500ms` or `0`
+    ClockSkew(ClockSkew),
+}
+
+/// Parses a comma separated `1,3,5` list
+fn parse_list(list: &str) -> Result<Vec<i32>> {
+    list.split(',')
+        .map(|n| {
+            n.trim()
+                .parse()
+                .map_err(|_| anyhow!("'{}' is not a list of numbers", list))
+        })
+        .collect()
+}
+
+/// Splits `key=value::key=value` arguments
+fn parse_keyed_args(arg: &str) -> Result<HashMap<&str, &str>> {
+    arg.split("::")
+        .map(|pair| {
+            pair.split_once('=')
+                .ok_or_else(|| anyhow!("Expected key=value, found '{}'", pair))
+        })
+        .collect()
+}
+
+/// `case-insensitive=true|false` and `normalize=<form>` out of keyed arguments
+fn parse_match_options(args: &HashMap<&str, &str>) -> Result<MatchOptions> {
+    let mut options = MatchOptions::default();
+    if let Some(case_insensitive) = args.get("case-insensitive") {
+        options.case_insensitive = case_insensitive.parse()?;
+    }
+    if let Some(normalize) = args.get("normalize") {
+        options.normalize = normalize.parse()?;
+    }
+    Ok(options)
+}
+
+impl FromStr for Command {
+    type Err = anyhow::Error;
+
+    fn from_str(line: &str) -> Result<Self> {
+        let line = line.trim();
+        let body = line
+            .strip_prefix(COMMAND_PREFIX)
+            .ok_or_else(|| anyhow!("Unknown command '{}'", line))?;
+        let (name, arg) = body.split_once(':').unwrap_or((body, ""));
+
+        let path_arg = || {
+            if arg.is_empty() {
+                Err(anyhow!("Command '{}' expects a path", name))
+            } else {
+                Ok(PathBuf::from(arg))
+            }
+        };
+
+        match name {
+            "clear-cache" => Ok(Command::ClearCache),
+            "display-cache-usage" => Ok(Command::DisplayCacheUsage),
+            "unsynced-data-report" => Ok(Command::UnsyncedDataReport),
+            "help" => Ok(Command::Help),
+            "sync-file" => Ok(Command::SyncFile(path_arg()?)),
+            "sync-prefix" => Ok(Command::SyncPrefix(path_arg()?)),
+            "unsynced" => Ok(Command::Unsynced(path_arg()?)),
+            "self-test" => Ok(Command::SelfTest(path_arg()?)),
+            "quarantine-retry" => Ok(Command::QuarantineRetry(path_arg()?)),
+            "quarantine-drop" => Ok(Command::QuarantineDrop(path_arg()?)),
+            "invalidate" => Ok(Command::Invalidate(path_arg()?)),
+            "dry-run" => match arg {
+                "on" => Ok(Command::DryRun(true)),
+                "off" => Ok(Command::DryRun(false)),
+                _ => Err(anyhow!("Command 'dry-run' expects on or off")),
+            },
+            "snapshot" => {
+                let args = parse_keyed_args(arg.strip_prefix(':').unwrap_or(arg))?;
+                let path_of = |key| {
+                    args.get(key)
+                        .map(PathBuf::from)
+                        .ok_or_else(|| anyhow!("Command 'snapshot' expects {}=<file>", key))
+                };
+                Ok(Command::Snapshot {
+                    path: path_of("path")?,
+                    target: path_of("target")?,
+                })
+            }
+            "lock-stats" => Ok(Command::LockStats),
+            "fence-writes" => Ok(Command::FenceWrites),
+            "unfence-writes" => Ok(Command::UnfenceWrites),
+            "queue-stats" => Ok(Command::QueueStats),
+            "evictions" => Ok(Command::Evictions),
+            "clock-skew" => Ok(Command::ClockSkew(arg.parse()?)),
+            "mark" if !arg.is_empty() => Ok(Command::Mark(arg.to_string())),
+            "mark" => Err(anyhow!("Command 'mark' expects a label")),
+            "diff" => {
+                let (from, to) = arg
+                    .split_once(':')
+                    .ok_or_else(|| anyhow!("Command 'diff' expects <label>:<label>"))?;
+                Ok(Command::Diff(from.to_string(), to.to_string()))
+            }
+            "op-limit" => {
+                let args = parse_keyed_args(arg.strip_prefix(':').unwrap_or(arg))?;
+                let op = match args.get("op").copied() {
+                    Some("all") => None,
+                    Some(op) => Some(op.parse()?),
+                    None => return Err(anyhow!("Command 'op-limit' expects op=<op>|all")),
+                };
+                let limit = args
+                    .get("limit")
+                    .ok_or_else(|| anyhow!("Command 'op-limit' expects limit=<n>"))?
+                    .parse()?;
+                Ok(Command::OpLimit { op, limit })
+            }
+            "top" => {
+                let (n, metric) = arg
+                    .split_once(':')
+                    .ok_or_else(|| anyhow!("Command 'top' expects <n>:<metric>"))?;
+                Ok(Command::Top(n.parse()?, metric.parse()?))
+            }
+            "quota" => {
+                let args = parse_keyed_args(arg.strip_prefix(':').unwrap_or(arg))?;
+                let path_regex = args
+                    .get("path")
+                    .ok_or_else(|| anyhow!("Command 'quota' expects path=<regex>"))?
+                    .to_string();
+                let budget = args
+                    .get("budget")
+                    .ok_or_else(|| anyhow!("Command 'quota' expects budget=<bytes>"))?
+                    .parse()?;
+                let mode = match args.get("mode").copied() {
+                    None | Some("enospc") => QuotaMode::NoSpace,
+                    Some("short-write") => QuotaMode::ShortWrite,
+                    Some(mode) => return Err(anyhow!("Unknown quota mode '{}'", mode)),
+                };
+                Ok(Command::Quota {
+                    path_regex,
+                    budget,
+                    mode,
+                    options: parse_match_options(&args)?,
+                })
+            }
+            "crash" => {
+                let args = parse_keyed_args(arg.strip_prefix(':').unwrap_or(arg))?;
+                let arg_of = |key, what| {
+                    args.get(key)
+                        .copied()
+                        .ok_or_else(|| anyhow!("Command 'crash' expects {}={}", key, what))
+                };
+                // The original LazyFS calls the path pattern `from_rgx`
+                let path = match args.get("from_rgx") {
+                    Some(path) => path,
+                    None => arg_of("path", "<regex>")?,
+                };
+                let matcher = PathMatcher::new(path, parse_match_options(&args)?)?;
+                let mut spec = CrashFaultSpec::new(
+                    arg_of("op", "<op>")?.parse()?,
+                    arg_of("timing", "before|after")?.parse()?,
+                    matcher,
+                );
+                if let Some(mode) = args.get("mode") {
+                    spec = spec.with_mode(mode.parse()?);
+                }
+                if let Some(occurrence) = args.get("occurrence") {
+                    spec = spec.with_occurrence(occurrence.parse()?);
+                }
+                Ok(Command::Crash(spec))
+            }
+            "torn-op" | "torn-seq" => {
+                let args = parse_keyed_args(arg.strip_prefix(':').unwrap_or(arg))?;
+                let arg_of = |key, what| {
+                    args.get(key)
+                        .copied()
+                        .ok_or_else(|| anyhow!("Command '{}' expects {}={}", name, key, what))
+                };
+                let file = arg_of("file", "<path>")?.to_string();
+                let persist = parse_list(arg_of("persist", "<i,j,..>")?)?;
+                let occurrence = match args.get("occurrence") {
+                    Some(occurrence) => occurrence.parse()?,
+                    None => 1,
+                };
+                let spec = if name == "torn-op" {
+                    InjectionSpec::SplitWrite {
+                        file,
+                        occurrence,
+                        parts: args.get("parts").map(|n| n.parse()).transpose()?,
+                        parts_bytes: args.get("parts_bytes").map(|l| parse_list(l)).transpose()?,
+                        sector_torn: None,
+                        persist,
+                    }
+                } else {
+                    InjectionSpec::Reorder {
+                        file,
+                        op: arg_of("op", "<op>")?.to_string(),
+                        occurrence,
+                        persist,
+                    }
+                };
+                // Built once here so that a batch holding a bad fault applies nothing
+                spec.to_fault()?;
+                Ok(Command::Inject(spec))
+            }
+            "test-match" => {
+                let args = parse_keyed_args(arg.strip_prefix(':').unwrap_or(arg))?;
+                let path = args
+                    .get("path")
+                    .ok_or_else(|| anyhow!("Command 'test-match' expects path=<path>"))?;
+                let pattern = args
+                    .get("pattern")
+                    .ok_or_else(|| anyhow!("Command 'test-match' expects pattern=<regex>"))?;
+                Ok(Command::TestMatch {
+                    path: PathBuf::from(path),
+                    pattern: pattern.to_string(),
+                    options: parse_match_options(&args)?,
+                })
+            }
+            _ => Err(anyhow!("Unknown command '{}'", line)),
+        }
+    }
+}
+
+/// How to take back the effect of a command when a batch it was part of fails
+enum Undo {
+    RemoveQuotaFault(Arc<QuotaFault>),
+    RemoveCrashFault(FaultId),
+    RemoveFault(String, Arc<dyn Fault>),
+    SetDryRun(bool),
+    UnfenceWrites,
+    SetOpLimit(Option<FsOperation>, usize),
+    SetClockSkew(ClockSkew),
+}
+
+impl Undo {
+    fn apply(self, lazyfs: &LazyFS) -> Result<()> {
+        match self {
+            Undo::RemoveQuotaFault(fault) => lazyfs.remove_quota_fault(&fault).map(|_| ()),
+            Undo::RemoveCrashFault(id) => lazyfs.remove_crash_fault(id).map(|_| ()),
+            Undo::RemoveFault(file, fault) => lazyfs.remove_fault(&file, &fault).map(|_| ()),
+            Undo::SetDryRun(dry_run) => {
+                lazyfs.set_dry_run(dry_run);
+                Ok(())
+            }
+            Undo::UnfenceWrites => lazyfs.unfence_writes().map(|_| ()),
+            Undo::SetOpLimit(op, limit) => lazyfs.set_op_limit(op, limit).map(|_| ()),
+            Undo::SetClockSkew(skew) => {
+                lazyfs.cache().set_clock_skew(skew);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Command {
+    pub fn execute(&self, lazyfs: &LazyFS) -> Result<String> {
+        self.execute_undoable(lazyfs).map(|(msg, _)| msg)
+    }
+
+    fn execute_undoable(&self, lazyfs: &LazyFS) -> Result<(String, Option<Undo>)> {
+        let cache = lazyfs.cache();
+        match self {
+            Command::ClearCache => {
+                cache.clear_cache()?;
+                Ok(("cache cleared".to_string(), None))
+            }
+            Command::DisplayCacheUsage => Ok((
+                format!("cache usage: {:.2}%", cache.get_cache_usage()?),
+                None,
+            )),
+            Command::UnsyncedDataReport => {
+                let (unsynced, omitted) = CrashReport::summarize(&cache.report_unsynced_data()?);
+                let entries: Vec<_> = unsynced
+                    .iter()
+                    .map(|owner| format!("{}={}", owner.owner, owner.dirty_bytes))
+                    .collect();
+                Ok((
+                    format!(
+                        "unsynced data: {} ({} owners omitted)",
+                        entries.join(" "),
+                        omitted
+                    ),
+                    None,
+                ))
+            }
+            Command::Help => Ok((format!("commands: {}", COMMANDS.join(" ")), None)),
+            Command::SyncFile(path) => {
+                let bytes = cache.sync_file(path.clone())?;
+                Ok((format!("synced {} bytes", bytes), None))
+            }
+            Command::SyncPrefix(dir) => {
+                let (files, bytes) = cache.sync_prefix(dir.clone())?;
+                Ok((format!("synced {} bytes in {} files", bytes, files), None))
+            }
+            Command::QuarantineRetry(path) => {
+                let bytes = cache.retry_quarantined(path.clone())?;
+                Ok((format!("synced {} bytes", bytes), None))
+            }
+            Command::QuarantineDrop(path) => {
+                let bytes = cache.drop_quarantined(path.clone())?;
+                Ok((format!("dropped {} unsynced bytes", bytes), None))
+            }
+            Command::Invalidate(path) => {
+                let owner = cache
+                    .get_original_inode(path.clone())?
+                    .ok_or_else(|| NotCached(path.display().to_string()))?;
+                cache.invalidate_owner(owner)?;
+                Ok((format!("invalidated {}", path.display()), None))
+            }
+            Command::Unsynced(dir) => {
+                let entries: Vec<_> = cache
+                    .unsynced_by_prefix(dir)?
+                    .iter()
+                    .map(|(dir, bytes)| format!("{}={}", dir.display(), bytes))
+                    .collect();
+                Ok((format!("unsynced bytes: {}", entries.join(" ")), None))
+            }
+            Command::Top(n, metric) => {
+                let top = cache.top_owners(*metric, *n)?;
+                let entries: Vec<_> = top
+                    .iter()
+                    .map(|(owner, paths, value)| {
+                        let paths: Vec<_> = paths.iter().map(|p| p.display().to_string()).collect();
+                        format!("{} [{}]={}", owner, paths.join(","), value)
+                    })
+                    .collect();
+                Ok((
+                    format!("top {} by {}: {}", n, metric, entries.join(" ")),
+                    None,
+                ))
+            }
+            Command::SelfTest(root) => {
+                let report = self_test::run(cache, lazyfs.config(), root);
+                if !report.passed() {
+                    return Err(anyhow!("{}", report));
+                }
+                Ok((report.to_string(), None))
+            }
+            Command::DryRun(dry_run) => {
+                let previous = lazyfs.set_dry_run(*dry_run);
+                let state = if *dry_run { "on" } else { "off" };
+                Ok((
+                    format!("dry-run {}", state),
+                    Some(Undo::SetDryRun(previous)),
+                ))
+            }
+            Command::Snapshot { path, target } => {
+                let snapshot = match cache.get_original_inode(path.clone())? {
+                    Some(owner) => match cache.read_consistent_to(owner, path.clone(), target) {
+                        Err(e) if e.is::<NotCached>() => None,
+                        res => Some(res?),
+                    },
+                    None => None,
+                };
+                // Nothing cached, the backing file is all there is
+                let bytes = match snapshot {
+                    Some(bytes) => bytes,
+                    None => fs::copy(path, target)?,
+                };
+                Ok((
+                    format!("wrote {} bytes to {}", bytes, target.display()),
+                    None,
+                ))
+            }
+            Command::LockStats => {
+                if cfg!(not(feature = "lock-diagnostics")) {
+                    return Err(anyhow!("lazyfs was built without lock-diagnostics"));
+                }
+                let entries: Vec<_> = cache
+                    .lock_stats()
+                    .iter()
+                    .map(|s| {
+                        format!(
+                            "{} acquisitions={} wait_us={} max_wait_us={} max_hold_us={}",
+                            s.site,
+                            s.acquisitions,
+                            s.total_wait.as_micros(),
+                            s.max_wait.as_micros(),
+                            s.max_hold.as_micros()
+                        )
+                    })
+                    .collect();
+                Ok((format!("lock stats: {}", entries.join("; ")), None))
+            }
+            Command::FenceWrites => {
+                lazyfs.fence_writes()?;
+                Ok(("writes fenced".to_string(), Some(Undo::UnfenceWrites)))
+            }
+            Command::UnfenceWrites => {
+                let held = lazyfs.unfence_writes()?;
+                Ok((
+                    format!("writes unfenced after {} ms", held.as_millis()),
+                    None,
+                ))
+            }
+            Command::OpLimit { op, limit } => {
+                let previous = lazyfs.set_op_limit(*op, *limit)?;
+                Ok((
+                    format!(
+                        "{} limit {} (was {})",
+                        op.map_or("all", |op| op.as_str()),
+                        limit,
+                        previous
+                    ),
+                    Some(Undo::SetOpLimit(*op, previous)),
+                ))
+            }
+            Command::QueueStats => {
+                let entries: Vec<_> = lazyfs
+                    .queue_wait_stats()?
+                    .iter()
+                    .map(|(op, s)| {
+                        format!(
+                            "{} admitted={} queued={} wait_us={} max_wait_us={}",
+                            op.as_str(),
+                            s.admitted,
+                            s.queued,
+                            s.total_wait.as_micros(),
+                            s.max_wait.as_micros()
+                        )
+                    })
+                    .collect();
+                Ok((format!("queue stats: {}", entries.join("; ")), None))
+            }
+            Command::Mark(label) => {
+                let blocks = lazyfs.mark(label)?;
+                Ok((format!("marked {} with {} blocks", label, blocks), None))
+            }
+            Command::Diff(from, to) => Ok((lazyfs.diff_marks(from, to)?.to_json(), None)),
+            Command::Evictions => {
+                let records: Vec<_> = lazyfs
+                    .cache()
+                    .eviction_history()?
+                    .iter()
+                    .map(|record| record.to_string())
+                    .collect();
+                Ok((format!("evictions: {}", records.join("; ")), None))
+            }
+            Command::ClockSkew(skew) => {
+                let previous = lazyfs.cache().set_clock_skew(*skew);
+                Ok((
+                    format!("clock skew {} (was {})", skew, previous),
+                    Some(Undo::SetClockSkew(previous)),
+                ))
+            }
+            Command::Crash(spec) => {
+                let registration = lazyfs.register_crash_fault(spec.clone())?;
+                Ok((
+                    registration.to_string(),
+                    Some(Undo::RemoveCrashFault(registration.id)),
+                ))
+            }
+            Command::Inject(spec) => {
+                let fault = spec.to_fault()?;
+                lazyfs.add_fault(spec.file(), fault.clone())?;
+                Ok((
+                    format!("armed {} on {}", fault.spec(), spec.file()),
+                    Some(Undo::RemoveFault(spec.file().to_string(), fault)),
+                ))
+            }
+            Command::TestMatch {
+                path,
+                pattern,
+                options,
+            } => {
+                let matcher = PathMatcher::new(pattern, *options)?;
+                Ok((
+                    format!(
+                        "pattern={:?} path={:?} matched={}",
+                        matcher.pattern(),
+                        matcher.normalize(path),
+                        matcher.is_match(path)
+                    ),
+                    None,
+                ))
+            }
+            Command::Quota {
+                path_regex,
+                budget,
+                mode,
+                options,
+            } => {
+                let fault =
+                    QuotaFault::new(path_regex, *budget, *mode)?.with_match_options(*options)?;
+                let fault = lazyfs.add_quota_fault(fault)?;
+                Ok((
+                    format!("armed quota of {} bytes", budget),
+                    Some(Undo::RemoveQuotaFault(fault)),
+                ))
+            }
+        }
+    }
+}
+
+/// Parses and runs a single command line, returning the message for the completion FIFO.
+/// Failures are reported there as well so the sender doesn't have to dig through the log.
+pub fn run(line: &str, lazyfs: &LazyFS) -> String {
+    let line = line.trim();
+    match line.parse::<Command>().and_then(|cmd| cmd.execute(lazyfs)) {
+        Ok(msg) => format!("{} ok: {}", line, msg),
+        Err(e) => format!("{} error: {}", line, e),
+    }
+}
+
+/// Parses every line up front and runs them in order, with every handler kept out until the
+/// last one is done. If one fails, whatever the earlier ones installed is undone in reverse
+/// order.
+pub fn run_batch(lines: &[String], lazyfs: &LazyFS) -> String {
+    let mut commands = Vec::with_capacity(lines.len());
+    for line in lines {
+        match line.parse::<Command>() {
+            Ok(cmd) => commands.push(cmd),
+            Err(e) => {
+                return format!(
+                    "batch error: nothing applied, '{}' is invalid: {}",
+                    line.trim(),
+                    e
+                )
+            }
+        }
+    }
+
+    let _control = match lazyfs.begin_control() {
+        Ok(control) => control,
+        Err(e) => return format!("batch error: nothing applied, {}", e),
+    };
+    let mut results = Vec::with_capacity(lines.len());
+    let mut undos = Vec::new();
+    for (line, cmd) in lines.iter().zip(&commands) {
+        match cmd.execute_undoable(lazyfs) {
+            Ok((msg, undo)) => {
+                results.push(format!("{} ok: {}", line.trim(), msg));
+                undos.extend(undo);
+            }
+            Err(e) => {
+                results.push(format!("{} error: {}", line.trim(), e));
+                for undo in undos.into_iter().rev() {
+                    if let Err(e) = undo.apply(lazyfs) {
+                        results.push(format!("rollback error: {}", e));
+                    }
+                }
+                return format!("batch error: rolled back [{}]", results.join("; "));
+            }
+        }
+    }
+
+    format!("batch ok: [{}]", results.join("; "))
+}
+
+/// Splits the commands of `lazyfs::batch:[...]` at every `;` but those written `\;`, which
+/// stand for a `;` inside a command, such as in a path regex
+fn split_batch(batch: &str) -> Vec<String> {
+    let mut lines = vec![String::new()];
+    let mut chars = batch.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&';') => {
+                lines.last_mut().unwrap().push(';');
+                chars.next();
+            }
+            ';' => lines.push(String::new()),
+            c => lines.last_mut().unwrap().push(c),
+        }
+    }
+    lines
+}
+
+/// Control plane state of one command stream. Commands between `lazyfs::begin` and
+/// `lazyfs::commit` are queued and applied together, `lazyfs::abort` drops them.
+/// `lazyfs::batch:[cmd1;cmd2;...]` does the same on a single line, see `split_batch`. The
+/// session is driven by a single reader, so a batch never interleaves with other commands, and
+/// `run_batch` keeps the handlers out while it is applied.
+#[derive(Debug, Default)]
+pub struct Session {
+    pending: Option<Vec<String>>,
+}
+
+impl Session {
+    pub fn handle(&mut self, line: &str, lazyfs: &LazyFS) -> String {
+        let line = line.trim();
+        match line {
+            "lazyfs::begin" => {
+                if self.pending.is_some() {
+                    return format!("{} error: a batch is already open", line);
+                }
+                self.pending = Some(Vec::new());
+                format!("{} ok: batch started", line)
+            }
+            "lazyfs::commit" => match self.pending.take() {
+                Some(lines) => run_batch(&lines, lazyfs),
+                None => format!("{} error: no batch is open", line),
+            },
+            "lazyfs::abort" => match self.pending.take() {
+                Some(lines) => format!("{} ok: discarded {} commands", line, lines.len()),
+                None => format!("{} error: no batch is open", line),
+            },
+            _ => {
+                if let Some(batch) = line
+                    .strip_prefix("lazyfs::batch:[")
+                    .and_then(|rest| rest.strip_suffix(']'))
+                {
+                    return run_batch(&split_batch(batch), lazyfs);
+                }
+                match self.pending.as_mut() {
+                    Some(pending) => {
+                        pending.push(line.to_string());
+                        format!("{} ok: queued", line)
+                    }
+                    None => run(line, lazyfs),
+                }
+            }
+        }
+    }
+}
+
+/// Reads commands from `fifo_path` on a thread of its own, one per line, and writes the
+/// result of each to `fifo_path_completed` once it is done. The thread only holds on to
+/// `LazyFS` while a command runs, and stops when the listener is dropped.
+#[derive(Debug)]
+pub struct Listener {
+    stop: Arc<AtomicBool>,
+    /// The FIFO the thread reads, written to wake it up
+    wake: File,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Listener {
+    /// Creates the command FIFO if it doesn't exist yet. No completions are written if
+    /// `completed` is empty.
+    pub fn spawn(lazyfs: Weak<LazyFS>, fifo: &Path, completed: &Path) -> Result<Self> {
+        match fs::symlink_metadata(fifo) {
+            Ok(meta) if meta.file_type().is_fifo() => {}
+            Ok(_) => return Err(anyhow!("{} is not a fifo", fifo.display())),
+            Err(e) if e.kind() == ErrorKind::NotFound => startup::mkfifo(fifo)?,
+            Err(e) => return Err(e.into()),
+        }
+        // Opened for writing too, so that it never reads end of file between two senders
+        let commands = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(fifo)
+            .map_err(|e| anyhow!("Unable to open fifo {}: {}", fifo.display(), e))?;
+        let wake = commands.try_clone()?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = Arc::clone(&stop);
+            let completed = completed.to_path_buf();
+            thread::Builder::new()
+                .name("lazyfs-commands".to_string())
+                .spawn(move || listen(lazyfs, commands, &completed, &stop))?
+        };
+        info!(target: TRACING_TARGET, fifo = %fifo.display(), "listening for commands");
+        Ok(Listener {
+            stop,
+            wake,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Err(e) = self.wake.write_all(b"\n") {
+            warn!(target: TRACING_TARGET, "Failed to wake the command listener: {:?}", e);
+            return;
+        }
+        let thread = match self.thread.take() {
+            Some(thread) => thread,
+            None => return,
+        };
+        // The listener itself may be the one letting go of `LazyFS` last
+        if thread.thread().id() != thread::current().id() && thread.join().is_err() {
+            warn!(target: TRACING_TARGET, "command listener panicked");
+        }
+    }
+}
+
+fn listen(lazyfs: Weak<LazyFS>, commands: File, completed: &Path, stop: &AtomicBool) {
+    let mut session = Session::default();
+    for line in BufReader::new(commands).lines() {
+        if stop.load(Ordering::SeqCst) {
+            break;
+        }
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                warn!(target: TRACING_TARGET, "Failed to read command: {:?}", e);
+                break;
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let msg = match lazyfs.upgrade() {
+            Some(lazyfs) => session.handle(&line, &lazyfs),
+            None => break,
+        };
+        info!(target: TRACING_TARGET, command = line.trim(), result = msg, "ran command");
+        if let Err(e) = complete(completed, &msg) {
+            warn!(target: TRACING_TARGET, "Failed to report command completion: {:?}", e);
+        }
+    }
+    debug!(target: TRACING_TARGET, "command listener stopped");
+}
+
+/// Writes `msg` to the completion FIFO. Nobody reading it isn't an error, the line is dropped
+/// rather than holding up the next command.
+fn complete(completed: &Path, msg: &str) -> Result<()> {
+    if completed.as_os_str().is_empty() {
+        return Ok(());
+    }
+    let mut fifo = match OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(completed)
+    {
+        Ok(fifo) => fifo,
+        Err(e) if e.raw_os_error() == Some(libc::ENXIO) => {
+            debug!(target: TRACING_TARGET, msg, "no reader for the command completion");
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    fifo.write_all(format!("{}\n", msg).as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache_diff;
+    use crate::crash_faults::{CrashMode, CrashTiming};
+    use crate::fence::FenceMode;
+    use crate::pagecache::config::Config;
+    use crate::pagecache::engine::AllocateOperationType;
+    use crate::path_matcher::Normalization;
+    use std::time::Duration;
+
+    fn new_lazyfs() -> Arc<LazyFS> {
+        new_lazyfs_with_config(Config::default())
+    }
+
+    fn new_lazyfs_with_config(config: Config) -> Arc<LazyFS> {
+        LazyFS::builder().config(config).build().unwrap()
+    }
+
+    fn quotas(lazyfs: &LazyFS) -> usize {
+        lazyfs.fault_status().unwrap().len()
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(
+            "lazyfs::sync-file:/data/wal\n".parse::<Command>().unwrap(),
+            Command::SyncFile("/data/wal".into())
+        );
+        assert_eq!(
+            "lazyfs::sync-prefix:/data".parse::<Command>().unwrap(),
+            Command::SyncPrefix("/data".into())
+        );
+        assert!("lazyfs::sync-file".parse::<Command>().is_err());
+        assert_eq!(
+            "lazyfs::quarantine-retry:/data/wal"
+                .parse::<Command>()
+                .unwrap(),
+            Command::QuarantineRetry(PathBuf::from("/data/wal"))
+        );
+        assert_eq!(
+            "lazyfs::quarantine-drop:/data/wal"
+                .parse::<Command>()
+                .unwrap(),
+            Command::QuarantineDrop(PathBuf::from("/data/wal"))
+        );
+        assert!("lazyfs::quarantine-drop".parse::<Command>().is_err());
+        assert_eq!(
+            "lazyfs::invalidate:/data/wal".parse::<Command>().unwrap(),
+            Command::Invalidate(PathBuf::from("/data/wal"))
+        );
+        assert!("lazyfs::sync-everything:/".parse::<Command>().is_err());
+        assert!("sync-file:/data/wal".parse::<Command>().is_err());
+        assert_eq!(
+            "lazyfs::top:5:bytes-written".parse::<Command>().unwrap(),
+            Command::Top(5, StatMetric::BytesWritten)
+        );
+        assert!("lazyfs::top:5".parse::<Command>().is_err());
+        assert_eq!(
+            "lazyfs::unsynced:/data".parse::<Command>().unwrap(),
+            Command::Unsynced("/data".into())
+        );
+        assert_eq!(
+            "lazyfs::dry-run:on".parse::<Command>().unwrap(),
+            Command::DryRun(true)
+        );
+        assert!("lazyfs::dry-run:maybe".parse::<Command>().is_err());
+        assert_eq!(
+            "lazyfs::snapshot::path=/data/db::target=/tmp/db.snap"
+                .parse::<Command>()
+                .unwrap(),
+            Command::Snapshot {
+                path: "/data/db".into(),
+                target: "/tmp/db.snap".into(),
+            }
+        );
+        assert!("lazyfs::snapshot::path=/data/db"
+            .parse::<Command>()
+            .is_err());
+        assert_eq!(
+            "lazyfs::lock-stats".parse::<Command>().unwrap(),
+            Command::LockStats
+        );
+        assert_eq!(
+            "lazyfs::self-test:/mnt/backing".parse::<Command>().unwrap(),
+            Command::SelfTest("/mnt/backing".into())
+        );
+        assert!("lazyfs::top:five:reads".parse::<Command>().is_err());
+        assert!("lazyfs::top:5:latency".parse::<Command>().is_err());
+        assert_eq!(
+            "lazyfs::quota::path=wal.*::budget=4096::mode=short-write"
+                .parse::<Command>()
+                .unwrap(),
+            Command::Quota {
+                path_regex: "wal.*".to_string(),
+                budget: 4096,
+                mode: QuotaMode::ShortWrite,
+                options: MatchOptions::default(),
+            }
+        );
+        assert_eq!(
+            "lazyfs::quota::path=WAL::budget=1::case-insensitive=true::normalize=nfd"
+                .parse::<Command>()
+                .unwrap(),
+            Command::Quota {
+                path_regex: "WAL".to_string(),
+                budget: 1,
+                mode: QuotaMode::NoSpace,
+                options: MatchOptions {
+                    case_insensitive: true,
+                    normalize: Normalization::Nfd,
+                },
+            }
+        );
+        assert_eq!(
+            "lazyfs::crash::op=fsync::timing=after::path=^/data/wal$"
+                .parse::<Command>()
+                .unwrap(),
+            Command::Crash(CrashFaultSpec::new(
+                FsOperation::Fsync,
+                CrashTiming::After,
+                PathMatcher::new("^/data/wal$", MatchOptions::default()).unwrap(),
+            ))
+        );
+        assert_eq!(
+            "lazyfs::crash::op=write::timing=before::path=wal::mode=clear-cache::occurrence=2"
+                .parse::<Command>()
+                .unwrap(),
+            Command::Crash(
+                CrashFaultSpec::new(
+                    FsOperation::Write,
+                    CrashTiming::Before,
+                    PathMatcher::new("wal", MatchOptions::default()).unwrap(),
+                )
+                .with_mode(CrashMode::ClearCache)
+                .with_occurrence(2)
+            )
+        );
+        assert_eq!(
+            "lazyfs::fence-writes".parse::<Command>().unwrap(),
+            Command::FenceWrites
+        );
+        assert_eq!(
+            "lazyfs::unfence-writes".parse::<Command>().unwrap(),
+            Command::UnfenceWrites
+        );
+        assert_eq!(
+            "lazyfs::op-limit::op=write::limit=1"
+                .parse::<Command>()
+                .unwrap(),
+            Command::OpLimit {
+                op: Some(FsOperation::Write),
+                limit: 1,
+            }
+        );
+        assert_eq!(
+            "lazyfs::op-limit::op=all::limit=0"
+                .parse::<Command>()
+                .unwrap(),
+            Command::OpLimit { op: None, limit: 0 }
+        );
+        assert!("lazyfs::op-limit::op=write".parse::<Command>().is_err());
+        assert_eq!(
+            "lazyfs::queue-stats".parse::<Command>().unwrap(),
+            Command::QueueStats
+        );
+        assert_eq!(
+            "lazyfs::mark:before-crash".parse::<Command>().unwrap(),
+            Command::Mark("before-crash".to_string())
+        );
+        assert!("lazyfs::mark".parse::<Command>().is_err());
+        assert_eq!(
+            "lazyfs::diff:before-crash:now".parse::<Command>().unwrap(),
+            Command::Diff("before-crash".to_string(), "now".to_string())
+        );
+        assert!("lazyfs::diff:before-crash".parse::<Command>().is_err());
+        assert_eq!(
+            "lazyfs::evictions".parse::<Command>().unwrap(),
+            Command::Evictions
+        );
+        assert_eq!(
+            "lazyfs::clock-skew:-90s".parse::<Command>().unwrap(),
+            Command::ClockSkew(ClockSkew::from_nanos(-90_000_000_000))
+        );
+        assert!("lazyfs::clock-skew:-90".parse::<Command>().is_err());
+        assert!("lazyfs::crash::op=fsync::timing=during::path=wal"
+            .parse::<Command>()
+            .is_err());
+        assert!("lazyfs::test-match::path=/data/wal::normalize=nfkc"
+            .parse::<Command>()
+            .is_err());
+        assert!("lazyfs::quota::path=wal.*".parse::<Command>().is_err());
+        assert!("lazyfs::quota::path=wal::budget=1::mode=full"
+            .parse::<Command>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_match_shows_normalized_forms() {
+        let lazyfs = new_lazyfs();
+
+        assert_eq!(
+            run("lazyfs::test-match::path=/data/WAL::pattern=wal$", &lazyfs),
+            "lazyfs::test-match::path=/data/WAL::pattern=wal$ ok: \
+             pattern=\"wal$\" path=\"/data/WAL\" matched=false"
+        );
+        assert!(run(
+            "lazyfs::test-match::path=/data/WAL::pattern=wal$::case-insensitive=true",
+            &lazyfs
+        )
+        .ends_with("matched=true"));
+
+        // Composed pattern against a decomposed name, combining marks are shown escaped
+        let line = "lazyfs::test-match::path=/data/cafe\u{301}::pattern=caf\u{e9}::normalize=nfd";
+        assert_eq!(
+            run(line, &lazyfs),
+            format!(
+                "{} ok: pattern=\"cafe\\u{{301}}\" path=\"/data/cafe\\u{{301}}\" matched=true",
+                line
+            )
+        );
+    }
+
+    #[test]
+    fn crash_faults_report_cost_and_cap() {
+        let config = Config {
+            max_crash_faults: 1,
+            ..Default::default()
+        };
+        let lazyfs = new_lazyfs_with_config(config);
+
+        let reply = run("lazyfs::crash::op=write::timing=before::path=wal", &lazyfs);
+        assert!(reply.contains("ok: crash fault 0 compiled in"));
+        assert!(reply.ends_with("1 crash patterns"));
+        assert!(
+            run("lazyfs::crash::op=mknod::timing=before::path=wal", &lazyfs)
+                .ends_with("error: Crash faults are not supported for 'mknod'")
+        );
+        assert!(
+            run("lazyfs::crash::op=write::timing=after::path=sst", &lazyfs)
+                .contains("max_crash_faults (1) reached")
+        );
+
+        let found = lazyfs
+            .crash_fault_for(
+                FsOperation::Write,
+                CrashTiming::Before,
+                Path::new("/data/wal"),
+            )
+            .unwrap();
+        assert_eq!(found.unwrap().pattern, "wal");
+    }
+
+    #[test]
+    fn fenced_writes_fail_with_eagain() {
+        let config = Config {
+            fence_mode: FenceMode::Eagain,
+            ..Default::default()
+        };
+        let lazyfs = new_lazyfs_with_config(config);
+
+        assert_eq!(
+            run("lazyfs::fence-writes", &lazyfs),
+            "lazyfs::fence-writes ok: writes fenced"
+        );
+        assert!(run("lazyfs::fence-writes", &lazyfs).ends_with("error: Writes are already fenced"));
+        let err = lazyfs.begin_mutation().unwrap_err();
+        let errno = err
+            .downcast_ref::<std::io::Error>()
+            .and_then(|e| e.raw_os_error());
+        assert_eq!(errno, Some(libc::EAGAIN));
+
+        assert!(run("lazyfs::unfence-writes", &lazyfs).contains("ok: writes unfenced after"));
+        assert!(lazyfs.begin_mutation().is_ok());
+
+        // A fence in a failed batch doesn't outlive it
+        let mut session = Session::default();
+        let reply = session.handle(
+            "lazyfs::batch:[lazyfs::fence-writes;lazyfs::sync-file:/not/cached]",
+            &lazyfs,
+        );
+        assert!(reply.starts_with("batch error: rolled back"));
+        assert!(lazyfs.begin_mutation().is_ok());
+    }
+
+    #[test]
+    fn op_limits_change_at_runtime() {
+        let lazyfs = new_lazyfs();
+
+        assert_eq!(
+            run("lazyfs::op-limit::op=write::limit=1", &lazyfs),
+            "lazyfs::op-limit::op=write::limit=1 ok: write limit 1 (was 0)"
+        );
+        drop(lazyfs.begin_op(FsOperation::Write).unwrap());
+        drop(lazyfs.begin_op(FsOperation::Write).unwrap());
+        let reply = run("lazyfs::queue-stats", &lazyfs);
+        assert!(reply.contains("write admitted=2 queued=0"), "{}", reply);
+
+        // A limit set in a failed batch doesn't outlive it
+        let mut session = Session::default();
+        let reply = session.handle(
+            "lazyfs::batch:[lazyfs::op-limit::op=all::limit=1;lazyfs::sync-file:/not/cached]",
+            &lazyfs,
+        );
+        assert!(reply.starts_with("batch error: rolled back"));
+        assert_eq!(lazyfs.set_op_limit(None, 0).unwrap(), 0);
+    }
+
+    #[test]
+    fn diffs_marks() {
+        let lazyfs = new_lazyfs();
+
+        assert_eq!(
+            run("lazyfs::mark:empty", &lazyfs),
+            "lazyfs::mark:empty ok: marked empty with 0 blocks"
+        );
+        assert!(run("lazyfs::mark:now", &lazyfs).ends_with("error: Invalid mark label 'now'"));
+        let data = vec![1u8; 10];
+        lazyfs
+            .cache()
+            .put_data_blocks(
+                "owner".to_string(),
+                HashMap::from([(0, (&data, 0, 9))]),
+                AllocateOperationType::OpWrite,
+                None,
+            )
+            .unwrap();
+        assert_eq!(
+            run("lazyfs::diff:empty:now", &lazyfs),
+            "lazyfs::diff:empty:now ok: {\"from\":\"empty\",\"to\":\"now\",\"files\":\
+             [{\"owner\":\"owner\",\"change\":\"added\",\"dirtied\":[0]}]}"
+        );
+        assert!(
+            run("lazyfs::diff:missing:now", &lazyfs).ends_with("error: No mark named 'missing'")
+        );
+
+        // Only the most recent marks are kept
+        for i in 0..cache_diff::MAX_MARKS {
+            lazyfs.mark(&format!("mark-{}", i)).unwrap();
+        }
+        assert!(lazyfs.diff_marks("empty", "now").is_err());
+        assert!(lazyfs.diff_marks("mark-0", "now").is_ok());
+    }
+
+    #[test]
+    fn lists_evictions() {
+        let lazyfs = new_lazyfs_with_config(Config {
+            cache_nr_pages: 1,
+            apply_lru_eviction: true,
+            ..Default::default()
+        });
+        assert_eq!(
+            run("lazyfs::evictions", &lazyfs),
+            "lazyfs::evictions ok: evictions: "
+        );
+
+        let data = vec![1u8; 10];
+        for owner in ["a", "b"] {
+            lazyfs
+                .cache()
+                .put_data_blocks(
+                    owner.to_string(),
+                    HashMap::from([(0, (&data, 0, 9))]),
+                    AllocateOperationType::OpPassthrough,
+                    None,
+                )
+                .unwrap();
+        }
+        assert_eq!(
+            run("lazyfs::evictions", &lazyfs),
+            "lazyfs::evictions ok: evictions: \
+             #1 page=0 owner=a blocks=0 dirty=false reason=policy for=b"
+        );
+    }
+
+    #[test]
+    fn sets_the_clock_skew() {
+        let lazyfs = new_lazyfs_with_config(Config {
+            clock_skew: "+1s".parse().unwrap(),
+            ..Default::default()
+        });
+        assert_eq!(
+            run("lazyfs::clock-skew:-1500ms", &lazyfs),
+            "lazyfs::clock-skew:-1500ms ok: clock skew -1500ms (was +1s)"
+        );
+        assert_eq!(lazyfs.cache().clock_skew().to_string(), "-1500ms");
+    }
+
+    #[test]
+    fn parses_fault_injections() {
+        assert_eq!(
+            "lazyfs::crash::timing=after::op=write::from_rgx=wal.*"
+                .parse::<Command>()
+                .unwrap(),
+            Command::Crash(CrashFaultSpec::new(
+                FsOperation::Write,
+                CrashTiming::After,
+                PathMatcher::new("wal.*", MatchOptions::default()).unwrap(),
+            ))
+        );
+        assert_eq!(
+            "lazyfs::torn-op::file=/data/wal::persist=1,3::parts=5"
+                .parse::<Command>()
+                .unwrap(),
+            Command::Inject(InjectionSpec::SplitWrite {
+                file: "/data/wal".to_string(),
+                occurrence: 1,
+                parts: Some(5),
+                parts_bytes: None,
+                sector_torn: None,
+                persist: vec![1, 3],
+            })
+        );
+        assert_eq!(
+            "lazyfs::torn-op::file=/data/wal::persist=2::parts_bytes=512,3584::occurrence=4"
+                .parse::<Command>()
+                .unwrap(),
+            Command::Inject(InjectionSpec::SplitWrite {
+                file: "/data/wal".to_string(),
+                occurrence: 4,
+                parts: None,
+                parts_bytes: Some(vec![512, 3584]),
+                sector_torn: None,
+                persist: vec![2],
+            })
+        );
+        assert_eq!(
+            "lazyfs::torn-seq::op=write::file=/data/wal::persist=2"
+                .parse::<Command>()
+                .unwrap(),
+            Command::Inject(InjectionSpec::Reorder {
+                file: "/data/wal".to_string(),
+                op: "write".to_string(),
+                occurrence: 1,
+                persist: vec![2],
+            })
+        );
+
+        let invalid = [
+            "lazyfs::torn-op::persist=1::parts=2",
+            "lazyfs::torn-op::file=/data/wal::persist=3::parts=2",
+            "lazyfs::torn-op::file=/data/wal::persist=1,x::parts=2",
+            "lazyfs::torn-op::file=/data/wal::persist=1::parts=2::parts_bytes=1,1",
+            "lazyfs::torn-seq::file=/data/wal::persist=2",
+            "lazyfs::torn-seq::op=write::file=/data/wal::persist=2::occurrence=0",
+        ];
+        for line in invalid {
+            assert!(line.parse::<Command>().is_err(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn faults_armed_at_runtime_fire() {
+        let lazyfs = new_lazyfs();
+        let wal = Path::new("/data/wal");
+        assert!(run(
+            "lazyfs::torn-op::file=/data/wal::persist=1::parts=2::occurrence=2",
+            &lazyfs
+        )
+        .contains(" ok: armed "));
+        assert!(run(
+            "lazyfs::crash::timing=after::op=fsync::from_rgx=wal$::mode=clear-cache",
+            &lazyfs
+        )
+        .contains(" ok: "));
+        let reply = run("lazyfs::torn-seq::op=fsync::file=/data/wal", &lazyfs);
+        assert!(reply.ends_with("error: Command 'torn-seq' expects persist=<i,j,..>"));
+
+        let fired = |op| lazyfs.check_and_trigger_faults(op, wal).unwrap().len();
+        assert_eq!(fired("write"), 0);
+        assert_eq!(fired("write"), 1);
+        lazyfs.cache().insert_item("1".to_string()).unwrap();
+        let crashed = lazyfs
+            .crash_hook(FsOperation::Fsync, CrashTiming::After, wal, None)
+            .unwrap();
+        assert!(crashed.is_some());
+        assert!(!lazyfs.cache().has_content_cached("1".to_string()).unwrap());
+
+        // A failed batch takes its faults back out
+        let reply = Session::default().handle(
+            "lazyfs::batch:[lazyfs::torn-seq::op=write::file=/data/sst::persist=1;lazyfs::nope]",
+            &lazyfs,
+        );
+        assert!(reply.starts_with("batch error"));
+        assert!(lazyfs
+            .check_and_trigger_faults("write", Path::new("/data/sst"))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn reports_errors_on_completion() {
+        let lazyfs = new_lazyfs();
+
+        assert_eq!(
+            run("lazyfs::sync-file:/not/cached", &lazyfs),
+            "lazyfs::sync-file:/not/cached error: /not/cached is not cached"
+        );
+        assert_eq!(
+            run("lazyfs::sync-prefix:/nothing", &lazyfs),
+            "lazyfs::sync-prefix:/nothing ok: synced 0 bytes in 0 files"
+        );
+        assert_eq!(
+            run("lazyfs::quarantine-retry:/not/cached", &lazyfs),
+            "lazyfs::quarantine-retry:/not/cached error: /not/cached is not cached"
+        );
+    }
+
+    #[test]
+    fn invalidate_drops_clean_blocks_only() {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-invalidate", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file");
+        fs::write(&path, [b'a'; 8192]).unwrap();
+        let lazyfs = new_lazyfs();
+
+        // Block 0 cached clean by a read, block 1 dirty
+        let mut buf = vec![0; 8192];
+        lazyfs.do_read(&path, 7, 0, 4096, &mut buf).unwrap();
+        lazyfs.do_write(&path, 7, 4096, &[b'w'; 4096]).unwrap();
+        fs::write(&path, [b'z'; 8192]).unwrap();
+        lazyfs.do_read(&path, 7, 0, 8192, &mut buf).unwrap();
+        assert_eq!(buf[0], b'a');
+
+        let line = format!("lazyfs::invalidate:{}", path.display());
+        assert_eq!(
+            run(&line, &lazyfs),
+            format!("{} ok: invalidated {}", line, path.display())
+        );
+        lazyfs.do_read(&path, 7, 0, 8192, &mut buf).unwrap();
+        assert!(buf[..4096].iter().all(|&b| b == b'z'));
+        assert!(buf[4096..].iter().all(|&b| b == b'w'));
+        assert_eq!(
+            run("lazyfs::invalidate:/not/cached", &lazyfs),
+            "lazyfs::invalidate:/not/cached error: /not/cached is not cached"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn batch_commit_and_abort() {
+        let lazyfs = new_lazyfs();
+        let mut session = Session::default();
+
+        session.handle("lazyfs::begin", &lazyfs);
+        session.handle("lazyfs::quota::path=wal::budget=10", &lazyfs);
+        session.handle("lazyfs::quota::path=sst::budget=20", &lazyfs);
+        assert_eq!(quotas(&lazyfs), 0);
+        assert_eq!(
+            session.handle("lazyfs::commit", &lazyfs),
+            "batch ok: [lazyfs::quota::path=wal::budget=10 ok: armed quota of 10 bytes; \
+             lazyfs::quota::path=sst::budget=20 ok: armed quota of 20 bytes]"
+        );
+        assert_eq!(quotas(&lazyfs), 2);
+
+        session.handle("lazyfs::begin", &lazyfs);
+        session.handle("lazyfs::quota::path=log::budget=10", &lazyfs);
+        assert_eq!(
+            session.handle("lazyfs::abort", &lazyfs),
+            "lazyfs::abort ok: discarded 1 commands"
+        );
+        assert_eq!(quotas(&lazyfs), 2);
+        assert!(session
+            .handle("lazyfs::commit", &lazyfs)
+            .contains("no batch is open"));
+    }
+
+    #[test]
+    fn failed_batch_rolls_back() {
+        let lazyfs = new_lazyfs();
+        let mut session = Session::default();
+
+        // Invalid commands are caught before anything runs
+        let reply = session.handle(
+            "lazyfs::batch:[lazyfs::quota::path=wal::budget=10;lazyfs::quota::budget=1]",
+            &lazyfs,
+        );
+        assert!(reply.starts_with("batch error: nothing applied"));
+        assert_eq!(quotas(&lazyfs), 0);
+
+        // Failing midway undoes the quota armed before the failure
+        let reply = session.handle(
+            "lazyfs::batch:[lazyfs::quota::path=wal::budget=10;lazyfs::sync-file:/not/cached]",
+            &lazyfs,
+        );
+        assert!(reply.starts_with("batch error: rolled back"));
+        assert!(reply.contains("/not/cached is not cached"));
+        assert_eq!(quotas(&lazyfs), 0);
+    }
+
+    #[test]
+    fn batch_commands_escape_semicolons() {
+        assert_eq!(
+            split_batch(r"lazyfs::quota::path=a\;b::budget=10;lazyfs::help"),
+            [r"lazyfs::quota::path=a;b::budget=10", "lazyfs::help"]
+        );
+        assert_eq!(split_batch(r"a\.log$;b"), [r"a\.log$", "b"]);
+
+        let lazyfs = new_lazyfs();
+        let reply = Session::default().handle(
+            r"lazyfs::batch:[lazyfs::quota::path=a\;b::budget=10;lazyfs::quota::path=c::budget=20]",
+            &lazyfs,
+        );
+        assert!(reply.starts_with("batch ok"));
+        assert_eq!(quotas(&lazyfs), 2);
+    }
+
+    #[test]
+    fn batch_commit_keeps_handlers_out() {
+        let lazyfs = new_lazyfs();
+
+        // The commit waits for the handler already running
+        let permit = lazyfs.begin_op(FsOperation::Write).unwrap();
+        let committed = Arc::new(AtomicBool::new(false));
+        let commit = {
+            let (lazyfs, committed) = (Arc::clone(&lazyfs), Arc::clone(&committed));
+            thread::spawn(move || {
+                let lines = ["lazyfs::quota::path=wal::budget=10".to_string()];
+                let reply = run_batch(&lines, &lazyfs);
+                committed.store(true, Ordering::SeqCst);
+                reply
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!committed.load(Ordering::SeqCst));
+        assert_eq!(quotas(&lazyfs), 0);
+        drop(permit);
+        assert!(commit.join().unwrap().starts_with("batch ok"));
+
+        // And new handlers wait for the commit
+        let control = lazyfs.begin_control().unwrap();
+        let admitted = Arc::new(AtomicBool::new(false));
+        let handler = {
+            let (lazyfs, admitted) = (Arc::clone(&lazyfs), Arc::clone(&admitted));
+            thread::spawn(move || {
+                let _permit = lazyfs.begin_op(FsOperation::Write).unwrap();
+                admitted.store(true, Ordering::SeqCst);
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!admitted.load(Ordering::SeqCst));
+        drop(control);
+        handler.join().unwrap();
+        assert!(admitted.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn listener_acknowledges_commands() {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-listener", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = Config {
+            fifo_path: dir.join("faults.fifo"),
+            fifo_path_completed: dir.join("completed.fifo"),
+            ..Default::default()
+        };
+        let _ = fs::remove_file(&config.fifo_path);
+        let _ = fs::remove_file(&config.fifo_path_completed);
+        startup::mkfifo(&config.fifo_path_completed).unwrap();
+
+        let lazyfs = LazyFS::builder()
+            .config(config.clone())
+            .with_fifo_listener(true)
+            .build()
+            .unwrap();
+        assert!(lazyfs.start_command_listener().is_err());
+        lazyfs.cache().insert_item("1".to_string()).unwrap();
+
+        // Held open for writing too, so that opening it doesn't wait for the listener
+        let completed = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&config.fifo_path_completed)
+            .unwrap();
+        let mut completed = BufReader::new(completed).lines();
+        let mut send = |line: &str| {
+            let mut fifo = OpenOptions::new()
+                .write(true)
+                .open(&config.fifo_path)
+                .unwrap();
+            writeln!(fifo, "{}", line).unwrap();
+            completed.next().unwrap().unwrap()
+        };
+
+        assert_eq!(
+            send("lazyfs::clear-cache"),
+            "lazyfs::clear-cache ok: cache cleared"
+        );
+        assert!(!lazyfs.cache().has_content_cached("1".to_string()).unwrap());
+        assert_eq!(
+            send("lazyfs::display-cache-usage"),
+            "lazyfs::display-cache-usage ok: cache usage: 0.00%"
+        );
+        assert!(send("lazyfs::help").contains(" unsynced-data-report "));
+        assert!(send("lazyfs::nope").contains("error: "));
+
+        // Dropping the last reference stops the thread
+        let weak = Arc::downgrade(&lazyfs);
+        drop(lazyfs);
+        assert!(weak.upgrade().is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,585 @@
+use anyhow::{anyhow, Result};
+use regex::{Regex, RegexSet, RegexSetBuilder};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+use crate::path_matcher::{Normalization, PathMatcher};
+use crate::TRACING_TARGET;
+
+/// Whether a crash fault fires before or after the operation reaches the backing file
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum CrashTiming {
+    Before,
+    After,
+}
+
+impl CrashTiming {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CrashTiming::Before => "before",
+            CrashTiming::After => "after",
+        }
+    }
+}
+
+impl FromStr for CrashTiming {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "before" => Ok(CrashTiming::Before),
+            "after" => Ok(CrashTiming::After),
+            _ => Err(anyhow!("Unknown crash timing '{}'", s)),
+        }
+    }
+}
+
+/// File system operations a crash fault can be attached to
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum FsOperation {
+    Unlink,
+    Truncate,
+    Fsync,
+    Write,
+    Create,
+    Access,
+    Open,
+    Read,
+    Rename,
+    Link,
+    Symlink,
+    Mkdir,
+    Rmdir,
+    Setxattr,
+    Getxattr,
+    Listxattr,
+    Removexattr,
+    Setattr,
+}
+
+impl FsOperation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FsOperation::Unlink => "unlink",
+            FsOperation::Truncate => "truncate",
+            FsOperation::Fsync => "fsync",
+            FsOperation::Write => "write",
+            FsOperation::Create => "create",
+            FsOperation::Access => "access",
+            FsOperation::Open => "open",
+            FsOperation::Read => "read",
+            FsOperation::Rename => "rename",
+            FsOperation::Link => "link",
+            FsOperation::Symlink => "symlink",
+            FsOperation::Mkdir => "mkdir",
+            FsOperation::Rmdir => "rmdir",
+            FsOperation::Setxattr => "setxattr",
+            FsOperation::Getxattr => "getxattr",
+            FsOperation::Listxattr => "listxattr",
+            FsOperation::Removexattr => "removexattr",
+            FsOperation::Setattr => "setattr",
+        }
+    }
+}
+
+impl fmt::Display for FsOperation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl FromStr for FsOperation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "unlink" => Ok(FsOperation::Unlink),
+            "truncate" => Ok(FsOperation::Truncate),
+            "fsync" => Ok(FsOperation::Fsync),
+            "write" => Ok(FsOperation::Write),
+            "create" => Ok(FsOperation::Create),
+            "access" => Ok(FsOperation::Access),
+            "open" => Ok(FsOperation::Open),
+            "read" => Ok(FsOperation::Read),
+            "rename" => Ok(FsOperation::Rename),
+            "link" => Ok(FsOperation::Link),
+            "symlink" => Ok(FsOperation::Symlink),
+            "mkdir" => Ok(FsOperation::Mkdir),
+            "rmdir" => Ok(FsOperation::Rmdir),
+            "setxattr" => Ok(FsOperation::Setxattr),
+            "getxattr" => Ok(FsOperation::Getxattr),
+            "listxattr" => Ok(FsOperation::Listxattr),
+            "removexattr" => Ok(FsOperation::Removexattr),
+            "setattr" => Ok(FsOperation::Setattr),
+            _ => Err(anyhow!("Crash faults are not supported for '{}'", s)),
+        }
+    }
+}
+
+/// What happens when a crash fault fires
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CrashMode {
+    /// Abort the process, losing everything that wasn't synced
+    #[default]
+    Kill,
+    /// Drop the cache as a crash would and keep serving
+    ClearCache,
+    /// Keep the process up but fail every operation from then on with EIO, so that whatever
+    /// the cache held never reaches the backing files
+    Freeze,
+}
+
+impl FromStr for CrashMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "kill" => Ok(CrashMode::Kill),
+            "clear-cache" => Ok(CrashMode::ClearCache),
+            "freeze" => Ok(CrashMode::Freeze),
+            _ => Err(anyhow!("Unknown crash mode '{}'", s)),
+        }
+    }
+}
+
+/// Handle of a registered crash fault
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct FaultId(pub u64);
+
+impl fmt::Display for FaultId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A crash fault as registered through `LazyFS::add_crash_fault`
+#[derive(Clone, Debug, PartialEq)]
+pub struct CrashFaultSpec {
+    pub op: FsOperation,
+    pub timing: CrashTiming,
+    pub matcher: PathMatcher,
+    pub mode: CrashMode,
+    /// Which matching operation crashes, 1 being the first
+    pub occurrence: u32,
+}
+
+impl CrashFaultSpec {
+    /// Kills the process on the first matching operation
+    pub fn new(op: FsOperation, timing: CrashTiming, matcher: PathMatcher) -> Self {
+        CrashFaultSpec {
+            op,
+            timing,
+            matcher,
+            mode: CrashMode::default(),
+            occurrence: 1,
+        }
+    }
+
+    pub fn with_mode(mut self, mode: CrashMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn with_occurrence(mut self, occurrence: u32) -> Self {
+        self.occurrence = occurrence;
+        self
+    }
+
+    /// The pattern as it goes into the bucket's `RegexSet`, case folding included
+    fn set_pattern(&self) -> String {
+        if self.matcher.options().case_insensitive {
+            format!("(?i:{})", self.matcher.pattern())
+        } else {
+            self.matcher.pattern().to_string()
+        }
+    }
+}
+
+impl fmt::Display for CrashFaultSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let timing = match self.timing {
+            CrashTiming::Before => "before",
+            CrashTiming::After => "after",
+        };
+        write!(
+            f,
+            "op={} timing={} path={} mode={:?} occurrence={}",
+            self.op, timing, self.matcher, self.mode, self.occurrence
+        )
+    }
+}
+
+/// What registering a crash fault cost
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CrashRegistration {
+    pub id: FaultId,
+    /// Time spent compiling the new pattern
+    pub compile_time: Duration,
+    /// Crash patterns registered over all buckets, this one included
+    pub total_patterns: usize,
+}
+
+impl fmt::Display for CrashRegistration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "crash fault {} compiled in {}us, {} crash patterns",
+            self.id,
+            self.compile_time.as_micros(),
+            self.total_patterns
+        )
+    }
+}
+
+/// The crash fault that fired for a path
+#[derive(Clone, Debug, PartialEq)]
+pub struct CrashMatch {
+    pub id: FaultId,
+    pub pattern: String,
+    pub mode: CrashMode,
+}
+
+/// A registered crash fault and how many operations matched it so far
+#[derive(Clone, Debug, PartialEq)]
+pub struct CrashFaultStatus {
+    pub spec: CrashFaultSpec,
+    pub seen: u32,
+}
+
+#[derive(Debug)]
+struct Entry {
+    id: FaultId,
+    spec: CrashFaultSpec,
+    seen: u32,
+}
+
+/// Faults of one (op, timing) bucket, matched together with a single `RegexSet`. The set is
+/// only rebuilt on the first match after the bucket changed, so registering many faults in a
+/// row compiles it once.
+#[derive(Debug, Default)]
+struct Bucket {
+    entries: Vec<Entry>,
+    set: Option<RegexSet>,
+}
+
+impl Bucket {
+    /// A fault matching several of `paths` counts the operation once
+    fn matching(&mut self, paths: &[&Path]) -> Result<Option<CrashMatch>> {
+        if self.entries.is_empty() {
+            return Ok(None);
+        }
+        if self.set.is_none() {
+            let started = Instant::now();
+            // Thousands of per-file patterns don't fit the default size limit
+            let set = RegexSetBuilder::new(self.entries.iter().map(|e| e.spec.set_pattern()))
+                .size_limit(usize::MAX)
+                .build()?;
+            debug!(
+                target: TRACING_TARGET,
+                patterns = self.entries.len(),
+                elapsed_us = started.elapsed().as_micros() as u64,
+                "rebuilt crash fault set"
+            );
+            self.set = Some(set);
+        }
+
+        // Each fault sees the path in its own normal form, so run the set once per form in use
+        let set = self.set.as_ref().unwrap();
+        let mut matched = Vec::new();
+        for normalize in [Normalization::None, Normalization::Nfc, Normalization::Nfd] {
+            let in_form: Vec<_> = self
+                .entries
+                .iter()
+                .map(|e| e.spec.matcher.options().normalize == normalize)
+                .collect();
+            if !in_form.contains(&true) {
+                continue;
+            }
+            for path in paths {
+                let path = normalize.apply(&path.to_string_lossy());
+                matched.extend(set.matches(&path).iter().filter(|&i| in_form[i]));
+            }
+        }
+        matched.sort_unstable();
+        matched.dedup();
+
+        // Every matching fault counts the operation, the oldest one due fires
+        let mut fired = None;
+        for i in matched {
+            let entry = &mut self.entries[i];
+            entry.seen = entry.seen.saturating_add(1);
+            if entry.seen == entry.spec.occurrence && fired.is_none() {
+                fired = Some(CrashMatch {
+                    id: entry.id,
+                    pattern: entry.spec.matcher.to_string(),
+                    mode: entry.spec.mode,
+                });
+            }
+        }
+        Ok(fired)
+    }
+}
+
+/// Crash faults registered at runtime, bucketed by operation and timing
+#[derive(Debug)]
+pub struct CrashFaults {
+    buckets: HashMap<(FsOperation, CrashTiming), Bucket>,
+    max_faults: usize,
+    next_id: u64,
+}
+
+impl CrashFaults {
+    pub fn new(max_faults: usize) -> Self {
+        CrashFaults {
+            buckets: HashMap::new(),
+            max_faults,
+            next_id: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.values().map(|b| b.entries.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Takes the next id, for a fault registered somewhere else that has to be told apart from
+    /// the crash faults
+    pub fn allocate_id(&mut self) -> FaultId {
+        let id = FaultId(self.next_id);
+        self.next_id += 1;
+        id
+    }
+
+    pub fn add(&mut self, spec: CrashFaultSpec) -> Result<CrashRegistration> {
+        let total = self.len();
+        if total >= self.max_faults {
+            return Err(anyhow!(
+                "Unable to add crash fault for '{}': max_crash_faults ({}) reached",
+                spec.matcher,
+                self.max_faults
+            ));
+        }
+        if spec.occurrence == 0 {
+            return Err(anyhow!("Crash fault occurrence starts at 1"));
+        }
+
+        let started = Instant::now();
+        Regex::new(&spec.set_pattern())?;
+        let compile_time = started.elapsed();
+
+        let id = self.allocate_id();
+        let bucket = self.buckets.entry((spec.op, spec.timing)).or_default();
+        bucket.entries.push(Entry { id, spec, seen: 0 });
+        bucket.set = None;
+
+        Ok(CrashRegistration {
+            id,
+            compile_time,
+            total_patterns: total + 1,
+        })
+    }
+
+    pub fn remove(&mut self, id: FaultId) -> bool {
+        for bucket in self.buckets.values_mut() {
+            if let Some(i) = bucket.entries.iter().position(|e| e.id == id) {
+                bucket.entries.remove(i);
+                bucket.set = None;
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Takes back the last operation the fault with `id` counted, so the next matching one is
+    /// counted as that one again
+    pub fn release(&mut self, id: FaultId) {
+        let entry = self
+            .buckets
+            .values_mut()
+            .flat_map(|b| &mut b.entries)
+            .find(|e| e.id == id);
+        if let Some(entry) = entry {
+            entry.seen = entry.seen.saturating_sub(1);
+        }
+    }
+
+    pub fn status(&self, id: FaultId) -> Option<CrashFaultStatus> {
+        self.buckets
+            .values()
+            .flat_map(|b| &b.entries)
+            .find(|e| e.id == id)
+            .map(|e| CrashFaultStatus {
+                spec: e.spec.clone(),
+                seen: e.seen,
+            })
+    }
+
+    /// Counts `op` on `path` against the faults of its bucket and returns the one that fires,
+    /// if any
+    pub fn matching(
+        &mut self,
+        op: FsOperation,
+        timing: CrashTiming,
+        path: &Path,
+    ) -> Result<Option<CrashMatch>> {
+        self.matching_any(op, timing, &[path])
+    }
+
+    /// Same as `matching`, for operations on several paths such as a rename. A fault matching
+    /// any of them counts the operation.
+    pub fn matching_any(
+        &mut self,
+        op: FsOperation,
+        timing: CrashTiming,
+        paths: &[&Path],
+    ) -> Result<Option<CrashMatch>> {
+        match self.buckets.get_mut(&(op, timing)) {
+            Some(bucket) => bucket.matching(paths),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::path_matcher::MatchOptions;
+
+    fn spec(op: FsOperation, timing: CrashTiming, pattern: &str) -> CrashFaultSpec {
+        let matcher = PathMatcher::new(pattern, MatchOptions::default()).unwrap();
+        CrashFaultSpec::new(op, timing, matcher)
+    }
+
+    #[test]
+    fn thousands_of_patterns_match_in_one_pass() {
+        let mut faults = CrashFaults::new(3000);
+        for i in 0..2000 {
+            let timing = if i % 2 == 0 {
+                CrashTiming::Before
+            } else {
+                CrashTiming::After
+            };
+            let registration = faults
+                .add(spec(
+                    FsOperation::Write,
+                    timing,
+                    &format!("^/data/file-{}$", i),
+                ))
+                .unwrap();
+            assert_eq!(registration.total_patterns, i + 1);
+        }
+        faults
+            .add(spec(FsOperation::Fsync, CrashTiming::Before, "^/data/"))
+            .unwrap();
+
+        let started = Instant::now();
+        for i in (0..2000).step_by(2) {
+            let path = format!("/data/file-{}", i);
+            let found = faults
+                .matching(FsOperation::Write, CrashTiming::Before, Path::new(&path))
+                .unwrap()
+                .unwrap();
+            assert_eq!(found.id, FaultId(i));
+            assert_eq!(found.pattern, format!("^{}$", path));
+        }
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // Odd files only crash after the write, other ops have their own bucket
+        assert!(faults
+            .matching(
+                FsOperation::Write,
+                CrashTiming::Before,
+                Path::new("/data/file-1235")
+            )
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            faults
+                .matching(
+                    FsOperation::Fsync,
+                    CrashTiming::Before,
+                    Path::new("/data/file-7")
+                )
+                .unwrap()
+                .unwrap()
+                .id,
+            FaultId(2000)
+        );
+    }
+
+    #[test]
+    fn fires_on_its_occurrence_with_its_own_options() {
+        let mut faults = CrashFaults::new(10);
+        let options = MatchOptions {
+            case_insensitive: true,
+            normalize: Normalization::Nfc,
+        };
+        let matcher = PathMatcher::new("caf\u{e9}\\.log$", options).unwrap();
+        let third = faults
+            .add(
+                CrashFaultSpec::new(FsOperation::Write, CrashTiming::After, matcher)
+                    .with_mode(CrashMode::ClearCache)
+                    .with_occurrence(3),
+            )
+            .unwrap()
+            .id;
+        let first = faults
+            .add(spec(FsOperation::Write, CrashTiming::After, "\\.log$"))
+            .unwrap()
+            .id;
+
+        let path = Path::new("/data/CAFE\u{301}.LOG");
+        let mut fired = Vec::new();
+        for _ in 0..4 {
+            fired.push(
+                faults
+                    .matching(FsOperation::Write, CrashTiming::After, path)
+                    .unwrap()
+                    .map(|m| (m.id, m.mode)),
+            );
+        }
+        // Upper case .LOG never matches the case sensitive fault
+        assert_eq!(
+            fired,
+            [None, None, Some((third, CrashMode::ClearCache)), None]
+        );
+        assert_eq!(faults.status(third).unwrap().seen, 4);
+        assert_eq!(faults.status(first).unwrap().seen, 0);
+
+        assert!(faults.remove(third));
+        assert!(faults.status(third).is_none());
+        assert!(!faults.remove(third));
+    }
+
+    #[test]
+    fn registration_is_capped() {
+        let mut faults = CrashFaults::new(2);
+        let zeroth = spec(FsOperation::Write, CrashTiming::Before, "a").with_occurrence(0);
+        assert!(faults.add(zeroth).is_err());
+        faults
+            .add(spec(FsOperation::Write, CrashTiming::Before, "a"))
+            .unwrap();
+        let second = faults
+            .add(spec(FsOperation::Write, CrashTiming::After, "b"))
+            .unwrap();
+
+        let err = faults
+            .add(spec(FsOperation::Write, CrashTiming::Before, "c"))
+            .unwrap_err();
+        assert!(err.to_string().contains("max_crash_faults (2) reached"));
+
+        assert!(faults.remove(second.id));
+        faults
+            .add(spec(FsOperation::Write, CrashTiming::Before, "c"))
+            .unwrap();
+        assert_eq!(faults.len(), 2);
+        assert!("mknod".parse::<FsOperation>().is_err());
+    }
+}
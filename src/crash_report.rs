@@ -0,0 +1,118 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use crate::formats::{self, Artifact, Compat, FormatVersion, Header};
+use crate::pagecache::cache::UnsyncedOwner;
+
+pub const CRASH_REPORT_VERSION: FormatVersion = FormatVersion { major: 1, minor: 0 };
+
+/// Most owners listed in a report, the heaviest ones are kept
+pub const MAX_REPORTED_OWNERS: usize = 64;
+
+/// Written right before a crash fault takes effect, so whoever collects the disk image knows
+/// what it is looking at
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct CrashReport {
+    pub fault_id: u64,
+    /// Spec of the fault that fired
+    pub spec: String,
+    pub op: String,
+    pub timing: String,
+    pub path: String,
+    pub offset: Option<u64>,
+    pub size: Option<u64>,
+    /// Global op count when the fault fired
+    pub op_count: u64,
+    /// Owners with unsynced data left out past `MAX_REPORTED_OWNERS`
+    pub unsynced_omitted: usize,
+    /// Kept last, toml wants tables after plain values
+    pub unsynced: Vec<UnsyncedSummary>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct UnsyncedSummary {
+    pub owner: String,
+    pub dirty_bytes: u64,
+}
+
+impl CrashReport {
+    /// Sums up the dirty bytes of each owner, keeping the `MAX_REPORTED_OWNERS` heaviest
+    pub fn summarize(unsynced: &[UnsyncedOwner]) -> (Vec<UnsyncedSummary>, usize) {
+        let mut summary: Vec<_> = unsynced
+            .iter()
+            .map(|(owner, _, blocks, _)| UnsyncedSummary {
+                owner: owner.clone(),
+                dirty_bytes: blocks
+                    .iter()
+                    .map(|(_, (from, to), _, _)| (to - from + 1).max(0) as u64)
+                    .sum(),
+            })
+            .collect();
+        summary.sort_by(|a, b| {
+            b.dirty_bytes
+                .cmp(&a.dirty_bytes)
+                .then(a.owner.cmp(&b.owner))
+        });
+        let omitted = summary.len().saturating_sub(MAX_REPORTED_OWNERS);
+        summary.truncate(MAX_REPORTED_OWNERS);
+        (summary, omitted)
+    }
+
+    /// Writes the report behind `header` and fsyncs it, so it survives the crash that follows
+    pub fn write(&self, path: &Path, header: &Header) -> Result<()> {
+        let mut contents = Vec::new();
+        formats::write_header(&mut contents, header)?;
+        contents.extend_from_slice(toml::to_string(self)?.as_bytes());
+        let mut file = File::create(path)?;
+        file.write_all(&contents)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<(Header, Self)> {
+        let contents = std::fs::read(path)?;
+        let mut body = &contents[..];
+        let header = formats::read_header(&mut body, Artifact::CrashReport)?;
+        match header.negotiate(CRASH_REPORT_VERSION)? {
+            Compat::Current => {}
+            Compat::Upgrade => {
+                return Err(anyhow!(
+                    "Crash report {} has no known upgrade from {}",
+                    path.display(),
+                    header.version
+                ))
+            }
+        }
+        let report = toml::from_str(std::str::from_utf8(body)?)?;
+        Ok((header, report))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_keeps_the_heaviest_owners() {
+        let unsynced: Vec<UnsyncedOwner> = (0..MAX_REPORTED_OWNERS + 2)
+            .map(|i| {
+                let blocks = vec![(0, (0, i as i32), 0, None), (1, (10, 19), 1, None)];
+                (format!("owner-{}", i), 0, blocks, None)
+            })
+            .collect();
+        let (summary, omitted) = CrashReport::summarize(&unsynced);
+        assert_eq!(omitted, 2);
+        assert_eq!(summary.len(), MAX_REPORTED_OWNERS);
+        assert_eq!(
+            summary[0],
+            UnsyncedSummary {
+                owner: format!("owner-{}", MAX_REPORTED_OWNERS + 1),
+                dirty_bytes: MAX_REPORTED_OWNERS as u64 + 2 + 10,
+            }
+        );
+        assert_eq!(summary.last().unwrap().owner, "owner-2");
+    }
+}
@@ -0,0 +1,169 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Weak;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+use tracing::{debug, warn};
+
+use crate::formats::{self, Artifact, Compat, FormatVersion, Header};
+use crate::lazyfs::LazyFS;
+use crate::pagecache::config::FaultState;
+use crate::TRACING_TARGET;
+
+/// Version 1 was headerless toml with a `version` field, version 2 moved to a `formats` header
+pub const FAULT_STATE_VERSION: FormatVersion = FormatVersion { major: 2, minor: 0 };
+
+/// Fault counters saved across remounts
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct FaultStateFile {
+    pub op_count: u64,
+    pub faults: Vec<SavedFault>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SavedFault {
+    pub key: String,
+    /// Hash of the fault's spec, so state is never restored into a fault defined differently
+    pub spec_hash: String,
+    pub state: FaultState,
+}
+
+impl FaultStateFile {
+    /// Returns `None` if there is no state file at `path`. Version 1 files are upgraded.
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        if !formats::has_magic(&contents, Artifact::FaultState) {
+            return Self::load_v1(path, &contents).map(Some);
+        }
+        let mut body = &contents[..];
+        let header = formats::read_header(&mut body, Artifact::FaultState)
+            .map_err(|e| anyhow!("Fault state file {}: {}", path.display(), e))?;
+        match header.negotiate(FAULT_STATE_VERSION)? {
+            Compat::Current => Ok(Some(toml::from_str(std::str::from_utf8(body)?)?)),
+            Compat::Upgrade => Self::load_v1(path, body).map(Some),
+        }
+    }
+
+    fn load_v1(path: &Path, contents: &[u8]) -> Result<Self> {
+        #[derive(Deserialize)]
+        struct Versioned {
+            version: u32,
+        }
+
+        let contents = std::str::from_utf8(contents)?;
+        let versioned: Versioned = toml::from_str(contents)?;
+        if versioned.version != 1 {
+            return Err(anyhow!(
+                "Fault state file {} has version {}, expected 1",
+                path.display(),
+                versioned.version
+            ));
+        }
+        Ok(toml::from_str(contents)?)
+    }
+
+    /// Writes the state next to `path` first and renames it over, so a crash mid-save leaves the
+    /// previous state intact
+    pub fn save(&self, path: &Path, geometry_hash: u64, created: SystemTime) -> Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let header = Header::new(
+            Artifact::FaultState,
+            FAULT_STATE_VERSION,
+            geometry_hash,
+            created,
+        );
+        let mut contents = Vec::new();
+        formats::write_header(&mut contents, &header)?;
+        contents.extend_from_slice(toml::to_string(self)?.as_bytes());
+        fs::write(&tmp, contents)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+/// Saves the fault state of `LazyFS` every `interval` on a thread of its own, so that a mount
+/// that dies without shutting down loses at most that much of it. The thread only holds on to
+/// `LazyFS` while it saves, and stops when the saver is dropped.
+#[derive(Debug)]
+pub struct Saver {
+    /// Dropped to wake the thread up and stop it
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Saver {
+    pub fn spawn(lazyfs: Weak<LazyFS>, interval: Duration) -> Result<Self> {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = thread::Builder::new()
+            .name("lazyfs-fault-state".to_string())
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    let lazyfs = match lazyfs.upgrade() {
+                        Some(lazyfs) => lazyfs,
+                        None => break,
+                    };
+                    if let Err(e) = lazyfs.save_fault_state() {
+                        warn!(target: TRACING_TARGET, "Failed to save fault state: {:?}", e);
+                    }
+                }
+                debug!(target: TRACING_TARGET, "fault state saver stopped");
+            })?;
+        Ok(Saver {
+            stop: Some(stop),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Saver {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        let thread = match self.thread.take() {
+            Some(thread) => thread,
+            None => return,
+        };
+        // The saver itself may be the one letting go of `LazyFS` last
+        if thread.thread().id() != thread::current().id() && thread.join().is_err() {
+            warn!(target: TRACING_TARGET, "fault state saver panicked");
+        }
+    }
+}
+
+/// FNV-1a, stable across builds unlike the std hasher
+pub fn spec_hash(spec: &str) -> String {
+    let hash = spec.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{:016x}", hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_1_files_are_upgraded() {
+        let path =
+            std::env::temp_dir().join(format!("lazyfs-rs-{}-fault-state-v1", std::process::id()));
+        fs::write(&path, "version = 1\nop_count = 7\nfaults = []\n").unwrap();
+        let file = FaultStateFile::load(&path).unwrap().unwrap();
+        assert_eq!(file.op_count, 7);
+
+        file.save(&path, 0, SystemTime::now()).unwrap();
+        assert_eq!(FaultStateFile::load(&path).unwrap(), Some(file));
+
+        fs::write(&path, "version = 3\nop_count = 7\nfaults = []\n").unwrap();
+        assert!(FaultStateFile::load(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
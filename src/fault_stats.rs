@@ -0,0 +1,272 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use crate::clock::Clock;
+use crate::crash_faults::{FaultId, FsOperation};
+use crate::pagecache::config::Fault;
+
+/// Buckets of `OpLatency`: bucket `i` counts operations under `2^i` microseconds, the last one
+/// everything longer
+pub const LATENCY_BUCKETS: usize = 24;
+
+/// Most operations `FaultStats` keeps in its log, the oldest go first
+pub const OP_LOG_LEN: usize = 4096;
+
+/// How often a fault was looked at and what came of it, as `LazyFS::fault_status` reports it
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FaultCounters {
+    /// Operations the fault was checked against
+    pub evaluated: u64,
+    /// Checked while active and targeting the operation, whether or not dry-run let it through
+    pub matched: u64,
+    /// Matched and injected
+    pub triggered: u64,
+    /// Operations that finished tagged with the fault
+    pub affected_ops: u64,
+}
+
+/// What came of checking a fault against an operation
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Evaluation {
+    /// Inactive, or not targeting the operation
+    Missed,
+    /// Targeting the operation, but let through by dry-run
+    Matched,
+    Triggered,
+}
+
+/// An operation as it finished, see `LazyFS::op_log`
+#[derive(Clone, Debug, PartialEq)]
+pub struct OpRecord {
+    /// Global op count of the operation
+    pub op: u64,
+    pub kind: FsOperation,
+    pub path: PathBuf,
+    /// The fault that interfered with it, the first one if several did
+    pub injected: Option<FaultId>,
+    pub latency: Duration,
+}
+
+/// Latency of the operations of one kind, those a fault interfered with apart from the others
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OpLatency {
+    pub clean: [u64; LATENCY_BUCKETS],
+    pub injected: [u64; LATENCY_BUCKETS],
+}
+
+impl OpLatency {
+    fn record(&mut self, latency: Duration, injected: bool) {
+        let micros = latency.as_micros() as u64;
+        let bucket = ((u64::BITS - micros.leading_zeros()) as usize).min(LATENCY_BUCKETS - 1);
+        match injected {
+            true => self.injected[bucket] += 1,
+            false => self.clean[bucket] += 1,
+        }
+    }
+}
+
+/// Per-fault counters, along with the log and latency of the operations the faults were checked
+/// against
+#[derive(Debug, Default)]
+pub struct FaultStats {
+    /// Ids handed out to the faults seen so far, by the address of the fault
+    ids: HashMap<usize, FaultId>,
+    counters: HashMap<FaultId, FaultCounters>,
+    latency: HashMap<FsOperation, OpLatency>,
+    log: VecDeque<OpRecord>,
+}
+
+impl FaultStats {
+    fn address(fault: &dyn Fault) -> usize {
+        fault as *const dyn Fault as *const () as usize
+    }
+
+    /// Id of `fault`, handing it the one `allocate` returns the first time it is seen
+    pub fn id_of<E>(
+        &mut self,
+        fault: &dyn Fault,
+        allocate: impl FnOnce() -> Result<FaultId, E>,
+    ) -> Result<FaultId, E> {
+        let address = Self::address(fault);
+        if let Some(&id) = self.ids.get(&address) {
+            return Ok(id);
+        }
+        let id = allocate()?;
+        self.ids.insert(address, id);
+        Ok(id)
+    }
+
+    /// Drops the id of a fault that was removed, so another one allocated in its place gets its
+    /// own. Its counters stay.
+    pub fn forget(&mut self, fault: &dyn Fault) {
+        self.ids.remove(&Self::address(fault));
+    }
+
+    pub fn tally(&mut self, id: FaultId, evaluation: Evaluation) {
+        let counters = self.counters.entry(id).or_default();
+        counters.evaluated += 1;
+        if evaluation != Evaluation::Missed {
+            counters.matched += 1;
+        }
+        if evaluation == Evaluation::Triggered {
+            counters.triggered += 1;
+        }
+    }
+
+    pub fn counters(&self, id: FaultId) -> FaultCounters {
+        self.counters.get(&id).copied().unwrap_or_default()
+    }
+
+    pub fn record(&mut self, record: OpRecord) {
+        self.latency
+            .entry(record.kind)
+            .or_default()
+            .record(record.latency, record.injected.is_some());
+        if let Some(id) = record.injected {
+            self.counters.entry(id).or_default().affected_ops += 1;
+        }
+        if self.log.len() == OP_LOG_LEN {
+            self.log.pop_front();
+        }
+        self.log.push_back(record);
+    }
+
+    pub fn log(&self) -> Vec<OpRecord> {
+        self.log.iter().cloned().collect()
+    }
+
+    pub fn latency(&self) -> Vec<(FsOperation, OpLatency)> {
+        let mut latency: Vec<_> = self
+            .latency
+            .iter()
+            .map(|(&op, latency)| (op, latency.clone()))
+            .collect();
+        latency.sort_by_key(|(op, _)| op.as_str());
+        latency
+    }
+}
+
+/// What an operation went through, handed down its handler's call chain. The first fault that
+/// interferes with the operation tags it, and once the handler is done the operation is logged
+/// and counted against that fault.
+pub struct OpContext<'a> {
+    pub kind: FsOperation,
+    /// Global op count of the operation
+    pub op: u64,
+    pub injected: Option<FaultId>,
+    path: PathBuf,
+    started: SystemTime,
+    recorder: Option<(&'a Mutex<FaultStats>, &'a dyn Clock)>,
+}
+
+impl<'a> OpContext<'a> {
+    /// A context recorded nowhere, for calling the fault checks on their own
+    pub fn new(kind: FsOperation) -> Self {
+        OpContext {
+            kind,
+            op: 0,
+            injected: None,
+            path: PathBuf::new(),
+            started: SystemTime::UNIX_EPOCH,
+            recorder: None,
+        }
+    }
+
+    /// A context recorded into `stats` when dropped, with the latency `clock` measures
+    pub fn recorded(
+        kind: FsOperation,
+        op: u64,
+        path: &Path,
+        stats: &'a Mutex<FaultStats>,
+        clock: &'a dyn Clock,
+    ) -> Self {
+        OpContext {
+            kind,
+            op,
+            injected: None,
+            path: path.to_path_buf(),
+            started: clock.now(),
+            recorder: Some((stats, clock)),
+        }
+    }
+
+    /// Tags the operation with `id`, unless an earlier fault already did
+    pub fn inject(&mut self, id: Option<FaultId>) {
+        if self.injected.is_none() {
+            self.injected = id;
+        }
+    }
+}
+
+impl Drop for OpContext<'_> {
+    fn drop(&mut self) {
+        let (stats, clock) = match self.recorder {
+            Some(recorder) => recorder,
+            None => return,
+        };
+        let record = OpRecord {
+            op: self.op,
+            kind: self.kind,
+            path: std::mem::take(&mut self.path),
+            injected: self.injected,
+            latency: clock.now().duration_since(self.started).unwrap_or_default(),
+        };
+        stats
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .record(record);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::pagecache::config::DelayFault;
+
+    #[test]
+    fn tagged_ops_are_counted_apart() {
+        let clock = ManualClock::new(SystemTime::UNIX_EPOCH);
+        let stats = Mutex::new(FaultStats::default());
+        let fault = DelayFault::new("read", Duration::from_millis(5));
+        let id = stats
+            .lock()
+            .unwrap()
+            .id_of::<()>(&fault, || Ok(FaultId(7)))
+            .unwrap();
+
+        for i in 0..4 {
+            let mut ctx =
+                OpContext::recorded(FsOperation::Read, i, Path::new("/wal"), &stats, &clock);
+            let evaluation = match i % 2 {
+                0 => Evaluation::Triggered,
+                _ => Evaluation::Missed,
+            };
+            stats.lock().unwrap().tally(id, evaluation);
+            if evaluation == Evaluation::Triggered {
+                ctx.inject(Some(id));
+                clock.advance(Duration::from_millis(5));
+            }
+        }
+
+        let stats = stats.into_inner().unwrap();
+        assert_eq!(
+            stats.counters(id),
+            FaultCounters {
+                evaluated: 4,
+                matched: 2,
+                triggered: 2,
+                affected_ops: 2,
+            }
+        );
+        let (kind, latency) = &stats.latency()[0];
+        assert_eq!(*kind, FsOperation::Read);
+        // Under 1us, then 5ms in the bucket under 8.192ms
+        assert_eq!(latency.clean[0], 2);
+        assert_eq!(latency.injected[13], 2);
+        let tags: Vec<_> = stats.log().iter().map(|record| record.injected).collect();
+        assert_eq!(tags, [Some(id), None, Some(id), None]);
+    }
+}
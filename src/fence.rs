@@ -0,0 +1,314 @@
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+use crate::clock::{Clock, SystemClock};
+use crate::TRACING_TARGET;
+
+/// Longest a waiter goes without looking at the clock, so a clock moved ahead by hand lifts the
+/// fence without anyone notifying the waiters
+const CLOCK_POLL: Duration = Duration::from_millis(10);
+
+/// What a mutating operation does while writes are fenced
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FenceMode {
+    /// Wait for the fence to be lifted
+    #[default]
+    Block,
+    /// Fail right away with EAGAIN
+    Eagain,
+}
+
+#[derive(Debug, Default)]
+struct FenceState {
+    fenced_at: Option<SystemTime>,
+    /// Mutating operations currently past the gate
+    in_flight: usize,
+    /// Mutating operations waiting at the gate for the fence to go
+    waiting: usize,
+}
+
+/// Gate at the top of the mutating handlers. Any number of writes pass it at once, while a fence
+/// waits for them to drain and then keeps new ones out until it is lifted or `max_hold` runs out,
+/// so an orchestrator that dies mid-snapshot can't wedge the mount. `max_hold` is measured on
+/// the clock given to `with_clock`.
+pub struct WriteFence {
+    state: Mutex<FenceState>,
+    changed: Condvar,
+    mode: FenceMode,
+    max_hold: Duration,
+    clock: Arc<dyn Clock>,
+}
+
+impl fmt::Debug for WriteFence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WriteFence")
+            .field("state", &self.state)
+            .field("mode", &self.mode)
+            .field("max_hold", &self.max_hold)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Held by a mutating operation while it runs
+#[derive(Debug)]
+pub struct MutationGuard<'a> {
+    fence: &'a WriteFence,
+}
+
+impl Drop for MutationGuard<'_> {
+    fn drop(&mut self) {
+        let mut state = self.fence.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.in_flight -= 1;
+        self.fence.changed.notify_all();
+    }
+}
+
+impl WriteFence {
+    pub fn new(mode: FenceMode, max_hold: Duration) -> Self {
+        WriteFence {
+            state: Mutex::new(FenceState::default()),
+            changed: Condvar::new(),
+            mode,
+            max_hold,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Time on the clock since `since`
+    fn elapsed(&self, since: SystemTime) -> Duration {
+        self.clock.now().duration_since(since).unwrap_or_default()
+    }
+
+    /// Waits for `changed` for at most `timeout`, and never longer than `CLOCK_POLL`
+    fn wait<'a>(
+        &self,
+        state: MutexGuard<'a, FenceState>,
+        timeout: Duration,
+    ) -> Result<MutexGuard<'a, FenceState>> {
+        Ok(self
+            .changed
+            .wait_timeout(state, timeout.min(CLOCK_POLL))
+            .map_err(|e| anyhow!("Unable to acquire lock on write fence: {:?}", e))?
+            .0)
+    }
+
+    /// Lifts a fence held for longer than `max_hold`. Returns whether writes are still fenced.
+    fn expire(&self, state: &mut FenceState) -> bool {
+        match state.fenced_at {
+            Some(fenced_at) if self.elapsed(fenced_at) >= self.max_hold => {
+                warn!(
+                    target: TRACING_TARGET,
+                    held_ms = self.elapsed(fenced_at).as_millis() as u64,
+                    "write fence timed out, letting writes through"
+                );
+                state.fenced_at = None;
+                self.changed.notify_all();
+                false
+            }
+            Some(_) => true,
+            None => false,
+        }
+    }
+
+    /// Waits for writes in flight to finish and keeps new ones out. Gives up, leaving writes
+    /// unfenced, if they don't drain within `max_hold`.
+    pub fn fence(&self) -> Result<()> {
+        let mut state = self
+            .state
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on write fence: {:?}", e))?;
+        if self.expire(&mut state) {
+            return Err(anyhow!("Writes are already fenced"));
+        }
+
+        let fenced_at = self.clock.now();
+        state.fenced_at = Some(fenced_at);
+        while state.in_flight > 0 {
+            let remaining = match self.max_hold.checked_sub(self.elapsed(fenced_at)) {
+                Some(remaining) if !remaining.is_zero() => remaining,
+                _ => {
+                    state.fenced_at = None;
+                    self.changed.notify_all();
+                    return Err(anyhow!(
+                        "{} writes still running after {} ms, not fencing",
+                        state.in_flight,
+                        self.max_hold.as_millis()
+                    ));
+                }
+            };
+            state = self.wait(state, remaining)?;
+        }
+
+        info!(
+            target: TRACING_TARGET,
+            drain_ms = self.elapsed(fenced_at).as_millis() as u64,
+            "fenced writes"
+        );
+        Ok(())
+    }
+
+    /// Lets writes through again, returning how long the fence was held
+    pub fn unfence(&self) -> Result<Duration> {
+        let mut state = self
+            .state
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on write fence: {:?}", e))?;
+        if !self.expire(&mut state) {
+            return Err(anyhow!("Writes are not fenced"));
+        }
+
+        let held = self.elapsed(state.fenced_at.take().unwrap());
+        self.changed.notify_all();
+        info!(
+            target: TRACING_TARGET,
+            held_ms = held.as_millis() as u64,
+            "unfenced writes"
+        );
+        Ok(held)
+    }
+
+    pub fn is_fenced(&self) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        self.expire(&mut state)
+    }
+
+    /// Passes the gate, waiting out a fence or failing with EAGAIN depending on the mode
+    pub fn enter(&self) -> Result<MutationGuard<'_>> {
+        let mut state = self
+            .state
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on write fence: {:?}", e))?;
+        while self.expire(&mut state) {
+            if self.mode == FenceMode::Eagain {
+                return Err(io::Error::from_raw_os_error(libc::EAGAIN).into());
+            }
+            let fenced_for = state
+                .fenced_at
+                .map_or(Duration::ZERO, |at| self.elapsed(at));
+            let remaining = self.max_hold.saturating_sub(fenced_for);
+            state.waiting += 1;
+            state = self.wait(state, remaining)?;
+            state.waiting -= 1;
+        }
+
+        state.in_flight += 1;
+        Ok(MutationGuard { fence: self })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    fn manual_fence(mode: FenceMode, max_hold: Duration) -> (Arc<WriteFence>, Arc<ManualClock>) {
+        let clock = Arc::new(ManualClock::default());
+        let fence = WriteFence::new(mode, max_hold).with_clock(clock.clone());
+        (Arc::new(fence), clock)
+    }
+
+    /// Spins until `n` writers wait at the gate
+    fn wait_for_writers(fence: &WriteFence, n: usize) {
+        while fence.state.lock().unwrap().waiting < n {
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn fence_blocks_writers_until_released() {
+        let (fence, clock) = manual_fence(FenceMode::Block, Duration::from_secs(60));
+        fence.fence().unwrap();
+        assert!(fence.fence().is_err());
+
+        let wrote = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let fence = fence.clone();
+            let wrote = wrote.clone();
+            thread::spawn(move || {
+                let _guard = fence.enter().unwrap();
+                wrote.store(true, Ordering::SeqCst);
+            })
+        };
+        wait_for_writers(&fence, 1);
+        clock.advance(Duration::from_secs(59));
+        assert!(fence.is_fenced());
+        assert!(!wrote.load(Ordering::SeqCst));
+
+        assert_eq!(fence.unfence().unwrap(), Duration::from_secs(59));
+        writer.join().unwrap();
+        assert!(wrote.load(Ordering::SeqCst));
+        assert!(fence.unfence().is_err());
+    }
+
+    #[test]
+    fn fence_waits_for_writes_in_flight() {
+        let (fence, clock) = manual_fence(FenceMode::Block, Duration::from_secs(60));
+        let guard = fence.enter().unwrap();
+        let fencer = {
+            let fence = fence.clone();
+            thread::spawn(move || fence.fence())
+        };
+        while !fence.is_fenced() {
+            thread::yield_now();
+        }
+        assert!(!fencer.is_finished());
+        drop(guard);
+        fencer.join().unwrap().unwrap();
+        assert!(fence.is_fenced());
+        fence.unfence().unwrap();
+
+        // Writes that don't drain within max_hold leave writes unfenced
+        let guard = fence.enter().unwrap();
+        let fencer = {
+            let fence = fence.clone();
+            thread::spawn(move || fence.fence())
+        };
+        while !fence.is_fenced() {
+            thread::yield_now();
+        }
+        clock.advance(Duration::from_secs(60));
+        assert!(fencer.join().unwrap().is_err());
+        assert!(!fence.is_fenced());
+        drop(guard);
+    }
+
+    #[test]
+    fn fence_times_out() {
+        let (fence, clock) = manual_fence(FenceMode::Block, Duration::from_millis(100));
+        fence.fence().unwrap();
+        let writer = {
+            let fence = fence.clone();
+            thread::spawn(move || fence.enter().map(drop))
+        };
+        wait_for_writers(&fence, 1);
+        clock.advance(Duration::from_millis(100));
+        writer.join().unwrap().unwrap();
+        assert!(!fence.is_fenced());
+        assert!(fence.unfence().is_err());
+
+        let (fence, clock) = manual_fence(FenceMode::Eagain, Duration::from_millis(100));
+        fence.fence().unwrap();
+        let err = fence.enter().unwrap_err();
+        let errno = err
+            .downcast_ref::<io::Error>()
+            .and_then(|e| e.raw_os_error());
+        assert_eq!(errno, Some(libc::EAGAIN));
+        clock.advance(Duration::from_millis(99));
+        assert!(fence.enter().is_err());
+        clock.advance(Duration::from_millis(1));
+        assert!(fence.enter().is_ok());
+    }
+}
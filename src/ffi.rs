@@ -0,0 +1,235 @@
+//! C ABI over `LazyFS`, for crash-testing harnesses that aren't written in Rust. Build the
+//! library with `cargo rustc --release --features ffi --crate-type cdylib` (or `staticlib`)
+//! and include `include/lazyfs.h`, which the build script regenerates from this file.
+//!
+//! Paths are those of the backing files and must exist before they are written. Calls return
+//! 0, or a byte count, on success and a negative errno on failure. The message of the last
+//! failure on the calling thread is kept for `lazyfs_last_error_message`.
+
+use anyhow::Result;
+use std::cell::RefCell;
+use std::ffi::{c_char, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::crash_report::CrashReport;
+use crate::lazyfs::{errno_of, LazyFS};
+
+/// A `LazyFS` and the cache under it, opaque to C
+pub struct LazyFsCache {
+    lazyfs: Arc<LazyFS>,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(e: &anyhow::Error) {
+    let message = CString::new(format!("{:#}", e).replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+/// Runs `f`, turning an error or a panic into a negative errno and keeping its message
+fn call(f: impl FnOnce() -> Result<i64>) -> i64 {
+    let result = panic::catch_unwind(AssertUnwindSafe(f))
+        .unwrap_or_else(|_| Err(std::io::Error::from_raw_os_error(libc::EIO).into()));
+    match result {
+        Ok(value) => value,
+        Err(e) => {
+            set_last_error(&e);
+            -(errno_of(&e) as i64)
+        }
+    }
+}
+
+fn einval(what: &str) -> anyhow::Error {
+    anyhow::Error::from(std::io::Error::from_raw_os_error(libc::EINVAL))
+        .context(format!("{} is NULL", what))
+}
+
+/// # Safety
+///
+/// `cache` must be NULL or come from `lazyfs_cache_new` and not have been freed
+unsafe fn cache_ref<'a>(cache: *const LazyFsCache) -> Result<&'a LazyFS> {
+    cache
+        .as_ref()
+        .map(|cache| cache.lazyfs.as_ref())
+        .ok_or_else(|| einval("cache"))
+}
+
+/// # Safety
+///
+/// `path` must be NULL or a NUL-terminated string
+unsafe fn path_ref<'a>(path: *const c_char) -> Result<&'a Path> {
+    if path.is_null() {
+        return Err(einval("path"));
+    }
+    let path = CStr::from_ptr(path).to_str()?;
+    Ok(Path::new(path))
+}
+
+/// Sets up LazyFS with the config file at `config_path`, or the default config if it is NULL.
+/// Returns NULL on failure, see `lazyfs_last_error_message`.
+///
+/// # Safety
+///
+/// `config_path` must be NULL or a NUL-terminated string
+#[no_mangle]
+pub unsafe extern "C" fn lazyfs_cache_new(config_path: *const c_char) -> *mut LazyFsCache {
+    let mut built = None;
+    call(|| {
+        let mut builder = LazyFS::builder();
+        if !config_path.is_null() {
+            builder = builder.config_file(CStr::from_ptr(config_path).to_str()?);
+        }
+        built = Some(builder.build()?);
+        Ok(0)
+    });
+    match built {
+        Some(lazyfs) => Box::into_raw(Box::new(LazyFsCache { lazyfs })),
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// Shuts LazyFS down without syncing, what wasn't fsynced is lost
+///
+/// # Safety
+///
+/// `cache` must be NULL or come from `lazyfs_cache_new`, and is not to be used afterwards
+#[no_mangle]
+pub unsafe extern "C" fn lazyfs_cache_free(cache: *mut LazyFsCache) {
+    if cache.is_null() {
+        return;
+    }
+    let cache = Box::from_raw(cache);
+    call(|| cache.lazyfs.shutdown(false).map(|_| 0));
+}
+
+/// Writes `len` bytes of `buf` at `offset` of `path` through the cache. Returns how many were
+/// accepted, which faults may make fewer than `len`.
+///
+/// # Safety
+///
+/// `cache` must come from `lazyfs_cache_new`, `path` be a NUL-terminated string and `buf`
+/// point to `len` readable bytes
+#[no_mangle]
+pub unsafe extern "C" fn lazyfs_write(
+    cache: *const LazyFsCache,
+    path: *const c_char,
+    buf: *const u8,
+    len: usize,
+    offset: u64,
+) -> i64 {
+    call(|| {
+        let lazyfs = cache_ref(cache)?;
+        let path = path_ref(path)?;
+        if buf.is_null() && len > 0 {
+            return Err(einval("buf"));
+        }
+        let data = match len {
+            0 => &[][..],
+            _ => std::slice::from_raw_parts(buf, len),
+        };
+        Ok(lazyfs.do_write(path, 0, offset, data)? as i64)
+    })
+}
+
+/// Reads up to `len` bytes at `offset` of `path` into `buf`, unsynced writes included. Returns
+/// how many were read.
+///
+/// # Safety
+///
+/// `cache` must come from `lazyfs_cache_new`, `path` be a NUL-terminated string and `buf`
+/// point to `len` writable bytes
+#[no_mangle]
+pub unsafe extern "C" fn lazyfs_read(
+    cache: *const LazyFsCache,
+    path: *const c_char,
+    buf: *mut u8,
+    len: usize,
+    offset: u64,
+) -> i64 {
+    call(|| {
+        let lazyfs = cache_ref(cache)?;
+        let path = path_ref(path)?;
+        if buf.is_null() && len > 0 {
+            return Err(einval("buf"));
+        }
+        let buf = match len {
+            0 => &mut [][..],
+            _ => std::slice::from_raw_parts_mut(buf, len),
+        };
+        Ok(lazyfs.do_read(path, 0, offset, len, buf)? as i64)
+    })
+}
+
+/// Syncs what the cache holds of `path` to the backing file
+///
+/// # Safety
+///
+/// `cache` must come from `lazyfs_cache_new` and `path` be a NUL-terminated string
+#[no_mangle]
+pub unsafe extern "C" fn lazyfs_fsync(cache: *const LazyFsCache, path: *const c_char) -> i32 {
+    call(|| {
+        let lazyfs = cache_ref(cache)?;
+        lazyfs.do_fsync(path_ref(path)?, false)?;
+        Ok(0)
+    }) as i32
+}
+
+/// Drops everything the cache holds, as a crash would, losing what wasn't fsynced
+///
+/// # Safety
+///
+/// `cache` must come from `lazyfs_cache_new`
+#[no_mangle]
+pub unsafe extern "C" fn lazyfs_drop_unsynced(cache: *const LazyFsCache) -> i32 {
+    call(|| {
+        cache_ref(cache)?.cache().clear_cache()?;
+        Ok(0)
+    }) as i32
+}
+
+/// The unsynced bytes of each owner as JSON, `{"unsynced":[{"owner":..,"dirty_bytes":..}],
+/// "omitted":..}`, heaviest first as in crash reports. Returns NULL on failure, the string is
+/// to be freed with `lazyfs_string_free`.
+///
+/// # Safety
+///
+/// `cache` must come from `lazyfs_cache_new`
+#[no_mangle]
+pub unsafe extern "C" fn lazyfs_unsynced_report_json(cache: *const LazyFsCache) -> *mut c_char {
+    let mut report = None;
+    call(|| {
+        let unsynced = cache_ref(cache)?.cache().report_unsynced_data()?;
+        let (unsynced, omitted) = CrashReport::summarize(&unsynced);
+        let json = serde_json::json!({ "unsynced": unsynced, "omitted": omitted });
+        report = Some(CString::new(json.to_string())?);
+        Ok(0)
+    });
+    report.map_or(std::ptr::null_mut(), CString::into_raw)
+}
+
+/// Frees a string returned by LazyFS
+///
+/// # Safety
+///
+/// `s` must be NULL or come from LazyFS, and is not to be used afterwards
+#[no_mangle]
+pub unsafe extern "C" fn lazyfs_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+/// Message of the last failure on the calling thread, or NULL if there was none. Valid until
+/// the next failing call on the thread.
+#[no_mangle]
+pub extern "C" fn lazyfs_last_error_message() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(std::ptr::null(), |message| message.as_ptr())
+    })
+}
@@ -0,0 +1,321 @@
+use anyhow::Result;
+use std::fmt;
+use std::io::{ErrorKind, Read, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::pagecache::config::Config;
+
+/// Kind of file LazyFS persists, each recognized by its magic bytes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Artifact {
+    FaultState,
+    CrashReport,
+    Journal,
+    Trace,
+    Snapshot,
+    CacheImage,
+}
+
+impl Artifact {
+    pub fn magic(&self) -> [u8; 4] {
+        match self {
+            Artifact::FaultState => *b"LZFS",
+            Artifact::CrashReport => *b"LZCR",
+            Artifact::Journal => *b"LZJN",
+            Artifact::Trace => *b"LZTR",
+            Artifact::Snapshot => *b"LZSN",
+            Artifact::CacheImage => *b"LZCI",
+        }
+    }
+}
+
+/// A new major version can't be read by older builds, a new minor version only adds things
+/// older builds can skip
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FormatVersion {
+    pub major: u16,
+    pub minor: u16,
+}
+
+impl fmt::Display for FormatVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// What a reader supporting some version should do with a file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compat {
+    /// Same major version, read as is
+    Current,
+    /// Older major version, to be upgraded by the reader
+    Upgrade,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Header {
+    pub artifact: Artifact,
+    pub version: FormatVersion,
+    /// Version of the crate that wrote the file
+    pub crate_version: String,
+    /// `geometry_hash` of the config the file was written under
+    pub geometry_hash: u64,
+    /// Seconds since the epoch
+    pub created_at: u64,
+}
+
+/// A header that can't be read
+#[derive(Debug, PartialEq)]
+pub enum FormatError {
+    Truncated,
+    BadMagic {
+        expected: [u8; 4],
+        found: [u8; 4],
+    },
+    BadChecksum {
+        expected: u32,
+        found: u32,
+    },
+    Unsupported {
+        found: FormatVersion,
+        supported: FormatVersion,
+    },
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatError::Truncated => write!(f, "header is truncated"),
+            FormatError::BadMagic { expected, found } => write!(
+                f,
+                "bad magic {:?}, expected {:?}",
+                String::from_utf8_lossy(found),
+                String::from_utf8_lossy(expected)
+            ),
+            FormatError::BadChecksum { expected, found } => write!(
+                f,
+                "header checksum is {:08x}, expected {:08x}",
+                found, expected
+            ),
+            FormatError::Unsupported { found, supported } => write!(
+                f,
+                "format version {} is newer than the supported {}",
+                found, supported
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FormatError {}
+
+impl Header {
+    pub fn new(
+        artifact: Artifact,
+        version: FormatVersion,
+        geometry_hash: u64,
+        created: SystemTime,
+    ) -> Self {
+        Header {
+            artifact,
+            version,
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+            geometry_hash,
+            created_at: created
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or(Duration::ZERO)
+                .as_secs(),
+        }
+    }
+
+    /// Refuses files from a newer major version than `supported`
+    pub fn negotiate(&self, supported: FormatVersion) -> Result<Compat> {
+        if self.version.major > supported.major {
+            return Err(FormatError::Unsupported {
+                found: self.version,
+                supported,
+            }
+            .into());
+        }
+        if self.version.major < supported.major {
+            return Ok(Compat::Upgrade);
+        }
+        Ok(Compat::Current)
+    }
+}
+
+/// Hash of the cache geometry, so a file can be told apart from one written under another layout
+pub fn geometry_hash(config: &Config) -> u64 {
+    [
+        config.io_block_size,
+        config.cache_page_size,
+        config.cache_nr_pages,
+        config.disk_sector_size,
+    ]
+    .iter()
+    .flat_map(|value| (*value as u64).to_le_bytes())
+    .fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Whether `bytes` start like a file of `artifact`
+pub fn has_magic(bytes: &[u8], artifact: Artifact) -> bool {
+    bytes.starts_with(&artifact.magic())
+}
+
+/// Layout: magic, major and minor version, geometry hash, creation time, crate version as a
+/// length prefixed string, then the CRC-32 of all of the above. Integers are little endian.
+pub fn write_header<W: Write>(writer: &mut W, header: &Header) -> Result<()> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&header.artifact.magic());
+    bytes.extend_from_slice(&header.version.major.to_le_bytes());
+    bytes.extend_from_slice(&header.version.minor.to_le_bytes());
+    bytes.extend_from_slice(&header.geometry_hash.to_le_bytes());
+    bytes.extend_from_slice(&header.created_at.to_le_bytes());
+    bytes.extend_from_slice(&(header.crate_version.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(header.crate_version.as_bytes());
+    let crc = crc32(&bytes);
+    bytes.extend_from_slice(&crc.to_le_bytes());
+    writer.write_all(&bytes)?;
+    Ok(())
+}
+
+/// Reads a header written by `write_header` for `artifact`, leaving `reader` at the body
+pub fn read_header<R: Read>(reader: &mut R, artifact: Artifact) -> Result<Header> {
+    let mut read = Vec::new();
+    let mut take = |len: usize| -> Result<Vec<u8>> {
+        let mut buf = vec![0; len];
+        match reader.read_exact(&mut buf) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                return Err(FormatError::Truncated.into())
+            }
+            Err(e) => return Err(e.into()),
+        }
+        read.extend_from_slice(&buf);
+        Ok(buf)
+    };
+
+    let mut found = [0; 4];
+    found.copy_from_slice(&take(4)?);
+    if found != artifact.magic() {
+        return Err(FormatError::BadMagic {
+            expected: artifact.magic(),
+            found,
+        }
+        .into());
+    }
+    let major = u16::from_le_bytes(take(2)?.try_into().unwrap());
+    let minor = u16::from_le_bytes(take(2)?.try_into().unwrap());
+    let geometry_hash = u64::from_le_bytes(take(8)?.try_into().unwrap());
+    let created_at = u64::from_le_bytes(take(8)?.try_into().unwrap());
+    let len = u16::from_le_bytes(take(2)?.try_into().unwrap());
+    let crate_version = String::from_utf8_lossy(&take(len as usize)?).into_owned();
+    let found_crc = u32::from_le_bytes(take(4)?.try_into().unwrap());
+
+    let expected_crc = crc32(&read[..read.len() - 4]);
+    if found_crc != expected_crc {
+        return Err(FormatError::BadChecksum {
+            expected: expected_crc,
+            found: found_crc,
+        }
+        .into());
+    }
+
+    Ok(Header {
+        artifact,
+        version: FormatVersion { major, minor },
+        crate_version,
+        geometry_hash,
+        created_at,
+    })
+}
+
+/// CRC-32 (IEEE), bit by bit since headers are tiny
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0u32, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| {
+            if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb88320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const V1: FormatVersion = FormatVersion { major: 1, minor: 2 };
+
+    fn header() -> Header {
+        Header::new(
+            Artifact::Journal,
+            V1,
+            geometry_hash(&Config::default()),
+            UNIX_EPOCH,
+        )
+    }
+
+    fn written() -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_header(&mut bytes, &header()).unwrap();
+        bytes.extend_from_slice(b"body");
+        bytes
+    }
+
+    fn format_error(res: Result<Header>) -> FormatError {
+        res.unwrap_err().downcast::<FormatError>().unwrap()
+    }
+
+    #[test]
+    fn header_round_trip() {
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+
+        let bytes = written();
+        let mut reader = &bytes[..];
+        assert_eq!(
+            read_header(&mut reader, Artifact::Journal).unwrap(),
+            header()
+        );
+        assert_eq!(reader, b"body");
+    }
+
+    #[test]
+    fn damaged_headers_are_refused() {
+        let bytes = written();
+        assert_eq!(
+            format_error(read_header(&mut &bytes[..10], Artifact::Journal)),
+            FormatError::Truncated
+        );
+        assert!(matches!(
+            format_error(read_header(&mut &bytes[..], Artifact::Trace)),
+            FormatError::BadMagic { .. }
+        ));
+
+        let mut flipped = bytes.clone();
+        flipped[6] ^= 1;
+        assert!(matches!(
+            format_error(read_header(&mut &flipped[..], Artifact::Journal)),
+            FormatError::BadChecksum { .. }
+        ));
+    }
+
+    #[test]
+    fn versions_are_negotiated() {
+        let supported = |major, minor| FormatVersion { major, minor };
+        let header = header();
+        assert_eq!(header.negotiate(supported(1, 0)).unwrap(), Compat::Current);
+        assert_eq!(header.negotiate(supported(2, 0)).unwrap(), Compat::Upgrade);
+        let err = header.negotiate(supported(0, 9)).unwrap_err();
+        assert_eq!(
+            err.downcast::<FormatError>().unwrap(),
+            FormatError::Unsupported {
+                found: V1,
+                supported: supported(0, 9)
+            }
+        );
+    }
+}
@@ -0,0 +1,734 @@
+use anyhow::{anyhow, Result};
+use fuser::{
+    BackgroundSession, FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyCreate,
+    ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite,
+    ReplyXattr, Request, TimeOrNow,
+};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+use crate::crash_faults::{CrashTiming, FsOperation};
+use crate::lazyfs::{errno_of, LazyFS, SetTime, SetattrChanges};
+use crate::TRACING_TARGET;
+
+/// Inode the kernel asks for the mount root by
+const ROOT_INO: u64 = 1;
+
+/// Sizes and times change in the cache behind the kernel's back, so it keeps nothing
+const TTL: Duration = Duration::ZERO;
+
+/// Write flag of a delayed write from the kernel's page cache. `fuser` only exports it with its
+/// `abi-7-9` feature, the value is part of the stable FUSE ABI.
+const FUSE_WRITE_CACHE: u32 = 1 << 0;
+
+fn errno(code: i32) -> anyhow::Error {
+    io::Error::from_raw_os_error(code).into()
+}
+
+/// `LazyFS` mounted through FUSE over `backing_dir`. Files go through the cache once opened,
+/// everything else is passed through to the backing directory. Kept apart from `LazyFS` as the
+/// session takes the file system by value while the command listener shares it.
+pub struct LazyFuse {
+    lazyfs: Arc<LazyFS>,
+    /// Backing path of every inode handed to the kernel, by the inode number it knows
+    paths: HashMap<u64, PathBuf>,
+    next_fh: u64,
+}
+
+impl LazyFuse {
+    pub fn new(lazyfs: Arc<LazyFS>) -> Result<Self> {
+        let backing_dir = lazyfs.config().backing_dir.clone();
+        if !backing_dir.is_dir() {
+            return Err(anyhow!(
+                "backing_dir '{}' is not a directory",
+                backing_dir.display()
+            ));
+        }
+        Ok(LazyFuse {
+            lazyfs,
+            paths: HashMap::from([(ROOT_INO, backing_dir)]),
+            next_fh: 1,
+        })
+    }
+
+    fn path(&self, ino: u64) -> Result<PathBuf> {
+        self.paths
+            .get(&ino)
+            .cloned()
+            .ok_or_else(|| errno(libc::ENOENT))
+    }
+
+    fn child(&self, parent: u64, name: &OsStr) -> Result<PathBuf> {
+        Ok(self.path(parent)?.join(name))
+    }
+
+    /// Attributes of `path` from `LazyFS::do_getattr`. Remembers the inode for later requests.
+    fn attr(&mut self, path: &Path) -> Result<FileAttr> {
+        let (ino, metadata) = self.lazyfs.do_getattr(path)?;
+        let ino = if path == self.lazyfs.config().backing_dir {
+            ROOT_INO
+        } else {
+            ino
+        };
+        self.paths.insert(ino, path.to_path_buf());
+        Ok(FileAttr {
+            ino,
+            size: metadata.size,
+            blocks: metadata.blocks.max(metadata.size.div_ceil(512)),
+            atime: metadata.atim,
+            mtime: metadata.mtim,
+            ctime: metadata.ctim,
+            crtime: metadata.ctim,
+            kind: file_type(metadata.mode),
+            perm: (metadata.mode & 0o7777) as u16,
+            nlink: metadata.nlinks,
+            uid: metadata.uid,
+            gid: metadata.gid,
+            rdev: metadata.rdev as u32,
+            blksize: self.lazyfs.config().io_block_size as u32,
+            flags: 0,
+        })
+    }
+
+    fn check_frozen(&self) -> Result<()> {
+        if self.lazyfs.is_frozen() {
+            return Err(errno(libc::EIO));
+        }
+        Ok(())
+    }
+
+    fn do_open(&mut self, ino: u64, flags: i32) -> Result<u64> {
+        let _permit = self.lazyfs.begin_op(FsOperation::Open)?;
+        self.lazyfs.next_op();
+        let path = self.path(ino)?;
+        self.lazyfs
+            .crash_hook(FsOperation::Open, CrashTiming::Before, &path, None)?;
+        let owner = self.lazyfs.owner_of(&path)?;
+        self.lazyfs
+            .cache()
+            .check_external_change(owner.clone(), path.clone())?;
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        self.lazyfs.open_handle(fh, &path, &owner, flags)?;
+        self.lazyfs
+            .crash_hook(FsOperation::Open, CrashTiming::After, &path, None)?;
+        Ok(fh)
+    }
+
+    fn do_create(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+    ) -> Result<(FileAttr, u64)> {
+        // The permit outlives the borrows of `self` below
+        let lazyfs = Arc::clone(&self.lazyfs);
+        let _permit = lazyfs.begin_op(FsOperation::Create)?;
+        lazyfs.next_op();
+        let path = self.child(parent, name)?;
+        lazyfs.crash_hook(FsOperation::Create, CrashTiming::Before, &path, None)?;
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .create_new(flags & libc::O_EXCL != 0)
+            .mode(mode & !umask)
+            .open(&path)?;
+        let owner = self.lazyfs.owner_of(&path)?;
+        let cache = lazyfs.cache();
+        if flags & libc::O_TRUNC != 0 {
+            cache.truncate_item(owner.clone(), 0)?;
+        }
+        cache.dirent_created(path.clone(), &owner)?;
+        let fh = self.next_fh;
+        self.next_fh += 1;
+        lazyfs.open_handle(fh, &path, &owner, flags)?;
+        let attr = self.attr(&path)?;
+        lazyfs.crash_hook(FsOperation::Create, CrashTiming::After, &path, None)?;
+        Ok((attr, fh))
+    }
+
+    fn do_setattr(&mut self, ino: u64, changes: SetattrChanges) -> Result<FileAttr> {
+        let path = self.path(ino)?;
+        self.lazyfs.do_setattr(&path, changes)?;
+        self.attr(&path)
+    }
+
+    fn do_read(&mut self, fh: u64, offset: i64, size: u32) -> Result<Vec<u8>> {
+        let handle = self.lazyfs.handle(fh)?.ok_or_else(|| errno(libc::EBADF))?;
+        let offset = u64::try_from(offset).map_err(|_| errno(libc::EINVAL))?;
+        let mut data = vec![0; size as usize];
+        let read = self
+            .lazyfs
+            .do_read(&handle.path, fh, offset, data.len(), &mut data)?;
+        data.truncate(read);
+        Ok(data)
+    }
+
+    /// Without a writeback cache, the kernel only marks a write `FUSE_WRITE_CACHE` when it
+    /// writes back the pages of a shared writable mapping. That is the one sign of an mmap the
+    /// mount gets, so the owner is flagged then, or the write refused under `deny_mmap`.
+    fn do_write(&mut self, fh: u64, offset: i64, data: &[u8], write_flags: u32) -> Result<u32> {
+        let handle = self.lazyfs.handle(fh)?.ok_or_else(|| errno(libc::EBADF))?;
+        let offset = u64::try_from(offset).map_err(|_| errno(libc::EINVAL))?;
+        if write_flags & FUSE_WRITE_CACHE != 0 {
+            self.lazyfs.on_mmap(&handle.path, &handle.owner)?;
+        }
+        self.lazyfs.do_write(&handle.path, fh, offset, data)
+    }
+
+    fn do_fsync(&mut self, fh: u64, datasync: bool) -> Result<()> {
+        let handle = self.lazyfs.handle(fh)?.ok_or_else(|| errno(libc::EBADF))?;
+        self.lazyfs.do_fsync(&handle.path, datasync)
+    }
+
+    fn do_release(&mut self, fh: u64) -> Result<()> {
+        // Left to the next fsync while the owner holds data the backing file never saw
+        if let Some(handle) = self.lazyfs.release_handle(fh)? {
+            let cache = self.lazyfs.cache();
+            if !cache.has_dirty_data(handle.owner.clone())? {
+                cache.settle_external_modification(handle.owner, handle.path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn do_unlink(&mut self, parent: u64, name: &OsStr) -> Result<()> {
+        let path = self.child(parent, name)?;
+        self.lazyfs.do_unlink(&path)
+    }
+
+    fn do_rename(
+        &mut self,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+    ) -> Result<()> {
+        let (from, to) = (self.child(parent, name)?, self.child(newparent, newname)?);
+        self.lazyfs.do_rename(&from, &to, flags)?;
+        self.attr(&to)?;
+        if flags & libc::RENAME_EXCHANGE != 0 {
+            self.attr(&from)?;
+        }
+        Ok(())
+    }
+
+    fn do_link(&mut self, ino: u64, newparent: u64, newname: &OsStr) -> Result<FileAttr> {
+        let (existing, new) = (self.path(ino)?, self.child(newparent, newname)?);
+        self.lazyfs.do_link(&existing, &new)?;
+        self.attr(&new)
+    }
+
+    fn do_symlink(&mut self, parent: u64, link_name: &OsStr, target: &Path) -> Result<FileAttr> {
+        let link = self.child(parent, link_name)?;
+        self.lazyfs.do_symlink(target, &link)?;
+        self.attr(&link)
+    }
+
+    fn do_mkdir(&mut self, parent: u64, name: &OsStr, mode: u32, umask: u32) -> Result<FileAttr> {
+        let path = self.child(parent, name)?;
+        self.lazyfs.do_mkdir(&path, mode & !umask)?;
+        self.attr(&path)
+    }
+
+    fn do_rmdir(&mut self, parent: u64, name: &OsStr) -> Result<()> {
+        let path = self.child(parent, name)?;
+        self.lazyfs.do_rmdir(&path)
+    }
+
+    fn do_fsyncdir(&mut self, ino: u64) -> Result<()> {
+        let dir = self.path(ino)?;
+        self.lazyfs.do_fsyncdir(&dir)
+    }
+
+    /// The path of `ino` and the attribute `name` as a string, for the xattr requests
+    fn xattr_target<'a>(&self, ino: u64, name: &'a OsStr) -> Result<(PathBuf, &'a str)> {
+        let name = name.to_str().ok_or_else(|| errno(libc::EINVAL))?;
+        Ok((self.path(ino)?, name))
+    }
+
+    fn do_readdir(&mut self, ino: u64) -> Result<Vec<(u64, FileType, PathBuf)>> {
+        self.check_frozen()?;
+        let dir = self.path(ino)?;
+        let mut entries = vec![
+            (ino, FileType::Directory, PathBuf::from(".")),
+            (ino, FileType::Directory, PathBuf::from("..")),
+        ];
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let stat = entry.metadata()?;
+            self.paths.insert(stat.ino(), entry.path());
+            entries.push((stat.ino(), file_type(stat.mode()), entry.file_name().into()));
+        }
+        Ok(entries)
+    }
+}
+
+fn file_type(mode: u32) -> FileType {
+    match mode & libc::S_IFMT {
+        libc::S_IFDIR => FileType::Directory,
+        libc::S_IFLNK => FileType::Symlink,
+        libc::S_IFIFO => FileType::NamedPipe,
+        libc::S_IFSOCK => FileType::Socket,
+        libc::S_IFCHR => FileType::CharDevice,
+        libc::S_IFBLK => FileType::BlockDevice,
+        _ => FileType::RegularFile,
+    }
+}
+
+/// Replies to getxattr and listxattr with `value`, or only its size if that is all `size` asks
+/// for
+fn reply_xattr(reply: ReplyXattr, size: u32, value: &[u8]) {
+    if size == 0 {
+        reply.size(value.len() as u32);
+    } else if value.len() > size as usize {
+        reply.error(libc::ERANGE);
+    } else {
+        reply.data(value);
+    }
+}
+
+/// Logs a failed request and hands back the errno to reply with
+fn failed(op: &str, e: anyhow::Error) -> i32 {
+    let code = errno_of(&e);
+    if code == libc::ENOENT {
+        debug!(target: TRACING_TARGET, op, "{:?}", e);
+    } else {
+        warn!(target: TRACING_TARGET, op, "{:?}", e);
+    }
+    code
+}
+
+impl Filesystem for LazyFuse {
+    fn destroy(&mut self) {
+        let flush = self.lazyfs.config().sync_on_shutdown;
+        if let Err(e) = self.lazyfs.shutdown(flush) {
+            warn!(target: TRACING_TARGET, "Failed to shut down cleanly: {:?}", e);
+        }
+    }
+
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let attr = self
+            .check_frozen()
+            .and_then(|_| self.child(parent, name))
+            .and_then(|path| self.attr(&path));
+        match attr {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(failed("lookup", e)),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        let attr = self
+            .check_frozen()
+            .and_then(|_| self.path(ino))
+            .and_then(|path| self.attr(&path));
+        match attr {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(failed("getattr", e)),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<std::time::SystemTime>,
+        _fh: Option<u64>,
+        _crtime: Option<std::time::SystemTime>,
+        _chgtime: Option<std::time::SystemTime>,
+        _bkuptime: Option<std::time::SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        let time = |time: Option<TimeOrNow>| {
+            time.map(|time| match time {
+                TimeOrNow::Now => SetTime::Now,
+                TimeOrNow::SpecificTime(time) => SetTime::At(time),
+            })
+        };
+        let changes = SetattrChanges {
+            size,
+            atime: time(atime),
+            mtime: time(mtime),
+            mode,
+            uid,
+            gid,
+        };
+        match self.do_setattr(ino, changes) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(e) => reply.error(failed("setattr", e)),
+        }
+    }
+
+    fn open(&mut self, _req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        match self.do_open(ino, flags) {
+            Ok(fh) => reply.opened(fh, 0),
+            Err(e) => reply.error(failed("open", e)),
+        }
+    }
+
+    fn create(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        match self.do_create(parent, name, mode, umask, flags) {
+            Ok((attr, fh)) => reply.created(&TTL, &attr, 0, fh, 0),
+            Err(e) => reply.error(failed("create", e)),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        match self.do_read(fh, offset, size) {
+            Ok(data) => reply.data(&data),
+            Err(e) => reply.error(failed("read", e)),
+        }
+    }
+
+    fn write(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        match self.do_write(fh, offset, data, write_flags) {
+            Ok(written) => reply.written(written),
+            Err(e) => reply.error(failed("write", e)),
+        }
+    }
+
+    /// Closing a file syncs nothing, only fsync does
+    fn flush(&mut self, _req: &Request<'_>, _ino: u64, _fh: u64, _lock: u64, reply: ReplyEmpty) {
+        reply.ok();
+    }
+
+    fn release(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        match self.do_release(fh) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(failed("release", e)),
+        }
+    }
+
+    fn fsync(&mut self, _req: &Request<'_>, _ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        match self.do_fsync(fh, datasync) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(failed("fsync", e)),
+        }
+    }
+
+    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.do_unlink(parent, name) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(failed("unlink", e)),
+        }
+    }
+
+    fn rename(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        match self.do_rename(parent, name, newparent, newname, flags) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(failed("rename", e)),
+        }
+    }
+
+    fn link(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        match self.do_link(ino, newparent, newname) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(failed("link", e)),
+        }
+    }
+
+    fn mkdir(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        match self.do_mkdir(parent, name, mode, umask) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(failed("mkdir", e)),
+        }
+    }
+
+    fn rmdir(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        match self.do_rmdir(parent, name) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(failed("rmdir", e)),
+        }
+    }
+
+    fn fsyncdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        _datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        match self.do_fsyncdir(ino) {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(failed("fsyncdir", e)),
+        }
+    }
+
+    fn setxattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        let set = self
+            .xattr_target(ino, name)
+            .and_then(|(path, name)| self.lazyfs.do_setxattr(&path, name, value, flags));
+        match set {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(failed("setxattr", e)),
+        }
+    }
+
+    fn getxattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        let value = self
+            .xattr_target(ino, name)
+            .and_then(|(path, name)| self.lazyfs.do_getxattr(&path, name));
+        match value {
+            Ok(value) => reply_xattr(reply, size, &value),
+            Err(e) => reply.error(failed("getxattr", e)),
+        }
+    }
+
+    fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        let names = self
+            .path(ino)
+            .and_then(|path| self.lazyfs.do_listxattr(&path));
+        match names {
+            // Each name followed by a NUL, as listxattr(2) hands them out
+            Ok(names) => {
+                let list: Vec<u8> = names
+                    .iter()
+                    .flat_map(|name| name.bytes().chain([0]))
+                    .collect();
+                reply_xattr(reply, size, &list)
+            }
+            Err(e) => reply.error(failed("listxattr", e)),
+        }
+    }
+
+    fn removexattr(&mut self, _req: &Request<'_>, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        let removed = self
+            .xattr_target(ino, name)
+            .and_then(|(path, name)| self.lazyfs.do_removexattr(&path, name));
+        match removed {
+            Ok(()) => reply.ok(),
+            Err(e) => reply.error(failed("removexattr", e)),
+        }
+    }
+
+    fn symlink(
+        &mut self,
+        _req: &Request<'_>,
+        parent: u64,
+        link_name: &OsStr,
+        target: &Path,
+        reply: ReplyEntry,
+    ) {
+        match self.do_symlink(parent, link_name, target) {
+            Ok(attr) => reply.entry(&TTL, &attr, 0),
+            Err(e) => reply.error(failed("symlink", e)),
+        }
+    }
+
+    fn statfs(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyStatfs) {
+        let statfs = self
+            .check_frozen()
+            .and_then(|_| self.path(ino))
+            .and_then(|path| self.lazyfs.do_statfs(&path));
+        match statfs {
+            Ok(s) => reply.statfs(
+                s.blocks, s.bfree, s.bavail, s.files, s.ffree, s.bsize, s.namelen, s.bsize,
+            ),
+            Err(e) => reply.error(failed("statfs", e)),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        let target = self
+            .check_frozen()
+            .and_then(|_| self.path(ino))
+            .and_then(|path| Ok(fs::read_link(path)?));
+        match target {
+            Ok(target) => reply.data(target.as_os_str().as_bytes()),
+            Err(e) => reply.error(failed("readlink", e)),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let entries = match self.do_readdir(ino) {
+            Ok(entries) => entries,
+            Err(e) => return reply.error(failed("readdir", e)),
+        };
+        for (i, (ino, kind, name)) in entries.into_iter().enumerate().skip(offset as usize) {
+            if reply.add(ino, i as i64 + 1, kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+}
+
+fn mount_options() -> Vec<MountOption> {
+    vec![MountOption::FSName("lazyfs".to_string())]
+}
+
+/// Mounts `lazyfs` at `mount_root` over `backing_dir` and serves it until it is unmounted
+///
+/// Whatever a previous run left behind is only cleaned up if `lazyfs` was built with
+/// `LazyFSBuilder::with_startup_recovery`, which has to happen before its command listener
+/// opens the fifo.
+pub fn mount(lazyfs: Arc<LazyFS>) -> Result<()> {
+    let mount_root = lazyfs.config().mount_root.clone();
+    fuser::mount2(LazyFuse::new(lazyfs)?, mount_root, &mount_options())?;
+    Ok(())
+}
+
+/// Same as `mount` on a thread of its own, unmounted once the session is dropped
+pub fn spawn_mount(lazyfs: Arc<LazyFS>) -> Result<BackgroundSession> {
+    let mount_root = lazyfs.config().mount_root.clone();
+    Ok(fuser::spawn_mount2(
+        LazyFuse::new(lazyfs)?,
+        mount_root,
+        &mount_options(),
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pagecache::config::Config;
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    /// Needs /dev/fuse and fusermount3, run with `cargo test --features fuse -- --ignored`
+    #[test]
+    #[ignore]
+    fn writes_fsyncs_and_reads_through_a_mount() {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-mount", std::process::id()));
+        let config = Config {
+            mount_root: dir.join("mnt"),
+            backing_dir: dir.join("backing"),
+            ..Default::default()
+        };
+        fs::create_dir_all(&config.mount_root).unwrap();
+        fs::create_dir_all(&config.backing_dir).unwrap();
+        let lazyfs = LazyFS::builder().config(config.clone()).build().unwrap();
+        let session = spawn_mount(lazyfs).unwrap();
+
+        let mounted = config.mount_root.join("wal");
+        let backing = config.backing_dir.join("wal");
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&mounted)
+            .unwrap();
+        file.write_all(b"hello lazyfs").unwrap();
+        // Nothing reaches the backing file before the fsync
+        assert!(fs::read(&backing).unwrap().is_empty());
+        file.sync_all().unwrap();
+        assert_eq!(fs::read(&backing).unwrap(), b"hello lazyfs");
+
+        let mut read = String::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_string(&mut read).unwrap();
+        assert_eq!(read, "hello lazyfs");
+        drop(file);
+
+        fs::remove_file(&mounted).unwrap();
+        assert!(!backing.exists());
+        drop(session);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
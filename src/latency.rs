@@ -0,0 +1,117 @@
+use anyhow::Result;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::pagecache::config::{splitmix64, LatencyConfig};
+use crate::path_matcher::PathMatcher;
+
+/// Compiled form of `LatencyConfig`. Given the same seed and sequence of reads it always produces
+/// the same delays.
+#[derive(Debug, Default)]
+pub struct LatencyModel {
+    hit_latency: Duration,
+    miss_latency: Duration,
+    jitter: Duration,
+    seed: u64,
+    counter: AtomicU64,
+    overrides: Vec<(PathMatcher, Duration, Duration)>,
+}
+
+impl LatencyModel {
+    pub fn from_config(config: &LatencyConfig) -> Result<Self> {
+        let overrides = config
+            .overrides
+            .iter()
+            .map(|o| {
+                let matcher = PathMatcher::new(&o.path_regex, o.match_options)?;
+                Ok((matcher, o.hit_latency, o.miss_latency))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(LatencyModel {
+            hit_latency: config.hit_latency,
+            miss_latency: config.miss_latency,
+            jitter: config.jitter,
+            seed: config.seed,
+            counter: AtomicU64::new(0),
+            overrides,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.hit_latency.is_zero()
+            || !self.miss_latency.is_zero()
+            || !self.jitter.is_zero()
+            || !self.overrides.is_empty()
+    }
+
+    /// Delay for a read of `path` that found `hits` blocks in the cache and had to fetch `misses`
+    /// from the backing file
+    pub fn delay(&self, path: &Path, hits: u32, misses: u32) -> Duration {
+        let (hit_latency, miss_latency) = self
+            .overrides
+            .iter()
+            .find(|(matcher, _, _)| matcher.is_match(path))
+            .map(|&(_, hit, miss)| (hit, miss))
+            .unwrap_or((self.hit_latency, self.miss_latency));
+
+        let mut delay = hit_latency * hits + miss_latency * misses;
+        let jitter_nanos = self.jitter.as_nanos() as u64;
+        if jitter_nanos > 0 {
+            let counter = self.counter.fetch_add(1, Ordering::SeqCst);
+            delay += Duration::from_nanos(splitmix64(self.seed ^ counter) % (jitter_nanos + 1));
+        }
+        delay
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pagecache::config::LatencyOverride;
+
+    #[test]
+    fn delay_depends_on_hits_misses_and_path() {
+        let config = LatencyConfig {
+            hit_latency: Duration::from_micros(10),
+            miss_latency: Duration::from_millis(2),
+            overrides: vec![LatencyOverride {
+                path_regex: "\\.sst$".to_string(),
+                match_options: Default::default(),
+                hit_latency: Duration::ZERO,
+                miss_latency: Duration::from_millis(5),
+            }],
+            ..Default::default()
+        };
+        let model = LatencyModel::from_config(&config).unwrap();
+
+        assert_eq!(
+            model.delay(Path::new("/data/wal"), 3, 1),
+            Duration::from_micros(2030)
+        );
+        assert_eq!(
+            model.delay(Path::new("/data/000001.sst"), 3, 2),
+            Duration::from_millis(10)
+        );
+    }
+
+    #[test]
+    fn jitter_is_bounded_and_reproducible() {
+        let config = LatencyConfig {
+            miss_latency: Duration::from_millis(1),
+            jitter: Duration::from_micros(500),
+            seed: 42,
+            ..Default::default()
+        };
+        let first = LatencyModel::from_config(&config).unwrap();
+        let second = LatencyModel::from_config(&config).unwrap();
+
+        for _ in 0..100 {
+            let delay = first.delay(Path::new("/wal"), 0, 1);
+            assert!(delay >= Duration::from_millis(1));
+            assert!(delay <= Duration::from_micros(1500));
+            assert_eq!(delay, second.delay(Path::new("/wal"), 0, 1));
+        }
+    }
+}
@@ -1,32 +1,376 @@
 use anyhow::{anyhow, Result};
-use regex::Regex;
-use std::collections::{HashMap, HashSet};
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::CString;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, FileExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
 
-use crate::pagecache::{cache, config};
+use crate::cache_diff::{self, CacheDiff, CacheMark};
+use crate::clock::{Clock, SystemClock};
+use crate::commands::Listener;
+use crate::crash_faults::{
+    CrashFaultSpec, CrashFaultStatus, CrashFaults, CrashMatch, CrashMode, CrashRegistration,
+    CrashTiming, FaultId, FsOperation,
+};
+use crate::crash_report::{CrashReport, CRASH_REPORT_VERSION};
+use crate::fault_state::{self, spec_hash, FaultStateFile, SavedFault};
+use crate::fault_stats::{Evaluation, FaultCounters, FaultStats, OpContext, OpLatency, OpRecord};
+use crate::fence::{MutationGuard, WriteFence};
+use crate::formats::{geometry_hash, Artifact, Header};
+use crate::latency::LatencyModel;
+use crate::op_limit::{OpLimiter, OpPermit, QueueWaitStats};
+use crate::pagecache::config::Fault;
+use crate::pagecache::dirents;
+use crate::pagecache::engine::backends;
+use crate::pagecache::engine::PageCacheEngine;
+use crate::pagecache::item::metadata::{Metadata, MetadataField};
+use crate::pagecache::owner::OwnerKey;
+use crate::pagecache::{cache, config, OwnerId};
+use crate::path_matcher::PathMatcher;
+use crate::startup::{self, RecoveryReport};
+use crate::TRACING_TARGET;
 
-const ALLOW_CRASH_FS_OPERATIONS: [&str; 11] = [
-    "unlink", "truncate", "fsync", "write", "create", "access", "open", "read", "rename", "link",
-    "symlink",
-];
+/// Activation state of a single fault as reported by `LazyFS::fault_status`
+#[derive(Clone, Debug, PartialEq)]
+pub struct FaultStatus {
+    pub key: String,
+    pub id: FaultId,
+    pub window: config::FaultWindow,
+    pub detail: Option<String>,
+    /// Times the fault would have triggered while dry-run was on
+    pub dry_run_hits: usize,
+    pub counters: FaultCounters,
+}
+
+/// A fault that matched while dry-run was on and was let through instead of injected
+#[derive(Clone, Debug, PartialEq)]
+pub struct DryRunEvent {
+    /// Global op count at the time
+    pub op: u64,
+    /// Key of the fault, as in `FaultStatus`
+    pub fault: String,
+    pub spec: String,
+    pub path: PathBuf,
+    /// What the fault would have done
+    pub action: String,
+}
+
+/// Parts of a split write that reached the backing file, numbered from 1, with their bytes
+pub type PersistedParts = Vec<(u32, Vec<u8>)>;
+
+/// What the open flags of a handle ask of the writes made through it
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum WriteDurability {
+    /// Written back whenever the file is synced
+    #[default]
+    Cached,
+    /// `O_DSYNC`, the written data reaches the backing file before the write returns
+    Data,
+    /// `O_SYNC`, as with `O_DSYNC` along with the file's times
+    Full,
+}
+
+impl WriteDurability {
+    pub fn from_open_flags(flags: i32) -> Self {
+        // O_SYNC carries the O_DSYNC bit as well
+        if flags & libc::O_SYNC == libc::O_SYNC {
+            WriteDurability::Full
+        } else if flags & libc::O_DSYNC != 0 {
+            WriteDurability::Data
+        } else {
+            WriteDurability::Cached
+        }
+    }
+}
+
+/// How the data read and written through a handle goes through the cache, from its open flags
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CachePolicy {
+    /// Cached until the file is synced
+    #[default]
+    Cached,
+    /// `O_SYNC` or `O_DSYNC`, cached and synced before every write returns
+    WriteThrough,
+    /// `O_DIRECT`, reads and writes go straight to the backing file
+    Bypass,
+}
+
+impl CachePolicy {
+    pub fn from_open_flags(flags: i32) -> Self {
+        if flags & libc::O_DIRECT != 0 {
+            CachePolicy::Bypass
+        } else if WriteDurability::from_open_flags(flags) != WriteDurability::Cached {
+            CachePolicy::WriteThrough
+        } else {
+            CachePolicy::Cached
+        }
+    }
+}
+
+/// A file handle handed out by open or create
+#[derive(Clone, Debug, PartialEq)]
+pub struct OpenHandle {
+    pub path: PathBuf,
+    pub owner: OwnerId,
+    pub durability: WriteDurability,
+    /// The policy its own flags ask for, `LazyFS::cache_policy` is the one that applies
+    pub policy: CachePolicy,
+    /// `O_APPEND`, writes land at the cached size whatever their offset
+    pub append: bool,
+}
+
+/// Held by a handler from `begin_op` until it is done: its place under the op limits, and the
+/// control gate a batch commit keeps handlers out with
+#[derive(Debug)]
+pub struct HandlerPermit<'a> {
+    _permit: OpPermit<'a>,
+    _control: RwLockReadGuard<'a, ()>,
+}
+
+/// A time set by a setattr, as `utimensat` takes it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SetTime {
+    /// `UTIME_NOW`, the time of the call
+    Now,
+    At(SystemTime),
+}
+
+/// What a setattr changes, `None` for what it leaves alone (`UTIME_OMIT` for the times)
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SetattrChanges {
+    pub size: Option<u64>,
+    pub atime: Option<SetTime>,
+    pub mtime: Option<SetTime>,
+    /// Permission bits, the file type is kept
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+/// What a statfs reports, in blocks of `bsize` bytes as `statvfs` would
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StatFs {
+    pub blocks: u64,
+    pub bfree: u64,
+    /// Free blocks unprivileged users can take
+    pub bavail: u64,
+    pub files: u64,
+    pub ffree: u64,
+    pub bsize: u32,
+    pub namelen: u32,
+}
+
+impl StatFs {
+    /// The numbers of the file system holding `path`, with the block counts converted to
+    /// blocks of `bsize` bytes
+    fn of(path: &Path, bsize: u32) -> std::io::Result<Self> {
+        let c_path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+        if unsafe { libc::statvfs(c_path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let stat = unsafe { stat.assume_init() };
+        let blocks = |count: libc::fsblkcnt_t| count * stat.f_frsize / bsize as u64;
+        Ok(StatFs {
+            blocks: blocks(stat.f_blocks),
+            bfree: blocks(stat.f_bfree),
+            bavail: blocks(stat.f_bavail),
+            files: stat.f_files,
+            ffree: stat.f_ffree,
+            bsize,
+            namelen: stat.f_namemax as u32,
+        })
+    }
+
+    /// Replaces the block counts by those of a cache of `capacity` bytes with `usage` percent of
+    /// its pages taken
+    fn report_cache(&mut self, capacity: u64, usage: f64) {
+        self.blocks = capacity / self.bsize as u64;
+        let used = (self.blocks as f64 * usage / 100.0).round() as u64;
+        self.bfree = self.blocks.saturating_sub(used);
+        self.bavail = self.bfree;
+    }
+}
 
 pub struct LazyFS {
     cache: cache::Cache,
     config: config::Config,
-    faults: HashMap<String, Vec<Arc<dyn config::Fault>>>,
-    crash_faults: HashMap<String, HashSet<String>>,
+    /// Faults keyed by the file they target, from the config file or armed at runtime
+    faults: Mutex<HashMap<String, Vec<Arc<dyn config::Fault>>>>,
     pending_write: Mutex<Write>,
     path_injecting_fault: Mutex<PathBuf>,
 
-    pub crash_faults_before: HashMap<String, Vec<(Regex, String)>>,
-    pub crash_faults_after: HashMap<String, Vec<(Regex, String)>>,
+    /// Crash faults registered at runtime, capped at `max_crash_faults`
+    crash_patterns: Mutex<CrashFaults>,
 
-    allow_crash_fs_ops: HashSet<String>,
+    /// Operations on two paths, where a crash fault matching either one fires
     fs_op_mult_path: HashSet<String>,
+    /// Set by a `freeze` crash, after which every operation fails with EIO
+    frozen: AtomicBool,
+
+    /// Number of operations intercepted so far, used to schedule faults
+    op_counter: AtomicU64,
+    /// Time source for fault schedules
+    clock: Arc<dyn Clock>,
+    quota_faults: Mutex<Vec<Arc<config::QuotaFault>>>,
+    short_write_faults: Mutex<Vec<Arc<config::ShortWriteFault>>>,
+    stale_read_faults: Mutex<Vec<Arc<config::StaleReadFault>>>,
+    rename_tear_faults: Mutex<Vec<Arc<config::RenameTearFault>>>,
+    latency: LatencyModel,
+    dry_run: AtomicBool,
+    dry_run_events: Mutex<Vec<DryRunEvent>>,
+    /// What the faults were checked against and which operations they interfered with
+    fault_stats: Mutex<FaultStats>,
+    /// Labeled marks of the cache state, oldest first, at most `cache_diff::MAX_MARKS`
+    marks: Mutex<VecDeque<(String, CacheMark)>>,
+    /// Held by `fence-writes` to keep writes, truncates and renames out
+    write_fence: WriteFence,
+    /// Queue depth limits taken at the top of every handler
+    op_limiter: OpLimiter,
+    /// Taken for reading by every handler and for writing by a batch commit, so no operation
+    /// sees only part of a batch
+    control_gate: RwLock<()>,
+    /// Open file handles, by fh
+    handles: Mutex<HashMap<u64, OpenHandle>>,
+    /// Reads control commands from `fifo_path` once started
+    command_listener: Mutex<Option<Listener>>,
+    /// Saves the fault state every `fault_state_save_interval_ms`, if set
+    fault_state_saver: Mutex<Option<fault_state::Saver>>,
+    /// Set once `shutdown` ran
+    shut_down: AtomicBool,
+    /// What startup recovery cleaned up, if it ran
+    recovery_report: Option<RecoveryReport>,
+}
+
+/// Sets up a `LazyFS` and the cache under it. Everything is optional: by default the config is
+/// `Config::default()`, the engine the one its `engine` names, no fault is armed, no command
+/// listener is started and no leftover state is recovered.
+#[derive(Default)]
+pub struct LazyFSBuilder {
+    config: Option<config::Config>,
+    config_file: Option<String>,
+    engine: Option<Box<dyn PageCacheEngine>>,
+    faults: HashMap<String, Vec<Arc<dyn config::Fault>>>,
+    faults_from_config: bool,
+    fifo_listener: bool,
+    startup_recovery: bool,
+    clock: Option<Arc<dyn Clock>>,
+}
+
+impl LazyFSBuilder {
+    pub fn config(mut self, config: config::Config) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// Loads the config from `filename` on `build`, unless one is given with `config`
+    pub fn config_file(mut self, filename: &str) -> Self {
+        self.config_file = Some(filename.to_string());
+        self
+    }
+
+    pub fn engine(mut self, engine: impl PageCacheEngine + 'static) -> Self {
+        self.engine = Some(Box::new(engine));
+        self
+    }
+
+    /// Arms `faults` on top of any others, keyed by the file they target
+    pub fn faults(mut self, faults: HashMap<String, Vec<Arc<dyn config::Fault>>>) -> Self {
+        for (file, faults) in faults {
+            self.faults.entry(file).or_default().extend(faults);
+        }
+        self
+    }
+
+    /// Arms the faults declared in the `[[injection]]` tables of the `config_file`
+    pub fn faults_from_config(mut self) -> Self {
+        self.faults_from_config = true;
+        self
+    }
+
+    /// Whether to start reading commands from `fifo_path`, see `LazyFS::start_command_listener`
+    pub fn with_fifo_listener(mut self, listen: bool) -> Self {
+        self.fifo_listener = listen;
+        self
+    }
+
+    /// Whether to clean up what a previous run left behind before anything else starts, see
+    /// `LazyFS::recover_startup_state`
+    pub fn with_startup_recovery(mut self, recover: bool) -> Self {
+        self.startup_recovery = recover;
+        self
+    }
+
+    /// Time source for fault schedules, see `LazyFS::with_clock`
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    pub fn build(self) -> Result<Arc<LazyFS>> {
+        let config = match (self.config, &self.config_file) {
+            (Some(config), _) => config,
+            (None, Some(filename)) => config::Config::load_config(filename)?,
+            (None, None) => config::Config::default(),
+        };
+        let mut faults = self.faults;
+        if self.faults_from_config {
+            let filename = self
+                .config_file
+                .as_deref()
+                .ok_or_else(|| anyhow!("No config_file to load the faults from"))?;
+            for (file, loaded) in config::Config::load_faults(filename)? {
+                faults.entry(file).or_default().extend(loaded);
+            }
+        }
+        let engine = match self.engine {
+            Some(engine) => engine,
+            None => backends::from_config(&config)?,
+        };
+
+        let cache = cache::Cache::with_boxed_engine(config.clone(), engine);
+        let mut lazyfs = LazyFS::from_parts(cache, config, faults);
+        if let Some(clock) = self.clock {
+            lazyfs = lazyfs.with_clock(clock);
+        }
+        for fault in lazyfs
+            .faults
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .values()
+            .flatten()
+        {
+            fault.schedule().arm_once(lazyfs.clock.now());
+        }
+        // Ahead of the listener, which would otherwise open a stale fifo
+        if self.startup_recovery {
+            lazyfs.recover_startup_state()?;
+        }
+
+        lazyfs.resume_fault_state()?;
+        let lazyfs = Arc::new(lazyfs);
+        if lazyfs.config.fault_state_save_interval_ms > 0 {
+            lazyfs.start_fault_state_saver()?;
+        }
+        if self.fifo_listener {
+            lazyfs.start_command_listener()?;
+        }
+        Ok(lazyfs)
+    }
 }
 
 impl LazyFS {
+    pub fn builder() -> LazyFSBuilder {
+        LazyFSBuilder::default()
+    }
+
+    #[deprecated(note = "use LazyFS::builder")]
     pub fn new(
         cache: cache::Cache,
         config: config::Config,
@@ -34,68 +378,4570 @@ impl LazyFS {
         _fht_worker: fn(&LazyFS),
         faults: HashMap<String, Vec<Arc<dyn config::Fault>>>,
     ) -> LazyFS {
-        let mut crash_faults_before = HashMap::new();
-        let mut crash_faults_after = HashMap::new();
+        Self::from_parts(cache, config, faults)
+    }
 
-        for op in ALLOW_CRASH_FS_OPERATIONS.iter() {
-            crash_faults_before.insert(op.to_string(), Vec::new());
-            crash_faults_after.insert(op.to_string(), Vec::new());
-        }
+    fn from_parts(
+        cache: cache::Cache,
+        config: config::Config,
+        faults: HashMap<String, Vec<Arc<dyn config::Fault>>>,
+    ) -> LazyFS {
+        let crash_patterns = Mutex::new(CrashFaults::new(config.max_crash_faults));
+
+        let latency = LatencyModel::from_config(&config.latency).unwrap_or_else(|e| {
+            warn!(target: TRACING_TARGET, "ignoring invalid latency config: {:?}", e);
+            LatencyModel::default()
+        });
+
+        let dry_run = AtomicBool::new(config.dry_run);
+        let write_fence = WriteFence::new(
+            config.fence_mode,
+            Duration::from_millis(config.fence_max_ms),
+        );
+        let op_limiter = OpLimiter::from_config(&config);
 
         LazyFS {
             cache,
             config,
-            faults,
-            crash_faults: HashMap::new(),
+            faults: Mutex::new(faults),
             pending_write: Mutex::new(Write::default()),
             path_injecting_fault: Mutex::new(PathBuf::from("none")),
 
-            crash_faults_before,
-            crash_faults_after,
+            crash_patterns,
 
-            allow_crash_fs_ops: [
-                "unlink", "truncate", "fsync", "write", "create", "access", "open", "read",
-                "rename", "link", "symlink",
-            ]
-            .iter()
-            .map(|&s| s.into())
-            .collect(),
             fs_op_mult_path: ["rename", "link", "symlink"]
                 .iter()
                 .map(|&s| s.into())
                 .collect(),
+            frozen: AtomicBool::new(false),
+
+            op_counter: AtomicU64::new(0),
+            clock: Arc::new(SystemClock),
+            quota_faults: Mutex::new(Vec::new()),
+            short_write_faults: Mutex::new(Vec::new()),
+            stale_read_faults: Mutex::new(Vec::new()),
+            rename_tear_faults: Mutex::new(Vec::new()),
+            latency,
+            dry_run,
+            dry_run_events: Mutex::new(Vec::new()),
+            fault_stats: Mutex::new(FaultStats::default()),
+            marks: Mutex::new(VecDeque::new()),
+            write_fence,
+            op_limiter,
+            control_gate: RwLock::new(()),
+            handles: Mutex::new(HashMap::new()),
+            command_listener: Mutex::new(None),
+            fault_state_saver: Mutex::new(None),
+            shut_down: AtomicBool::new(false),
+            recovery_report: None,
         }
     }
 
-    pub fn get_path_injecting_fault(&self) -> Result<PathBuf> {
-        let lock = self
-            .path_injecting_fault
+    /// Cleans up what a previous run left behind, see `startup::recover`. Meant to run once
+    /// before mounting, the report is kept for `recovery_report`.
+    pub fn recover_startup_state(&mut self) -> Result<&RecoveryReport> {
+        let report = startup::recover(&self.config)?;
+        info!(target: TRACING_TARGET, "{}", report);
+        Ok(self.recovery_report.insert(report))
+    }
+
+    /// What startup recovery cleaned up, `None` unless `recover_startup_state` ran
+    pub fn recovery_report(&self) -> Option<&RecoveryReport> {
+        self.recovery_report.as_ref()
+    }
+
+    /// Time source for fault schedules, the write fence and the timestamps the cache stamps
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.cache = self.cache.with_clock(clock.clone());
+        self.write_fence = self.write_fence.with_clock(clock.clone());
+        self.clock = clock;
+        self
+    }
+
+    pub fn cache(&self) -> &cache::Cache {
+        &self.cache
+    }
+
+    pub fn config(&self) -> &config::Config {
+        &self.config
+    }
+
+    /// Starts running the commands sent to `fifo_path` on a thread of their own, see
+    /// `commands::Listener`. The thread stops when `LazyFS` is dropped.
+    pub fn start_command_listener(self: &Arc<Self>) -> Result<()> {
+        if self.config.fifo_path.as_os_str().is_empty() {
+            return Err(anyhow!("No fifo_path to read commands from"));
+        }
+        let mut command_listener = self
+            .command_listener
             .lock()
-            .map_err(|e| anyhow!("Unable to acquire lock on path injecting fault: {:?}", e))?;
-        Ok(lock.clone())
+            .map_err(|e| anyhow!("Unable to acquire lock on command listener: {:?}", e))?;
+        if command_listener.is_some() {
+            return Err(anyhow!("The command listener is already running"));
+        }
+        *command_listener = Some(Listener::spawn(
+            Arc::downgrade(self),
+            &self.config.fifo_path,
+            &self.config.fifo_path_completed,
+        )?);
+        Ok(())
     }
 
+    /// Starts saving the fault state every `fault_state_save_interval_ms` on a thread of its
+    /// own, see `fault_state::Saver`. The thread stops when `LazyFS` is shut down or dropped.
+    pub fn start_fault_state_saver(self: &Arc<Self>) -> Result<()> {
+        let mut saver = self
+            .fault_state_saver
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on fault state saver: {:?}", e))?;
+        if saver.is_some() {
+            return Err(anyhow!("The fault state saver is already running"));
+        }
+        *saver = Some(fault_state::Saver::spawn(
+            Arc::downgrade(self),
+            Duration::from_millis(self.config.fault_state_save_interval_ms),
+        )?);
+        Ok(())
+    }
 
-}
+    /// Ends the run the way a clean unmount would rather than an injected crash: stops the
+    /// command listener and the fault state saver and removes the FIFOs, syncs every cached file
+    /// first if `flush` is set (the write a reorder fault still holds back included), and saves
+    /// the fault state. Only the first call does anything.
+    pub fn shutdown(&self, flush: bool) -> Result<()> {
+        if self.shut_down.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        info!(target: TRACING_TARGET, flush, "shutting down");
 
-struct Write {
-    path: PathBuf,
-    buf: Vec<u8>,
-    offset: u32,
-}
+        let listener = self
+            .command_listener
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on command listener: {:?}", e))?
+            .take();
+        drop(listener);
+        let saver = self
+            .fault_state_saver
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on fault state saver: {:?}", e))?
+            .take();
+        drop(saver);
+        for fifo in [&self.config.fifo_path, &self.config.fifo_path_completed] {
+            if fifo.as_os_str().is_empty() {
+                continue;
+            }
+            match std::fs::remove_file(fifo) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    let path = fifo.display();
+                    warn!(target: TRACING_TARGET, %path, "Unable to remove fifo: {:?}", e);
+                }
+                _ => {}
+            }
+        }
 
-impl Write {
-    pub fn new(path: PathBuf, buf: Vec<u8>, offset: u32) -> Write {
-        Write { path, buf, offset }
+        if flush {
+            self.flush_pending_write()?;
+            self.cache.full_checkpoint()?;
+        }
+        self.save_fault_state()
     }
-}
 
-impl Default for Write {
-    fn default() -> Self {
-        Write {
-            path: "".into(),
-            buf: Vec::new(),
-            offset: 0,
+    /// Counts an intercepted operation, returning its position in the global op order
+    pub fn next_op(&self) -> u64 {
+        self.op_counter.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    pub fn op_count(&self) -> u64 {
+        self.op_counter.load(Ordering::SeqCst)
+    }
+
+    /// Activation window and counters of every configured fault, keyed like `faults`. Quota
+    /// faults are keyed by their index.
+    pub fn fault_status(&self) -> Result<Vec<FaultStatus>> {
+        let mut faults: Vec<(String, Arc<dyn config::Fault>)> = Vec::new();
+        {
+            let keyed = self
+                .faults
+                .lock()
+                .map_err(|e| anyhow!("Unable to acquire lock on faults: {:?}", e))?;
+            for (key, keyed) in keyed.iter() {
+                faults.extend(keyed.iter().map(|fault| (key.clone(), fault.clone())));
+            }
+            faults.sort_by(|a, b| a.0.cmp(&b.0));
+
+            let quota_faults = self
+                .quota_faults
+                .lock()
+                .map_err(|e| anyhow!("Unable to acquire lock on quota faults: {:?}", e))?;
+            for (i, fault) in quota_faults.iter().enumerate() {
+                faults.push((format!("quota-{}", i), fault.clone()));
+            }
+            let short_write_faults = self
+                .short_write_faults
+                .lock()
+                .map_err(|e| anyhow!("Unable to acquire lock on short write faults: {:?}", e))?;
+            for (i, fault) in short_write_faults.iter().enumerate() {
+                faults.push((format!("short-write-{}", i), fault.clone()));
+            }
+            let stale_read_faults = self
+                .stale_read_faults
+                .lock()
+                .map_err(|e| anyhow!("Unable to acquire lock on stale read faults: {:?}", e))?;
+            for (i, fault) in stale_read_faults.iter().enumerate() {
+                faults.push((format!("stale-read-{}", i), fault.clone()));
+            }
+            let rename_tear_faults = self
+                .rename_tear_faults
+                .lock()
+                .map_err(|e| anyhow!("Unable to acquire lock on rename tear faults: {:?}", e))?;
+            for (i, fault) in rename_tear_faults.iter().enumerate() {
+                faults.push((format!("rename-tear-{}", i), fault.clone()));
+            }
+        }
+
+        // Taken once the fault locks are released, the fault checks take it under them
+        let op_count = self.op_count();
+        let now = self.clock.now();
+        let events = self.dry_run_events()?;
+        let mut stats = self
+            .fault_stats
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on fault stats: {:?}", e))?;
+        faults
+            .into_iter()
+            .map(|(key, fault)| {
+                let id = self.fault_id(&mut stats, fault.as_ref())?;
+                Ok(FaultStatus {
+                    dry_run_hits: events.iter().filter(|event| event.fault == key).count(),
+                    key,
+                    id,
+                    window: fault.schedule().window(op_count, now),
+                    detail: fault.status_detail(),
+                    counters: stats.counters(id),
+                })
+            })
+            .collect()
+    }
+
+    /// Id of `fault` in the op log and the fault counters. Ids come from the same sequence as
+    /// those of crash faults, so the two never collide.
+    fn fault_id(&self, stats: &mut FaultStats, fault: &dyn config::Fault) -> Result<FaultId> {
+        stats.id_of(fault, || {
+            Ok(self
+                .crash_patterns
+                .lock()
+                .map_err(|e| anyhow!("Unable to acquire lock on crash faults: {:?}", e))?
+                .allocate_id())
+        })
+    }
+
+    /// Counts `fault` as checked against the operation of `ctx`, tagging the operation with it
+    /// if it was triggered
+    fn tally(
+        &self,
+        ctx: &mut OpContext,
+        fault: &dyn config::Fault,
+        evaluation: Evaluation,
+    ) -> Result<()> {
+        let id = self.count_evaluation(fault, evaluation)?;
+        if evaluation == Evaluation::Triggered {
+            ctx.inject(Some(id));
+        }
+        Ok(())
+    }
+
+    /// `tally` for checks made outside of a handler, returning the id of `fault`
+    fn count_evaluation(
+        &self,
+        fault: &dyn config::Fault,
+        evaluation: Evaluation,
+    ) -> Result<FaultId> {
+        let mut stats = self
+            .fault_stats
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on fault stats: {:?}", e))?;
+        let id = self.fault_id(&mut stats, fault)?;
+        stats.tally(id, evaluation);
+        Ok(id)
+    }
+
+    /// Counters of the fault with `id`, crash faults included. Crash faults are only counted on
+    /// the operation they fire on, or would have in dry-run, their matches are in
+    /// `crash_fault_status`.
+    pub fn fault_counters(&self, id: FaultId) -> Result<FaultCounters> {
+        let stats = self
+            .fault_stats
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on fault stats: {:?}", e))?;
+        Ok(stats.counters(id))
+    }
+
+    /// The last `fault_stats::OP_LOG_LEN` operations that went through the fault checks,
+    /// oldest first
+    pub fn op_log(&self) -> Result<Vec<OpRecord>> {
+        let stats = self
+            .fault_stats
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on fault stats: {:?}", e))?;
+        Ok(stats.log())
+    }
+
+    /// Latency of the operations that went through the fault checks, by kind
+    pub fn op_latency(&self) -> Result<Vec<(FsOperation, OpLatency)>> {
+        let stats = self
+            .fault_stats
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on fault stats: {:?}", e))?;
+        Ok(stats.latency())
+    }
+
+    /// Counts an intercepted `op` on `path` and starts its context, which is logged once
+    /// dropped at the end of the handler
+    fn op_context(&self, op: FsOperation, path: &Path) -> OpContext<'_> {
+        let op_id = self.next_op();
+        OpContext::recorded(op, op_id, path, &self.fault_stats, self.clock.as_ref())
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run.load(Ordering::SeqCst)
+    }
+
+    /// Turns dry-run on or off, returning the previous setting
+    pub fn set_dry_run(&self, dry_run: bool) -> bool {
+        info!(target: TRACING_TARGET, dry_run, "switching dry-run");
+        self.dry_run.swap(dry_run, Ordering::SeqCst)
+    }
+
+    /// Blocks new writes, truncates and renames once those already running finish. The fence
+    /// lifts itself after `fence_max_ms`.
+    pub fn fence_writes(&self) -> Result<()> {
+        self.write_fence.fence()
+    }
+
+    /// Lets writes through again, returning how long they were fenced
+    pub fn unfence_writes(&self) -> Result<Duration> {
+        self.write_fence.unfence()
+    }
+
+    /// To be taken at the top of the write, truncate and rename handlers and held until the
+    /// operation is done. Waits out a fence, or fails with EAGAIN if `fence_mode` says so.
+    pub fn begin_mutation(&self) -> Result<MutationGuard<'_>> {
+        self.write_fence.enter()
+    }
+
+    /// To be taken at the top of every handler and held until the operation is done. Waits
+    /// while a batch is being committed and while `op` is over its `max_concurrent_*` limit.
+    /// Fails with EIO once a `freeze` crash fired.
+    pub fn begin_op(&self, op: FsOperation) -> Result<HandlerPermit<'_>> {
+        if self.is_frozen() {
+            return Err(std::io::Error::from_raw_os_error(libc::EIO).into());
+        }
+        let control = self
+            .control_gate
+            .read()
+            .map_err(|e| anyhow!("Unable to acquire lock on control gate: {:?}", e))?;
+        Ok(HandlerPermit {
+            _permit: self.op_limiter.acquire(op)?,
+            _control: control,
+        })
+    }
+
+    /// To be held while a batch of commands is applied. Waits for the handlers already running
+    /// to finish, writes held at a write fence included, and keeps new ones out until dropped.
+    pub fn begin_control(&self) -> Result<RwLockWriteGuard<'_, ()>> {
+        self.control_gate
+            .write()
+            .map_err(|e| anyhow!("Unable to acquire lock on control gate: {:?}", e))
+    }
+
+    /// Whether a `freeze` crash fired
+    pub fn is_frozen(&self) -> bool {
+        self.frozen.load(Ordering::SeqCst)
+    }
+
+    /// Changes the queue depth of `op`, or the overall one if `None`, returning the previous one
+    pub fn set_op_limit(&self, op: Option<FsOperation>, limit: usize) -> Result<usize> {
+        self.op_limiter.set_limit(op, limit)
+    }
+
+    pub fn queue_wait_stats(&self) -> Result<Vec<(FsOperation, QueueWaitStats)>> {
+        self.op_limiter.wait_stats()
+    }
+
+    /// Records what the cache holds under `label`, replacing an older mark of the same name.
+    /// Returns how many blocks the mark covers.
+    pub fn mark(&self, label: &str) -> Result<usize> {
+        if label.is_empty() || label == cache_diff::NOW || label.contains(':') {
+            return Err(anyhow!("Invalid mark label '{}'", label));
+        }
+        let mark = self.cache.mark()?;
+        let blocks = mark.blocks();
+        let mut marks = self
+            .marks
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on marks: {:?}", e))?;
+        marks.retain(|(other, _)| other != label);
+        if marks.len() == cache_diff::MAX_MARKS {
+            if let Some((dropped, _)) = marks.pop_front() {
+                warn!(
+                    target: TRACING_TARGET,
+                    mark = %dropped,
+                    "too many marks, dropping the oldest"
+                );
+            }
+        }
+        marks.push_back((label.to_string(), mark));
+        Ok(blocks)
+    }
+
+    /// What changed in the cache between two marks, either of which can be `now`
+    pub fn diff_marks(&self, from: &str, to: &str) -> Result<CacheDiff> {
+        let mark_of = |label: &str| -> Result<CacheMark> {
+            if label == cache_diff::NOW {
+                return self.cache.mark();
+            }
+            let marks = self
+                .marks
+                .lock()
+                .map_err(|e| anyhow!("Unable to acquire lock on marks: {:?}", e))?;
+            marks
+                .iter()
+                .find(|(other, _)| other == label)
+                .map(|(_, mark)| mark.clone())
+                .ok_or_else(|| anyhow!("No mark named '{}'", label))
+        };
+        Ok(CacheDiff::between(from, &mark_of(from)?, to, &mark_of(to)?))
+    }
+
+    pub fn dry_run_events(&self) -> Result<Vec<DryRunEvent>> {
+        let events = self
+            .dry_run_events
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on dry-run events: {:?}", e))?;
+        Ok(events.clone())
+    }
+
+    fn record_dry_run(
+        &self,
+        fault: String,
+        spec: String,
+        path: &Path,
+        action: String,
+    ) -> Result<()> {
+        let event = DryRunEvent {
+            op: self.op_count(),
+            fault,
+            spec,
+            path: path.to_path_buf(),
+            action,
+        };
+        info!(
+            target: TRACING_TARGET,
+            op = event.op,
+            fault = %event.fault,
+            spec = %event.spec,
+            path = %path.display(),
+            "dry-run: would have injected {}",
+            event.action
+        );
+        self.dry_run_events
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on dry-run events: {:?}", e))?
+            .push(event);
+        Ok(())
+    }
+
+    /// Arms `fault` on `file`, next to the faults loaded from the config file
+    pub fn add_fault(&self, file: &str, fault: Arc<dyn config::Fault>) -> Result<()> {
+        let mut faults = self
+            .faults
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on faults: {:?}", e))?;
+        fault.schedule().arm_once(self.clock.now());
+        faults.entry(file.to_string()).or_default().push(fault);
+        Ok(())
+    }
+
+    pub fn remove_fault(&self, file: &str, fault: &Arc<dyn config::Fault>) -> Result<bool> {
+        let mut faults = self
+            .faults
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on faults: {:?}", e))?;
+        let keyed = match faults.get_mut(file) {
+            Some(keyed) => keyed,
+            None => return Ok(false),
+        };
+        let before = keyed.len();
+        keyed.retain(|f| !Arc::ptr_eq(f, fault));
+        let removed = keyed.len() != before;
+        if keyed.is_empty() {
+            faults.remove(file);
+        }
+        if removed {
+            self.forget_fault(fault.as_ref())?;
+        }
+        Ok(removed)
+    }
+
+    /// Drops the id of a removed fault, see `FaultStats::forget`
+    fn forget_fault(&self, fault: &dyn config::Fault) -> Result<()> {
+        self.fault_stats
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on fault stats: {:?}", e))?
+            .forget(fault);
+        Ok(())
+    }
+
+    /// The faults keyed by `path`, copied out so that the lock isn't held while they act
+    fn faults_for(&self, path: &Path) -> Result<Vec<Arc<dyn config::Fault>>> {
+        let faults = self
+            .faults
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on faults: {:?}", e))?;
+        Ok(faults
+            .get(path.to_string_lossy().as_ref())
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    pub fn add_quota_fault(&self, fault: config::QuotaFault) -> Result<Arc<config::QuotaFault>> {
+        let mut quota_faults = self
+            .quota_faults
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on quota faults: {:?}", e))?;
+        fault.schedule().arm_once(self.clock.now());
+        let fault = Arc::new(fault);
+        quota_faults.push(fault.clone());
+        Ok(fault)
+    }
+
+    pub fn remove_quota_fault(&self, fault: &Arc<config::QuotaFault>) -> Result<bool> {
+        let mut quota_faults = self
+            .quota_faults
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on quota faults: {:?}", e))?;
+        let before = quota_faults.len();
+        quota_faults.retain(|f| !Arc::ptr_eq(f, fault));
+        let removed = quota_faults.len() != before;
+        if removed {
+            self.forget_fault(fault.as_ref())?;
+        }
+        Ok(removed)
+    }
+
+    /// Checks an application write of `len` bytes against every active quota fault and charges
+    /// the accepted amount to the ones covering it
+    pub fn charge_write(
+        &self,
+        ctx: &mut OpContext,
+        path: &Path,
+        owner: &str,
+        len: usize,
+    ) -> Result<config::QuotaOutcome> {
+        let quota_faults = self
+            .quota_faults
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on quota faults: {:?}", e))?;
+        let op_count = self.op_count();
+        let now = self.clock.now();
+
+        let dry_run = self.is_dry_run();
+
+        let mut allowed = len;
+        let mut covering = Vec::new();
+        for (i, fault) in quota_faults.iter().enumerate() {
+            if !fault.is_active(op_count, now) {
+                self.tally(ctx, fault.as_ref(), Evaluation::Missed)?;
+                continue;
+            }
+            let allowance = match fault.allowance(path, owner, len)? {
+                Some(allowance) => allowance,
+                None => {
+                    self.tally(ctx, fault.as_ref(), Evaluation::Missed)?;
+                    continue;
+                }
+            };
+            let evaluation = match allowance < len {
+                false => Evaluation::Missed,
+                true if dry_run => Evaluation::Matched,
+                true => Evaluation::Triggered,
+            };
+            self.tally(ctx, fault.as_ref(), evaluation)?;
+            if evaluation == Evaluation::Matched {
+                let action = match allowance {
+                    0 => "ENOSPC".to_string(),
+                    _ => format!("short write of {} of {} bytes", allowance, len),
+                };
+                self.record_dry_run(format!("quota-{}", i), fault.spec(), path, action)?;
+            }
+            allowed = allowed.min(allowance);
+            covering.push(fault);
+        }
+
+        if allowed == 0 && len > 0 && !dry_run {
+            return Ok(config::QuotaOutcome::NoSpace);
+        }
+        // A dry run charges what a real run would have, so the budget runs out at the same point
+        if !dry_run || self.config.dry_run_consumes_occurences {
+            for fault in covering {
+                fault.charge(owner, allowed)?;
+            }
+        }
+        let accepted = if dry_run { len } else { allowed };
+        Ok(config::QuotaOutcome::Accept(accepted))
+    }
+
+    pub fn reset_quotas(&self) -> Result<()> {
+        let quota_faults = self
+            .quota_faults
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on quota faults: {:?}", e))?;
+        for fault in quota_faults.iter() {
+            fault.reset()?;
+        }
+        Ok(())
+    }
+
+    /// Counts an `op` on `path` against the faults keyed by it and returns those that fire on
+    /// it. Faults outside their schedule don't count it, and in dry-run none fire.
+    pub fn check_and_trigger_faults(
+        &self,
+        op: &str,
+        path: &Path,
+    ) -> Result<Vec<Arc<dyn config::Fault>>> {
+        let faults = self.faults_for(path)?;
+        let op_count = self.op_count();
+        let now = self.clock.now();
+
+        let mut fired = Vec::new();
+        for fault in faults {
+            if fault.op() != op {
+                continue;
+            }
+            if !fault.is_active(op_count, now) || !fault.should_trigger(path, fault.count_op()) {
+                self.count_evaluation(fault.as_ref(), Evaluation::Missed)?;
+                continue;
+            }
+            if self.is_dry_run() {
+                self.count_evaluation(fault.as_ref(), Evaluation::Matched)?;
+                let key = path.to_string_lossy().into_owned();
+                let action = format!("{} fault", op);
+                self.record_dry_run(key, fault.spec(), path, action)?;
+                if !self.config.dry_run_consumes_occurences {
+                    fault.release_op();
+                }
+                continue;
+            }
+            self.count_evaluation(fault.as_ref(), Evaluation::Triggered)?;
+            info!(
+                target: TRACING_TARGET,
+                path = %path.display(),
+                op,
+                fault = %fault.spec(),
+                "fault triggered"
+            );
+            fault.on_triggered();
+            fired.push(fault.clone());
+        }
+        Ok(fired)
+    }
+
+    /// Counts a write of `buf` at `offset` to `path` against the split-write faults keyed by it.
+    /// If one fires, only the parts it persists are written, through the cache and synced to
+    /// the backing file, and LazyFS crashes with the fault's mode before the rest gets there.
+    /// A sector-torn fault persists the whole `disk_sector_size` sectors it keeps as part 1.
+    /// Returns the parts written, numbered from 1, or `None` if no fault fired, or one did in
+    /// dry-run, and the write is left to the caller.
+    pub fn apply_split_write(
+        &self,
+        ctx: &mut OpContext,
+        path: &Path,
+        buf: &[u8],
+        offset: u64,
+    ) -> Result<Option<PersistedParts>> {
+        let faults = self.faults_for(path)?;
+        let op_count = self.op_count();
+        let now = self.clock.now();
+        let dry_run = self.is_dry_run();
+        let mut fired = None;
+        for fault in faults.iter().filter_map(|fault| fault.as_split_write()) {
+            if fault.op() != "write" {
+                continue;
+            }
+            let triggered =
+                fault.is_active(op_count, now) && fault.should_trigger(path, fault.count_op());
+            let evaluation = match triggered {
+                false => Evaluation::Missed,
+                true if dry_run => Evaluation::Matched,
+                true => Evaluation::Triggered,
+            };
+            self.tally(ctx, fault, evaluation)?;
+            if triggered {
+                fired = Some(fault);
+                break;
+            }
+        }
+        let fault = match fired {
+            Some(fault) => fault,
+            None => return Ok(None),
+        };
+        // A sector-torn write keeps its surviving prefix of whole sectors as its only part
+        let parts = match fault.sector_torn_len(offset, buf.len(), self.config.disk_sector_size) {
+            Some(0) => Vec::new(),
+            Some(survived) => vec![(1, 0..survived)],
+            None => fault.persisted_parts(buf.len()),
+        };
+        if dry_run {
+            let parts: Vec<_> = parts.iter().map(|(part, _)| *part).collect();
+            let action = format!("split write persisting parts {:?}", parts);
+            let key = path.to_string_lossy().into_owned();
+            self.record_dry_run(key, fault.spec(), path, action)?;
+            if !self.config.dry_run_consumes_occurences {
+                fault.release_op();
+            }
+            return Ok(None);
+        }
+        fault.on_triggered();
+
+        let owner = self
+            .cache
+            .get_original_inode(path.to_path_buf())?
+            .ok_or_else(|| cache::NotCached(path.display().to_string()))?;
+        // Straight to the backing file: syncing the owner would also make the earlier writes
+        // that the crash is meant to lose durable
+        let backing = std::fs::OpenOptions::new().write(true).open(path)?;
+        let mut persisted = Vec::new();
+        for (part, range) in parts {
+            let bytes = buf[range.clone()].to_vec();
+            let at = offset + range.start as u64;
+            self.cache.write_at(owner.clone(), at, &bytes)?;
+            backing.write_all_at(&bytes, at)?;
+            persisted.push((part, bytes));
+        }
+        backing.sync_data()?;
+        warn!(
+            target: TRACING_TARGET,
+            path = %path.display(),
+            offset,
+            len = buf.len(),
+            parts = ?persisted.iter().map(|(part, _)| *part).collect::<Vec<_>>(),
+            mode = ?fault.mode(),
+            "split write"
+        );
+        self.crash(fault.mode())?;
+        Ok(Some(persisted))
+    }
+
+    pub fn add_short_write_fault(&self, fault: config::ShortWriteFault) -> Result<()> {
+        let mut short_write_faults = self
+            .short_write_faults
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on short write faults: {:?}", e))?;
+        fault.schedule().arm_once(self.clock.now());
+        short_write_faults.push(Arc::new(fault));
+        Ok(())
+    }
+
+    /// How many bytes of a `len` byte write to `path` get accepted once short-write faults are
+    /// applied. Only that prefix may be cached and the short count is what the caller sees.
+    pub fn accepted_write_len(
+        &self,
+        ctx: &mut OpContext,
+        path: &Path,
+        len: usize,
+    ) -> Result<usize> {
+        let short_write_faults = self
+            .short_write_faults
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on short write faults: {:?}", e))?;
+        let op_count = self.op_count();
+        let now = self.clock.now();
+
+        let mut accepted = len;
+        for (i, fault) in short_write_faults.iter().enumerate() {
+            let short = match fault.is_active(op_count, now) {
+                true => fault.short_len(path, len),
+                false => None,
+            };
+            let short = match short {
+                Some(short) => short,
+                None => {
+                    self.tally(ctx, fault.as_ref(), Evaluation::Missed)?;
+                    continue;
+                }
+            };
+            if self.is_dry_run() {
+                self.tally(ctx, fault.as_ref(), Evaluation::Matched)?;
+                let action = format!("short write of {} of {} bytes", short, len);
+                self.record_dry_run(format!("short-write-{}", i), fault.spec(), path, action)?;
+                if !self.config.dry_run_consumes_occurences {
+                    fault.release();
+                }
+                continue;
+            }
+            self.tally(ctx, fault.as_ref(), Evaluation::Triggered)?;
+            info!(
+                target: TRACING_TARGET,
+                path = %path.display(),
+                requested = len,
+                accepted = short,
+                "injecting short write"
+            );
+            accepted = accepted.min(short);
+        }
+
+        Ok(accepted)
+    }
+
+    pub fn add_stale_read_fault(&self, fault: config::StaleReadFault) -> Result<()> {
+        let mut stale_read_faults = self
+            .stale_read_faults
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on stale read faults: {:?}", e))?;
+        fault.schedule().arm_once(self.clock.now());
+        stale_read_faults.push(Arc::new(fault));
+        Ok(())
+    }
+
+    /// Whether a read of `path` has to bypass dirty cached blocks and be answered with
+    /// `Cache::read_stale` instead
+    pub fn serve_stale_read(&self, ctx: &mut OpContext, path: &Path) -> Result<bool> {
+        let stale_read_faults = self
+            .stale_read_faults
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on stale read faults: {:?}", e))?;
+        let op_count = self.op_count();
+        let now = self.clock.now();
+
+        let mut stale = false;
+        for (i, fault) in stale_read_faults.iter().enumerate() {
+            let was_triggered = fault.is_triggered();
+            if !fault.is_active(op_count, now) || !fault.serves_stale(path) {
+                self.tally(ctx, fault.as_ref(), Evaluation::Missed)?;
+                continue;
+            }
+            if self.is_dry_run() {
+                self.tally(ctx, fault.as_ref(), Evaluation::Matched)?;
+                let action = "stale read".to_string();
+                self.record_dry_run(format!("stale-read-{}", i), fault.spec(), path, action)?;
+                if !self.config.dry_run_consumes_occurences {
+                    fault.release(was_triggered);
+                }
+                continue;
+            }
+            self.tally(ctx, fault.as_ref(), Evaluation::Triggered)?;
+            stale = true;
+        }
+        Ok(stale)
+    }
+
+    pub fn disarm_stale_reads(&self) -> Result<()> {
+        let stale_read_faults = self
+            .stale_read_faults
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on stale read faults: {:?}", e))?;
+        for fault in stale_read_faults.iter() {
+            fault.disarm();
+        }
+        Ok(())
+    }
+
+    pub fn add_rename_tear_fault(&self, fault: config::RenameTearFault) -> Result<()> {
+        let mut rename_tear_faults = self
+            .rename_tear_faults
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on rename tear faults: {:?}", e))?;
+        fault.schedule().arm_once(self.clock.now());
+        rename_tear_faults.push(Arc::new(fault));
+        Ok(())
+    }
+
+    /// Counts a rename of `from` to `to` against the rename tear faults and returns the half to
+    /// apply and how to crash for the first one that fires, if any
+    fn rename_tear_for(
+        &self,
+        ctx: &mut OpContext,
+        from: &Path,
+        to: &Path,
+    ) -> Result<Option<(config::RenameTear, CrashMode)>> {
+        let rename_tear_faults = self
+            .rename_tear_faults
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on rename tear faults: {:?}", e))?;
+        let op_count = self.op_count();
+        let now = self.clock.now();
+
+        for (i, fault) in rename_tear_faults.iter().enumerate() {
+            let tear = match fault.is_active(op_count, now) {
+                true => fault.tear_for(from, to),
+                false => None,
+            };
+            let tear = match tear {
+                Some(tear) => tear,
+                None => {
+                    self.tally(ctx, fault.as_ref(), Evaluation::Missed)?;
+                    continue;
+                }
+            };
+            if self.is_dry_run() {
+                self.tally(ctx, fault.as_ref(), Evaluation::Matched)?;
+                let action = format!("{:?} torn rename to {}", tear, to.display());
+                self.record_dry_run(format!("rename-tear-{}", i), fault.spec(), from, action)?;
+                if !self.config.dry_run_consumes_occurences {
+                    fault.release();
+                }
+                continue;
+            }
+            self.tally(ctx, fault.as_ref(), Evaluation::Triggered)?;
+            return Ok(Some((tear, fault.mode())));
+        }
+        Ok(None)
+    }
+
+    /// Renames the backing file `from` to `to`. When a rename tear fault fires the rename is
+    /// done as a link of `to` followed by an unlink of `from`, and LazyFS crashes once the half
+    /// picked by the fault is on the backing store. Returns that half, in which case the cache
+    /// was already dealt with by the crash and must not be renamed.
+    pub fn rename_backing(
+        &self,
+        ctx: &mut OpContext,
+        from: &Path,
+        to: &Path,
+    ) -> Result<Option<config::RenameTear>> {
+        let torn = if std::fs::symlink_metadata(from)?.is_dir() {
+            None
+        } else {
+            self.rename_tear_for(ctx, from, to)?
+        };
+        let (tear, mode) = match torn {
+            Some(torn) => torn,
+            None => {
+                std::fs::rename(from, to)?;
+                return Ok(None);
+            }
+        };
+
+        match tear {
+            config::RenameTear::DestinationOnly => {
+                match std::fs::remove_file(to) {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e.into()),
+                }
+                std::fs::hard_link(from, to)?;
+            }
+            config::RenameTear::SourceOnly => std::fs::remove_file(from)?,
         }
+        warn!(
+            target: TRACING_TARGET,
+            from = %from.display(),
+            to = %to.display(),
+            tear = ?tear,
+            mode = ?mode,
+            "tore rename"
+        );
+        self.crash(mode)?;
+        Ok(Some(tear))
+    }
+
+    /// Registers a crash fault, returning the id to remove it or query its status with
+    pub fn add_crash_fault(&self, spec: CrashFaultSpec) -> Result<FaultId> {
+        self.register_crash_fault(spec).map(|r| r.id)
+    }
+
+    /// Like `add_crash_fault`, also reporting what compiling the pattern cost
+    pub fn register_crash_fault(&self, spec: CrashFaultSpec) -> Result<CrashRegistration> {
+        let description = spec.to_string();
+        let registration = self
+            .crash_patterns
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on crash faults: {:?}", e))?
+            .add(spec)?;
+        info!(
+            target: TRACING_TARGET,
+            id = registration.id.0,
+            spec = description,
+            compile_us = registration.compile_time.as_micros() as u64,
+            total = registration.total_patterns,
+            "registered crash fault"
+        );
+        Ok(registration)
+    }
+
+    /// Registers a crash fault from its textual form, as the control commands spell it. `op`
+    /// must be one crash faults support and `mode` one of `kill`, `clear-cache` or `freeze`.
+    pub fn add_crash_fault_by_name(
+        &self,
+        timing: CrashTiming,
+        op: &str,
+        path_regex: &str,
+        mode: &str,
+    ) -> Result<FaultId> {
+        let matcher = PathMatcher::new(path_regex, Default::default())?;
+        let spec = CrashFaultSpec::new(op.parse()?, timing, matcher).with_mode(mode.parse()?);
+        self.add_crash_fault(spec)
+    }
+
+    pub fn remove_crash_fault(&self, id: FaultId) -> Result<bool> {
+        let mut crash_patterns = self
+            .crash_patterns
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on crash faults: {:?}", e))?;
+        Ok(crash_patterns.remove(id))
+    }
+
+    pub fn crash_fault_status(&self, id: FaultId) -> Result<Option<CrashFaultStatus>> {
+        let crash_patterns = self
+            .crash_patterns
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on crash faults: {:?}", e))?;
+        Ok(crash_patterns.status(id))
+    }
+
+    /// Counts `op` on `path` against the crash faults and returns the one that fires, if any
+    pub fn crash_fault_for(
+        &self,
+        op: FsOperation,
+        timing: CrashTiming,
+        path: &Path,
+    ) -> Result<Option<CrashMatch>> {
+        let mut crash_patterns = self
+            .crash_patterns
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on crash faults: {:?}", e))?;
+        crash_patterns.matching(op, timing, path)
+    }
+
+    /// To be called by the handler of `op` right before and after it reaches the backing file,
+    /// with the `(offset, size)` it touches if any, and without holding any cache lock.
+    /// Aborts the process or drops the cache if a crash fault fires, returning its id. The crash
+    /// report, if one is configured, is written before either happens. In dry-run the crash is
+    /// only recorded and the operation goes on.
+    pub fn crash_hook(
+        &self,
+        op: FsOperation,
+        timing: CrashTiming,
+        path: &Path,
+        range: Option<(u64, u64)>,
+    ) -> Result<Option<FaultId>> {
+        let crash = match self.crash_fault_for(op, timing, path)? {
+            Some(crash) => crash,
+            None => return Ok(None),
+        };
+        self.fire_crash_fault(crash, op, timing, path, range)
+    }
+
+    /// `crash_hook` for handlers without a range. Renames, links and symlinks also pass their
+    /// second path, and crash if a fault matches either one.
+    pub fn trigger_crash_fault(
+        &self,
+        timing: CrashTiming,
+        op: FsOperation,
+        path: &Path,
+        path2: Option<&Path>,
+    ) -> Result<Option<FaultId>> {
+        let mut paths = vec![path];
+        if self.fs_op_mult_path.contains(op.as_str()) {
+            paths.extend(path2);
+        }
+        let crash = self
+            .crash_patterns
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on crash faults: {:?}", e))?
+            .matching_any(op, timing, &paths)?;
+        match crash {
+            Some(crash) => self.fire_crash_fault(crash, op, timing, path, None),
+            None => Ok(None),
+        }
+    }
+
+    fn fire_crash_fault(
+        &self,
+        crash: CrashMatch,
+        op: FsOperation,
+        timing: CrashTiming,
+        path: &Path,
+        range: Option<(u64, u64)>,
+    ) -> Result<Option<FaultId>> {
+        let evaluation = match self.is_dry_run() {
+            true => Evaluation::Matched,
+            false => Evaluation::Triggered,
+        };
+        self.fault_stats
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on fault stats: {:?}", e))?
+            .tally(crash.id, evaluation);
+        if evaluation == Evaluation::Matched {
+            let spec = match self.crash_fault_status(crash.id)? {
+                Some(status) => status.spec.to_string(),
+                None => crash.pattern.clone(),
+            };
+            let action = format!("{:?} crash {} {}", crash.mode, timing.as_str(), op.as_str());
+            self.record_dry_run(format!("crash-{}", crash.id), spec, path, action)?;
+            if !self.config.dry_run_consumes_occurences {
+                self.crash_patterns
+                    .lock()
+                    .map_err(|e| anyhow!("Unable to acquire lock on crash faults: {:?}", e))?
+                    .release(crash.id);
+            }
+            return Ok(None);
+        }
+
+        warn!(
+            target: TRACING_TARGET,
+            id = crash.id.0,
+            op = op.as_str(),
+            path = %path.display(),
+            pattern = crash.pattern,
+            mode = ?crash.mode,
+            "crash fault fired"
+        );
+        if let Err(e) = self.write_crash_report(&crash, op, timing, path, range) {
+            warn!(target: TRACING_TARGET, id = crash.id.0, "Failed to write crash report: {:?}", e);
+        }
+        self.crash(crash.mode)?;
+        Ok(Some(crash.id))
+    }
+
+    /// Writes the report of `crash` to `crash_report_path`, if one is configured
+    fn write_crash_report(
+        &self,
+        crash: &CrashMatch,
+        op: FsOperation,
+        timing: CrashTiming,
+        path: &Path,
+        range: Option<(u64, u64)>,
+    ) -> Result<()> {
+        if self.config.crash_report_path.as_os_str().is_empty() {
+            return Ok(());
+        }
+        let spec = match self.crash_fault_status(crash.id)? {
+            Some(status) => status.spec.to_string(),
+            None => crash.pattern.clone(),
+        };
+        let (unsynced, unsynced_omitted) =
+            CrashReport::summarize(&self.cache.report_unsynced_data()?);
+        let report = CrashReport {
+            fault_id: crash.id.0,
+            spec,
+            op: op.as_str().to_string(),
+            timing: timing.as_str().to_string(),
+            path: path.to_string_lossy().into_owned(),
+            offset: range.map(|(offset, _)| offset),
+            size: range.map(|(_, size)| size),
+            op_count: self.op_count(),
+            unsynced_omitted,
+            unsynced,
+        };
+        let header = Header::new(
+            Artifact::CrashReport,
+            CRASH_REPORT_VERSION,
+            geometry_hash(&self.config),
+            self.clock.now(),
+        );
+        report.write(&self.config.crash_report_path, &header)?;
+        info!(
+            target: TRACING_TARGET,
+            id = crash.id.0,
+            path = %self.config.crash_report_path.display(),
+            "crash report written"
+        );
+        Ok(())
+    }
+
+    /// Sleeps for the simulated latency of a read of `path` that hit the cache for `hits` blocks
+    /// and missed for `misses`. Must be called without holding any cache or engine lock.
+    pub fn apply_read_latency(&self, path: &Path, hits: u32, misses: u32) -> Duration {
+        if !self.latency.is_enabled() {
+            return Duration::ZERO;
+        }
+        let delay = self.latency.delay(path, hits, misses);
+        self.clock.sleep(delay);
+        delay
+    }
+
+    /// Holds the operation of `ctx` on `path` for every delay fault keyed by `path` that targets
+    /// it, adding up their delays. Must be called without holding any cache or engine lock.
+    /// Returns the time slept.
+    pub fn apply_delay_faults(&self, ctx: &mut OpContext, path: &Path) -> Result<Duration> {
+        let faults = self.faults_for(path)?;
+        let op_count = self.op_count();
+        let now = self.clock.now();
+
+        let mut delay = Duration::ZERO;
+        for fault in faults.iter().filter_map(|fault| fault.as_delay()) {
+            if fault.op() != ctx.kind.as_str() {
+                continue;
+            }
+            if !fault.is_active(op_count, now) {
+                self.tally(ctx, fault, Evaluation::Missed)?;
+                continue;
+            }
+            fault.count_op();
+            if self.is_dry_run() {
+                self.tally(ctx, fault, Evaluation::Matched)?;
+                let key = path.to_string_lossy().into_owned();
+                let action = format!("delay of {:?}", fault.delay());
+                self.record_dry_run(key, fault.spec(), path, action)?;
+                continue;
+            }
+            self.tally(ctx, fault, Evaluation::Triggered)?;
+            fault.on_triggered();
+            delay += fault.delay();
+        }
+
+        if !delay.is_zero() {
+            info!(
+                target: TRACING_TARGET,
+                path = %path.display(),
+                op = ctx.kind.as_str(),
+                delay = ?delay,
+                "delaying operation"
+            );
+            self.clock.sleep(delay);
+        }
+        Ok(delay)
+    }
+
+    /// Every fault with a stable key: `<key>#<index>` for the keyed faults, `<kind>-<index>` for
+    /// the rest
+    fn keyed_faults(&self) -> Result<Vec<(String, Arc<dyn config::Fault>)>> {
+        let mut keyed: Vec<(String, Arc<dyn config::Fault>)> = Vec::new();
+        let keyed_by_file = self
+            .faults
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on faults: {:?}", e))?;
+        for (key, faults) in keyed_by_file.iter() {
+            for (i, fault) in faults.iter().enumerate() {
+                keyed.push((format!("{}#{}", key, i), fault.clone()));
+            }
+        }
+
+        let quota_faults = self
+            .quota_faults
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on quota faults: {:?}", e))?;
+        for (i, fault) in quota_faults.iter().enumerate() {
+            keyed.push((format!("quota-{}", i), fault.clone()));
+        }
+        let short_write_faults = self
+            .short_write_faults
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on short write faults: {:?}", e))?;
+        for (i, fault) in short_write_faults.iter().enumerate() {
+            keyed.push((format!("short-write-{}", i), fault.clone()));
+        }
+        let stale_read_faults = self
+            .stale_read_faults
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on stale read faults: {:?}", e))?;
+        for (i, fault) in stale_read_faults.iter().enumerate() {
+            keyed.push((format!("stale-read-{}", i), fault.clone()));
+        }
+        let rename_tear_faults = self
+            .rename_tear_faults
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on rename tear faults: {:?}", e))?;
+        for (i, fault) in rename_tear_faults.iter().enumerate() {
+            keyed.push((format!("rename-tear-{}", i), fault.clone()));
+        }
+
+        keyed.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(keyed)
+    }
+
+    /// Saves fault counters and the op count to `fault_state_path`, if one is configured
+    pub fn save_fault_state(&self) -> Result<()> {
+        if self.config.fault_state_path.as_os_str().is_empty() {
+            return Ok(());
+        }
+
+        let faults = self
+            .keyed_faults()?
+            .into_iter()
+            .map(|(key, fault)| SavedFault {
+                key,
+                spec_hash: spec_hash(&fault.spec()),
+                state: fault.save_state(),
+            })
+            .collect();
+        let file = FaultStateFile {
+            op_count: self.op_count(),
+            faults,
+        };
+        file.save(
+            &self.config.fault_state_path,
+            geometry_hash(&self.config),
+            self.clock.now(),
+        )
+    }
+
+    /// Restores the state saved by a previous mount when `resume_faults` is set. Returns whether
+    /// anything was restored. Fails without touching any fault if the saved faults don't match
+    /// the configured ones. `LazyFSBuilder::build` calls it once the faults it was given are
+    /// armed, so only those are resumed.
+    pub fn resume_fault_state(&self) -> Result<bool> {
+        if !self.config.resume_faults || self.config.fault_state_path.as_os_str().is_empty() {
+            return Ok(false);
+        }
+        let file = match FaultStateFile::load(&self.config.fault_state_path)? {
+            Some(file) => file,
+            None => return Ok(false),
+        };
+
+        let faults = self.keyed_faults()?;
+        let saved_keys: Vec<_> = file.faults.iter().map(|f| &f.key).collect();
+        let keys: Vec<_> = faults.iter().map(|(key, _)| key).collect();
+        if saved_keys != keys {
+            return Err(anyhow!(
+                "Saved faults {:?} don't match the configured faults {:?}",
+                saved_keys,
+                keys
+            ));
+        }
+        for (saved, (key, fault)) in file.faults.iter().zip(&faults) {
+            if saved.spec_hash != spec_hash(&fault.spec()) {
+                return Err(anyhow!(
+                    "Fault {} was saved with a different definition than '{}'",
+                    key,
+                    fault.spec()
+                ));
+            }
+        }
+
+        for (saved, (key, fault)) in file.faults.iter().zip(&faults) {
+            fault
+                .restore_state(&saved.state)
+                .map_err(|e| anyhow!("Unable to restore fault {}: {}", key, e))?;
+            info!(target: TRACING_TARGET, fault = %key, state = ?saved.state, "resumed fault");
+        }
+        self.op_counter.store(file.op_count, Ordering::SeqCst);
+        info!(target: TRACING_TARGET, op_count = file.op_count, "resumed fault state");
+
+        Ok(true)
+    }
+
+    /// To be called when the FUSE layer sees pages of a shared mapping of `path` written back.
+    /// Fails with EOPNOTSUPP under `deny_mmap`, otherwise flags the owner so it is invalidated
+    /// on its next fsync or close.
+    pub fn on_mmap(&self, path: &Path, owner: &OwnerId) -> Result<()> {
+        if self.config.deny_mmap {
+            info!(target: TRACING_TARGET, path = %path.display(), "denying mmap");
+            return Err(std::io::Error::from_raw_os_error(libc::EOPNOTSUPP).into());
+        }
+        self.cache.mark_externally_modified(owner.clone())?;
+        Ok(())
+    }
+
+    /// To be called by open and create with the fh they hand out and the flags the file was
+    /// opened with
+    pub fn open_handle(
+        &self,
+        fh: u64,
+        path: &Path,
+        owner: &OwnerId,
+        flags: i32,
+    ) -> Result<WriteDurability> {
+        let durability = WriteDurability::from_open_flags(flags);
+        let policy = CachePolicy::from_open_flags(flags);
+        self.handles
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on handles: {:?}", e))?
+            .insert(
+                fh,
+                OpenHandle {
+                    path: path.to_path_buf(),
+                    owner: owner.clone(),
+                    durability,
+                    policy,
+                    append: flags & libc::O_APPEND != 0,
+                },
+            );
+
+        // What is cached would hide the handle's writes from the others, or theirs from it
+        if policy == CachePolicy::Bypass && self.cache.has_content_cached(owner.to_string())? {
+            self.cache
+                .sync_owner(owner.to_string(), false, path.to_path_buf())?;
+            self.cache.invalidate_owner(owner.to_string())?;
+        }
+        Ok(durability)
+    }
+
+    /// The policy the data of `fh` goes through the cache with: the strictest one among the
+    /// handles open on its file, so a file open with conflicting flags is never cached behind
+    /// the back of a handle that bypasses the cache, nor left unsynced under one that syncs
+    /// every write. Handles that are gone don't count anymore.
+    pub fn cache_policy(&self, fh: u64) -> Result<CachePolicy> {
+        let handles = self
+            .handles
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on handles: {:?}", e))?;
+        let owner = match handles.get(&fh) {
+            Some(handle) => &handle.owner,
+            None => return Ok(CachePolicy::Cached),
+        };
+        Ok(handles
+            .values()
+            .filter(|handle| &handle.owner == owner)
+            .map(|handle| handle.policy)
+            .max()
+            .unwrap_or_default())
+    }
+
+    /// The owner `path` is cached under. A file the cache doesn't know yet is mapped to the
+    /// dev:ino of its backing file, with the metadata `stat` reports for it.
+    pub fn owner_of(&self, path: &Path) -> Result<OwnerId> {
+        if let Some(owner) = self.cache.get_original_inode(path.to_path_buf())? {
+            return Ok(owner);
+        }
+        self.map_owner(path, &std::fs::metadata(path)?)
+    }
+
+    /// Maps `path` to the dev:ino of `stat`, caching that owner with the metadata of `stat` if
+    /// it is new. A new regular file also starts out as if last synced as `stat` has it.
+    fn map_owner(&self, path: &Path, stat: &std::fs::Metadata) -> Result<OwnerId> {
+        let owner: OwnerId = OwnerKey {
+            dev: stat.dev(),
+            ino: stat.ino(),
+        }
+        .to_string()
+        .into();
+        if self.cache.insert_item_if_not_exists(owner.clone())? {
+            self.cache.update_content_metadata(
+                owner.clone(),
+                Metadata::from_fs_metadata(stat),
+                &MetadataField::ALL,
+            )?;
+            if stat.is_file() {
+                self.cache.observe_backing_file(owner.clone(), stat)?;
+            }
+            self.cache.load_xattrs(owner.clone(), path)?;
+        }
+        self.cache
+            .insert_inode_mapping(path.to_path_buf(), owner, false)
+    }
+
+    /// The write handler: caches `data` at `offset` of `path`, open as `fh`, going through the
+    /// crash, split-write, reorder, short-write and quota faults on the way. Blocks only partly
+    /// written are merged with what the cache or the backing file holds first, and the size
+    /// and mtime follow the write. The handle's `CachePolicy` may send the write straight to
+    /// the backing file or sync it before returning, and an `O_APPEND` handle writes at the
+    /// cached size. Returns how many bytes were accepted.
+    pub fn do_write(&self, path: &Path, fh: u64, offset: u64, data: &[u8]) -> Result<u32> {
+        let _permit = self.begin_op(FsOperation::Write)?;
+        let _guard = self.begin_mutation()?;
+        let mut ctx = self.op_context(FsOperation::Write, path);
+        let handle = self.handle(fh)?;
+        let owner = match &handle {
+            Some(handle) => handle.owner.clone(),
+            None => self.owner_of(path)?,
+        };
+        let policy = self.cache_policy(fh)?;
+        let offset = match handle.filter(|handle| handle.append) {
+            Some(_) => self
+                .cache
+                .get_content_metadata(owner.clone())?
+                .map_or(offset, |metadata| metadata.size),
+            None => offset,
+        };
+        let range = Some((offset, data.len() as u64));
+        ctx.inject(self.crash_hook(FsOperation::Write, CrashTiming::Before, path, range)?);
+        self.apply_delay_faults(&mut ctx, path)?;
+
+        // A torn write took LazyFS down with only some of its parts out
+        if self
+            .apply_split_write(&mut ctx, path, data, offset)?
+            .is_some()
+        {
+            return Err(std::io::Error::from_raw_os_error(libc::EIO).into());
+        }
+        if self.hold_reordered_write(&mut ctx, path, data, offset)? {
+            return Ok(data.len() as u32);
+        }
+
+        let mut accepted = self.accepted_write_len(&mut ctx, path, data.len())?;
+        match self.charge_write(&mut ctx, path, &owner, accepted)? {
+            config::QuotaOutcome::Accept(len) => accepted = accepted.min(len),
+            config::QuotaOutcome::NoSpace => {
+                return Err(std::io::Error::from_raw_os_error(libc::ENOSPC).into())
+            }
+        }
+        let written = if policy == CachePolicy::Bypass {
+            self.write_backing(path, &owner, offset, &data[..accepted])?
+        } else {
+            let written = self
+                .cache
+                .write_at_op(owner, offset, &data[..accepted], Some(ctx.op))?;
+            self.sync_written(&mut ctx, fh, offset, written as u64)?;
+            written
+        };
+        ctx.inject(self.crash_hook(FsOperation::Write, CrashTiming::After, path, range)?);
+        Ok(written as u32)
+    }
+
+    /// The read handler: reads up to `size` bytes at `offset` of `path` into `buf`, going
+    /// through the crash, stale-read and latency faults on the way. Blocks the cache holds are
+    /// served from it, the others are read from the backing file and cached as they go by, see
+    /// `Cache::get_data_blocks_or_load`. Stops at the cached size, which runs ahead of the
+    /// backing file while writes are unsynced. A handle whose `CachePolicy` bypasses the cache
+    /// reads the backing file instead. Returns how many bytes were read.
+    pub fn do_read(
+        &self,
+        path: &Path,
+        fh: u64,
+        offset: u64,
+        size: usize,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        let _permit = self.begin_op(FsOperation::Read)?;
+        let mut ctx = self.op_context(FsOperation::Read, path);
+        let owner = self.owner_of(path)?;
+        let size = size.min(buf.len());
+        let range = Some((offset, size as u64));
+        ctx.inject(self.crash_hook(FsOperation::Read, CrashTiming::Before, path, range)?);
+        self.apply_delay_faults(&mut ctx, path)?;
+
+        if self.cache_policy(fh)? == CachePolicy::Bypass {
+            let read = cache::read_fully(&std::fs::File::open(path)?, offset, &mut buf[..size])?;
+            ctx.inject(self.crash_hook(FsOperation::Read, CrashTiming::After, path, range)?);
+            return Ok(read);
+        }
+
+        if self.serve_stale_read(&mut ctx, path)? {
+            let stale = self
+                .cache
+                .read_stale(owner.clone(), path.to_path_buf(), offset, size)?;
+            if let Some(data) = stale {
+                buf[..data.len()].copy_from_slice(&data);
+                ctx.inject(self.crash_hook(FsOperation::Read, CrashTiming::After, path, range)?);
+                return Ok(data.len());
+            }
+        }
+
+        let file_size = self
+            .cache
+            .get_content_metadata(owner.clone())?
+            .map_or(0, |metadata| metadata.size);
+        let end = offset.saturating_add(size as u64).min(file_size);
+        if offset >= end {
+            ctx.inject(self.crash_hook(FsOperation::Read, CrashTiming::After, path, range)?);
+            return Ok(0);
+        }
+
+        let block_size = self.config.io_block_size as u64;
+        let first = self.cache.block_of(offset)?;
+        let last = self.cache.block_of(end - 1)?;
+        let mut blocks: HashMap<i32, Vec<u8>> = (first..=last)
+            .map(|block_id| (block_id, vec![0; block_size as usize]))
+            .collect();
+        let from_cache = self.cache.get_data_blocks_or_load(
+            owner,
+            path.to_path_buf(),
+            blocks
+                .iter_mut()
+                .map(|(&block_id, data)| (block_id, &mut data[..]))
+                .collect(),
+        )?;
+        let hits = from_cache.values().filter(|&&hit| hit).count();
+        let misses = blocks.len() - hits;
+
+        let mut read = 0;
+        for block_id in first..=last {
+            let block_start = block_id as u64 * block_size;
+            let from = offset.max(block_start);
+            let to = end.min(block_start + block_size);
+            let data =
+                &blocks[&block_id][(from - block_start) as usize..(to - block_start) as usize];
+            buf[read..read + data.len()].copy_from_slice(data);
+            read += data.len();
+        }
+
+        self.apply_read_latency(path, hits as u32, misses as u32);
+        ctx.inject(self.crash_hook(FsOperation::Read, CrashTiming::After, path, range)?);
+        Ok(read)
+    }
+
+    /// The fsync handler: syncs what the cache holds of `path`, only its data if `datasync`,
+    /// between the before and after fsync crash faults. A path the cache never saw is synced
+    /// straight on the backing file. An mmap'd owner is first reread from the backing file. If
+    /// the sync fails the owner stays unsynced and the caller gets EIO, as a disk that lost the
+    /// write would report.
+    pub fn do_fsync(&self, path: &Path, datasync: bool) -> Result<()> {
+        let _permit = self.begin_op(FsOperation::Fsync)?;
+        let mut ctx = self.op_context(FsOperation::Fsync, path);
+        ctx.inject(self.crash_hook(FsOperation::Fsync, CrashTiming::Before, path, None)?);
+        self.fsync_pending_write(path)?;
+
+        match self.cache.get_original_inode(path.to_path_buf())? {
+            Some(owner) => {
+                // Settled first, so the sync goes by the size the mapping left the file at
+                self.cache
+                    .settle_external_modification(owner.clone(), path.to_path_buf())?;
+                self.cache
+                    .sync_owner(owner, datasync, path.to_path_buf())
+                    .map_err(|e| e.context(std::io::Error::from_raw_os_error(libc::EIO)))?;
+            }
+            None => {
+                let file = std::fs::File::open(path)?;
+                if datasync {
+                    file.sync_data()?;
+                } else {
+                    file.sync_all()?;
+                }
+            }
+        }
+
+        ctx.inject(self.crash_hook(FsOperation::Fsync, CrashTiming::After, path, None)?);
+        Ok(())
+    }
+
+    /// The unlink handler: removes `path` from the backing directory and takes its link away
+    /// from the cached content, between the unlink crash faults. The content and its engine
+    /// pages go with the last link, dirty or not, while the other names of a hard-linked file
+    /// keep it with one link less.
+    pub fn do_unlink(&self, path: &Path) -> Result<()> {
+        let _permit = self.begin_op(FsOperation::Unlink)?;
+        let mut ctx = self.op_context(FsOperation::Unlink, path);
+        ctx.inject(self.crash_hook(FsOperation::Unlink, CrashTiming::Before, path, None)?);
+
+        let owner = self.linked_owner(path)?;
+        std::fs::remove_file(path)?;
+        if let Some(owner) = owner {
+            self.cache
+                .remove_cached_item(owner, path.to_path_buf(), false)?;
+        }
+
+        ctx.inject(self.crash_hook(FsOperation::Unlink, CrashTiming::After, path, None)?);
+        Ok(())
+    }
+
+    /// The owner of the cached content `path` links to, if any. A name the cache never saw may
+    /// still be a hard link to content it holds under another one.
+    fn linked_owner(&self, path: &Path) -> Result<Option<OwnerId>> {
+        if let Some(owner) = self.cache.get_original_inode(path.to_path_buf())? {
+            return Ok(Some(owner));
+        }
+        let stat = std::fs::symlink_metadata(path)?;
+        let owner: OwnerId = OwnerKey {
+            dev: stat.dev(),
+            ino: stat.ino(),
+        }
+        .to_string()
+        .into();
+        Ok(self
+            .cache
+            .has_content_cached(owner.clone())?
+            .then_some(owner))
+    }
+
+    /// The rename handler: renames `from` to `to` in the backing directory and the cache,
+    /// between the rename crash faults, which fire on either path. `RENAME_NOREPLACE` fails
+    /// with EEXIST if `to` exists and `RENAME_EXCHANGE` swaps both files. A file renamed over
+    /// loses that link, and with its last one its cached content goes unsynced, dirty or not.
+    /// Open handles follow the file to its new name.
+    pub fn do_rename(&self, from: &Path, to: &Path, flags: u32) -> Result<()> {
+        let _permit = self.begin_op(FsOperation::Rename)?;
+        let _guard = self.begin_mutation()?;
+        let mut ctx = self.op_context(FsOperation::Rename, from);
+        ctx.inject(self.trigger_crash_fault(
+            CrashTiming::Before,
+            FsOperation::Rename,
+            from,
+            Some(to),
+        )?);
+
+        let source = std::fs::symlink_metadata(from)?;
+        let target = match std::fs::symlink_metadata(to) {
+            Ok(target) => Some(target),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        if flags & libc::RENAME_EXCHANGE != 0 {
+            dirents::exchange(from, to)?;
+            self.cache
+                .exchange_items(from.to_path_buf(), to.to_path_buf())?;
+            self.rename_handles(from, to, true)?;
+        } else if flags & libc::RENAME_NOREPLACE != 0 && target.is_some() {
+            return Err(std::io::Error::from_raw_os_error(libc::EEXIST).into());
+        } else if target
+            .as_ref()
+            .is_some_and(|target| (target.dev(), target.ino()) == (source.dev(), source.ino()))
+        {
+            // Two links to the same file, which both stay
+        } else {
+            // Only a link the cache doesn't map is left for this to drop, `rename_item` takes
+            // care of the others
+            let replaced = match target {
+                Some(_) if self.cache.get_original_inode(to.to_path_buf())?.is_none() => {
+                    self.linked_owner(to)?
+                }
+                _ => None,
+            };
+            if self.rename_backing(&mut ctx, from, to)?.is_some() {
+                return Err(std::io::Error::from_raw_os_error(libc::EIO).into());
+            }
+            if let Some(replaced) = replaced {
+                self.cache
+                    .remove_cached_item(replaced, to.to_path_buf(), false)?;
+            }
+            self.cache
+                .rename_item(from.to_path_buf(), to.to_path_buf())?;
+            self.rename_handles(from, to, false)?;
+        }
+
+        ctx.inject(self.trigger_crash_fault(
+            CrashTiming::After,
+            FsOperation::Rename,
+            from,
+            Some(to),
+        )?);
+        Ok(())
+    }
+
+    /// The getattr handler: the metadata of `path` as the backing file system has it, with the
+    /// size, link count and times the cache holds if it has the file. Those run ahead of the
+    /// backing file while writes are unsynced, the rest only changes on the backing file. A
+    /// backing file changed outside the mount is noticed here. Returns the backing inode
+    /// number along with it.
+    pub fn do_getattr(&self, path: &Path) -> Result<(u64, Metadata)> {
+        let stat = std::fs::symlink_metadata(path)?;
+        let mut metadata = Metadata::from_fs_metadata(&stat);
+        if let Some(owner) = self.cache.get_original_inode(path.to_path_buf())? {
+            if stat.is_file() {
+                self.cache
+                    .check_external_change(owner.clone(), path.to_path_buf())?;
+            }
+            if let Some(cached) = self.cache.get_content_metadata(owner)? {
+                metadata.size = cached.size;
+                metadata.nlinks = cached.nlinks;
+                metadata.atim = cached.atim;
+                metadata.mtim = cached.mtim;
+                metadata.ctim = cached.ctim;
+            }
+        }
+        Ok((stat.ino(), metadata))
+    }
+
+    /// The statfs handler: the numbers of the backing file system in blocks of
+    /// `io_block_size`. With `statfs_reports_cache`, the size and free blocks are those of the
+    /// cache instead, so monitoring can watch how close it is to evicting or failing writes.
+    pub fn do_statfs(&self, path: &Path) -> Result<StatFs> {
+        let mut statfs = StatFs::of(path, self.config.io_block_size as u32)?;
+        if self.config.statfs_reports_cache {
+            let capacity = (self.config.cache_nr_pages * self.config.cache_page_size) as u64;
+            statfs.report_cache(capacity, self.cache.get_cache_usage()?);
+        }
+        Ok(statfs)
+    }
+
+    /// The setattr handler, between the setattr crash faults. A new size is cut like
+    /// `do_truncate` does, truncate crash faults included. Times, permission bits and ownership
+    /// are applied to the backing file right away and recorded in the cached metadata too,
+    /// along with the ctime, so syncing the file restores them over whatever the cache changed
+    /// since.
+    pub fn do_setattr(&self, path: &Path, changes: SetattrChanges) -> Result<()> {
+        let _permit = self.begin_op(FsOperation::Setattr)?;
+        let _guard = self.begin_mutation()?;
+        let mut ctx = self.op_context(FsOperation::Setattr, path);
+        ctx.inject(self.crash_hook(FsOperation::Setattr, CrashTiming::Before, path, None)?);
+        if let Some(size) = changes.size {
+            self.truncate(&mut ctx, path, size)?;
+        }
+        self.set_attributes(path, &changes)?;
+        ctx.inject(self.crash_hook(FsOperation::Setattr, CrashTiming::After, path, None)?);
+        Ok(())
+    }
+
+    /// Everything `do_setattr` changes besides the size
+    fn set_attributes(&self, path: &Path, changes: &SetattrChanges) -> Result<()> {
+        if *changes
+            == (SetattrChanges {
+                size: changes.size,
+                ..Default::default()
+            })
+        {
+            return Ok(());
+        }
+
+        // Stamped like the rest of the cached metadata, clock_skew included
+        let now = self.cache.now();
+        let time = |time: Option<SetTime>| {
+            time.map(|time| match time {
+                SetTime::Now => now,
+                SetTime::At(time) => time,
+            })
+        };
+        let (atime, mtime) = (time(changes.atime), time(changes.mtime));
+        if atime.is_some() || mtime.is_some() {
+            let mut times = std::fs::FileTimes::new();
+            if let Some(atime) = atime {
+                times = times.set_accessed(atime);
+            }
+            if let Some(mtime) = mtime {
+                times = times.set_modified(mtime);
+            }
+            std::fs::File::open(path)?.set_times(times)?;
+        }
+        if let Some(mode) = changes.mode {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o7777))?;
+        }
+        if changes.uid.is_some() || changes.gid.is_some() {
+            std::os::unix::fs::lchown(path, changes.uid, changes.gid)?;
+        }
+
+        let owner = match self.cache.get_original_inode(path.to_path_buf())? {
+            Some(owner) => owner,
+            None => return Ok(()),
+        };
+        // Set by the mount, not behind its back
+        let stat = std::fs::symlink_metadata(path)?;
+        if stat.is_file() && (atime.is_some() || mtime.is_some()) {
+            self.cache.observe_backing_file(owner.clone(), &stat)?;
+        }
+        let mut metadata = match self.cache.get_content_metadata(owner.clone())? {
+            Some(metadata) => metadata,
+            None => return Ok(()),
+        };
+        let mut fields = vec![MetadataField::Ctime];
+        metadata.ctim = now;
+        if let Some(atime) = atime {
+            metadata.atim = atime;
+            fields.push(MetadataField::Atime);
+        }
+        if let Some(mtime) = mtime {
+            metadata.mtim = mtime;
+            fields.push(MetadataField::Mtime);
+        }
+        if let Some(mode) = changes.mode {
+            metadata.mode = metadata.mode & libc::S_IFMT | mode & 0o7777;
+            fields.push(MetadataField::Mode);
+        }
+        if let Some(uid) = changes.uid {
+            metadata.uid = uid;
+            fields.push(MetadataField::Uid);
+        }
+        if let Some(gid) = changes.gid {
+            metadata.gid = gid;
+            fields.push(MetadataField::Gid);
+        }
+        self.cache
+            .update_content_metadata(owner, metadata, &fields)?;
+        Ok(())
+    }
+
+    /// The truncate handler: cuts or grows the cached file to `size`, between the truncate
+    /// crash faults
+    pub fn do_truncate(&self, path: &Path, size: u64) -> Result<()> {
+        let _permit = self.begin_op(FsOperation::Truncate)?;
+        let _guard = self.begin_mutation()?;
+        let mut ctx = self.op_context(FsOperation::Truncate, path);
+        self.truncate(&mut ctx, path, size)
+    }
+
+    /// Cuts or grows the cached file to `size` between the truncate crash faults, for handlers
+    /// that already hold their permit and guard
+    fn truncate(&self, ctx: &mut OpContext, path: &Path, size: u64) -> Result<()> {
+        let range = Some((size, 0));
+        ctx.inject(self.crash_hook(FsOperation::Truncate, CrashTiming::Before, path, range)?);
+        let owner = self.owner_of(path)?;
+        let size =
+            usize::try_from(size).map_err(|_| std::io::Error::from_raw_os_error(libc::EFBIG))?;
+        self.cache.truncate_item(owner, size)?;
+        ctx.inject(self.crash_hook(FsOperation::Truncate, CrashTiming::After, path, range)?);
+        Ok(())
+    }
+
+    /// The link handler: links `new` to the file at `existing`, between the link crash faults,
+    /// which fire on either path. Both names then resolve to the same cached content, which
+    /// gains a link, so writes through one are read through the other before any sync.
+    pub fn do_link(&self, existing: &Path, new: &Path) -> Result<()> {
+        let _permit = self.begin_op(FsOperation::Link)?;
+        let mut ctx = self.op_context(FsOperation::Link, existing);
+        ctx.inject(self.trigger_crash_fault(
+            CrashTiming::Before,
+            FsOperation::Link,
+            existing,
+            Some(new),
+        )?);
+
+        // Cached before the link is made, so the link count it starts from leaves it out
+        let owner = self.owner_of(existing)?;
+        std::fs::hard_link(existing, new)?;
+        let owner = self
+            .cache
+            .insert_inode_mapping(new.to_path_buf(), owner, true)?;
+        self.cache.dirent_created(new.to_path_buf(), &owner)?;
+
+        ctx.inject(self.trigger_crash_fault(
+            CrashTiming::After,
+            FsOperation::Link,
+            existing,
+            Some(new),
+        )?);
+        Ok(())
+    }
+
+    /// The symlink handler: creates `link` pointing at `target`, between the symlink crash
+    /// faults, which fire on either path. The link itself is cached with its metadata and no
+    /// content.
+    pub fn do_symlink(&self, target: &Path, link: &Path) -> Result<()> {
+        let _permit = self.begin_op(FsOperation::Symlink)?;
+        let mut ctx = self.op_context(FsOperation::Symlink, link);
+        ctx.inject(self.trigger_crash_fault(
+            CrashTiming::Before,
+            FsOperation::Symlink,
+            link,
+            Some(target),
+        )?);
+
+        std::os::unix::fs::symlink(target, link)?;
+        let owner = self.map_owner(link, &std::fs::symlink_metadata(link)?)?;
+        self.cache.dirent_created(link.to_path_buf(), &owner)?;
+
+        ctx.inject(self.trigger_crash_fault(
+            CrashTiming::After,
+            FsOperation::Symlink,
+            link,
+            Some(target),
+        )?);
+        Ok(())
+    }
+
+    /// The mkdir handler: creates the directory `path` in the backing directory with `mode`,
+    /// between the mkdir crash faults. Until its parent is fsynced a crash takes it back, along
+    /// with everything made in it.
+    pub fn do_mkdir(&self, path: &Path, mode: u32) -> Result<()> {
+        let _permit = self.begin_op(FsOperation::Mkdir)?;
+        let _guard = self.begin_mutation()?;
+        let mut ctx = self.op_context(FsOperation::Mkdir, path);
+        ctx.inject(self.crash_hook(FsOperation::Mkdir, CrashTiming::Before, path, None)?);
+
+        std::fs::DirBuilder::new().mode(mode).create(path)?;
+        let owner = OwnerKey::of(path)?.to_string();
+        self.cache.dir_created(path.to_path_buf(), &owner)?;
+
+        ctx.inject(self.crash_hook(FsOperation::Mkdir, CrashTiming::After, path, None)?);
+        Ok(())
+    }
+
+    /// The rmdir handler: removes the empty directory `path` from the backing directory, and
+    /// with it whatever the cache holds of it, between the rmdir crash faults. Until its parent
+    /// is fsynced a crash brings it back empty.
+    pub fn do_rmdir(&self, path: &Path) -> Result<()> {
+        let _permit = self.begin_op(FsOperation::Rmdir)?;
+        let _guard = self.begin_mutation()?;
+        let mut ctx = self.op_context(FsOperation::Rmdir, path);
+        ctx.inject(self.crash_hook(FsOperation::Rmdir, CrashTiming::Before, path, None)?);
+
+        let stat = std::fs::symlink_metadata(path)?;
+        std::fs::remove_dir(path)?;
+        if let Some(owner) = self.cache.get_original_inode(path.to_path_buf())? {
+            self.cache
+                .remove_cached_item(owner, path.to_path_buf(), false)?;
+        }
+        let owner = OwnerKey {
+            dev: stat.dev(),
+            ino: stat.ino(),
+        };
+        self.cache
+            .dir_removed(path.to_path_buf(), stat.mode() & 0o7777, &owner.to_string())?;
+
+        ctx.inject(self.crash_hook(FsOperation::Rmdir, CrashTiming::After, path, None)?);
+        Ok(())
+    }
+
+    /// The fsyncdir handler: syncs the directory `dir` in the backing directory, which makes
+    /// the names created or renamed in it durable, between the before and after fsync crash
+    /// faults
+    pub fn do_fsyncdir(&self, dir: &Path) -> Result<()> {
+        let _permit = self.begin_op(FsOperation::Fsync)?;
+        let mut ctx = self.op_context(FsOperation::Fsync, dir);
+        ctx.inject(self.crash_hook(FsOperation::Fsync, CrashTiming::Before, dir, None)?);
+
+        std::fs::File::open(dir)?.sync_all()?;
+        self.cache.sync_dir(dir)?;
+
+        ctx.inject(self.crash_hook(FsOperation::Fsync, CrashTiming::After, dir, None)?);
+        Ok(())
+    }
+
+    /// The setxattr handler: sets the extended attribute `name` of `path` in the cache, see
+    /// `Cache::set_xattr` for `flags`, between the setxattr crash faults. The backing file gets
+    /// it with the next sync of its metadata, until then a crash loses it.
+    pub fn do_setxattr(&self, path: &Path, name: &str, value: &[u8], flags: i32) -> Result<()> {
+        let _permit = self.begin_op(FsOperation::Setxattr)?;
+        let _guard = self.begin_mutation()?;
+        let mut ctx = self.op_context(FsOperation::Setxattr, path);
+        ctx.inject(self.crash_hook(FsOperation::Setxattr, CrashTiming::Before, path, None)?);
+
+        let owner = self.owner_of(path)?;
+        self.cache.set_xattr(owner, name, value, flags)?;
+
+        ctx.inject(self.crash_hook(FsOperation::Setxattr, CrashTiming::After, path, None)?);
+        Ok(())
+    }
+
+    /// The getxattr handler: the value of the extended attribute `name` of `path`, failing
+    /// with ENODATA if it isn't set
+    pub fn do_getxattr(&self, path: &Path, name: &str) -> Result<Vec<u8>> {
+        let _permit = self.begin_op(FsOperation::Getxattr)?;
+        let mut ctx = self.op_context(FsOperation::Getxattr, path);
+        ctx.inject(self.crash_hook(FsOperation::Getxattr, CrashTiming::Before, path, None)?);
+
+        let owner = self.owner_of(path)?;
+        let value = self
+            .cache
+            .get_xattr(owner, name)?
+            .ok_or_else(|| std::io::Error::from_raw_os_error(libc::ENODATA))?;
+
+        ctx.inject(self.crash_hook(FsOperation::Getxattr, CrashTiming::After, path, None)?);
+        Ok(value)
+    }
+
+    /// The listxattr handler: the names of the extended attributes of `path`, sorted
+    pub fn do_listxattr(&self, path: &Path) -> Result<Vec<String>> {
+        let _permit = self.begin_op(FsOperation::Listxattr)?;
+        let mut ctx = self.op_context(FsOperation::Listxattr, path);
+        ctx.inject(self.crash_hook(FsOperation::Listxattr, CrashTiming::Before, path, None)?);
+
+        let owner = self.owner_of(path)?;
+        let names = self.cache.list_xattrs(owner)?;
+
+        ctx.inject(self.crash_hook(FsOperation::Listxattr, CrashTiming::After, path, None)?);
+        Ok(names)
+    }
+
+    /// The removexattr handler: removes the extended attribute `name` of `path`, failing with
+    /// ENODATA if it isn't set, between the removexattr crash faults. Like setxattr, the
+    /// backing file follows on the next metadata sync.
+    pub fn do_removexattr(&self, path: &Path, name: &str) -> Result<()> {
+        let _permit = self.begin_op(FsOperation::Removexattr)?;
+        let _guard = self.begin_mutation()?;
+        let mut ctx = self.op_context(FsOperation::Removexattr, path);
+        ctx.inject(self.crash_hook(FsOperation::Removexattr, CrashTiming::Before, path, None)?);
+
+        let owner = self.owner_of(path)?;
+        if !self.cache.remove_xattr(owner, name)? {
+            return Err(std::io::Error::from_raw_os_error(libc::ENODATA).into());
+        }
+
+        ctx.inject(self.crash_hook(FsOperation::Removexattr, CrashTiming::After, path, None)?);
+        Ok(())
+    }
+
+    /// Points the handles open on `from` at `to`, and those on `to` at `from` if the two were
+    /// swapped
+    fn rename_handles(&self, from: &Path, to: &Path, exchange: bool) -> Result<()> {
+        let mut handles = self
+            .handles
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on handles: {:?}", e))?;
+        for handle in handles.values_mut() {
+            if handle.path == from {
+                handle.path = to.to_path_buf();
+            } else if exchange && handle.path == to {
+                handle.path = from.to_path_buf();
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes `data` at `offset` of the backing file of `path`, past the cache. The cached size
+    /// and mtime follow the write.
+    fn write_backing(&self, path: &Path, owner: &str, offset: u64, data: &[u8]) -> Result<usize> {
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .write_all_at(data, offset)?;
+        // Written by the mount, not behind its back
+        self.cache
+            .observe_backing_file(owner.to_string(), &std::fs::metadata(path)?)?;
+        if let Some(mut metadata) = self.cache.get_content_metadata(owner.to_string())? {
+            metadata.size = metadata.size.max(offset + data.len() as u64);
+            metadata.mtim = self.cache.now();
+            self.cache.update_content_metadata(
+                owner.to_string(),
+                metadata,
+                &[MetadataField::Size, MetadataField::Mtime],
+            )?;
+        }
+        Ok(data.len())
+    }
+
+    pub fn handle(&self, fh: u64) -> Result<Option<OpenHandle>> {
+        let handles = self
+            .handles
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on handles: {:?}", e))?;
+        Ok(handles.get(&fh).cloned())
+    }
+
+    /// To be called by release once the handle is gone
+    pub fn release_handle(&self, fh: u64) -> Result<Option<OpenHandle>> {
+        let mut handles = self
+            .handles
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on handles: {:?}", e))?;
+        Ok(handles.remove(&fh))
+    }
+
+    /// To be called by the write handler once `(offset, size)` is cached, before replying.
+    /// Syncs the file if the handle's `CachePolicy` is write-through, going through the fsync
+    /// crash faults if `sync_writes_match_fsync_faults` is set. Returns whether it synced.
+    pub fn sync_written(
+        &self,
+        ctx: &mut OpContext,
+        fh: u64,
+        offset: u64,
+        size: u64,
+    ) -> Result<bool> {
+        let handle = match self.handle(fh)? {
+            Some(handle) if self.cache_policy(fh)? == CachePolicy::WriteThrough => handle,
+            _ => return Ok(false),
+        };
+        let range = Some((offset, size));
+        let hook_faults = self.config.sync_writes_match_fsync_faults;
+        if hook_faults {
+            ctx.inject(self.crash_hook(
+                FsOperation::Fsync,
+                CrashTiming::Before,
+                &handle.path,
+                range,
+            )?);
+        }
+        self.cache.sync_owner(
+            handle.owner.clone(),
+            handle.durability == WriteDurability::Data,
+            handle.path.clone(),
+        )?;
+        if hook_faults {
+            ctx.inject(self.crash_hook(
+                FsOperation::Fsync,
+                CrashTiming::After,
+                &handle.path,
+                range,
+            )?);
+        }
+        Ok(true)
+    }
+
+    pub fn get_path_injecting_fault(&self) -> Result<PathBuf> {
+        let lock = self
+            .path_injecting_fault
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on path injecting fault: {:?}", e))?;
+        Ok(lock.clone())
+    }
+
+    /// Holds a write of `buf` at `offset` to `path` back if a reorder fault keyed by `path`
+    /// fires on it, applying the write held before it if there was one. Returns whether it was
+    /// held, in which case the caller must not apply it: `fsync_pending_write` applies it once
+    /// the writes after it went through, unless LazyFS crashes first.
+    pub fn hold_reordered_write(
+        &self,
+        ctx: &mut OpContext,
+        path: &Path,
+        buf: &[u8],
+        offset: u64,
+    ) -> Result<bool> {
+        let faults = self.faults_for(path)?;
+        let op_count = self.op_count();
+        let now = self.clock.now();
+        let mut held = None;
+        for fault in faults.iter().filter_map(|fault| fault.as_reorder()) {
+            if fault.op() != "write" {
+                continue;
+            }
+            if !fault.is_active(op_count, now) {
+                self.tally(ctx, fault, Evaluation::Missed)?;
+                continue;
+            }
+            let position = fault.count_op();
+            if !fault.should_trigger(path, position) {
+                self.tally(ctx, fault, Evaluation::Missed)?;
+                continue;
+            }
+            if self.is_dry_run() {
+                self.tally(ctx, fault, Evaluation::Matched)?;
+                let key = path.to_string_lossy().into_owned();
+                let action = format!("reordered write of {} bytes at {}", buf.len(), offset);
+                self.record_dry_run(key, fault.spec(), path, action)?;
+                if !self.config.dry_run_consumes_occurences {
+                    fault.release_op();
+                }
+                return Ok(false);
+            }
+            self.tally(ctx, fault, Evaluation::Triggered)?;
+            held = Some((fault, fault.persists(position)));
+            break;
+        }
+        let (fault, persist) = match held {
+            Some(held) => held,
+            None => return Ok(false),
+        };
+        fault.on_triggered();
+        self.flush_pending_write()?;
+
+        info!(
+            target: TRACING_TARGET,
+            path = %path.display(),
+            offset,
+            len = buf.len(),
+            persist,
+            "holding back reordered write"
+        );
+        let mut pending_write = self
+            .pending_write
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on pending write: {:?}", e))?;
+        *pending_write = Write::new(path.to_path_buf(), buf.to_vec(), offset, persist);
+        let mut path_injecting_fault = self
+            .path_injecting_fault
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on path injecting fault: {:?}", e))?;
+        *path_injecting_fault = path.to_path_buf();
+        Ok(true)
+    }
+
+    /// Applies the write held back by a reorder fault, if any, whether the fault persists it or
+    /// not. Returns whether there was one.
+    pub fn flush_pending_write(&self) -> Result<bool> {
+        match self.take_pending_write(None)? {
+            Some(write) => self.apply_pending_write(write).map(|_| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Ends the group of writes to `path` a reorder fault held a write of back. To be called by
+    /// the fsync handler before it syncs `path`: the held write is applied, after the writes
+    /// that came later, if the fault persists it and dropped otherwise. Returns whether it was
+    /// applied.
+    pub fn fsync_pending_write(&self, path: &Path) -> Result<bool> {
+        let write = match self.take_pending_write(Some(path))? {
+            Some(write) => write,
+            None => return Ok(false),
+        };
+        if !write.persist {
+            info!(
+                target: TRACING_TARGET,
+                path = %path.display(),
+                offset = write.offset,
+                len = write.buf.len(),
+                "dropping reordered write"
+            );
+            return Ok(false);
+        }
+        self.apply_pending_write(write)?;
+        Ok(true)
+    }
+
+    /// Takes the held write out of `pending_write`, if there is one for `path` or any path
+    fn take_pending_write(&self, path: Option<&Path>) -> Result<Option<Write>> {
+        let mut pending_write = self
+            .pending_write
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on pending write: {:?}", e))?;
+        let held = !pending_write.path.as_os_str().is_empty()
+            && path.is_none_or(|path| pending_write.path == path);
+        if !held {
+            return Ok(None);
+        }
+        let mut path_injecting_fault = self
+            .path_injecting_fault
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on path injecting fault: {:?}", e))?;
+        *path_injecting_fault = PathBuf::from("none");
+        Ok(Some(std::mem::take(&mut *pending_write)))
+    }
+
+    fn apply_pending_write(&self, write: Write) -> Result<()> {
+        let owner = self
+            .cache
+            .get_original_inode(write.path.clone())?
+            .ok_or_else(|| cache::NotCached(write.path.display().to_string()))?;
+        self.cache.write_at(owner, write.offset, &write.buf)?;
+        Ok(())
+    }
+
+    /// Crashes with `mode`, losing the write held back by a reorder fault if there is one
+    fn crash(&self, mode: CrashMode) -> Result<()> {
+        if let Some(write) = self.take_pending_write(None)? {
+            info!(
+                target: TRACING_TARGET,
+                path = %write.path.display(),
+                "reordered write lost in the crash"
+            );
+        }
+        match mode {
+            CrashMode::Kill => std::process::abort(),
+            CrashMode::ClearCache => self.cache.clear_cache(),
+            CrashMode::Freeze => {
+                self.frozen.store(true, Ordering::SeqCst);
+                Ok(())
+            }
+        }
+    }
+}
+
+struct Write {
+    path: PathBuf,
+    buf: Vec<u8>,
+    offset: u64,
+    /// Whether the reorder fault that held the write back lets it reach the disk
+    persist: bool,
+}
+
+impl Write {
+    pub fn new(path: PathBuf, buf: Vec<u8>, offset: u64, persist: bool) -> Write {
+        Write {
+            path,
+            buf,
+            offset,
+            persist,
+        }
+    }
+}
+
+impl Default for Write {
+    fn default() -> Self {
+        Write {
+            path: "".into(),
+            buf: Vec::new(),
+            offset: 0,
+            persist: false,
+        }
+    }
+}
+
+/// The errno an operation fails with: that of the I/O error behind `e` if there is one
+pub fn errno_of(e: &anyhow::Error) -> i32 {
+    if e.is::<cache::NotCached>() {
+        return libc::ENOENT;
+    }
+    e.chain()
+        .find_map(|cause| cause.downcast_ref::<std::io::Error>())
+        .and_then(|e| e.raw_os_error())
+        .unwrap_or(libc::EIO)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::pagecache::config::{
+        DelayFault, FaultSchedule, FaultWindow, QuotaFault, QuotaMode, QuotaOutcome, RenameTear,
+        RenameTearFault, ReorderFault, ShortWriteFault, ShortWriteLimit, SplitWriteFault,
+        StaleReadFault, TornSectors,
+    };
+    use crate::pagecache::dirents::DirentChange;
+    use crate::pagecache::engine::AllocateOperationType;
+    use crate::pagecache::item::stats::StatMetric;
+    use crate::pagecache::xattr;
+
+    fn new_lazyfs(clock: Arc<ManualClock>, schedule: FaultSchedule) -> Arc<LazyFS> {
+        new_lazyfs_with_config(clock, schedule, config::Config::default())
+    }
+
+    fn new_lazyfs_with_config(
+        clock: Arc<ManualClock>,
+        schedule: FaultSchedule,
+        config: config::Config,
+    ) -> Arc<LazyFS> {
+        // Armed on the clock by the builder
+        let fault = SplitWriteFault::from_parts(1, vec![1], 2).with_schedule(schedule);
+        let mut faults: HashMap<String, Vec<Arc<dyn config::Fault>>> = HashMap::new();
+        faults.insert("wal".to_string(), vec![Arc::new(fault)]);
+        LazyFS::builder()
+            .config(config)
+            .faults(faults)
+            .clock(clock)
+            .build()
+            .unwrap()
+    }
+
+    fn active(lazyfs: &LazyFS) -> bool {
+        lazyfs.faults.lock().unwrap()["wal"][0].is_active(lazyfs.op_count(), lazyfs.clock.now())
+    }
+
+    #[test]
+    fn builder_sets_up_the_cache_and_faults() {
+        let config = config::Config::default();
+        let engine = backends::custom::CustomCacheEngine::new(Box::new(config.clone())).unwrap();
+        let lazyfs = LazyFS::builder()
+            .config(config)
+            .engine(engine)
+            .build()
+            .unwrap();
+        assert!(lazyfs.fault_status().unwrap().is_empty());
+        assert_eq!(lazyfs.cache().get_cache_usage().unwrap(), 0.0);
+
+        let path =
+            std::env::temp_dir().join(format!("lazyfs-rs-{}-builder.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+            log_all_operations = false
+            is_default_config = false
+            cache_nr_pages = 8
+            cache_page_size = 4096
+            io_block_size = 4096
+            disk_sector_size = 512
+            apply_lru_eviction = false
+            fifo_path = ""
+            fifo_path_completed = ""
+            log_file = ""
+
+            [[injection]]
+            type = "reorder"
+            file = "/data/wal"
+            op = "write"
+            occurrence = 1
+            persist = [2]
+            "#,
+        )
+        .unwrap();
+        let filename = path.to_str().unwrap();
+        let lazyfs = LazyFS::builder()
+            .config_file(filename)
+            .faults_from_config()
+            .build()
+            .unwrap();
+        assert_eq!(lazyfs.config().cache_nr_pages, 8);
+        assert_eq!(lazyfs.fault_status().unwrap().len(), 1);
+        // Nowhere to load the faults from
+        assert!(LazyFS::builder().faults_from_config().build().is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn faults_trigger_on_their_occurrence() {
+        let split: Arc<dyn config::Fault> = Arc::new(SplitWriteFault::from_parts(3, vec![1], 2));
+        let reorder: Arc<dyn config::Fault> =
+            Arc::new(ReorderFault::from_op("fsync".to_string(), vec![1], 1));
+        let faults = HashMap::from([("/data/wal".to_string(), vec![split, reorder])]);
+        let lazyfs = LazyFS::builder().faults(faults).build().unwrap();
+        let wal = Path::new("/data/wal");
+
+        let fired = |op, path| {
+            let fired = lazyfs.check_and_trigger_faults(op, path).unwrap();
+            fired
+                .iter()
+                .map(|fault| fault.op().to_string())
+                .collect::<Vec<_>>()
+        };
+        // Writes to other files and other ops on the wal don't count towards the third write
+        assert_eq!(fired("write", wal), Vec::<String>::new());
+        assert_eq!(fired("write", Path::new("/data/sst")), Vec::<String>::new());
+        assert_eq!(fired("fsync", wal), vec!["fsync"]);
+        assert_eq!(fired("write", wal), Vec::<String>::new());
+        assert_eq!(fired("write", wal), vec!["write"]);
+        assert_eq!(fired("write", wal), Vec::<String>::new());
+        assert_eq!(fired("fsync", wal), Vec::<String>::new());
+    }
+
+    #[test]
+    fn split_write_persists_only_its_parts() {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-split", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let wal = dir.join("wal");
+        std::fs::write(&wal, vec![b'o'; 12288]).unwrap();
+        let owner = wal.to_string_lossy().to_string();
+
+        let fault = SplitWriteFault::from_parts(1, vec![1], 3).with_mode(CrashMode::ClearCache);
+        let faults: HashMap<String, Vec<Arc<dyn config::Fault>>> = HashMap::from([(
+            owner.clone(),
+            vec![Arc::new(fault) as Arc<dyn config::Fault>],
+        )]);
+        let lazyfs = LazyFS::builder().faults(faults).build().unwrap();
+        let cache = lazyfs.cache();
+        cache.insert_item(owner.clone()).unwrap();
+        cache
+            .insert_inode_mapping(wal.clone(), owner.clone(), false)
+            .unwrap();
+        let metadata = Metadata {
+            size: 12288,
+            ..Default::default()
+        };
+        cache
+            .update_content_metadata(owner.clone(), metadata, &[MetadataField::Size])
+            .unwrap();
+
+        // Never synced, so lost in the crash along with the parts the fault drops
+        cache.write_at(owner.clone(), 12288, b"unsynced").unwrap();
+
+        let persisted = lazyfs
+            .apply_split_write(
+                &mut OpContext::new(FsOperation::Write),
+                &wal,
+                &[b'n'; 12288],
+                0,
+            )
+            .unwrap()
+            .unwrap();
+        assert_eq!(persisted, vec![(1, vec![b'n'; 4096])]);
+        let mut expected = vec![b'n'; 4096];
+        expected.extend_from_slice(&[b'o'; 8192]);
+        assert_eq!(std::fs::read(&wal).unwrap(), expected);
+        // The crash dropped the cache, and the fault only fires once
+        assert_eq!(
+            lazyfs.cache().get_original_inode(wal.clone()).unwrap(),
+            None
+        );
+        assert_eq!(
+            lazyfs
+                .apply_split_write(&mut OpContext::new(FsOperation::Write), &wal, b"again", 0)
+                .unwrap(),
+            None
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Writes A, B and C one after the other to a file whose second write a reorder fault
+    /// persisting `persist` holds back, syncs it, through the fsync that applies the held write
+    /// if `fsync_pending`, and crashes. Returns what is left on disk.
+    fn reordered_writes(persist: Vec<i32>, fsync_pending: bool, name: &str) -> Vec<u8> {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        let wal = dir.join("wal");
+        std::fs::write(&wal, "").unwrap();
+        let owner = wal.to_string_lossy().to_string();
+
+        let fault = ReorderFault::from_op("write".to_string(), persist, 2);
+        let faults: HashMap<String, Vec<Arc<dyn config::Fault>>> = HashMap::from([(
+            owner.clone(),
+            vec![Arc::new(fault) as Arc<dyn config::Fault>],
+        )]);
+        let lazyfs = LazyFS::builder().faults(faults).build().unwrap();
+        let cache = lazyfs.cache();
+        cache.insert_item(owner.clone()).unwrap();
+        cache
+            .insert_inode_mapping(wal.clone(), owner.clone(), false)
+            .unwrap();
+
+        for (i, buf) in [b"AAAA", b"BBBB", b"CCCC"].iter().enumerate() {
+            let offset = i as u64 * 4;
+            if !lazyfs
+                .hold_reordered_write(&mut OpContext::new(FsOperation::Write), &wal, *buf, offset)
+                .unwrap()
+            {
+                lazyfs
+                    .cache()
+                    .write_at(owner.clone(), offset, *buf)
+                    .unwrap();
+            }
+        }
+        assert_eq!(lazyfs.get_path_injecting_fault().unwrap(), wal);
+        if fsync_pending {
+            lazyfs.fsync_pending_write(&wal).unwrap();
+        }
+        lazyfs
+            .cache()
+            .sync_owner(owner, false, wal.clone())
+            .unwrap();
+        lazyfs.crash(CrashMode::ClearCache).unwrap();
+        // Nothing is held back past the crash
+        assert!(!lazyfs.fsync_pending_write(&wal).unwrap());
+
+        let on_disk = std::fs::read(&wal).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        on_disk
+    }
+
+    #[test]
+    fn reordered_write_lands_after_its_group() {
+        // B isn't persisted, C still makes it
+        assert_eq!(
+            reordered_writes(vec![1, 3], true, "reorder-drop"),
+            b"AAAA\0\0\0\0CCCC"
+        );
+        assert_eq!(
+            reordered_writes(vec![1, 2, 3], true, "reorder-keep"),
+            b"AAAABBBBCCCC"
+        );
+    }
+
+    #[test]
+    fn crash_loses_the_reordered_write_it_finds_held() {
+        // B would have been persisted, but the crash came before the fsync that applies it
+        assert_eq!(
+            reordered_writes(vec![1, 2, 3], false, "reorder-crash"),
+            b"AAAA\0\0\0\0CCCC"
+        );
+    }
+
+    #[test]
+    fn op_counter_drives_fault_activation() {
+        let clock = Arc::new(ManualClock::default());
+        let lazyfs = new_lazyfs(
+            clock,
+            FaultSchedule {
+                active_after_ops: Some(2),
+                active_for_ops: Some(2),
+                ..Default::default()
+            },
+        );
+
+        assert!(!active(&lazyfs));
+        assert_eq!(lazyfs.next_op(), 1);
+        assert!(!active(&lazyfs));
+        lazyfs.next_op();
+        assert!(active(&lazyfs));
+        lazyfs.next_op();
+        assert!(active(&lazyfs));
+        lazyfs.next_op();
+        assert!(!active(&lazyfs));
+        let status = lazyfs.fault_status().unwrap();
+        assert_eq!(status.len(), 1);
+        assert_eq!(status[0].key, "wal");
+        assert_eq!(status[0].window, FaultWindow::Expired);
+    }
+
+    #[test]
+    fn clock_drives_fault_activation() {
+        let clock = Arc::new(ManualClock::default());
+        let lazyfs = new_lazyfs(
+            clock.clone(),
+            FaultSchedule {
+                active_after: Some(Duration::from_secs(30)),
+                active_for: Some(Duration::from_secs(10)),
+                ..Default::default()
+            },
+        );
+
+        assert!(!active(&lazyfs));
+        clock.advance(Duration::from_secs(30));
+        assert!(active(&lazyfs));
+        clock.sleep(Duration::from_secs(9));
+        assert_eq!(
+            lazyfs.fault_status().unwrap()[0].window,
+            FaultWindow::Active {
+                ops_left: None,
+                time_left: Some(Duration::from_secs(1))
+            }
+        );
+        clock.advance(Duration::from_secs(1));
+        assert!(!active(&lazyfs));
+    }
+
+    #[test]
+    fn faults_and_items_follow_the_builder_clock() {
+        let clock = Arc::new(ManualClock::default());
+        let lazyfs = new_lazyfs(clock.clone(), FaultSchedule::default());
+        let fault: Arc<dyn config::Fault> = Arc::new(
+            SplitWriteFault::from_parts(1, vec![1], 2).with_schedule(FaultSchedule {
+                active_after: Some(Duration::from_secs(30)),
+                ..Default::default()
+            }),
+        );
+        clock.advance(Duration::from_secs(100));
+        lazyfs.add_fault("sst", fault.clone()).unwrap();
+
+        // Armed at 100s on the manual clock, not at the real time
+        clock.advance(Duration::from_secs(29));
+        assert!(!fault.is_active(lazyfs.op_count(), clock.now()));
+        clock.advance(Duration::from_secs(1));
+        assert!(fault.is_active(lazyfs.op_count(), clock.now()));
+
+        lazyfs.cache().insert_item("owner".to_string()).unwrap();
+        let metadata = lazyfs
+            .cache()
+            .get_content_metadata("owner".to_string())
+            .unwrap()
+            .unwrap();
+        assert_eq!(metadata.mtim, clock.now());
+    }
+
+    #[test]
+    fn quota_runs_out() {
+        let lazyfs = new_lazyfs(Arc::new(ManualClock::default()), FaultSchedule::default());
+        lazyfs
+            .add_quota_fault(QuotaFault::new("^/data/wal", 10000, QuotaMode::NoSpace).unwrap())
+            .unwrap();
+        let wal = Path::new("/data/wal/000001.log");
+
+        assert_eq!(
+            lazyfs
+                .charge_write(&mut OpContext::new(FsOperation::Write), wal, "wal-1", 4096)
+                .unwrap(),
+            QuotaOutcome::Accept(4096)
+        );
+        assert_eq!(
+            lazyfs
+                .charge_write(&mut OpContext::new(FsOperation::Write), wal, "wal-1", 4096)
+                .unwrap(),
+            QuotaOutcome::Accept(4096)
+        );
+        assert_eq!(
+            lazyfs
+                .charge_write(&mut OpContext::new(FsOperation::Write), wal, "wal-1", 4096)
+                .unwrap(),
+            QuotaOutcome::NoSpace
+        );
+
+        // Still charged after being renamed out of the matching directory
+        let archived = Path::new("/data/archive/000001.log");
+        assert_eq!(
+            lazyfs
+                .charge_write(
+                    &mut OpContext::new(FsOperation::Write),
+                    archived,
+                    "wal-1",
+                    1000
+                )
+                .unwrap(),
+            QuotaOutcome::Accept(1000)
+        );
+        // Unrelated files are not affected
+        assert_eq!(
+            lazyfs
+                .charge_write(
+                    &mut OpContext::new(FsOperation::Write),
+                    archived,
+                    "other",
+                    4096
+                )
+                .unwrap(),
+            QuotaOutcome::Accept(4096)
+        );
+
+        let status = lazyfs.fault_status().unwrap();
+        assert_eq!(status[1].detail.as_deref(), Some("9192/10000 bytes"));
+
+        lazyfs.reset_quotas().unwrap();
+        assert_eq!(
+            lazyfs
+                .charge_write(&mut OpContext::new(FsOperation::Write), wal, "wal-1", 4096)
+                .unwrap(),
+            QuotaOutcome::Accept(4096)
+        );
+    }
+
+    #[test]
+    fn quota_short_writes() {
+        let lazyfs = new_lazyfs(Arc::new(ManualClock::default()), FaultSchedule::default());
+        lazyfs
+            .add_quota_fault(QuotaFault::new("wal", 6000, QuotaMode::ShortWrite).unwrap())
+            .unwrap();
+        let wal = Path::new("/data/wal");
+
+        assert_eq!(
+            lazyfs
+                .charge_write(&mut OpContext::new(FsOperation::Write), wal, "wal", 4096)
+                .unwrap(),
+            QuotaOutcome::Accept(4096)
+        );
+        assert_eq!(
+            lazyfs
+                .charge_write(&mut OpContext::new(FsOperation::Write), wal, "wal", 4096)
+                .unwrap(),
+            QuotaOutcome::Accept(1904)
+        );
+        assert_eq!(
+            lazyfs
+                .charge_write(&mut OpContext::new(FsOperation::Write), wal, "wal", 4096)
+                .unwrap(),
+            QuotaOutcome::NoSpace
+        );
+    }
+
+    #[test]
+    fn reads_go_on_once_the_quota_is_spent() {
+        let dir =
+            std::env::temp_dir().join(format!("lazyfs-rs-{}-quota-reads", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (wal, log) = (dir.join("wal"), dir.join("000001.log"));
+        for path in [&wal, &log] {
+            std::fs::write(path, b"").unwrap();
+        }
+        let lazyfs = new_lazyfs(Arc::new(ManualClock::default()), FaultSchedule::default());
+        lazyfs
+            .add_quota_fault(QuotaFault::new("/wal$", 6000, QuotaMode::NoSpace).unwrap())
+            .unwrap();
+        lazyfs
+            .add_quota_fault(QuotaFault::new("\\.log$", 6000, QuotaMode::ShortWrite).unwrap())
+            .unwrap();
+        let enospc = |e: anyhow::Error| errno_of(&e) == libc::ENOSPC;
+        let data: Vec<u8> = (0..4096).map(|i| (i % 251) as u8).collect();
+
+        assert_eq!(lazyfs.do_write(&wal, 7, 0, &data).unwrap(), 4096);
+        assert!(enospc(lazyfs.do_write(&wal, 7, 4096, &data).unwrap_err()));
+        assert_eq!(lazyfs.do_write(&log, 8, 0, &data).unwrap(), 4096);
+        assert_eq!(lazyfs.do_write(&log, 8, 4096, &data).unwrap(), 1904);
+        assert!(enospc(lazyfs.do_write(&log, 8, 6000, &data).unwrap_err()));
+
+        let mut buf = vec![0; 8192];
+        assert_eq!(lazyfs.do_read(&wal, 7, 0, 8192, &mut buf).unwrap(), 4096);
+        assert_eq!(&buf[..4096], &data[..]);
+        assert_eq!(lazyfs.do_read(&log, 8, 0, 8192, &mut buf).unwrap(), 6000);
+        assert_eq!(&buf[..4096], &data[..]);
+        assert_eq!(&buf[4096..6000], &data[..1904]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn short_write_truncates_targeted_write() {
+        let dir =
+            std::env::temp_dir().join(format!("lazyfs-rs-{}-short-write", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (log, current) = (dir.join("000001.log"), dir.join("CURRENT"));
+        for path in [&log, &current] {
+            std::fs::write(path, b"").unwrap();
+        }
+        // Room for both files
+        let config = config::Config {
+            cache_nr_pages: 32,
+            ..Default::default()
+        };
+        let lazyfs = new_lazyfs_with_config(
+            Arc::new(ManualClock::default()),
+            FaultSchedule::default(),
+            config,
+        );
+        lazyfs
+            .add_short_write_fault(
+                ShortWriteFault::new("\\.log$", 1, ShortWriteLimit::Bytes(4096)).unwrap(),
+            )
+            .unwrap();
+        let data: Vec<u8> = (0..64 * 1024).map(|i| (i % 251) as u8).collect();
+
+        assert_eq!(lazyfs.do_write(&current, 0, 0, &data).unwrap(), 64 * 1024);
+        assert_eq!(lazyfs.do_write(&log, 0, 0, &data).unwrap(), 4096);
+
+        // Only the accepted head is cached, and only it reaches the file
+        let mut buf = vec![0; 64 * 1024];
+        assert_eq!(
+            lazyfs.do_read(&log, 0, 0, 64 * 1024, &mut buf).unwrap(),
+            4096
+        );
+        assert_eq!(buf[..4096], data[..4096]);
+        lazyfs.do_fsync(&log, false).unwrap();
+        assert_eq!(std::fs::read(&log).unwrap(), data[..4096]);
+
+        // The retry of the tail goes through untouched
+        assert_eq!(
+            lazyfs.do_write(&log, 0, 4096, &data[4096..]).unwrap(),
+            60 * 1024
+        );
+        lazyfs.do_fsync(&log, false).unwrap();
+        assert_eq!(std::fs::read(&log).unwrap(), data);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Writes alternating between a log and a WAL, returning the ops whose write was cut short
+    fn faulted_ops(lazyfs: &LazyFS) -> Vec<u64> {
+        lazyfs
+            .add_short_write_fault(
+                ShortWriteFault::new("\\.log$", 3, ShortWriteLimit::Bytes(100)).unwrap(),
+            )
+            .unwrap();
+        lazyfs
+            .add_quota_fault(QuotaFault::new("wal", 10000, QuotaMode::NoSpace).unwrap())
+            .unwrap();
+
+        let mut faulted = Vec::new();
+        for i in 0..10 {
+            let op = lazyfs.next_op();
+            let accepted = if i % 2 == 0 {
+                lazyfs.accepted_write_len(
+                    &mut OpContext::new(FsOperation::Write),
+                    Path::new("/data/1.log"),
+                    4096,
+                )
+            } else {
+                match lazyfs
+                    .charge_write(
+                        &mut OpContext::new(FsOperation::Write),
+                        Path::new("/data/wal"),
+                        "wal",
+                        4096,
+                    )
+                    .unwrap()
+                {
+                    QuotaOutcome::Accept(n) => Ok(n),
+                    QuotaOutcome::NoSpace => Ok(0),
+                }
+            };
+            if accepted.unwrap() < 4096 {
+                faulted.push(op);
+            }
+        }
+        faulted
+    }
+
+    #[test]
+    fn dry_run_matches_real_trigger_points() {
+        let real = new_lazyfs(Arc::new(ManualClock::default()), FaultSchedule::default());
+        let expected = faulted_ops(&real);
+        assert_eq!(expected, vec![5, 6, 8, 10]);
+
+        let config = config::Config {
+            dry_run: true,
+            ..Default::default()
+        };
+        let clock = Arc::new(ManualClock::default());
+        let dry = new_lazyfs_with_config(clock, FaultSchedule::default(), config);
+        assert!(faulted_ops(&dry).is_empty());
+
+        let events = dry.dry_run_events().unwrap();
+        assert_eq!(events.iter().map(|e| e.op).collect::<Vec<_>>(), expected);
+        assert_eq!(events[0].fault, "short-write-0");
+        assert_eq!(events[1].fault, "quota-0");
+        let status = dry.fault_status().unwrap();
+        let hits: Vec<_> = status
+            .iter()
+            .map(|s| (s.key.as_str(), s.dry_run_hits))
+            .collect();
+        assert_eq!(hits, vec![("wal", 0), ("quota-0", 3), ("short-write-0", 1)]);
+    }
+
+    #[test]
+    fn dry_run_without_consuming_occurences() {
+        let config = config::Config {
+            dry_run: true,
+            dry_run_consumes_occurences: false,
+            ..Default::default()
+        };
+        let lazyfs = new_lazyfs_with_config(
+            Arc::new(ManualClock::default()),
+            FaultSchedule::default(),
+            config,
+        );
+        lazyfs
+            .add_stale_read_fault(StaleReadFault::new("^/data/", 2).unwrap())
+            .unwrap();
+        let path = Path::new("/data/table");
+
+        for _ in 0..4 {
+            lazyfs.next_op();
+            assert!(!lazyfs
+                .serve_stale_read(&mut OpContext::new(FsOperation::Read), path)
+                .unwrap());
+        }
+        let ops: Vec<_> = lazyfs
+            .dry_run_events()
+            .unwrap()
+            .iter()
+            .map(|e| e.op)
+            .collect();
+        assert_eq!(ops, vec![2, 3, 4]);
+
+        lazyfs.set_dry_run(false);
+        lazyfs.next_op();
+        assert!(lazyfs
+            .serve_stale_read(&mut OpContext::new(FsOperation::Read), path)
+            .unwrap());
+    }
+
+    /// Ops of a run of fsyncs of the wal and renames onto it that a crash fault fired on
+    fn crashed_ops(lazyfs: &LazyFS) -> Vec<u64> {
+        let wal = Path::new("/data/wal");
+        for (op, timing, occurrence) in [
+            (FsOperation::Fsync, CrashTiming::After, 2),
+            (FsOperation::Rename, CrashTiming::Before, 3),
+        ] {
+            let matcher = PathMatcher::new("^/data/wal$", Default::default()).unwrap();
+            let spec = CrashFaultSpec::new(op, timing, matcher)
+                .with_mode(CrashMode::ClearCache)
+                .with_occurrence(occurrence);
+            lazyfs.add_crash_fault(spec).unwrap();
+        }
+
+        let mut crashed = Vec::new();
+        for i in 0..8 {
+            let op = lazyfs.next_op();
+            let fired = match i % 2 {
+                0 => lazyfs.crash_hook(FsOperation::Fsync, CrashTiming::After, wal, None),
+                _ => lazyfs.trigger_crash_fault(
+                    CrashTiming::Before,
+                    FsOperation::Rename,
+                    Path::new("/data/wal.tmp"),
+                    Some(wal),
+                ),
+            };
+            if fired.unwrap().is_some() {
+                crashed.push(op);
+            }
+        }
+        crashed
+    }
+
+    #[test]
+    fn dry_run_matches_real_crash_points() {
+        let real = new_lazyfs(Arc::new(ManualClock::default()), FaultSchedule::default());
+        let expected = crashed_ops(&real);
+        assert_eq!(expected, vec![3, 6]);
+
+        let dry_run = |consumes| {
+            let config = config::Config {
+                dry_run: true,
+                dry_run_consumes_occurences: consumes,
+                ..Default::default()
+            };
+            new_lazyfs_with_config(
+                Arc::new(ManualClock::default()),
+                FaultSchedule::default(),
+                config,
+            )
+        };
+        let dry = dry_run(true);
+        assert!(crashed_ops(&dry).is_empty());
+        let events = dry.dry_run_events().unwrap();
+        assert_eq!(events.iter().map(|e| e.op).collect::<Vec<_>>(), expected);
+        assert_eq!(events[0].fault, "crash-0");
+        assert_eq!(events[1].fault, "crash-1");
+        for id in [FaultId(0), FaultId(1)] {
+            let (real, dry) = (
+                real.fault_counters(id).unwrap(),
+                dry.fault_counters(id).unwrap(),
+            );
+            assert_eq!((real.matched, real.triggered), (1, 1));
+            assert_eq!((dry.matched, dry.triggered), (1, 0));
+        }
+
+        // Without consuming its occurrence, each fault goes on matching every op after it
+        let dry = dry_run(false);
+        assert!(crashed_ops(&dry).is_empty());
+        let mut ops: Vec<_> = dry.dry_run_events().unwrap().iter().map(|e| e.op).collect();
+        ops.sort_unstable();
+        assert_eq!(ops, vec![3, 5, 6, 7, 8]);
+    }
+
+    #[test]
+    fn sector_torn_write_keeps_whole_sectors() {
+        let dir =
+            std::env::temp_dir().join(format!("lazyfs-rs-{}-sector-torn", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (wal, sst) = (dir.join("wal"), dir.join("sst"));
+        for path in [&wal, &sst] {
+            std::fs::write(path, vec![b'o'; 4096]).unwrap();
+        }
+        let config = config::Config {
+            disk_sector_size: 512,
+            ..Default::default()
+        };
+        let lazyfs = new_lazyfs_with_config(
+            Arc::new(ManualClock::default()),
+            FaultSchedule::default(),
+            config,
+        );
+        for (path, torn) in [
+            (&wal, TornSectors::Fixed(2)),
+            (&sst, TornSectors::Seeded(42)),
+        ] {
+            let fault = SplitWriteFault::from_sectors(1, torn).with_mode(CrashMode::ClearCache);
+            lazyfs
+                .add_fault(&path.to_string_lossy(), Arc::new(fault))
+                .unwrap();
+        }
+
+        // A 2000 byte write at offset 300 touches sectors 0..=4. The partially covered first one
+        // counts as a whole, so two sectors keep bytes 300..1024 and the rest is dropped.
+        assert!(lazyfs.do_write(&wal, 0, 300, &[b'n'; 2000]).is_err());
+        let mut expected = vec![b'o'; 4096];
+        expected[300..1024].fill(b'n');
+        assert_eq!(std::fs::read(&wal).unwrap(), expected);
+        let mut buf = vec![0; 4096];
+        assert_eq!(lazyfs.do_read(&wal, 0, 0, 4096, &mut buf).unwrap(), 4096);
+        assert_eq!(buf, expected);
+
+        assert!(lazyfs.do_write(&sst, 0, 300, &[b'n'; 2000]).is_err());
+        let on_disk = std::fs::read(&sst).unwrap();
+        let survived = on_disk.iter().filter(|&&byte| byte == b'n').count();
+        assert!(survived < 2000);
+        assert!(survived == 0 || (300 + survived) % 512 == 0);
+        assert!(on_disk[300..300 + survived]
+            .iter()
+            .all(|&byte| byte == b'n'));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dry_run_lets_split_and_reordered_writes_through() {
+        let config = config::Config {
+            dry_run: true,
+            ..Default::default()
+        };
+        let lazyfs = new_lazyfs_with_config(
+            Arc::new(ManualClock::default()),
+            FaultSchedule::default(),
+            config,
+        );
+        let split = SplitWriteFault::from_parts(2, vec![1], 2);
+        let reorder = ReorderFault::from_op("write".to_string(), vec![1], 1);
+        lazyfs.add_fault("/data/wal", Arc::new(split)).unwrap();
+        lazyfs.add_fault("/data/wal", Arc::new(reorder)).unwrap();
+        let wal = Path::new("/data/wal");
+
+        for _ in 0..2 {
+            lazyfs.next_op();
+            let mut ctx = OpContext::new(FsOperation::Write);
+            assert!(lazyfs
+                .apply_split_write(&mut ctx, wal, b"data", 0)
+                .unwrap()
+                .is_none());
+            assert!(!lazyfs
+                .hold_reordered_write(&mut ctx, wal, b"data", 0)
+                .unwrap());
+            assert_eq!(ctx.injected, None);
+        }
+        let events: Vec<_> = lazyfs
+            .dry_run_events()
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.op, e.action))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                (1, "reordered write of 4 bytes at 0".to_string()),
+                (2, "split write persisting parts [1]".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn stale_reads_until_disarmed() {
+        let lazyfs = new_lazyfs(Arc::new(ManualClock::default()), FaultSchedule::default());
+        lazyfs
+            .add_stale_read_fault(StaleReadFault::new("^/data/", 2).unwrap())
+            .unwrap();
+        let path = Path::new("/data/table");
+
+        assert!(!lazyfs
+            .serve_stale_read(&mut OpContext::new(FsOperation::Read), path)
+            .unwrap());
+        assert!(lazyfs
+            .serve_stale_read(&mut OpContext::new(FsOperation::Read), path)
+            .unwrap());
+        assert!(lazyfs
+            .serve_stale_read(&mut OpContext::new(FsOperation::Read), path)
+            .unwrap());
+        assert!(!lazyfs
+            .serve_stale_read(&mut OpContext::new(FsOperation::Read), Path::new("/other"))
+            .unwrap());
+
+        lazyfs.disarm_stale_reads().unwrap();
+        assert!(!lazyfs
+            .serve_stale_read(&mut OpContext::new(FsOperation::Read), path)
+            .unwrap());
+    }
+
+    #[test]
+    fn external_changes_are_noticed_before_the_first_fsync() {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-external", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("table");
+        std::fs::write(&path, b"before").unwrap();
+        let config = config::Config {
+            external_change_policy: config::ExternalChangePolicy::Invalidate,
+            ..Default::default()
+        };
+        let lazyfs = new_lazyfs_with_config(
+            Arc::new(ManualClock::default()),
+            FaultSchedule::default(),
+            config,
+        );
+
+        let mut buf = vec![0; 64];
+        let read = lazyfs.do_read(&path, 7, 0, buf.len(), &mut buf).unwrap();
+        assert_eq!(&buf[..read], b"before");
+        assert_eq!(lazyfs.do_getattr(&path).unwrap().1.size, 6);
+
+        std::fs::write(&path, b"changed outside").unwrap();
+        assert_eq!(lazyfs.do_getattr(&path).unwrap().1.size, 15);
+        let read = lazyfs.do_read(&path, 7, 0, buf.len(), &mut buf).unwrap();
+        assert_eq!(&buf[..read], b"changed outside");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_through_counts_as_misses() {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-misses", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("table");
+        std::fs::write(&path, vec![1; 8192]).unwrap();
+        let lazyfs = new_lazyfs(Arc::new(ManualClock::default()), FaultSchedule::default());
+        let cache = lazyfs.cache();
+        let owner_stat = |metric| cache.top_owners(metric, 1).unwrap()[0].2;
+
+        let mut buf = vec![0; 8192];
+        assert_eq!(
+            lazyfs.do_read(&path, 7, 0, buf.len(), &mut buf).unwrap(),
+            8192
+        );
+        assert_eq!(
+            (cache.stats().unwrap().hits, cache.stats().unwrap().misses),
+            (0, 2)
+        );
+        assert_eq!(owner_stat(StatMetric::CacheMisses), 2);
+
+        // Cached on the way through
+        lazyfs.do_read(&path, 7, 0, buf.len(), &mut buf).unwrap();
+        assert_eq!(
+            (cache.stats().unwrap().hits, cache.stats().unwrap().misses),
+            (2, 2)
+        );
+        assert_eq!(owner_stat(StatMetric::CacheHits), 2);
+        assert_eq!(owner_stat(StatMetric::Reads), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stale_reads_serve_what_was_on_disk_before_the_first_fsync() {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-stale", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("table");
+        std::fs::write(&path, b"old data").unwrap();
+        let lazyfs = new_lazyfs(Arc::new(ManualClock::default()), FaultSchedule::default());
+
+        lazyfs.do_write(&path, 7, 0, b"new data, longer").unwrap();
+        // Fires from the second read on, and again after that many once disarmed
+        lazyfs
+            .add_stale_read_fault(StaleReadFault::new("/table$", 2).unwrap())
+            .unwrap();
+        let mut buf = vec![0; 64];
+        let mut read = || {
+            let read = lazyfs.do_read(&path, 7, 0, buf.len(), &mut buf).unwrap();
+            buf[..read].to_vec()
+        };
+        assert_eq!(read(), b"new data, longer");
+        assert_eq!(read(), b"old data");
+        assert_eq!(read(), b"old data");
+
+        lazyfs.disarm_stale_reads().unwrap();
+        assert_eq!(read(), b"new data, longer");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn crash_fault_registered_by_spec() {
+        let lazyfs = new_lazyfs(Arc::new(ManualClock::default()), FaultSchedule::default());
+        let matcher = PathMatcher::new("^/data/wal$", Default::default()).unwrap();
+        let spec = CrashFaultSpec::new(FsOperation::Write, CrashTiming::After, matcher)
+            .with_mode(CrashMode::ClearCache)
+            .with_occurrence(2);
+        let id = lazyfs.add_crash_fault(spec.clone()).unwrap();
+        lazyfs.cache().insert_item("1".to_string()).unwrap();
+        let wal = Path::new("/data/wal");
+
+        let hook = |timing| {
+            lazyfs
+                .crash_hook(FsOperation::Write, timing, wal, None)
+                .unwrap()
+        };
+        assert_eq!(hook(CrashTiming::Before), None);
+        assert_eq!(hook(CrashTiming::After), None);
+        assert!(lazyfs.cache().has_content_cached("1".to_string()).unwrap());
+        assert_eq!(hook(CrashTiming::After), Some(id));
+        assert!(!lazyfs.cache().has_content_cached("1".to_string()).unwrap());
+
+        let status = lazyfs.crash_fault_status(id).unwrap().unwrap();
+        assert_eq!((status.spec, status.seen), (spec, 2));
+        assert!(lazyfs.remove_crash_fault(id).unwrap());
+        assert!(lazyfs.crash_fault_status(id).unwrap().is_none());
+        assert!(!lazyfs.remove_crash_fault(id).unwrap());
+    }
+
+    #[test]
+    fn frozen_after_a_soft_crash_on_either_path() {
+        let lazyfs = new_lazyfs(Arc::new(ManualClock::default()), FaultSchedule::default());
+        assert!(lazyfs
+            .add_crash_fault_by_name(CrashTiming::Before, "mknod", "^/data/", "freeze")
+            .is_err());
+        assert!(lazyfs
+            .add_crash_fault_by_name(CrashTiming::Before, "rename", "^/data/", "pause")
+            .is_err());
+        let id = lazyfs
+            .add_crash_fault_by_name(CrashTiming::Before, "rename", "^/data/wal$", "freeze")
+            .unwrap();
+
+        // Only the first path of a write is matched
+        let (tmp, wal) = (Path::new("/data/wal.tmp"), Path::new("/data/wal"));
+        let trigger = |op, path2| {
+            lazyfs
+                .trigger_crash_fault(CrashTiming::Before, op, tmp, path2)
+                .unwrap()
+        };
+        assert_eq!(trigger(FsOperation::Write, Some(wal)), None);
+        assert!(lazyfs.begin_op(FsOperation::Write).is_ok());
+        assert_eq!(trigger(FsOperation::Rename, Some(wal)), Some(id));
+
+        assert!(lazyfs.is_frozen());
+        let err = lazyfs.begin_op(FsOperation::Write).err().unwrap();
+        let err = err.downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+    }
+
+    #[test]
+    fn zero_length_write_counts_towards_faults() {
+        let lazyfs = new_lazyfs(Arc::new(ManualClock::default()), FaultSchedule::default());
+        let matcher = PathMatcher::new("^/data/wal$", Default::default()).unwrap();
+        let spec = CrashFaultSpec::new(FsOperation::Write, CrashTiming::After, matcher)
+            .with_mode(CrashMode::ClearCache)
+            .with_occurrence(2);
+        let id = lazyfs.add_crash_fault(spec).unwrap();
+        let wal = Path::new("/data/wal");
+        let write = |data: &Vec<u8>| {
+            let blocks = HashMap::from([(0, (data, 0, data.len() as i32 - 1))]);
+            lazyfs
+                .cache()
+                .put_data_blocks(
+                    "1".to_string(),
+                    blocks,
+                    AllocateOperationType::OpWrite,
+                    None,
+                )
+                .unwrap();
+            lazyfs
+                .crash_hook(
+                    FsOperation::Write,
+                    CrashTiming::After,
+                    wal,
+                    Some((0, data.len() as u64)),
+                )
+                .unwrap()
+        };
+
+        assert_eq!(write(&Vec::new()), None);
+        assert!(lazyfs.cache().block_map("1".to_string()).is_err());
+        assert_eq!(write(&b"wal".to_vec()), Some(id));
+    }
+
+    #[test]
+    fn crash_report_written_before_the_crash() {
+        let dir =
+            std::env::temp_dir().join(format!("lazyfs-rs-{}-crash-report", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = config::Config {
+            crash_report_path: dir.join("crash.report"),
+            ..Default::default()
+        };
+        let lazyfs = new_lazyfs_with_config(
+            Arc::new(ManualClock::default()),
+            FaultSchedule::default(),
+            config.clone(),
+        );
+        let matcher = PathMatcher::new("^/data/wal$", Default::default()).unwrap();
+        let spec = CrashFaultSpec::new(FsOperation::Write, CrashTiming::After, matcher)
+            .with_mode(CrashMode::ClearCache);
+        let id = lazyfs.add_crash_fault(spec.clone()).unwrap();
+        let data = vec![7; 100];
+        lazyfs
+            .cache()
+            .put_data_blocks(
+                "1".to_string(),
+                HashMap::from([(0, (&data, 0, 99))]),
+                AllocateOperationType::OpWrite,
+                None,
+            )
+            .unwrap();
+        lazyfs.next_op();
+
+        let wal = Path::new("/data/wal");
+        assert_eq!(
+            lazyfs
+                .crash_hook(FsOperation::Write, CrashTiming::After, wal, Some((0, 100)))
+                .unwrap(),
+            Some(id)
+        );
+        let (header, report) = CrashReport::load(&config.crash_report_path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(header.version, CRASH_REPORT_VERSION);
+        assert_eq!(header.geometry_hash, geometry_hash(&config));
+        assert_eq!(
+            report,
+            CrashReport {
+                fault_id: id.0,
+                spec: spec.to_string(),
+                op: "write".to_string(),
+                timing: "after".to_string(),
+                path: "/data/wal".to_string(),
+                offset: Some(0),
+                size: Some(100),
+                op_count: 1,
+                unsynced_omitted: 0,
+                unsynced: vec![crate::crash_report::UnsyncedSummary {
+                    owner: "1".to_string(),
+                    dirty_bytes: 100,
+                }],
+            }
+        );
+    }
+
+    #[test]
+    fn sync_handles_write_through() {
+        let dir =
+            std::env::temp_dir().join(format!("lazyfs-rs-{}-sync-writes", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = config::Config {
+            sync_writes_match_fsync_faults: true,
+            ..Default::default()
+        };
+        let lazyfs = new_lazyfs_with_config(
+            Arc::new(ManualClock::default()),
+            FaultSchedule::default(),
+            config,
+        );
+        for (fh, name, flags) in [
+            (1, "plain", libc::O_WRONLY),
+            (2, "dsync", libc::O_WRONLY | libc::O_DSYNC),
+            (3, "sync", libc::O_WRONLY | libc::O_SYNC),
+        ] {
+            let path = dir.join(name);
+            std::fs::write(&path, b"").unwrap();
+            let owner: OwnerId = path.to_string_lossy().into();
+            lazyfs
+                .cache()
+                .insert_inode_mapping(path.clone(), owner.clone(), false)
+                .unwrap();
+            lazyfs.open_handle(fh, &path, &owner, flags).unwrap();
+        }
+        let matcher = PathMatcher::new("/sync$", Default::default()).unwrap();
+        let spec = CrashFaultSpec::new(FsOperation::Fsync, CrashTiming::After, matcher)
+            .with_mode(CrashMode::ClearCache);
+        let id = lazyfs.add_crash_fault(spec).unwrap();
+
+        let data = vec![5u8; 100];
+        let write = |fh| {
+            let handle = lazyfs.handle(fh).unwrap().unwrap();
+            lazyfs
+                .cache()
+                .write_blocks(
+                    handle.owner,
+                    HashMap::from([(0, (&data, 0, 99))]),
+                    Some(lazyfs.next_op()),
+                    100,
+                )
+                .unwrap();
+            lazyfs
+                .sync_written(&mut OpContext::new(FsOperation::Write), fh, 0, 100)
+                .unwrap()
+        };
+        let on_disk = |name| std::fs::read(dir.join(name)).unwrap();
+
+        assert!(!write(1));
+        assert!(on_disk("plain").is_empty());
+        assert!(write(2));
+        assert_eq!(on_disk("dsync"), data);
+        assert_eq!(
+            lazyfs.handle(2).unwrap().unwrap().durability,
+            WriteDurability::Data
+        );
+
+        // The implicit sync counts as an fsync, the fault fires after the data is out
+        assert!(write(3));
+        assert_eq!(on_disk("sync"), data);
+        assert_eq!(lazyfs.crash_fault_status(id).unwrap().unwrap().seen, 1);
+        let owner = lazyfs.handle(3).unwrap().unwrap().owner;
+        assert!(!lazyfs.cache().has_content_cached(owner).unwrap());
+
+        assert!(lazyfs.release_handle(3).unwrap().is_some());
+        assert!(!lazyfs
+            .sync_written(&mut OpContext::new(FsOperation::Write), 3, 0, 100)
+            .unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unaligned_write_merges_its_edge_blocks() {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-do-write", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("data");
+        std::fs::write(&path, vec![b'a'; 4200]).unwrap();
+        let clock = Arc::new(ManualClock::default());
+        let lazyfs = new_lazyfs(clock.clone(), FaultSchedule::default());
+
+        // Spans the end of block 0 and most of block 1, past the end of the file, with no
+        // handle open so the owner is looked up from the path
+        let data = vec![b'b'; 5000];
+        assert_eq!(lazyfs.do_write(&path, 7, 100, &data).unwrap(), 5000);
+        let mut expected = vec![b'a'; 100];
+        expected.extend_from_slice(&data);
+
+        let cache = lazyfs.cache();
+        let owner = lazyfs.owner_of(&path).unwrap();
+        let metadata = cache.get_content_metadata(owner.clone()).unwrap().unwrap();
+        assert_eq!(metadata.size, 5100);
+        assert_eq!(metadata.mtim, clock.now());
+        let blocks: Vec<_> = cache
+            .block_map(owner.clone())
+            .unwrap()
+            .into_iter()
+            .map(|(block, _, offsets, op_id)| (block, offsets, op_id))
+            .collect();
+        assert_eq!(blocks, [(0, (0, 4095), Some(1)), (1, (0, 1003), Some(1))]);
+        let mut cached = vec![0; 8192];
+        assert_eq!(cache.read_at(owner.clone(), 0, &mut cached).unwrap(), 5100);
+        assert_eq!(&cached[..5100], &expected[..]);
+
+        assert_eq!(std::fs::read(&path).unwrap(), vec![b'a'; 4200]);
+        cache.sync_owner(owner, false, path.clone()).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), expected);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_through_fills_the_blocks_it_missed() {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-do-read", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("data");
+        let mut expected: Vec<u8> = (0..4).flat_map(|block| vec![b'a' + block; 4096]).collect();
+        expected.truncate(3 * 4096 + 500);
+        std::fs::write(&path, &expected).unwrap();
+        let lazyfs = new_lazyfs(Arc::new(ManualClock::default()), FaultSchedule::default());
+
+        // Blocks 1 and 3 cached, the latter grown past the backing file, 0 and 2 only on disk
+        let owner = lazyfs.owner_of(&path).unwrap();
+        lazyfs.do_write(&path, 7, 4096, &[b'x'; 4096]).unwrap();
+        lazyfs
+            .do_write(&path, 7, 3 * 4096 + 600, &[b'y'; 10])
+            .unwrap();
+        expected[4096..8192].fill(b'x');
+        expected.resize(3 * 4096 + 600, 0);
+        expected.extend_from_slice(&[b'y'; 10]);
+        let cache = lazyfs.cache();
+        let cached = |block_id| cache.is_block_cached(owner.clone(), block_id).unwrap();
+        assert_eq!(
+            [cached(0), cached(1), cached(2), cached(3)],
+            [false, true, false, true]
+        );
+
+        let mut buf = vec![0; 4 * 4096];
+        let read = lazyfs.do_read(&path, 7, 100, buf.len(), &mut buf).unwrap();
+        assert_eq!(read, expected.len() - 100);
+        assert_eq!(&buf[..read], &expected[100..]);
+        assert_eq!([cached(0), cached(1), cached(2), cached(3)], [true; 4]);
+
+        // Served from the cache now, short at the cached size
+        std::fs::write(&path, b"").unwrap();
+        let read = lazyfs.do_read(&path, 7, 12800, 100, &mut buf).unwrap();
+        assert_eq!(&buf[..read], &expected[12800..]);
+        let read = lazyfs.do_read(&path, 7, 0, 4096, &mut buf).unwrap();
+        assert_eq!(&buf[..read], &expected[..4096]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn shutdown_syncs_what_was_never_fsynced() {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-shutdown", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = config::Config {
+            fifo_path: dir.join("faults.fifo"),
+            fifo_path_completed: dir.join("completed.fifo"),
+            fault_state_path: dir.join("faults.state"),
+            ..Default::default()
+        };
+        let lazyfs = new_lazyfs_with_config(
+            Arc::new(ManualClock::default()),
+            FaultSchedule::default(),
+            config.clone(),
+        );
+        lazyfs.start_command_listener().unwrap();
+        let (wal, other) = (dir.join("wal"), dir.join("other"));
+        std::fs::write(&wal, b"").unwrap();
+        std::fs::write(&other, b"").unwrap();
+        lazyfs.do_write(&wal, 7, 0, &[1; 100]).unwrap();
+        lazyfs.do_write(&other, 8, 0, &[2; 5000]).unwrap();
+        assert!(std::fs::read(&wal).unwrap().is_empty());
+
+        lazyfs.shutdown(true).unwrap();
+        assert_eq!(std::fs::read(&wal).unwrap(), vec![1; 100]);
+        assert_eq!(std::fs::read(&other).unwrap(), vec![2; 5000]);
+        assert!(!config.fifo_path.exists());
+        assert!(config.fault_state_path.exists());
+
+        // Only the first call counts
+        lazyfs.do_write(&wal, 7, 0, &[3; 100]).unwrap();
+        lazyfs.shutdown(true).unwrap();
+        assert_eq!(std::fs::read(&wal).unwrap(), vec![1; 100]);
+
+        // Without flushing, unsynced data is lost like in a crash
+        let lazyfs = new_lazyfs(Arc::new(ManualClock::default()), FaultSchedule::default());
+        lazyfs.do_write(&wal, 7, 0, &[4; 100]).unwrap();
+        lazyfs.shutdown(false).unwrap();
+        assert_eq!(std::fs::read(&wal).unwrap(), vec![1; 100]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn crash_after_fsync_sees_the_data_on_disk() {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-do-fsync", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = config::Config {
+            crash_report_path: dir.join("crash.report"),
+            ..Default::default()
+        };
+        let lazyfs = new_lazyfs_with_config(
+            Arc::new(ManualClock::default()),
+            FaultSchedule::default(),
+            config.clone(),
+        );
+        let (wal, plain) = (dir.join("wal"), dir.join("plain"));
+        std::fs::write(&wal, b"").unwrap();
+        std::fs::write(&plain, b"untouched").unwrap();
+        let id = lazyfs
+            .add_crash_fault_by_name(CrashTiming::After, "fsync", "/wal$", "freeze")
+            .unwrap();
+
+        // Never cached, synced on the backing file without tripping the fault
+        lazyfs.do_fsync(&plain, true).unwrap();
+        assert!(!lazyfs.is_frozen());
+
+        lazyfs.do_write(&wal, 7, 0, &[3; 100]).unwrap();
+        assert!(std::fs::read(&wal).unwrap().is_empty());
+        lazyfs.do_fsync(&wal, false).unwrap();
+        assert!(lazyfs.is_frozen());
+        assert_eq!(std::fs::read(&wal).unwrap(), vec![3; 100]);
+
+        // Nothing was left unsynced when the fault fired
+        let (_, report) = CrashReport::load(&config.crash_report_path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!((report.fault_id, report.timing.as_str()), (id.0, "after"));
+        assert!(report.unsynced.is_empty());
+    }
+
+    #[test]
+    fn open_flags_pick_the_cache_policy() {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-policy", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let lazyfs = new_lazyfs(Arc::new(ManualClock::default()), FaultSchedule::default());
+        let open = |fh, name: &str, flags| {
+            let path = dir.join(name);
+            if !path.exists() {
+                std::fs::write(&path, b"").unwrap();
+            }
+            let owner = lazyfs.owner_of(&path).unwrap();
+            lazyfs.open_handle(fh, &path, &owner, flags).unwrap();
+            path
+        };
+        let on_disk = |path: &Path| std::fs::read(path).unwrap();
+
+        // Written back on fsync only, unless the handle syncs every write
+        let plain = open(1, "plain", libc::O_WRONLY);
+        let sync = open(2, "sync", libc::O_WRONLY | libc::O_SYNC);
+        lazyfs.do_write(&plain, 1, 0, &[1; 100]).unwrap();
+        lazyfs.do_write(&sync, 2, 0, &[2; 100]).unwrap();
+        assert!(on_disk(&plain).is_empty());
+        assert_eq!(on_disk(&sync), vec![2; 100]);
+        lazyfs.do_fsync(&plain, false).unwrap();
+        assert_eq!(on_disk(&plain), vec![1; 100]);
+
+        // An O_DIRECT handle on the same file takes the other one past the cache too, until
+        // it is released
+        lazyfs.do_write(&plain, 1, 0, &[3; 10]).unwrap();
+        open(3, "plain", libc::O_RDWR | libc::O_DIRECT);
+        assert_eq!(on_disk(&plain)[..10], [3; 10]);
+        assert_eq!(lazyfs.cache_policy(1).unwrap(), CachePolicy::Bypass);
+        lazyfs.do_write(&plain, 1, 100, &[4; 10]).unwrap();
+        assert_eq!(on_disk(&plain).len(), 110);
+        std::fs::write(&plain, [5; 20]).unwrap();
+        let mut buf = [0; 50];
+        assert_eq!(lazyfs.do_read(&plain, 3, 0, 50, &mut buf).unwrap(), 20);
+        assert_eq!(buf[..20], [5; 20]);
+        lazyfs.release_handle(3).unwrap();
+        assert_eq!(lazyfs.cache_policy(1).unwrap(), CachePolicy::Cached);
+
+        // Appended at the cached size, ahead of the backing file
+        let log = open(4, "log", libc::O_WRONLY | libc::O_APPEND);
+        lazyfs.do_write(&log, 4, 0, b"abc").unwrap();
+        lazyfs.do_write(&log, 4, 0, b"def").unwrap();
+        assert!(on_disk(&log).is_empty());
+        assert_eq!(lazyfs.do_read(&log, 4, 0, 50, &mut buf).unwrap(), 6);
+        assert_eq!(&buf[..6], b"abcdef");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bypassed_writes_stamp_the_skewed_clock() {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-bypass", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("data");
+        std::fs::write(&path, b"").unwrap();
+        let clock = Arc::new(ManualClock::default());
+        clock.advance(Duration::from_secs(3600));
+        let lazyfs = new_lazyfs(clock.clone(), FaultSchedule::default());
+        lazyfs.cache().set_clock_skew("-90s".parse().unwrap());
+
+        let owner = lazyfs.owner_of(&path).unwrap();
+        lazyfs
+            .open_handle(1, &path, &owner, libc::O_WRONLY | libc::O_DIRECT)
+            .unwrap();
+        lazyfs.do_write(&path, 1, 0, &[1; 100]).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), vec![1; 100]);
+        let metadata = lazyfs.cache().get_content_metadata(owner).unwrap().unwrap();
+        assert_eq!(metadata.mtim, clock.now() - Duration::from_secs(90));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn unlink_drops_the_last_link_only() {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-unlink", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let lazyfs = new_lazyfs(Arc::new(ManualClock::default()), FaultSchedule::default());
+        let cache = lazyfs.cache();
+
+        // Never synced, its pages are freed along with it
+        let tmp = dir.join("tmp");
+        std::fs::write(&tmp, b"").unwrap();
+        lazyfs.do_write(&tmp, 7, 0, &[1; 8192]).unwrap();
+        let owner = lazyfs.owner_of(&tmp).unwrap();
+        assert!(cache.get_cache_usage().unwrap() > 0.0);
+        lazyfs.do_unlink(&tmp).unwrap();
+        assert!(!tmp.exists());
+        assert!(!cache.has_content_cached(owner).unwrap());
+        assert_eq!(cache.get_cache_usage().unwrap(), 0.0);
+
+        // The data written through one link is synced through the other
+        let (wal, link) = (dir.join("wal"), dir.join("wal.link"));
+        std::fs::write(&wal, b"").unwrap();
+        std::fs::hard_link(&wal, &link).unwrap();
+        lazyfs.do_write(&wal, 7, 0, &[2; 100]).unwrap();
+        let owner = lazyfs.owner_of(&wal).unwrap();
+        lazyfs.do_unlink(&wal).unwrap();
+        let metadata = cache.get_content_metadata(owner.clone()).unwrap().unwrap();
+        assert_eq!((metadata.nlinks, metadata.size), (1, 100));
+        assert_eq!(lazyfs.owner_of(&link).unwrap(), owner);
+        assert!(std::fs::read(&link).unwrap().is_empty());
+        lazyfs.do_fsync(&link, false).unwrap();
+        assert_eq!(std::fs::read(&link).unwrap(), vec![2; 100]);
+
+        lazyfs.do_unlink(&link).unwrap();
+        assert!(!cache.has_content_cached(owner).unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rename_over_a_dirty_file_discards_it() {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-do-rename", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = config::Config {
+            dirent_durability: config::DirentDurability::Strict,
+            ..Default::default()
+        };
+        let lazyfs = new_lazyfs_with_config(
+            Arc::new(ManualClock::default()),
+            FaultSchedule::default(),
+            config,
+        );
+        let cache = lazyfs.cache();
+        let (tmp, wal) = (dir.join("wal.tmp"), dir.join("wal"));
+        for (path, byte) in [(&tmp, 1), (&wal, 2)] {
+            std::fs::write(path, b"").unwrap();
+            lazyfs.do_write(path, 7, 0, &[byte; 100]).unwrap();
+        }
+        let (tmp_owner, wal_owner) = (
+            lazyfs.owner_of(&tmp).unwrap(),
+            lazyfs.owner_of(&wal).unwrap(),
+        );
+        lazyfs
+            .open_handle(1, &tmp, &tmp_owner, libc::O_WRONLY)
+            .unwrap();
+
+        let err = lazyfs
+            .do_rename(&tmp, &wal, libc::RENAME_NOREPLACE)
+            .unwrap_err();
+        let err = err.downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EEXIST));
+
+        // The old wal goes without its data ever reaching the disk
+        lazyfs.do_rename(&tmp, &wal, 0).unwrap();
+        assert!(!tmp.exists());
+        assert!(!cache.has_content_cached(wal_owner).unwrap());
+        assert_eq!(
+            cache.get_original_inode(wal.clone()).unwrap(),
+            Some(tmp_owner)
+        );
+        assert_eq!(lazyfs.handle(1).unwrap().unwrap().path, wal);
+        assert!(std::fs::read(&wal).unwrap().is_empty());
+        lazyfs.do_fsync(&wal, false).unwrap();
+        assert_eq!(std::fs::read(&wal).unwrap(), vec![1; 100]);
+
+        // Swapped along with their unsynced data, and matched by the destination
+        let other = dir.join("other");
+        std::fs::write(&other, b"").unwrap();
+        lazyfs.do_write(&other, 7, 0, &[3; 50]).unwrap();
+        lazyfs.do_write(&wal, 7, 0, &[4; 10]).unwrap();
+        lazyfs
+            .add_crash_fault_by_name(CrashTiming::After, "rename", "/other$", "freeze")
+            .unwrap();
+        lazyfs
+            .do_rename(&wal, &other, libc::RENAME_EXCHANGE)
+            .unwrap();
+        assert!(lazyfs.is_frozen());
+        let mut buf = [0; 200];
+        let n = cache
+            .read_at(lazyfs.owner_of(&wal).unwrap(), 0, &mut buf)
+            .unwrap();
+        assert_eq!(buf[..n], [3; 50]);
+        let n = cache
+            .read_at(lazyfs.owner_of(&other).unwrap(), 0, &mut buf)
+            .unwrap();
+        assert_eq!(buf[..10], [4; 10]);
+        assert_eq!(buf[10..n], [1; 90]);
+        assert_eq!(
+            cache.pending_dirents().unwrap().last(),
+            Some(&DirentChange::Exchange {
+                first: wal.clone(),
+                second: other.clone()
+            })
+        );
+
+        // The directory was never fsynced, so a crash swaps them back and undoes the rename,
+        // while the wal renamed over stays lost
+        cache.clear_cache().unwrap();
+        assert_eq!(std::fs::read(&tmp).unwrap(), vec![1; 100]);
+        assert!(std::fs::read(&other).unwrap().is_empty());
+        assert!(!wal.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hard_links_share_their_cached_content() {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-do-link", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let lazyfs = new_lazyfs(Arc::new(ManualClock::default()), FaultSchedule::default());
+        let cache = lazyfs.cache();
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        std::fs::write(&a, b"").unwrap();
+        lazyfs.do_write(&a, 7, 0, &[1; 100]).unwrap();
+        lazyfs.do_link(&a, &b).unwrap();
+
+        let owner = lazyfs.owner_of(&a).unwrap();
+        assert_eq!(lazyfs.owner_of(&b).unwrap(), owner);
+        let mut paths = cache.find_files_mapped_to_inode(owner.clone()).unwrap();
+        paths.sort();
+        assert_eq!(paths, [a.clone(), b.clone()]);
+        let metadata = cache.get_content_metadata(owner.clone()).unwrap().unwrap();
+        assert_eq!(metadata.nlinks, 2);
+
+        // Unsynced writes through either name are read through the other
+        let mut buf = [0; 200];
+        assert_eq!(lazyfs.do_read(&b, 7, 0, 200, &mut buf).unwrap(), 100);
+        assert_eq!(buf[..100], [1; 100]);
+        lazyfs.do_write(&b, 7, 100, &[2; 20]).unwrap();
+        assert_eq!(lazyfs.do_read(&a, 7, 100, 200, &mut buf).unwrap(), 20);
+        assert_eq!(buf[..20], [2; 20]);
+        assert!(std::fs::read(&a).unwrap().is_empty());
+
+        // A symlink is cached as a file of its own, with no content, and its fault matches
+        // the target as well
+        let link = dir.join("link");
+        let id = lazyfs
+            .add_crash_fault_by_name(CrashTiming::After, "symlink", "/a$", "freeze")
+            .unwrap();
+        lazyfs.do_symlink(&a, &link).unwrap();
+        assert_eq!(lazyfs.crash_fault_status(id).unwrap().unwrap().seen, 1);
+        let link_owner = cache.get_original_inode(link.clone()).unwrap().unwrap();
+        assert_ne!(link_owner, owner);
+        let metadata = cache
+            .get_content_metadata(link_owner.clone())
+            .unwrap()
+            .unwrap();
+        assert_eq!(metadata.mode & libc::S_IFMT, libc::S_IFLNK);
+        assert!(cache.block_map(link_owner).unwrap().is_empty());
+        assert_eq!(std::fs::read_link(&link).unwrap(), a);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn getattr_sees_unsynced_writes() {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-getattr", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let lazyfs = new_lazyfs(Arc::new(ManualClock::default()), FaultSchedule::default());
+        let path = dir.join("new");
+        std::fs::write(&path, b"").unwrap();
+        let (ino, metadata) = lazyfs.do_getattr(&path).unwrap();
+        assert_eq!(
+            (ino, metadata.size),
+            (std::fs::metadata(&path).unwrap().ino(), 0)
+        );
+
+        // Grows with the furthest write, not the last one
+        lazyfs.do_write(&path, 7, 4096, &[1; 6144]).unwrap();
+        lazyfs.do_write(&path, 7, 0, &[2; 4096]).unwrap();
+        let (_, metadata) = lazyfs.do_getattr(&path).unwrap();
+        assert_eq!(metadata.size, 10240);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+        assert_eq!(
+            (metadata.nlinks, metadata.mode & libc::S_IFMT),
+            (1, libc::S_IFREG)
+        );
+
+        lazyfs.do_fsync(&path, false).unwrap();
+        let (_, synced) = lazyfs.do_getattr(&path).unwrap();
+        assert_eq!((synced.size, synced.mtim), (10240, metadata.mtim));
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 10240);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn statfs_reports_the_free_cache_pages() {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-statfs", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("data");
+        std::fs::write(&path, b"").unwrap();
+        let passthrough = new_lazyfs(Arc::new(ManualClock::default()), FaultSchedule::default());
+        let backing = passthrough.do_statfs(&dir).unwrap();
+        assert_eq!(backing.bsize, 4096);
+        assert!(backing.blocks > 0 && backing.namelen > 0);
+
+        let config = config::Config {
+            cache_nr_pages: 8,
+            statfs_reports_cache: true,
+            ..Default::default()
+        };
+        let lazyfs = new_lazyfs_with_config(
+            Arc::new(ManualClock::default()),
+            FaultSchedule::default(),
+            config,
+        );
+        let statfs = lazyfs.do_statfs(&dir).unwrap();
+        assert_eq!((statfs.blocks, statfs.bfree, statfs.bavail), (8, 8, 8));
+        assert_eq!(statfs.namelen, backing.namelen);
+
+        lazyfs.do_write(&path, 7, 0, &[1; 4 * 4096]).unwrap();
+        let statfs = lazyfs.do_statfs(&dir).unwrap();
+        assert_eq!(
+            (statfs.bfree, statfs.bavail),
+            (statfs.blocks / 2, statfs.blocks / 2)
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn setattr_survives_later_writes_through_sync() {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-setattr", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let lazyfs = new_lazyfs(Arc::new(ManualClock::default()), FaultSchedule::default());
+        let path = dir.join("data");
+        std::fs::write(&path, b"").unwrap();
+        lazyfs.do_write(&path, 7, 0, &[1; 100]).unwrap();
+
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let changes = SetattrChanges {
+            size: Some(50),
+            mtime: Some(SetTime::At(mtime)),
+            mode: Some(0o600),
+            ..Default::default()
+        };
+        lazyfs.do_setattr(&path, changes).unwrap();
+        let backing = std::fs::metadata(&path).unwrap();
+        assert_eq!(backing.modified().unwrap(), mtime);
+        assert_eq!(backing.permissions().mode() & 0o7777, 0o600);
+        let (_, metadata) = lazyfs.do_getattr(&path).unwrap();
+        assert_eq!((metadata.size, metadata.mtim), (50, mtime));
+        assert_eq!(metadata.mode, libc::S_IFREG | 0o600);
+
+        // The sync writes the data out, then puts the times set back on the backing file
+        lazyfs.do_fsync(&path, false).unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), vec![1; 50]);
+        assert_eq!(std::fs::metadata(&path).unwrap().modified().unwrap(), mtime);
+
+        // Left alone when omitted
+        let atime = SystemTime::UNIX_EPOCH + Duration::from_secs(2_000_000);
+        let changes = SetattrChanges {
+            atime: Some(SetTime::At(atime)),
+            ..Default::default()
+        };
+        lazyfs.do_setattr(&path, changes).unwrap();
+        let (_, metadata) = lazyfs.do_getattr(&path).unwrap();
+        assert_eq!((metadata.atim, metadata.mtim), (atime, mtime));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn setattr_stamps_the_skewed_clock() {
+        let dir =
+            std::env::temp_dir().join(format!("lazyfs-rs-{}-setattr-skew", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let clock = Arc::new(ManualClock::default());
+        clock.advance(Duration::from_secs(1_000_000));
+        let lazyfs = new_lazyfs(clock.clone(), FaultSchedule::default());
+        lazyfs.cache().set_clock_skew("-1h".parse().unwrap());
+        let path = dir.join("data");
+        std::fs::write(&path, b"").unwrap();
+        lazyfs.do_write(&path, 7, 0, &[1; 100]).unwrap();
+
+        let changes = SetattrChanges {
+            mtime: Some(SetTime::Now),
+            mode: Some(0o600),
+            ..Default::default()
+        };
+        lazyfs.do_setattr(&path, changes).unwrap();
+        let skewed = clock.now() - Duration::from_secs(3600);
+        let (_, metadata) = lazyfs.do_getattr(&path).unwrap();
+        assert_eq!((metadata.mtim, metadata.ctim), (skewed, skewed));
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().modified().unwrap(),
+            skewed
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn setattr_times_are_no_external_change() {
+        let dir =
+            std::env::temp_dir().join(format!("lazyfs-rs-{}-setattr-external", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("data");
+        std::fs::write(&path, [1; 100]).unwrap();
+        let config = config::Config {
+            external_change_policy: config::ExternalChangePolicy::Invalidate,
+            ..Default::default()
+        };
+        let lazyfs = new_lazyfs_with_config(
+            Arc::new(ManualClock::default()),
+            FaultSchedule::default(),
+            config,
+        );
+        let mut buf = vec![0; 100];
+        lazyfs.do_read(&path, 7, 0, buf.len(), &mut buf).unwrap();
+        let owner = lazyfs.owner_of(&path).unwrap();
+        assert_eq!(lazyfs.cache().block_map(owner.clone()).unwrap().len(), 1);
+
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let changes = SetattrChanges {
+            mtime: Some(SetTime::At(mtime)),
+            ..Default::default()
+        };
+        lazyfs.do_setattr(&path, changes).unwrap();
+        lazyfs.do_getattr(&path).unwrap();
+        assert!(!lazyfs
+            .cache()
+            .check_external_change(owner.clone(), path.clone())
+            .unwrap());
+        assert_eq!(lazyfs.cache().block_map(owner).unwrap().len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn setattr_is_fenced_limited_and_frozen_like_a_write() {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-setattr-op", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("data");
+        std::fs::write(&path, [1; 100]).unwrap();
+        let config = config::Config {
+            fence_mode: crate::fence::FenceMode::Eagain,
+            ..Default::default()
+        };
+        let lazyfs = new_lazyfs_with_config(
+            Arc::new(ManualClock::default()),
+            FaultSchedule::default(),
+            config,
+        );
+        let errno = |e: anyhow::Error| errno_of(&e);
+        let chmod = SetattrChanges {
+            mode: Some(0o600),
+            ..Default::default()
+        };
+
+        lazyfs.fence_writes().unwrap();
+        assert_eq!(
+            errno(lazyfs.do_setattr(&path, chmod.clone()).unwrap_err()),
+            libc::EAGAIN
+        );
+        lazyfs.unfence_writes().unwrap();
+        lazyfs.do_setattr(&path, chmod.clone()).unwrap();
+        let admitted = |op| {
+            lazyfs
+                .queue_wait_stats()
+                .unwrap()
+                .into_iter()
+                .find(|(found, _)| *found == op)
+                .map_or(0, |(_, stats)| stats.admitted)
+        };
+        assert_eq!(admitted(FsOperation::Setattr), 2);
+
+        // A new size is cut under the setattr permit, without taking one for the truncate
+        let shrink = SetattrChanges {
+            size: Some(10),
+            ..Default::default()
+        };
+        lazyfs.do_setattr(&path, shrink.clone()).unwrap();
+        assert_eq!(admitted(FsOperation::Setattr), 3);
+        assert_eq!(admitted(FsOperation::Truncate), 0);
+
+        lazyfs
+            .add_crash_fault_by_name(CrashTiming::Before, "setattr", "/data$", "freeze")
+            .unwrap();
+        lazyfs.do_setattr(&path, chmod).unwrap();
+        assert!(lazyfs.is_frozen());
+        let chmod = SetattrChanges {
+            mode: Some(0o644),
+            ..Default::default()
+        };
+        assert_eq!(
+            errno(lazyfs.do_setattr(&path, chmod).unwrap_err()),
+            libc::EIO
+        );
+        assert_eq!(
+            errno(lazyfs.do_setattr(&path, shrink).unwrap_err()),
+            libc::EIO
+        );
+        assert_eq!(std::fs::metadata(&path).unwrap().mode() & 0o7777, 0o600);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Renames `wal.tmp` over `wal` in a fresh directory with `tear` armed on the second rename
+    /// to `wal`, returning the directory after the crash
+    fn torn_rename(tear: RenameTear, name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        let lazyfs = new_lazyfs(Arc::new(ManualClock::default()), FaultSchedule::default());
+        let fault = RenameTearFault::new("/wal$", 2, tear)
+            .unwrap()
+            .with_mode(CrashMode::ClearCache);
+        lazyfs.add_rename_tear_fault(fault).unwrap();
+
+        let (tmp, wal) = (dir.join("wal.tmp"), dir.join("wal"));
+        std::fs::write(&tmp, "old").unwrap();
+        assert_eq!(
+            lazyfs
+                .rename_backing(&mut OpContext::new(FsOperation::Rename), &tmp, &wal)
+                .unwrap(),
+            None
+        );
+        assert!(!tmp.exists());
+
+        std::fs::write(&tmp, "new").unwrap();
+        lazyfs
+            .cache()
+            .insert_inode_mapping(wal.clone(), "wal".to_string(), false)
+            .unwrap();
+        assert_eq!(
+            lazyfs
+                .rename_backing(&mut OpContext::new(FsOperation::Rename), &tmp, &wal)
+                .unwrap(),
+            Some(tear)
+        );
+        // The crash dropped the cache
+        assert_eq!(
+            lazyfs.cache().get_original_inode(wal.clone()).unwrap(),
+            None
+        );
+        dir
+    }
+
+    #[test]
+    fn torn_rename_keeps_both_names() {
+        let dir = torn_rename(RenameTear::DestinationOnly, "rename-tear-destination");
+        assert_eq!(std::fs::read(dir.join("wal.tmp")).unwrap(), b"new");
+        assert_eq!(std::fs::read(dir.join("wal")).unwrap(), b"new");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn torn_rename_loses_the_file() {
+        let dir = torn_rename(RenameTear::SourceOnly, "rename-tear-source");
+        assert!(!dir.join("wal.tmp").exists());
+        // The destination never got the new file
+        assert_eq!(std::fs::read(dir.join("wal")).unwrap(), b"old");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn read_latency_advances_clock() {
+        let clock = Arc::new(ManualClock::default());
+        let config = config::Config {
+            latency: config::LatencyConfig {
+                hit_latency: Duration::from_micros(20),
+                miss_latency: Duration::from_millis(3),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let lazyfs = new_lazyfs_with_config(clock.clone(), FaultSchedule::default(), config);
+        let start = clock.now();
+
+        lazyfs.apply_read_latency(Path::new("/data/wal"), 4, 0);
+        assert_eq!(clock.now(), start + Duration::from_micros(80));
+        lazyfs.apply_read_latency(Path::new("/data/wal"), 0, 2);
+        assert_eq!(clock.now(), start + Duration::from_micros(6080));
+    }
+
+    #[test]
+    fn ops_tagged_with_the_delay_fault() {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-tagged", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (wal, sst) = (dir.join("wal"), dir.join("sst"));
+        std::fs::write(&wal, b"").unwrap();
+        std::fs::write(&sst, vec![1; 4096]).unwrap();
+        let clock = Arc::new(ManualClock::default());
+        let lazyfs = new_lazyfs(clock.clone(), FaultSchedule::default());
+        let fault = DelayFault::new("read", Duration::from_millis(2));
+        lazyfs
+            .add_fault(&wal.to_string_lossy(), Arc::new(fault))
+            .unwrap();
+
+        let mut buf = vec![0; 100];
+        for i in 0..3 {
+            lazyfs.do_write(&wal, 0, i * 100, &[i as u8; 100]).unwrap();
+            lazyfs.do_read(&wal, 0, i * 100, 100, &mut buf).unwrap();
+        }
+        let start = clock.now();
+        lazyfs.do_read(&sst, 0, 0, 100, &mut buf).unwrap();
+        lazyfs.do_read(&sst, 0, 100, 100, &mut buf).unwrap();
+        assert_eq!(clock.now(), start);
+
+        let status = lazyfs.fault_status().unwrap();
+        let id = status[0].id;
+        assert_eq!(
+            status[0].counters,
+            FaultCounters {
+                evaluated: 3,
+                matched: 3,
+                triggered: 3,
+                affected_ops: 3,
+            }
+        );
+        let tagged: Vec<_> = lazyfs
+            .op_log()
+            .unwrap()
+            .into_iter()
+            .map(|record| (record.kind, record.path == wal, record.injected))
+            .collect();
+        assert_eq!(
+            tagged,
+            [
+                (FsOperation::Write, true, None),
+                (FsOperation::Read, true, Some(id)),
+                (FsOperation::Write, true, None),
+                (FsOperation::Read, true, Some(id)),
+                (FsOperation::Write, true, None),
+                (FsOperation::Read, true, Some(id)),
+                (FsOperation::Read, false, None),
+                (FsOperation::Read, false, None),
+            ]
+        );
+
+        // The delayed reads took 2ms, the others no time on the manual clock
+        let latency = lazyfs.op_latency().unwrap();
+        let (op, reads) = &latency[0];
+        assert_eq!(*op, FsOperation::Read);
+        assert_eq!(reads.injected[11], 3);
+        assert_eq!(reads.clean[0], 2);
+        assert_eq!(reads.injected.iter().sum::<u64>(), 3);
+        let (op, writes) = &latency[1];
+        assert_eq!(*op, FsOperation::Write);
+        assert_eq!(writes.clean[0], 3);
+        assert!(writes.injected.iter().all(|&ops| ops == 0));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fault_state_survives_remount() {
+        let dir =
+            std::env::temp_dir().join(format!("lazyfs-rs-{}-fault-state", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = config::Config {
+            fault_state_path: dir.join("faults.state"),
+            resume_faults: true,
+            ..Default::default()
+        };
+        let mount = |occurence| {
+            let fault: Arc<dyn config::Fault> =
+                Arc::new(SplitWriteFault::from_parts(occurence, vec![1], 2));
+            LazyFS::builder()
+                .config(config.clone())
+                .faults(HashMap::from([("wal".to_string(), vec![fault])]))
+                .clock(Arc::new(ManualClock::default()))
+                .build()
+        };
+        let counter = |lazyfs: &LazyFS| lazyfs.faults.lock().unwrap()["wal"][0].save_state();
+
+        let first = mount(3).unwrap();
+        first.next_op();
+        first.faults.lock().unwrap()["wal"][0].count_op();
+        first.shutdown(false).unwrap();
+
+        // Resumed by the builder, before any op of the new mount
+        let second = mount(3).unwrap();
+        assert_eq!(second.op_count(), 1);
+        assert_eq!(counter(&second).counters, [1]);
+        drop(second);
+
+        assert!(mount(4).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fault_state_saved_periodically() {
+        let dir =
+            std::env::temp_dir().join(format!("lazyfs-rs-{}-fault-saver", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = config::Config {
+            fault_state_path: dir.join("faults.state"),
+            fault_state_save_interval_ms: 10,
+            ..Default::default()
+        };
+        let lazyfs = new_lazyfs_with_config(
+            Arc::new(ManualClock::default()),
+            FaultSchedule::default(),
+            config.clone(),
+        );
+        lazyfs.next_op();
+
+        let saved = (0..500).find_map(|_| {
+            std::thread::sleep(Duration::from_millis(10));
+            FaultStateFile::load(&config.fault_state_path)
+                .unwrap()
+                .filter(|file| file.op_count == 1)
+        });
+        assert!(saved.is_some());
+        drop(lazyfs);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn mmap_denied_by_default() {
+        let lazyfs = new_lazyfs(Arc::new(ManualClock::default()), FaultSchedule::default());
+        let err = lazyfs
+            .on_mmap(Path::new("/data/db"), &"db".into())
+            .unwrap_err();
+        let err = err.downcast::<std::io::Error>().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::EOPNOTSUPP));
+
+        let config = config::Config {
+            deny_mmap: false,
+            ..Default::default()
+        };
+        let lazyfs = new_lazyfs_with_config(
+            Arc::new(ManualClock::default()),
+            FaultSchedule::default(),
+            config,
+        );
+        assert!(lazyfs.on_mmap(Path::new("/data/db"), &"db".into()).is_ok());
+    }
+
+    #[test]
+    fn fsyncdir_makes_the_names_in_it_durable() {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-fsyncdir", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = config::Config {
+            dirent_durability: config::DirentDurability::Strict,
+            ..Default::default()
+        };
+        let lazyfs = new_lazyfs_with_config(
+            Arc::new(ManualClock::default()),
+            FaultSchedule::default(),
+            config,
+        );
+        let sub = dir.join("sub");
+        lazyfs.do_mkdir(&sub, 0o755).unwrap();
+        let link = sub.join("current");
+        lazyfs.do_symlink(Path::new("wal.1"), &link).unwrap();
+        assert_eq!(lazyfs.cache().pending_dirents().unwrap().len(), 2);
+
+        // The link is in `sub`, `sub` itself in `dir`
+        lazyfs.do_fsyncdir(&sub).unwrap();
+        assert_eq!(
+            lazyfs.cache().pending_dirents().unwrap(),
+            [DirentChange::Mkdir { path: sub.clone() }]
+        );
+        lazyfs.do_fsyncdir(&dir).unwrap();
+        assert!(lazyfs.cache().pending_dirents().unwrap().is_empty());
+
+        let err = lazyfs.do_rmdir(&sub).unwrap_err();
+        let err = err.downcast_ref::<std::io::Error>().unwrap();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOTEMPTY));
+        lazyfs.do_unlink(&link).unwrap();
+        lazyfs.do_rmdir(&sub).unwrap();
+        assert!(!sub.exists());
+
+        // Back until `dir` is fsynced
+        lazyfs.cache().clear_cache().unwrap();
+        assert!(sub.is_dir());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn mkdir_without_fsyncdir_is_rolled_back_in_strict_mode() {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-mkdir", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = config::Config {
+            dirent_durability: config::DirentDurability::Strict,
+            ..Default::default()
+        };
+        let lazyfs = new_lazyfs_with_config(
+            Arc::new(ManualClock::default()),
+            FaultSchedule::default(),
+            config,
+        );
+
+        // The file and its name in `sub` are durable, `sub` in `dir` isn't
+        let sub = dir.join("sub");
+        lazyfs.do_mkdir(&sub, 0o755).unwrap();
+        let path = sub.join("db");
+        std::fs::write(&path, b"").unwrap();
+        lazyfs.do_write(&path, 7, 0, b"data").unwrap();
+        lazyfs.do_fsync(&path, false).unwrap();
+        lazyfs.do_fsyncdir(&sub).unwrap();
+
+        lazyfs.cache().clear_cache().unwrap();
+        assert!(!sub.exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn frozen_lazyfs_leaves_directories_and_xattrs_alone() {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-frozen-dir", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("db");
+        std::fs::write(&path, b"").unwrap();
+        let lazyfs = new_lazyfs(Arc::new(ManualClock::default()), FaultSchedule::default());
+        let eio = |e: anyhow::Error| errno_of(&e) == libc::EIO;
+
+        let sub = dir.join("sub");
+        let id = lazyfs
+            .add_crash_fault_by_name(CrashTiming::After, "mkdir", "/sub$", "freeze")
+            .unwrap();
+        lazyfs.do_mkdir(&sub, 0o755).unwrap();
+        assert_eq!(lazyfs.crash_fault_status(id).unwrap().unwrap().seen, 1);
+        assert!(lazyfs.is_frozen());
+
+        assert!(eio(lazyfs.do_rmdir(&sub).unwrap_err()));
+        assert!(sub.is_dir());
+        assert!(eio(lazyfs.do_mkdir(&dir.join("other"), 0o755).unwrap_err()));
+        assert!(!dir.join("other").exists());
+        assert!(eio(lazyfs
+            .do_setxattr(&path, "user.a", b"1", 0)
+            .unwrap_err()));
+        assert!(eio(lazyfs.do_removexattr(&path, "user.a").unwrap_err()));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn xattrs_through_the_handlers() {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-xattr", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("db");
+        std::fs::write(&path, b"").unwrap();
+        let lazyfs = new_lazyfs(Arc::new(ManualClock::default()), FaultSchedule::default());
+        let enodata = |e: anyhow::Error| {
+            e.downcast_ref::<std::io::Error>().unwrap().raw_os_error() == Some(libc::ENODATA)
+        };
+
+        lazyfs.do_setxattr(&path, "user.b", b"2", 0).unwrap();
+        lazyfs.do_setxattr(&path, "user.a", b"1", 0).unwrap();
+        assert_eq!(lazyfs.do_getxattr(&path, "user.a").unwrap(), b"1");
+        assert_eq!(lazyfs.do_listxattr(&path).unwrap(), ["user.a", "user.b"]);
+
+        lazyfs.do_removexattr(&path, "user.a").unwrap();
+        assert!(enodata(lazyfs.do_getxattr(&path, "user.a").unwrap_err()));
+        assert!(enodata(lazyfs.do_removexattr(&path, "user.a").unwrap_err()));
+        assert_eq!(lazyfs.do_listxattr(&path).unwrap(), ["user.b"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn xattrs_come_from_and_go_to_the_backing_file() {
+        let dir =
+            std::env::temp_dir().join(format!("lazyfs-rs-{}-xattr-backing", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("db");
+        std::fs::write(&path, b"").unwrap();
+        xattr::set(&path, "user.before", b"0").unwrap();
+        let lazyfs = new_lazyfs(Arc::new(ManualClock::default()), FaultSchedule::default());
+
+        assert_eq!(lazyfs.do_getxattr(&path, "user.before").unwrap(), b"0");
+        lazyfs.do_setxattr(&path, "user.after", b"1", 0).unwrap();
+        lazyfs.do_removexattr(&path, "user.before").unwrap();
+        assert_eq!(
+            xattr::read_all(&path).unwrap(),
+            HashMap::from([("user.before".to_string(), b"0".to_vec())])
+        );
+
+        // A data-only sync leaves them for the next full one
+        lazyfs.do_fsync(&path, true).unwrap();
+        assert!(xattr::read_all(&path).unwrap().contains_key("user.before"));
+        lazyfs.do_fsync(&path, false).unwrap();
+        assert_eq!(
+            xattr::read_all(&path).unwrap(),
+            HashMap::from([("user.after".to_string(), b"1".to_vec())])
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn mmap_writes_are_picked_up_on_fsync() {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-mmap", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("db");
+        std::fs::write(&path, b"before").unwrap();
+        let config = config::Config {
+            deny_mmap: false,
+            ..Default::default()
+        };
+        let lazyfs = new_lazyfs_with_config(
+            Arc::new(ManualClock::default()),
+            FaultSchedule::default(),
+            config,
+        );
+
+        let mut buf = vec![0; 64];
+        let read = lazyfs.do_read(&path, 7, 0, buf.len(), &mut buf).unwrap();
+        assert_eq!(&buf[..read], b"before");
+        let owner = lazyfs.owner_of(&path).unwrap();
+        lazyfs.on_mmap(&path, &owner).unwrap();
+
+        // Stores through the mapping land in the page cache, not in ours
+        std::fs::write(&path, b"mapped write").unwrap();
+        lazyfs.do_fsync(&path, false).unwrap();
+        let read = lazyfs.do_read(&path, 7, 0, buf.len(), &mut buf).unwrap();
+        assert_eq!(&buf[..read], b"mapped write");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn mmap_fsync_keeps_dirty_data() {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-mmap-dirty", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("db");
+        std::fs::write(&path, b"").unwrap();
+        let config = config::Config {
+            deny_mmap: false,
+            ..Default::default()
+        };
+        let lazyfs = new_lazyfs_with_config(
+            Arc::new(ManualClock::default()),
+            FaultSchedule::default(),
+            config,
+        );
+
+        let data = vec![b'd'; 10 * 1024];
+        assert_eq!(
+            lazyfs.do_write(&path, 0, 0, &data).unwrap(),
+            data.len() as u32
+        );
+        let owner = lazyfs.owner_of(&path).unwrap();
+        lazyfs.on_mmap(&path, &owner).unwrap();
+        lazyfs.do_fsync(&path, false).unwrap();
+
+        // The settle went by the size we wrote, not the empty backing file
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+        assert!(!lazyfs.cache().has_dirty_data(owner).unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn shrink_then_grow_zeroes_the_backing_file() {
+        let dir =
+            std::env::temp_dir().join(format!("lazyfs-rs-{}-shrink-grow", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("db");
+        std::fs::write(&path, vec![b'o'; 4096]).unwrap();
+        let lazyfs = new_lazyfs(Arc::new(ManualClock::default()), FaultSchedule::default());
+
+        lazyfs.do_truncate(&path, 10).unwrap();
+        lazyfs.do_truncate(&path, 4096).unwrap();
+        lazyfs.do_fsync(&path, false).unwrap();
+
+        let mut expected = vec![b'o'; 10];
+        expected.resize(4096, 0);
+        let mut buf = vec![0; 4096];
+        let read = lazyfs.do_read(&path, 7, 0, buf.len(), &mut buf).unwrap();
+        assert_eq!(&buf[..read], expected);
+        assert_eq!(std::fs::read(&path).unwrap(), expected);
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -1,5 +1,24 @@
+pub mod cache_diff;
+pub mod clock;
+pub mod commands;
+pub mod crash_faults;
+pub mod crash_report;
+pub mod fault_state;
+pub mod fault_stats;
+pub mod fence;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod formats;
+#[cfg(feature = "fuse")]
+pub mod fuse;
+pub mod latency;
+pub mod lock_diag;
+pub mod op_limit;
 pub mod pagecache;
 pub mod lazyfs;
+pub mod path_matcher;
+pub mod self_test;
+pub mod startup;
 
 const TRACING_TARGET: &str = "lazyfs-rs";
 
@@ -0,0 +1,278 @@
+//! Locks used by the cache and engine. Every acquisition names its call site, so with the
+//! `lock-diagnostics` feature on, wait and hold times can be attributed to the code that caused
+//! them. With the feature off the types are the plain std locks and the site is ignored.
+
+use std::sync::LockResult;
+use std::time::Duration;
+
+#[cfg(not(feature = "lock-diagnostics"))]
+pub use std::sync::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+#[cfg(feature = "lock-diagnostics")]
+pub use instrumented::{Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// Contention seen at one acquisition site
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LockStats {
+    pub site: &'static str,
+    pub acquisitions: u64,
+    pub total_wait: Duration,
+    pub max_wait: Duration,
+    pub max_hold: Duration,
+}
+
+pub trait RwLockAt<T> {
+    fn read_at(&self, site: &'static str) -> LockResult<RwLockReadGuard<'_, T>>;
+    fn write_at(&self, site: &'static str) -> LockResult<RwLockWriteGuard<'_, T>>;
+}
+
+pub trait MutexAt<T> {
+    fn lock_at(&self, site: &'static str) -> LockResult<MutexGuard<'_, T>>;
+}
+
+#[cfg(not(feature = "lock-diagnostics"))]
+impl<T> RwLockAt<T> for RwLock<T> {
+    #[inline]
+    fn read_at(&self, _site: &'static str) -> LockResult<RwLockReadGuard<'_, T>> {
+        self.read()
+    }
+
+    #[inline]
+    fn write_at(&self, _site: &'static str) -> LockResult<RwLockWriteGuard<'_, T>> {
+        self.write()
+    }
+}
+
+#[cfg(not(feature = "lock-diagnostics"))]
+impl<T> MutexAt<T> for Mutex<T> {
+    #[inline]
+    fn lock_at(&self, _site: &'static str) -> LockResult<MutexGuard<'_, T>> {
+        self.lock()
+    }
+}
+
+/// Per-site statistics recorded so far, sorted by site. Always empty without the
+/// `lock-diagnostics` feature.
+pub fn lock_stats() -> Vec<LockStats> {
+    #[cfg(feature = "lock-diagnostics")]
+    return instrumented::snapshot();
+    #[cfg(not(feature = "lock-diagnostics"))]
+    Vec::new()
+}
+
+#[cfg(feature = "lock-diagnostics")]
+mod instrumented {
+    use std::collections::BTreeMap;
+    use std::fmt;
+    use std::ops::{Deref, DerefMut};
+    use std::sync::{self, LockResult, PoisonError};
+    use std::time::{Duration, Instant};
+
+    use super::{LockStats, MutexAt, RwLockAt};
+
+    static STATS: sync::Mutex<BTreeMap<&'static str, LockStats>> =
+        sync::Mutex::new(BTreeMap::new());
+
+    fn entry<F: FnOnce(&mut LockStats)>(site: &'static str, update: F) {
+        let mut stats = STATS.lock().unwrap_or_else(PoisonError::into_inner);
+        update(stats.entry(site).or_insert_with(|| LockStats {
+            site,
+            ..Default::default()
+        }));
+    }
+
+    fn record_wait(site: &'static str, wait: Duration) {
+        entry(site, |s| {
+            s.acquisitions += 1;
+            s.total_wait += wait;
+            s.max_wait = s.max_wait.max(wait);
+        });
+    }
+
+    pub(super) fn snapshot() -> Vec<LockStats> {
+        let stats = STATS.lock().unwrap_or_else(PoisonError::into_inner);
+        stats.values().cloned().collect()
+    }
+
+    /// Records how long the guard it lives in was held. Declared after the std guard so the lock
+    /// is released before the hold is recorded.
+    struct Hold {
+        site: &'static str,
+        acquired: Instant,
+    }
+
+    impl Hold {
+        fn start(site: &'static str, started: Instant) -> Self {
+            let acquired = Instant::now();
+            record_wait(site, acquired - started);
+            Hold { site, acquired }
+        }
+    }
+
+    impl Drop for Hold {
+        fn drop(&mut self) {
+            let held = self.acquired.elapsed();
+            entry(self.site, |s| s.max_hold = s.max_hold.max(held));
+        }
+    }
+
+    fn wrap<G, W>(result: LockResult<G>, into: impl FnOnce(G) -> W) -> LockResult<W> {
+        match result {
+            Ok(guard) => Ok(into(guard)),
+            Err(poisoned) => Err(PoisonError::new(into(poisoned.into_inner()))),
+        }
+    }
+
+    #[derive(Default)]
+    pub struct RwLock<T> {
+        inner: sync::RwLock<T>,
+    }
+
+    impl<T> RwLock<T> {
+        pub fn new(value: T) -> Self {
+            RwLock {
+                inner: sync::RwLock::new(value),
+            }
+        }
+    }
+
+    impl<T: fmt::Debug> fmt::Debug for RwLock<T> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            self.inner.fmt(f)
+        }
+    }
+
+    pub struct RwLockReadGuard<'a, T> {
+        guard: sync::RwLockReadGuard<'a, T>,
+        _hold: Hold,
+    }
+
+    pub struct RwLockWriteGuard<'a, T> {
+        guard: sync::RwLockWriteGuard<'a, T>,
+        _hold: Hold,
+    }
+
+    impl<T> RwLockAt<T> for RwLock<T> {
+        fn read_at(&self, site: &'static str) -> LockResult<RwLockReadGuard<'_, T>> {
+            let started = Instant::now();
+            wrap(self.inner.read(), |guard| RwLockReadGuard {
+                guard,
+                _hold: Hold::start(site, started),
+            })
+        }
+
+        fn write_at(&self, site: &'static str) -> LockResult<RwLockWriteGuard<'_, T>> {
+            let started = Instant::now();
+            wrap(self.inner.write(), |guard| RwLockWriteGuard {
+                guard,
+                _hold: Hold::start(site, started),
+            })
+        }
+    }
+
+    impl<T> Deref for RwLockReadGuard<'_, T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            &self.guard
+        }
+    }
+
+    impl<T> Deref for RwLockWriteGuard<'_, T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            &self.guard
+        }
+    }
+
+    impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+        fn deref_mut(&mut self) -> &mut T {
+            &mut self.guard
+        }
+    }
+
+    #[derive(Default)]
+    pub struct Mutex<T> {
+        inner: sync::Mutex<T>,
+    }
+
+    impl<T> Mutex<T> {
+        pub fn new(value: T) -> Self {
+            Mutex {
+                inner: sync::Mutex::new(value),
+            }
+        }
+    }
+
+    impl<T: fmt::Debug> fmt::Debug for Mutex<T> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            self.inner.fmt(f)
+        }
+    }
+
+    pub struct MutexGuard<'a, T> {
+        guard: sync::MutexGuard<'a, T>,
+        _hold: Hold,
+    }
+
+    impl<T> MutexAt<T> for Mutex<T> {
+        fn lock_at(&self, site: &'static str) -> LockResult<MutexGuard<'_, T>> {
+            let started = Instant::now();
+            wrap(self.inner.lock(), |guard| MutexGuard {
+                guard,
+                _hold: Hold::start(site, started),
+            })
+        }
+    }
+
+    impl<T> Deref for MutexGuard<'_, T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            &self.guard
+        }
+    }
+
+    impl<T> DerefMut for MutexGuard<'_, T> {
+        fn deref_mut(&mut self) -> &mut T {
+            &mut self.guard
+        }
+    }
+}
+
+#[cfg(all(test, feature = "lock-diagnostics"))]
+mod tests {
+    use super::*;
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+
+    fn site(name: &str) -> LockStats {
+        lock_stats().into_iter().find(|s| s.site == name).unwrap()
+    }
+
+    #[test]
+    fn contention_is_recorded_per_site() {
+        let lock = Arc::new(RwLock::new(0));
+        let (held_tx, held_rx) = mpsc::channel();
+
+        let holder = {
+            let lock = lock.clone();
+            thread::spawn(move || {
+                let mut guard = lock.write_at("lock_diag::tests/holder").unwrap();
+                held_tx.send(()).unwrap();
+                thread::sleep(Duration::from_millis(50));
+                *guard += 1;
+            })
+        };
+        held_rx.recv().unwrap();
+        assert_eq!(*lock.read_at("lock_diag::tests/waiter").unwrap(), 1);
+        holder.join().unwrap();
+
+        let waiter = site("lock_diag::tests/waiter");
+        assert_eq!(waiter.acquisitions, 1);
+        assert!(waiter.max_wait > Duration::ZERO);
+        assert_eq!(waiter.total_wait, waiter.max_wait);
+        assert!(site("lock_diag::tests/holder").max_hold >= Duration::from_millis(50));
+    }
+}
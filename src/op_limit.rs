@@ -0,0 +1,264 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+use crate::crash_faults::FsOperation;
+use crate::pagecache::config::Config;
+use crate::TRACING_TARGET;
+
+/// Buckets of `QueueWaitStats::histogram`. Bucket `i` counts waits under `2^i` microseconds,
+/// the last one everything longer.
+pub const WAIT_BUCKETS: usize = 24;
+
+/// Time operations of one kind spent queued behind the limits
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QueueWaitStats {
+    pub admitted: u64,
+    /// Admitted operations that found the queue full and had to wait
+    pub queued: u64,
+    pub total_wait: Duration,
+    pub max_wait: Duration,
+    pub histogram: [u64; WAIT_BUCKETS],
+}
+
+impl QueueWaitStats {
+    fn record(&mut self, wait: Duration, queued: bool) {
+        self.admitted += 1;
+        self.queued += queued as u64;
+        self.total_wait += wait;
+        self.max_wait = self.max_wait.max(wait);
+        let micros = wait.as_micros() as u64;
+        let bucket = (u64::BITS - micros.leading_zeros()) as usize;
+        self.histogram[bucket.min(WAIT_BUCKETS - 1)] += 1;
+    }
+}
+
+#[derive(Debug, Default)]
+struct LimiterState {
+    /// Most operations of any kind running at once, 0 for no limit
+    max_total: usize,
+    max_per_op: HashMap<FsOperation, usize>,
+    in_flight: usize,
+    in_flight_per_op: HashMap<FsOperation, usize>,
+    waits: HashMap<FsOperation, QueueWaitStats>,
+}
+
+impl LimiterState {
+    fn is_full(&self, op: FsOperation) -> bool {
+        let limit = self.max_per_op.get(&op).copied().unwrap_or(0);
+        let running = self.in_flight_per_op.get(&op).copied().unwrap_or(0);
+        (self.max_total > 0 && self.in_flight >= self.max_total) || (limit > 0 && running >= limit)
+    }
+}
+
+/// Queue depth of the mount, like that of a device with a short queue. Operations past the
+/// limit for their kind, or past the overall one, wait at the top of their handler until one
+/// already running finishes. A limit of 0 lets any number through.
+#[derive(Debug)]
+pub struct OpLimiter {
+    state: Mutex<LimiterState>,
+    changed: Condvar,
+}
+
+/// Held by an operation while it runs
+#[derive(Debug)]
+pub struct OpPermit<'a> {
+    limiter: &'a OpLimiter,
+    op: FsOperation,
+}
+
+impl Drop for OpPermit<'_> {
+    fn drop(&mut self) {
+        let mut state = self.limiter.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.in_flight -= 1;
+        if let Some(running) = state.in_flight_per_op.get_mut(&self.op) {
+            *running -= 1;
+        }
+        self.limiter.changed.notify_all();
+    }
+}
+
+impl OpLimiter {
+    pub fn new(max_total: usize, max_per_op: HashMap<FsOperation, usize>) -> Self {
+        OpLimiter {
+            state: Mutex::new(LimiterState {
+                max_total,
+                max_per_op,
+                ..Default::default()
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Self {
+        let max_per_op = [
+            (FsOperation::Read, config.max_concurrent_reads),
+            (FsOperation::Write, config.max_concurrent_writes),
+            (FsOperation::Fsync, config.max_concurrent_fsyncs),
+        ]
+        .into_iter()
+        .filter(|&(_, limit)| limit > 0)
+        .collect();
+        Self::new(config.max_concurrent_ops, max_per_op)
+    }
+
+    /// Waits until `op` fits under the limits and admits it
+    pub fn acquire(&self, op: FsOperation) -> Result<OpPermit<'_>> {
+        let started = Instant::now();
+        let mut state = self
+            .state
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on op limiter: {:?}", e))?;
+        let queued = state.is_full(op);
+        while state.is_full(op) {
+            state = self
+                .changed
+                .wait(state)
+                .map_err(|e| anyhow!("Unable to acquire lock on op limiter: {:?}", e))?;
+        }
+
+        state.in_flight += 1;
+        *state.in_flight_per_op.entry(op).or_insert(0) += 1;
+        state
+            .waits
+            .entry(op)
+            .or_default()
+            .record(started.elapsed(), queued);
+        Ok(OpPermit { limiter: self, op })
+    }
+
+    /// Changes the limit of `op`, or the overall one if `None`, returning the previous one.
+    /// Operations already running are not affected, waiting ones are let in if they now fit.
+    pub fn set_limit(&self, op: Option<FsOperation>, limit: usize) -> Result<usize> {
+        let mut state = self
+            .state
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on op limiter: {:?}", e))?;
+        let previous = match op {
+            Some(op) if limit == 0 => state.max_per_op.remove(&op),
+            Some(op) => state.max_per_op.insert(op, limit),
+            None => Some(std::mem::replace(&mut state.max_total, limit)),
+        };
+        self.changed.notify_all();
+        info!(
+            target: TRACING_TARGET,
+            op = op.map_or("all", |op| op.as_str()),
+            limit,
+            "changed op limit"
+        );
+        Ok(previous.unwrap_or(0))
+    }
+
+    /// Queue waits so far for each kind of operation that was admitted at least once, sorted
+    /// by operation
+    pub fn wait_stats(&self) -> Result<Vec<(FsOperation, QueueWaitStats)>> {
+        let state = self
+            .state
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on op limiter: {:?}", e))?;
+        let mut stats: Vec<_> = state
+            .waits
+            .iter()
+            .map(|(&op, stats)| (op, stats.clone()))
+            .collect();
+        stats.sort_by_key(|(op, _)| op.as_str());
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    /// Runs `threads` operations of `op` that each take `hold`, returning when each ran
+    fn run_concurrently(
+        limiter: &Arc<OpLimiter>,
+        op: FsOperation,
+        threads: usize,
+        hold: Duration,
+    ) -> Vec<(Instant, Instant)> {
+        let handles: Vec<_> = (0..threads)
+            .map(|_| {
+                let limiter = limiter.clone();
+                thread::spawn(move || {
+                    let _permit = limiter.acquire(op).unwrap();
+                    let started = Instant::now();
+                    thread::sleep(hold);
+                    (started, Instant::now())
+                })
+            })
+            .collect();
+        handles.into_iter().map(|h| h.join().unwrap()).collect()
+    }
+
+    /// Most runs overlapping at any one time
+    fn max_overlap(runs: &[(Instant, Instant)]) -> usize {
+        runs.iter()
+            .map(|&(started, _)| {
+                runs.iter()
+                    .filter(|&&(from, to)| from <= started && started < to)
+                    .count()
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    #[test]
+    fn writes_are_serialized() {
+        let limiter = Arc::new(OpLimiter::new(0, HashMap::from([(FsOperation::Write, 1)])));
+        let hold = Duration::from_millis(20);
+
+        let mut runs = run_concurrently(&limiter, FsOperation::Write, 4, hold);
+        assert_eq!(max_overlap(&runs), 1);
+        runs.sort();
+        for pair in runs.windows(2) {
+            assert!(pair[1].0 >= pair[0].1);
+        }
+
+        // Reads have no limit of their own
+        let reads = run_concurrently(&limiter, FsOperation::Read, 4, hold);
+        assert_eq!(max_overlap(&reads), 4);
+
+        let stats = limiter.wait_stats().unwrap();
+        assert_eq!(stats[0].0, FsOperation::Read);
+        assert_eq!(stats[0].1.queued, 0);
+        let writes = &stats[1].1;
+        assert_eq!(stats[1].0, FsOperation::Write);
+        assert_eq!(writes.admitted, 4);
+        assert_eq!(writes.queued, 3);
+        // The last one in waited for the other three
+        assert!(writes.max_wait >= hold * 3 - Duration::from_millis(5));
+        assert!(writes.total_wait >= hold * 6 - Duration::from_millis(15));
+        assert_eq!(writes.histogram.iter().sum::<u64>(), 4);
+    }
+
+    #[test]
+    fn limits_change_at_runtime() {
+        let limiter = Arc::new(OpLimiter::new(2, HashMap::new()));
+        let hold = Duration::from_millis(20);
+        assert!(max_overlap(&run_concurrently(&limiter, FsOperation::Read, 4, hold)) <= 2);
+
+        assert_eq!(limiter.set_limit(None, 0).unwrap(), 2);
+        assert_eq!(
+            max_overlap(&run_concurrently(&limiter, FsOperation::Read, 4, hold)),
+            4
+        );
+
+        // Lifting the limit lets queued operations in right away
+        assert_eq!(limiter.set_limit(None, 1).unwrap(), 0);
+        let permit = limiter.acquire(FsOperation::Fsync).unwrap();
+        let waiter = {
+            let limiter = limiter.clone();
+            thread::spawn(move || drop(limiter.acquire(FsOperation::Fsync).unwrap()))
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!waiter.is_finished());
+        limiter.set_limit(None, 0).unwrap();
+        waiter.join().unwrap();
+        drop(permit);
+    }
+}
@@ -1,12 +1,206 @@
 use anyhow::{anyhow, Result};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::Read;
-use std::path::PathBuf;
-use std::sync::atomic::AtomicI32;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, SystemTime};
 use toml;
 
-pub trait Fault {}
+use crate::clock::ClockSkew;
+use crate::crash_faults::CrashMode;
+use crate::fence::FenceMode;
+use crate::path_matcher::{MatchOptions, PathMatcher};
+
+pub trait Fault: Send + Sync {
+    fn schedule(&self) -> &FaultSchedule;
+
+    /// Operation the fault counts, such as "write". Faults that hook into LazyFS some other way
+    /// count none.
+    fn op(&self) -> &str {
+        ""
+    }
+
+    /// Counts an `op()` on a path the fault is keyed by, returning how many it has seen
+    fn count_op(&self) -> i32 {
+        0
+    }
+
+    /// Whether the `op_count`-th `op()` on `path` is the one the fault fires on
+    fn should_trigger(&self, _path: &Path, _op_count: i32) -> bool {
+        false
+    }
+
+    /// The fault fired
+    fn on_triggered(&self) {}
+
+    /// Takes back the last `count_op()`, so the next op is counted as that one again
+    fn release_op(&self) {}
+
+    fn as_split_write(&self) -> Option<&SplitWriteFault> {
+        None
+    }
+
+    fn as_reorder(&self) -> Option<&ReorderFault> {
+        None
+    }
+
+    fn as_delay(&self) -> Option<&DelayFault> {
+        None
+    }
+
+    /// Canonical description of how the fault was defined. Saved state is only restored into a
+    /// fault with the same spec.
+    fn spec(&self) -> String;
+
+    /// Runtime state (counters, triggered flags) that has to survive a remount
+    fn save_state(&self) -> FaultState {
+        FaultState::default()
+    }
+
+    fn restore_state(&self, _state: &FaultState) -> Result<()> {
+        Ok(())
+    }
+
+    /// Fault specific state worth reporting next to the schedule
+    fn status_detail(&self) -> Option<String> {
+        None
+    }
+
+    /// Whether the fault may fire for the op with the given global op count at time `now`
+    fn is_active(&self, op_count: u64, now: SystemTime) -> bool {
+        matches!(
+            self.schedule().window(op_count, now),
+            FaultWindow::Active { .. }
+        )
+    }
+}
+
+/// Serializable runtime state of a fault
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct FaultState {
+    #[serde(default)]
+    pub counters: Vec<u64>,
+    #[serde(default)]
+    pub owners: Vec<String>,
+}
+
+impl FaultState {
+    fn from_counters(counters: Vec<u64>) -> Self {
+        FaultState {
+            counters,
+            owners: Vec::new(),
+        }
+    }
+
+    fn expect_counters(&self, n: usize) -> Result<&[u64]> {
+        if self.counters.len() != n {
+            return Err(anyhow!(
+                "expected {} saved counters, found {}",
+                n,
+                self.counters.len()
+            ));
+        }
+        Ok(&self.counters)
+    }
+}
+
+/// Where a scheduled fault currently stands relative to its activation window. The remaining
+/// amounts are `None` when that side of the window is not bounded.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FaultWindow {
+    Pending {
+        ops_left: Option<u64>,
+        time_left: Option<Duration>,
+    },
+    Active {
+        ops_left: Option<u64>,
+        time_left: Option<Duration>,
+    },
+    Expired,
+}
+
+/// Restricts a fault to a window of global op counts and/or time since it was armed. An empty
+/// schedule is always active.
+#[derive(Clone, Debug)]
+pub struct FaultSchedule {
+    pub active_after_ops: Option<u64>,
+    pub active_for_ops: Option<u64>,
+    pub active_after: Option<Duration>,
+    pub active_for: Option<Duration>,
+    /// Where the time window is measured from, taken from the clock of the `LazyFS` the fault is
+    /// armed on
+    pub armed_at: OnceLock<SystemTime>,
+}
+
+impl FaultSchedule {
+    /// `now` starts the time window if nothing armed the schedule yet
+    pub fn window(&self, op_count: u64, now: SystemTime) -> FaultWindow {
+        let armed_at = *self.armed_at.get_or_init(|| now);
+        let elapsed = now.duration_since(armed_at).unwrap_or_default();
+        self.window_at(op_count, elapsed)
+    }
+
+    pub fn window_at(&self, op_count: u64, elapsed: Duration) -> FaultWindow {
+        let ops_start = self.active_after_ops.unwrap_or(0);
+        let ops_end = self.active_for_ops.map(|ops| ops_start.saturating_add(ops));
+        let time_start = self.active_after.unwrap_or_default();
+        let time_end = self.active_for.map(|d| time_start.saturating_add(d));
+
+        if ops_end.is_some_and(|end| op_count >= end) || time_end.is_some_and(|end| elapsed >= end)
+        {
+            return FaultWindow::Expired;
+        }
+
+        if op_count < ops_start || elapsed < time_start {
+            return FaultWindow::Pending {
+                ops_left: ops_start.checked_sub(op_count).filter(|&ops| ops > 0),
+                time_left: time_start.checked_sub(elapsed).filter(|d| !d.is_zero()),
+            };
+        }
+
+        FaultWindow::Active {
+            ops_left: ops_end.map(|end| end - op_count),
+            time_left: time_end.map(|end| end - elapsed),
+        }
+    }
+
+    /// Restarts the time window from `now`
+    pub fn arm(&mut self, now: SystemTime) {
+        self.armed_at = OnceLock::from(now);
+    }
+
+    /// Starts the time window at `now`, unless it already started
+    pub fn arm_once(&self, now: SystemTime) {
+        let _ = self.armed_at.set(now);
+    }
+}
+
+impl Default for FaultSchedule {
+    fn default() -> Self {
+        Self {
+            active_after_ops: None,
+            active_for_ops: None,
+            active_after: None,
+            active_for: None,
+            armed_at: OnceLock::new(),
+        }
+    }
+}
+
+/// How many whole disk sectors of a torn write make it to disk
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TornSectors {
+    /// Always persist this many sectors
+    Fixed(u32),
+    /// Pick a count below the number of sectors the write touches, derived from the seed and the
+    /// fault counter so reruns tear the same way
+    Seeded(u64),
+}
 
 pub struct SplitWriteFault {
     occurence: i32,
@@ -14,6 +208,9 @@ pub struct SplitWriteFault {
     persist: Vec<i32>,
     parts: i32,
     parts_bytes: Vec<i32>,
+    sector_torn: Option<TornSectors>,
+    mode: CrashMode,
+    schedule: FaultSchedule,
 }
 
 impl SplitWriteFault {
@@ -24,6 +221,9 @@ impl SplitWriteFault {
             persist,
             parts,
             parts_bytes: Vec::new(),
+            sector_torn: None,
+            mode: CrashMode::default(),
+            schedule: FaultSchedule::default(),
         }
     }
 
@@ -34,11 +234,152 @@ impl SplitWriteFault {
             persist,
             parts: 0,
             parts_bytes,
+            sector_torn: None,
+            mode: CrashMode::default(),
+            schedule: FaultSchedule::default(),
+        }
+    }
+
+    pub fn from_sectors(occurence: i32, sector_torn: TornSectors) -> Self {
+        SplitWriteFault {
+            occurence,
+            sector_torn: Some(sector_torn),
+            ..Default::default()
         }
     }
+
+    pub fn with_schedule(mut self, schedule: FaultSchedule) -> Self {
+        self.schedule = schedule;
+        self
+    }
+
+    /// How LazyFS crashes once the persisted parts of the split write are on disk
+    pub fn with_mode(mut self, mode: CrashMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn mode(&self) -> CrashMode {
+        self.mode
+    }
+
+    /// The parts of a `len` byte write listed in `persist`, numbered from 1 and in order, with
+    /// the bytes of the write each one covers. `parts` equal parts give the remainder of the
+    /// division to the first ones, so a write shorter than `parts` leaves the last ones empty.
+    /// `parts_bytes` parts stop at the end of the write, and the bytes past the last of them
+    /// belong to no part and are never persisted. Empty parts are left out.
+    pub fn persisted_parts(&self, len: usize) -> Vec<(u32, Range<usize>)> {
+        let sizes: Vec<usize> = if self.parts_bytes.is_empty() {
+            let parts = self.parts.max(1) as usize;
+            (0..parts)
+                .map(|i| len / parts + usize::from(i < len % parts))
+                .collect()
+        } else {
+            self.parts_bytes.iter().map(|&size| size as usize).collect()
+        };
+
+        let mut start = 0;
+        let ranges: Vec<_> = sizes
+            .into_iter()
+            .map(|size| {
+                let range = start.min(len)..(start + size).min(len);
+                start += size;
+                range
+            })
+            .collect();
+        let mut persist = self.persist.clone();
+        persist.sort_unstable();
+        persist.dedup();
+        persist
+            .into_iter()
+            .filter_map(|part| {
+                let range = ranges.get(usize::try_from(part).ok()?.checked_sub(1)?)?;
+                (!range.is_empty()).then(|| (part as u32, range.clone()))
+            })
+            .collect()
+    }
+
+    /// For sector-torn faults, returns how many leading bytes of a write of `len` bytes at
+    /// `offset` survive when only whole sectors of `sector_size` bytes reach the disk. The first
+    /// sector the write touches counts as a whole sector even when `offset` is not aligned.
+    /// `None` without sectors to tear at, a `sector_size` of 0 included, in which case the
+    /// fault persists none of the write.
+    pub fn sector_torn_len(&self, offset: u64, len: usize, sector_size: usize) -> Option<usize> {
+        let torn = self.sector_torn?;
+        if sector_size == 0 {
+            return None;
+        }
+        if len == 0 {
+            return Some(0);
+        }
+
+        let sector_size = sector_size as u64;
+        let end = offset + len as u64;
+        let first_sector = offset / sector_size;
+        let touched_sectors = end.div_ceil(sector_size) - first_sector;
+
+        let persisted = match torn {
+            TornSectors::Fixed(count) => (count as u64).min(touched_sectors),
+            TornSectors::Seeded(seed) => {
+                let counter = self.counter.load(Ordering::SeqCst) as u64;
+                splitmix64(seed ^ counter) % touched_sectors
+            }
+        };
+        let survive_end = ((first_sector + persisted) * sector_size).min(end);
+
+        Some(survive_end.saturating_sub(offset) as usize)
+    }
 }
 
-impl Fault for SplitWriteFault {}
+pub(crate) fn splitmix64(seed: u64) -> u64 {
+    let mut z = seed.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+impl Fault for SplitWriteFault {
+    fn schedule(&self) -> &FaultSchedule {
+        &self.schedule
+    }
+
+    fn op(&self) -> &str {
+        "write"
+    }
+
+    fn count_op(&self) -> i32 {
+        self.counter.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    fn release_op(&self) {
+        self.counter.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    fn should_trigger(&self, _path: &Path, op_count: i32) -> bool {
+        op_count == self.occurence
+    }
+
+    fn as_split_write(&self) -> Option<&SplitWriteFault> {
+        Some(self)
+    }
+
+    fn spec(&self) -> String {
+        format!(
+            "split-write occurence={} persist={:?} parts={} parts_bytes={:?} sector_torn={:?}",
+            self.occurence, self.persist, self.parts, self.parts_bytes, self.sector_torn
+        )
+    }
+
+    fn save_state(&self) -> FaultState {
+        FaultState::from_counters(vec![self.counter.load(Ordering::SeqCst) as u64])
+    }
+
+    fn restore_state(&self, state: &FaultState) -> Result<()> {
+        let counters = state.expect_counters(1)?;
+        self.counter.store(counters[0] as i32, Ordering::SeqCst);
+        Ok(())
+    }
+}
 
 impl Default for SplitWriteFault {
     fn default() -> Self {
@@ -48,6 +389,9 @@ impl Default for SplitWriteFault {
             persist: Vec::new(),
             parts: 0,
             parts_bytes: Vec::new(),
+            sector_torn: None,
+            mode: CrashMode::default(),
+            schedule: FaultSchedule::default(),
         }
     }
 }
@@ -58,6 +402,7 @@ pub struct ReorderFault {
     counter: AtomicI32,
     persist: Vec<i32>,
     group_counter: AtomicI32,
+    schedule: FaultSchedule,
 }
 
 impl ReorderFault {
@@ -68,11 +413,74 @@ impl ReorderFault {
             counter: AtomicI32::new(0),
             persist,
             group_counter: AtomicI32::new(0),
+            schedule: FaultSchedule::default(),
         }
     }
+
+    pub fn with_schedule(mut self, schedule: FaultSchedule) -> Self {
+        self.schedule = schedule;
+        self
+    }
+
+    /// Whether the `op_count`-th op the fault counted, the one it held back if it fired on it,
+    /// reaches the disk at the end of its group
+    pub fn persists(&self, op_count: i32) -> bool {
+        self.persist.contains(&op_count)
+    }
 }
 
-impl Fault for ReorderFault {}
+impl Fault for ReorderFault {
+    fn schedule(&self) -> &FaultSchedule {
+        &self.schedule
+    }
+
+    fn op(&self) -> &str {
+        &self.op
+    }
+
+    fn count_op(&self) -> i32 {
+        self.counter.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    fn release_op(&self) {
+        self.counter.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    fn should_trigger(&self, _path: &Path, op_count: i32) -> bool {
+        op_count == self.occurence
+    }
+
+    /// Starts collecting the group of ops to reorder
+    fn on_triggered(&self) {
+        self.group_counter.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn as_reorder(&self) -> Option<&ReorderFault> {
+        Some(self)
+    }
+
+    fn spec(&self) -> String {
+        format!(
+            "reorder op={} occurence={} persist={:?}",
+            self.op, self.occurence, self.persist
+        )
+    }
+
+    fn save_state(&self) -> FaultState {
+        FaultState::from_counters(vec![
+            self.counter.load(Ordering::SeqCst) as u64,
+            self.group_counter.load(Ordering::SeqCst) as u64,
+        ])
+    }
+
+    fn restore_state(&self, state: &FaultState) -> Result<()> {
+        let counters = state.expect_counters(2)?;
+        self.counter.store(counters[0] as i32, Ordering::SeqCst);
+        self.group_counter
+            .store(counters[1] as i32, Ordering::SeqCst);
+        Ok(())
+    }
+}
 
 impl Default for ReorderFault {
     fn default() -> Self {
@@ -82,10 +490,750 @@ impl Default for ReorderFault {
             counter: AtomicI32::new(0),
             persist: Vec::new(),
             group_counter: AtomicI32::new(0),
+            schedule: FaultSchedule::default(),
+        }
+    }
+}
+
+/// How much of a write a short-write fault lets through
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShortWriteLimit {
+    Bytes(usize),
+    /// Fraction of the requested length, rounded down
+    Fraction(f64),
+}
+
+/// Makes the `occurence`-th write to a matching path return fewer bytes than requested. Only the
+/// accepted prefix is cached, the tail is left for the application to retry.
+pub struct ShortWriteFault {
+    path_regex: PathMatcher,
+    occurence: i32,
+    counter: AtomicI32,
+    limit: ShortWriteLimit,
+    schedule: FaultSchedule,
+}
+
+impl ShortWriteFault {
+    pub fn new(path_regex: &str, occurence: i32, limit: ShortWriteLimit) -> Result<Self> {
+        if let ShortWriteLimit::Fraction(fraction) = limit {
+            if !(0.0..=1.0).contains(&fraction) {
+                return Err(anyhow!("short write fraction must be between 0 and 1"));
+            }
+        }
+
+        Ok(ShortWriteFault {
+            path_regex: PathMatcher::new(path_regex, MatchOptions::default())?,
+            occurence,
+            counter: AtomicI32::new(0),
+            limit,
+            schedule: FaultSchedule::default(),
+        })
+    }
+
+    pub fn with_schedule(mut self, schedule: FaultSchedule) -> Self {
+        self.schedule = schedule;
+        self
+    }
+
+    /// Matches paths with `options` applied to both the pattern and the path
+    pub fn with_match_options(mut self, options: MatchOptions) -> Result<Self> {
+        self.path_regex = self.path_regex.with_options(options)?;
+        Ok(self)
+    }
+
+    /// Counts a write of `len` bytes to `path` and returns the shortened length if this is the
+    /// write the fault targets
+    pub fn short_len(&self, path: &Path, len: usize) -> Option<usize> {
+        if !self.path_regex.is_match(path) {
+            return None;
+        }
+        if self.counter.fetch_add(1, Ordering::SeqCst) + 1 != self.occurence {
+            return None;
+        }
+
+        let short = match self.limit {
+            ShortWriteLimit::Bytes(bytes) => bytes,
+            ShortWriteLimit::Fraction(fraction) => (len as f64 * fraction) as usize,
+        };
+        Some(short.min(len))
+    }
+
+    /// Takes back the count of the last matching write, so the next one is targeted again
+    pub fn release(&self) {
+        self.counter.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Fault for ShortWriteFault {
+    fn schedule(&self) -> &FaultSchedule {
+        &self.schedule
+    }
+
+    fn spec(&self) -> String {
+        format!(
+            "short-write path={} occurence={} limit={:?}",
+            self.path_regex, self.occurence, self.limit
+        )
+    }
+
+    fn save_state(&self) -> FaultState {
+        FaultState::from_counters(vec![self.counter.load(Ordering::SeqCst) as u64])
+    }
+
+    fn restore_state(&self, state: &FaultState) -> Result<()> {
+        let counters = state.expect_counters(1)?;
+        self.counter.store(counters[0] as i32, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+/// Once the `occurence`-th read of a matching path happens, that and every later matching read
+/// is served from the backing file as of the last sync, ignoring newer dirty data in the cache.
+/// Stays triggered until disarmed.
+pub struct StaleReadFault {
+    path_regex: PathMatcher,
+    occurence: i32,
+    counter: AtomicI32,
+    triggered: AtomicBool,
+    schedule: FaultSchedule,
+}
+
+impl StaleReadFault {
+    pub fn new(path_regex: &str, occurence: i32) -> Result<Self> {
+        Ok(StaleReadFault {
+            path_regex: PathMatcher::new(path_regex, MatchOptions::default())?,
+            occurence,
+            counter: AtomicI32::new(0),
+            triggered: AtomicBool::new(false),
+            schedule: FaultSchedule::default(),
+        })
+    }
+
+    pub fn with_schedule(mut self, schedule: FaultSchedule) -> Self {
+        self.schedule = schedule;
+        self
+    }
+
+    /// Matches paths with `options` applied to both the pattern and the path
+    pub fn with_match_options(mut self, options: MatchOptions) -> Result<Self> {
+        self.path_regex = self.path_regex.with_options(options)?;
+        Ok(self)
+    }
+
+    /// Counts a read of `path` and returns whether it has to be served stale
+    pub fn serves_stale(&self, path: &Path) -> bool {
+        if !self.path_regex.is_match(path) {
+            return false;
+        }
+        if self.counter.fetch_add(1, Ordering::SeqCst) + 1 >= self.occurence {
+            self.triggered.store(true, Ordering::SeqCst);
+        }
+        self.triggered.load(Ordering::SeqCst)
+    }
+
+    /// Takes back the count of the last matching read and, unless it had triggered before that,
+    /// the trigger itself
+    pub fn release(&self, was_triggered: bool) {
+        self.counter.fetch_sub(1, Ordering::SeqCst);
+        self.triggered.store(was_triggered, Ordering::SeqCst);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.triggered.load(Ordering::SeqCst)
+    }
+
+    /// Goes back to serving fresh reads and restarts the occurrence count
+    pub fn disarm(&self) {
+        self.triggered.store(false, Ordering::SeqCst);
+        self.counter.store(0, Ordering::SeqCst);
+    }
+}
+
+impl Fault for StaleReadFault {
+    fn schedule(&self) -> &FaultSchedule {
+        &self.schedule
+    }
+
+    fn spec(&self) -> String {
+        format!(
+            "stale-read path={} occurence={}",
+            self.path_regex, self.occurence
+        )
+    }
+
+    fn save_state(&self) -> FaultState {
+        FaultState::from_counters(vec![
+            self.counter.load(Ordering::SeqCst) as u64,
+            self.triggered.load(Ordering::SeqCst) as u64,
+        ])
+    }
+
+    fn restore_state(&self, state: &FaultState) -> Result<()> {
+        let counters = state.expect_counters(2)?;
+        self.counter.store(counters[0] as i32, Ordering::SeqCst);
+        self.triggered.store(counters[1] != 0, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn status_detail(&self) -> Option<String> {
+        self.triggered
+            .load(Ordering::SeqCst)
+            .then(|| "serving stale reads".to_string())
+    }
+}
+
+/// Which half of a torn rename reaches the backing store before the crash
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RenameTear {
+    /// The destination is linked but the source is never unlinked, both names are visible
+    DestinationOnly,
+    /// The source is unlinked but the destination is never created, the file goes missing
+    SourceOnly,
+}
+
+/// Tears the `occurence`-th rename whose source or destination matches: only one half of it is
+/// applied to the backing store before LazyFS crashes with `mode`. Renames of directories are
+/// not counted, they can't be split into a link and an unlink.
+pub struct RenameTearFault {
+    path_regex: PathMatcher,
+    occurence: i32,
+    counter: AtomicI32,
+    tear: RenameTear,
+    mode: CrashMode,
+    schedule: FaultSchedule,
+}
+
+impl RenameTearFault {
+    pub fn new(path_regex: &str, occurence: i32, tear: RenameTear) -> Result<Self> {
+        Ok(RenameTearFault {
+            path_regex: PathMatcher::new(path_regex, MatchOptions::default())?,
+            occurence,
+            counter: AtomicI32::new(0),
+            tear,
+            mode: CrashMode::default(),
+            schedule: FaultSchedule::default(),
+        })
+    }
+
+    pub fn with_schedule(mut self, schedule: FaultSchedule) -> Self {
+        self.schedule = schedule;
+        self
+    }
+
+    /// Matches paths with `options` applied to both the pattern and the path
+    pub fn with_match_options(mut self, options: MatchOptions) -> Result<Self> {
+        self.path_regex = self.path_regex.with_options(options)?;
+        Ok(self)
+    }
+
+    pub fn with_mode(mut self, mode: CrashMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn mode(&self) -> CrashMode {
+        self.mode
+    }
+
+    /// Counts a rename of `from` to `to` and returns the half to apply if this is the rename the
+    /// fault targets
+    pub fn tear_for(&self, from: &Path, to: &Path) -> Option<RenameTear> {
+        if !self.path_regex.is_match(from) && !self.path_regex.is_match(to) {
+            return None;
+        }
+        if self.counter.fetch_add(1, Ordering::SeqCst) + 1 != self.occurence {
+            return None;
+        }
+        Some(self.tear)
+    }
+
+    /// Takes back the count of the last matching rename, so the next one is targeted again
+    pub fn release(&self) {
+        self.counter.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Fault for RenameTearFault {
+    fn schedule(&self) -> &FaultSchedule {
+        &self.schedule
+    }
+
+    fn spec(&self) -> String {
+        format!(
+            "rename-tear path={} occurence={} tear={:?} mode={:?}",
+            self.path_regex, self.occurence, self.tear, self.mode
+        )
+    }
+
+    fn save_state(&self) -> FaultState {
+        FaultState::from_counters(vec![self.counter.load(Ordering::SeqCst) as u64])
+    }
+
+    fn restore_state(&self, state: &FaultState) -> Result<()> {
+        let counters = state.expect_counters(1)?;
+        self.counter.store(counters[0] as i32, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+/// Holds every `op` on the path it is keyed by for `delay` before it goes on, as a slow disk
+/// would
+pub struct DelayFault {
+    op: String,
+    delay: Duration,
+    counter: AtomicI32,
+    schedule: FaultSchedule,
+}
+
+impl DelayFault {
+    pub fn new(op: &str, delay: Duration) -> Self {
+        DelayFault {
+            op: op.to_string(),
+            delay,
+            counter: AtomicI32::new(0),
+            schedule: FaultSchedule::default(),
+        }
+    }
+
+    pub fn with_schedule(mut self, schedule: FaultSchedule) -> Self {
+        self.schedule = schedule;
+        self
+    }
+
+    pub fn delay(&self) -> Duration {
+        self.delay
+    }
+}
+
+impl Fault for DelayFault {
+    fn schedule(&self) -> &FaultSchedule {
+        &self.schedule
+    }
+
+    fn op(&self) -> &str {
+        &self.op
+    }
+
+    fn count_op(&self) -> i32 {
+        self.counter.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    fn should_trigger(&self, _path: &Path, _op_count: i32) -> bool {
+        true
+    }
+
+    fn as_delay(&self) -> Option<&DelayFault> {
+        Some(self)
+    }
+
+    fn spec(&self) -> String {
+        format!("delay op={} delay={:?}", self.op, self.delay)
+    }
+
+    fn save_state(&self) -> FaultState {
+        FaultState::from_counters(vec![self.counter.load(Ordering::SeqCst) as u64])
+    }
+
+    fn restore_state(&self, state: &FaultState) -> Result<()> {
+        let counters = state.expect_counters(1)?;
+        self.counter.store(counters[0] as i32, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+/// What to do when the backing file was modified outside of the mount since the last sync.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExternalChangePolicy {
+    /// Trust the cached state unconditionally
+    #[default]
+    Ignore,
+    /// Log a divergence event but keep serving the cached state
+    Detect,
+    /// Log the divergence, drop the owner's clean blocks and refresh its metadata
+    Invalidate,
+}
+
+/// Where the owner a backing file is cached under comes from
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OwnerIdentity {
+    /// Whatever the caller of `Cache::insert_inode_mapping` passes
+    #[default]
+    Caller,
+    /// The device and inode of the backing file, see `OwnerKey`
+    Inode,
+}
+
+/// Which names a crash that drops unsynced data takes back, see `DirentJournal`
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DirentDurability {
+    /// Names stay as they are
+    #[default]
+    Off,
+    /// A created or renamed name only survives once its directory was fsynced
+    Strict,
+    /// As on ext4, where fsyncing the file also commits its name
+    Ext4Like,
+}
+
+/// What a write does when it would take the unsynced data past `Config::max_unsynced_bytes`
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum UnsyncedOverflowPolicy {
+    /// Wait until a sync makes room
+    #[default]
+    Block,
+    /// Sync the owners that have been dirty the longest until the write fits
+    Writeback,
+    /// Fail the write with EIO
+    Fail,
+}
+
+/// What a quota fault does to a write that no longer fits in the remaining budget
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QuotaMode {
+    /// Fail the whole write with ENOSPC
+    NoSpace,
+    /// Accept whatever still fits and return a short count, ENOSPC once nothing fits
+    ShortWrite,
+}
+
+/// Decision for a single write checked against the quota faults
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QuotaOutcome {
+    /// Accept this many bytes of the write (possibly fewer than requested)
+    Accept(usize),
+    NoSpace,
+}
+
+struct QuotaState {
+    consumed: u64,
+    owners: HashSet<String>,
+}
+
+/// Pretends the disk fills up once the bytes written to matching paths add up to `budget`.
+/// Owners that were charged once stay charged after being renamed to a non-matching path.
+pub struct QuotaFault {
+    path_regex: PathMatcher,
+    budget: u64,
+    mode: QuotaMode,
+    state: Mutex<QuotaState>,
+    schedule: FaultSchedule,
+}
+
+impl QuotaFault {
+    pub fn new(path_regex: &str, budget: u64, mode: QuotaMode) -> Result<Self> {
+        Ok(QuotaFault {
+            path_regex: PathMatcher::new(path_regex, MatchOptions::default())?,
+            budget,
+            mode,
+            state: Mutex::new(QuotaState {
+                consumed: 0,
+                owners: HashSet::new(),
+            }),
+            schedule: FaultSchedule::default(),
+        })
+    }
+
+    pub fn with_schedule(mut self, schedule: FaultSchedule) -> Self {
+        self.schedule = schedule;
+        self
+    }
+
+    /// Matches paths with `options` applied to both the pattern and the path
+    pub fn with_match_options(mut self, options: MatchOptions) -> Result<Self> {
+        self.path_regex = self.path_regex.with_options(options)?;
+        Ok(self)
+    }
+
+    /// How many of `len` bytes written by `owner` through `path` fit in the budget, or `None`
+    /// when the write is not covered by this quota
+    pub fn allowance(&self, path: &Path, owner: &str, len: usize) -> Result<Option<usize>> {
+        let state = self.lock_state()?;
+        if !self.path_regex.is_match(path) && !state.owners.contains(owner) {
+            return Ok(None);
         }
+
+        let remaining = self.budget.saturating_sub(state.consumed);
+        let allowed = match self.mode {
+            QuotaMode::NoSpace if len as u64 <= remaining => len,
+            QuotaMode::NoSpace => 0,
+            QuotaMode::ShortWrite => remaining.min(len as u64) as usize,
+        };
+        Ok(Some(allowed))
+    }
+
+    pub fn charge(&self, owner: &str, bytes: usize) -> Result<()> {
+        let mut state = self.lock_state()?;
+        state.consumed += bytes as u64;
+        state.owners.insert(owner.to_string());
+        Ok(())
+    }
+
+    pub fn consumed(&self) -> Result<u64> {
+        Ok(self.lock_state()?.consumed)
+    }
+
+    pub fn reset(&self) -> Result<()> {
+        let mut state = self.lock_state()?;
+        state.consumed = 0;
+        state.owners.clear();
+        Ok(())
+    }
+
+    fn lock_state(&self) -> Result<MutexGuard<'_, QuotaState>> {
+        self.state
+            .lock()
+            .map_err(|e| anyhow!("Unable to acquire lock on quota state: {:?}", e))
     }
 }
 
+impl Fault for QuotaFault {
+    fn schedule(&self) -> &FaultSchedule {
+        &self.schedule
+    }
+
+    fn spec(&self) -> String {
+        format!(
+            "quota path={} budget={} mode={:?}",
+            self.path_regex, self.budget, self.mode
+        )
+    }
+
+    fn save_state(&self) -> FaultState {
+        // No way to fail here, and a panic mid-charge leaves the counters consistent anyway
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let mut owners: Vec<_> = state.owners.iter().cloned().collect();
+        owners.sort();
+        FaultState {
+            counters: vec![state.consumed],
+            owners,
+        }
+    }
+
+    fn restore_state(&self, saved: &FaultState) -> Result<()> {
+        let counters = saved.expect_counters(1)?;
+        let mut state = self.lock_state()?;
+        state.consumed = counters[0];
+        state.owners = saved.owners.iter().cloned().collect();
+        Ok(())
+    }
+
+    fn status_detail(&self) -> Option<String> {
+        let consumed = self.consumed().ok()?;
+        Some(format!("{}/{} bytes", consumed, self.budget))
+    }
+}
+
+/// Simulated read latency, applied per block depending on whether it was served from the cache
+/// or had to come from the backing file. Durations are given in microseconds.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct LatencyConfig {
+    #[serde(default, rename = "hit_latency_us", deserialize_with = "micros")]
+    pub hit_latency: Duration,
+    #[serde(default, rename = "miss_latency_us", deserialize_with = "micros")]
+    pub miss_latency: Duration,
+    /// Upper bound of the extra delay added to each read, drawn from `seed`
+    #[serde(default, rename = "jitter_us", deserialize_with = "micros")]
+    pub jitter: Duration,
+    #[serde(default)]
+    pub seed: u64,
+    /// Checked in order, the first override whose regex matches the path wins
+    #[serde(default)]
+    pub overrides: Vec<LatencyOverride>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct LatencyOverride {
+    pub path_regex: String,
+    /// `case_insensitive` and `normalize`, applied to the regex and the path alike
+    #[serde(flatten)]
+    pub match_options: MatchOptions,
+    #[serde(default, rename = "hit_latency_us", deserialize_with = "micros")]
+    pub hit_latency: Duration,
+    #[serde(default, rename = "miss_latency_us", deserialize_with = "micros")]
+    pub miss_latency: Duration,
+}
+
+/// Behaviour of the files under a path, see `Config::path_policies`
+#[derive(Clone, Debug, Deserialize)]
+pub struct PathPolicy {
+    pub path_regex: String,
+    /// `case_insensitive` and `normalize`, applied to the regex and the path alike
+    #[serde(flatten)]
+    pub match_options: MatchOptions,
+    /// Files only ever read through the mount, such as SSTables. Their cached blocks are hashed
+    /// and kept over other blocks, and writes to them are refused unless
+    /// `reject_immutable_writes` is off. Sticks to the file when it is renamed.
+    #[serde(default)]
+    pub immutable: bool,
+}
+
+/// A fault declared in an `[[injection]]` table of the config file, see `Config::load_faults`
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum InjectionSpec {
+    /// The `occurrence`-th write to `file` is split into parts, of which only those numbered in
+    /// `persist` (from 1) reach the disk. The parts are either `parts` equal ones or sized by
+    /// `parts_bytes`. A `sector_torn` write instead keeps a prefix of whole disk sectors, and
+    /// takes no `persist`.
+    SplitWrite {
+        file: String,
+        occurrence: i32,
+        #[serde(default)]
+        parts: Option<i32>,
+        #[serde(default)]
+        parts_bytes: Option<Vec<i32>>,
+        #[serde(default)]
+        sector_torn: Option<TornSectors>,
+        #[serde(default)]
+        persist: Vec<i32>,
+    },
+    /// Of the `occurrence`-th group of consecutive `op`s on `file`, only those numbered in
+    /// `persist` (from 1) reach the disk
+    Reorder {
+        file: String,
+        op: String,
+        occurrence: i32,
+        persist: Vec<i32>,
+    },
+}
+
+impl InjectionSpec {
+    pub fn file(&self) -> &str {
+        match self {
+            InjectionSpec::SplitWrite { file, .. } | InjectionSpec::Reorder { file, .. } => file,
+        }
+    }
+
+    /// Builds the fault, failing on parameters it couldn't act on
+    pub fn to_fault(&self) -> Result<Arc<dyn Fault>> {
+        match self {
+            InjectionSpec::SplitWrite {
+                occurrence,
+                parts,
+                parts_bytes,
+                sector_torn,
+                persist,
+                ..
+            } => {
+                check_occurrence(*occurrence)?;
+                let fault = match (parts, parts_bytes, sector_torn) {
+                    (None, None, None) => {
+                        return Err(anyhow!("parts, parts_bytes or sector_torn is required"))
+                    }
+                    (Some(parts), None, None) => {
+                        if *parts < 1 {
+                            return Err(anyhow!("parts must be at least 1, got {}", parts));
+                        }
+                        check_persist(persist, Some(*parts))?;
+                        SplitWriteFault::from_parts(*occurrence, persist.clone(), *parts)
+                    }
+                    (None, Some(parts_bytes), None) => {
+                        if parts_bytes.is_empty() || parts_bytes.iter().any(|&len| len < 1) {
+                            return Err(anyhow!(
+                                "parts_bytes must list positive sizes, got {:?}",
+                                parts_bytes
+                            ));
+                        }
+                        check_persist(persist, Some(parts_bytes.len() as i32))?;
+                        SplitWriteFault::from_parts_bytes(
+                            *occurrence,
+                            persist.clone(),
+                            parts_bytes.clone(),
+                        )
+                    }
+                    (None, None, Some(sector_torn)) => {
+                        if !persist.is_empty() {
+                            return Err(anyhow!("sector_torn writes take no persist"));
+                        }
+                        SplitWriteFault::from_sectors(*occurrence, *sector_torn)
+                    }
+                    _ => {
+                        return Err(anyhow!(
+                            "give only one of parts, parts_bytes or sector_torn"
+                        ))
+                    }
+                };
+                Ok(Arc::new(fault))
+            }
+            InjectionSpec::Reorder {
+                op,
+                occurrence,
+                persist,
+                ..
+            } => {
+                check_occurrence(*occurrence)?;
+                if op.is_empty() {
+                    return Err(anyhow!("op is required"));
+                }
+                check_persist(persist, None)?;
+                Ok(Arc::new(ReorderFault::from_op(
+                    op.clone(),
+                    persist.clone(),
+                    *occurrence,
+                )))
+            }
+        }
+    }
+}
+
+fn check_occurrence(occurrence: i32) -> Result<()> {
+    if occurrence < 1 {
+        return Err(anyhow!("occurrence must be at least 1, got {}", occurrence));
+    }
+    Ok(())
+}
+
+/// Checks that `persist` numbers parts from 1 and, if the number of parts is known, stays
+/// within them
+fn check_persist(persist: &[i32], parts: Option<i32>) -> Result<()> {
+    if persist.is_empty() {
+        return Err(anyhow!("persist must list at least one part"));
+    }
+    for &index in persist {
+        let in_range = index >= 1 && parts.is_none_or(|parts| index <= parts);
+        if !in_range {
+            return Err(match parts {
+                Some(parts) => anyhow!(
+                    "persist index {} is out of range, there are {} parts",
+                    index,
+                    parts
+                ),
+                None => anyhow!(
+                    "persist index {} is out of range, parts count from 1",
+                    index
+                ),
+            });
+        }
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct Injections {
+    #[serde(default)]
+    injection: Vec<InjectionSpec>,
+}
+
+fn micros<'de, D>(deserializer: D) -> std::result::Result<Duration, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Ok(Duration::from_micros(u64::deserialize(deserializer)?))
+}
+
+fn clock_skew<'de, D>(deserializer: D) -> std::result::Result<ClockSkew, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(serde::de::Error::custom)
+}
+
 #[derive(Clone, Debug, Deserialize)]
 pub struct Config {
     pub log_all_operations: bool,
@@ -95,9 +1243,201 @@ pub struct Config {
     pub io_block_size: usize,
     pub disk_sector_size: usize,
     pub apply_lru_eviction: bool,
+    /// Which page makes room once the cache is full and `apply_lru_eviction` is on: `lru`,
+    /// `fifo` or `clock`
+    #[serde(default = "default_eviction_policy")]
+    pub eviction_policy: String,
+    /// How many of the latest evictions are kept for `Cache::eviction_history`
+    #[serde(default = "default_eviction_history_size")]
+    pub eviction_history_size: usize,
     pub fifo_path: PathBuf,
     pub fifo_path_completed: PathBuf,
+    /// Where the `fuse` feature mounts LazyFS
+    #[serde(default)]
+    pub mount_root: PathBuf,
+    /// Directory the mount passes through to, where files end up once synced
+    #[serde(default)]
+    pub backing_dir: PathBuf,
     pub log_file: PathBuf,
+    #[serde(default)]
+    pub external_change_policy: ExternalChangePolicy,
+    #[serde(default)]
+    pub latency: LatencyConfig,
+    /// Where fault counters are saved so a remount can pick up where the last one stopped
+    #[serde(default)]
+    pub fault_state_path: PathBuf,
+    /// Restore the state saved at `fault_state_path` on startup
+    #[serde(default)]
+    pub resume_faults: bool,
+    /// How often the fault state is saved to `fault_state_path` while mounted, in milliseconds.
+    /// With 0 it is only saved on shutdown.
+    #[serde(default)]
+    pub fault_state_save_interval_ms: u64,
+    /// Where a crash fault writes its report before it takes effect, none written when empty
+    #[serde(default)]
+    pub crash_report_path: PathBuf,
+    /// Refuse the writes of shared writable mmaps with EOPNOTSUPP, so msync fails and mapped
+    /// changes never reach the file. When disabled they go through, but the cache can no longer
+    /// vouch for the file's contents until its next fsync or close.
+    #[serde(default = "default_deny_mmap")]
+    pub deny_mmap: bool,
+    /// Run the cache round trip self-test when mounting
+    #[serde(default)]
+    pub self_test_on_start: bool,
+    /// Fail writes with ENOSPC when the cache can't hold every block instead of dropping the
+    /// blocks it has no room for
+    #[serde(default)]
+    pub strict_cache: bool,
+    /// Record the faults that would trigger instead of injecting them
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Whether a dry-run match counts towards the fault's occurence, as it would in a real run.
+    /// If not, the same fault keeps matching on every later candidate operation.
+    #[serde(default = "default_dry_run_consumes_occurences")]
+    pub dry_run_consumes_occurences: bool,
+    /// Most crash faults that can be registered at runtime
+    #[serde(default = "default_max_crash_faults")]
+    pub max_crash_faults: usize,
+    /// Longest a write fence holds before writes are let through again
+    #[serde(default = "default_fence_max_ms")]
+    pub fence_max_ms: u64,
+    /// Whether fenced writes wait or fail with EAGAIN
+    #[serde(default)]
+    pub fence_mode: FenceMode,
+    /// Most written bytes that can wait to be synced at once, like the volatile write cache of a
+    /// device. No limit when 0.
+    #[serde(default)]
+    pub max_unsynced_bytes: u64,
+    #[serde(default)]
+    pub unsynced_overflow_policy: UnsyncedOverflowPolicy,
+    /// Backoff after the first failed sync of an owner, doubled on each failure after that
+    #[serde(default = "default_sync_retry_base_ms")]
+    pub sync_retry_base_ms: u64,
+    /// Longest an owner waits between two sync attempts
+    #[serde(default = "default_sync_retry_max_ms")]
+    pub sync_retry_max_ms: u64,
+    /// Checked in order, the first policy whose regex matches a file's path applies to it
+    #[serde(default)]
+    pub path_policies: Vec<PathPolicy>,
+    /// Fail writes and truncates of immutable files with EROFS. When off they go through and the
+    /// written blocks are hashed again.
+    #[serde(default = "default_reject_immutable_writes")]
+    pub reject_immutable_writes: bool,
+    /// Share of cache hits on immutable files whose hash is checked again, from 0 to 1
+    #[serde(default)]
+    pub immutable_verify_rate: f64,
+    /// Most operations of any kind running at once, 0 for no limit. Those past it queue up
+    /// like on a device with a short queue.
+    #[serde(default)]
+    pub max_concurrent_ops: usize,
+    /// Most reads running at once on top of `max_concurrent_ops`, 0 for no limit
+    #[serde(default)]
+    pub max_concurrent_reads: usize,
+    /// Most writes running at once on top of `max_concurrent_ops`, 0 for no limit
+    #[serde(default)]
+    pub max_concurrent_writes: usize,
+    /// Most fsyncs running at once on top of `max_concurrent_ops`, 0 for no limit
+    #[serde(default)]
+    pub max_concurrent_fsyncs: usize,
+    /// Whether the syncs done by writes through `O_SYNC` and `O_DSYNC` handles go through the
+    /// crash faults of fsync, as they would if the application called it after each write
+    #[serde(default)]
+    pub sync_writes_match_fsync_faults: bool,
+    /// Hard links share an owner without the caller having to know with `inode`
+    #[serde(default)]
+    pub owner_identity: OwnerIdentity,
+    /// Offset of the times stamped into file metadata from the real time, like `-90s`, to
+    /// test how applications take timestamps going backwards. Changed later with
+    /// `lazyfs::clock-skew`.
+    #[serde(default, deserialize_with = "clock_skew")]
+    pub clock_skew: ClockSkew,
+    /// Whether clearing the cache also takes back names whose directory was never fsynced:
+    /// `off`, `strict` or `ext4-like`
+    #[serde(default)]
+    pub dirent_durability: DirentDurability,
+    /// Report the cache's capacity and free pages as the size and free blocks of the mount
+    /// instead of those of the backing file system, so `df` shows how full the cache is
+    #[serde(default)]
+    pub statfs_reports_cache: bool,
+    /// Whether a clean shutdown, on SIGTERM, SIGINT or unmount, syncs the data still in the
+    /// cache. If not, it is lost just like in a crash.
+    #[serde(default = "default_sync_on_shutdown")]
+    pub sync_on_shutdown: bool,
+    /// Engine behind the cache: `custom`, `memory`, `spill` to spill pages past
+    /// `cache_nr_pages` to disk, or `passthrough` to cache nothing and measure LazyFS against
+    /// the bare backing file system
+    #[serde(default = "default_engine")]
+    pub engine: String,
+    /// File the `spill` engine writes the pages it can't keep in memory to
+    #[serde(default)]
+    pub spill_path: PathBuf,
+    /// Most pages the `spill` engine spills, on top of the `cache_nr_pages` kept in memory
+    #[serde(default)]
+    pub spill_max_pages: usize,
+    /// Where the `custom` engine keeps its pages: `heap`, a buffer for each, or `mmap`, a
+    /// single region mapped at startup, which needs the `mmap` feature
+    #[serde(default = "default_page_storage")]
+    pub page_storage: String,
+    /// File behind the `mmap` region, anonymous memory if empty
+    #[serde(default)]
+    pub page_storage_path: PathBuf,
+    /// Locks the `custom` engine splits its owners across, so different files are cached in
+    /// parallel. Each shard evicts its own pages first, so with more than one the eviction
+    /// order is only followed within a shard.
+    #[serde(default = "default_engine_shards")]
+    pub engine_shards: usize,
+}
+
+fn default_eviction_policy() -> String {
+    "lru".to_string()
+}
+
+fn default_eviction_history_size() -> usize {
+    256
+}
+
+fn default_deny_mmap() -> bool {
+    true
+}
+
+fn default_dry_run_consumes_occurences() -> bool {
+    true
+}
+
+fn default_max_crash_faults() -> usize {
+    10000
+}
+
+fn default_fence_max_ms() -> u64 {
+    30000
+}
+
+fn default_sync_retry_base_ms() -> u64 {
+    100
+}
+
+fn default_sync_retry_max_ms() -> u64 {
+    60000
+}
+
+fn default_reject_immutable_writes() -> bool {
+    true
+}
+
+fn default_sync_on_shutdown() -> bool {
+    true
+}
+
+fn default_engine() -> String {
+    "custom".to_string()
+}
+
+fn default_page_storage() -> String {
+    "heap".to_string()
+}
+
+fn default_engine_shards() -> usize {
+    8
 }
 
 impl Config {
@@ -165,6 +1505,31 @@ impl Config {
 
         Ok(config)
     }
+
+    /// Builds the faults declared in the `[[injection]]` tables of `filename`, keyed by the file
+    /// they target as `LazyFSBuilder::faults` takes them
+    pub fn load_faults(filename: &str) -> Result<HashMap<String, Vec<Arc<dyn Fault>>>> {
+        let mut file = File::open(filename)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+
+        Self::faults_from_str(&contents)
+    }
+
+    fn faults_from_str(contents: &str) -> Result<HashMap<String, Vec<Arc<dyn Fault>>>> {
+        let injections: Injections = toml::from_str(contents)?;
+        let mut faults: HashMap<String, Vec<Arc<dyn Fault>>> = HashMap::new();
+        for (i, spec) in injections.injection.iter().enumerate() {
+            let fault = spec
+                .to_fault()
+                .map_err(|e| anyhow!("Invalid injection #{} on {}: {}", i + 1, spec.file(), e))?;
+            faults
+                .entry(spec.file().to_string())
+                .or_default()
+                .push(fault);
+        }
+        Ok(faults)
+    }
 }
 
 impl Default for Config {
@@ -177,9 +1542,310 @@ impl Default for Config {
             io_block_size: 4096,
             disk_sector_size: 512,
             apply_lru_eviction: false,
+            eviction_policy: default_eviction_policy(),
+            eviction_history_size: default_eviction_history_size(),
             fifo_path: "faults.fifo".to_string().into(),
             fifo_path_completed: "".to_string().into(),
+            mount_root: PathBuf::new(),
+            backing_dir: PathBuf::new(),
             log_file: "".to_string().into(),
+            external_change_policy: ExternalChangePolicy::default(),
+            latency: LatencyConfig::default(),
+            fault_state_path: PathBuf::new(),
+            resume_faults: false,
+            fault_state_save_interval_ms: 0,
+            crash_report_path: PathBuf::new(),
+            deny_mmap: default_deny_mmap(),
+            self_test_on_start: false,
+            strict_cache: false,
+            dry_run: false,
+            dry_run_consumes_occurences: default_dry_run_consumes_occurences(),
+            max_crash_faults: default_max_crash_faults(),
+            fence_max_ms: default_fence_max_ms(),
+            fence_mode: FenceMode::default(),
+            max_unsynced_bytes: 0,
+            unsynced_overflow_policy: UnsyncedOverflowPolicy::default(),
+            sync_retry_base_ms: default_sync_retry_base_ms(),
+            sync_retry_max_ms: default_sync_retry_max_ms(),
+            path_policies: Vec::new(),
+            reject_immutable_writes: default_reject_immutable_writes(),
+            immutable_verify_rate: 0.0,
+            max_concurrent_ops: 0,
+            max_concurrent_reads: 0,
+            max_concurrent_writes: 0,
+            max_concurrent_fsyncs: 0,
+            sync_writes_match_fsync_faults: false,
+            owner_identity: OwnerIdentity::default(),
+            clock_skew: ClockSkew::default(),
+            dirent_durability: DirentDurability::default(),
+            statfs_reports_cache: false,
+            sync_on_shutdown: default_sync_on_shutdown(),
+            engine: default_engine(),
+            spill_path: PathBuf::new(),
+            spill_max_pages: 0,
+            page_storage: default_page_storage(),
+            page_storage_path: PathBuf::new(),
+            engine_shards: default_engine_shards(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::path_matcher::Normalization;
+
+    #[test]
+    fn sector_torn_write_keeps_whole_sectors() {
+        // A 2000 byte write at offset 300 touches sectors 0..=4 (bytes 0..2560)
+        let len = |sectors| {
+            SplitWriteFault::from_sectors(1, TornSectors::Fixed(sectors))
+                .sector_torn_len(300, 2000, 512)
+                .unwrap()
+        };
+        assert_eq!(len(0), 0);
+        // The partially covered first sector persists as a whole: bytes 300..512
+        assert_eq!(len(1), 212);
+        assert_eq!(len(2), 724);
+        assert_eq!(len(5), 2000);
+        // More sectors than the write touches keep all of it
+        assert_eq!(len(9), 2000);
+
+        // An aligned write keeps exactly the sectors asked for
+        let aligned = SplitWriteFault::from_sectors(1, TornSectors::Fixed(2));
+        assert_eq!(aligned.sector_torn_len(1024, 2048, 512), Some(1024));
+
+        let seeded = SplitWriteFault::from_sectors(1, TornSectors::Seeded(42));
+        let survived = seeded.sector_torn_len(300, 2000, 512).unwrap();
+        assert!(survived < 2000);
+        assert!(survived == 0 || (300 + survived) % 512 == 0);
+        // The same seed and counter always tear at the same sector
+        assert_eq!(seeded.sector_torn_len(300, 2000, 512), Some(survived));
+        assert_eq!(
+            SplitWriteFault::from_sectors(1, TornSectors::Seeded(42))
+                .sector_torn_len(300, 2000, 512),
+            Some(survived)
+        );
+
+        // No sectors to tear at
+        assert_eq!(aligned.sector_torn_len(300, 2000, 0), None);
+        assert_eq!(
+            SplitWriteFault::from_parts(1, vec![1], 2).sector_torn_len(300, 2000, 512),
+            None
+        );
+    }
+
+    #[test]
+    fn split_write_parts() {
+        let parts = |fault: SplitWriteFault, len| fault.persisted_parts(len);
+        assert_eq!(
+            parts(SplitWriteFault::from_parts(1, vec![3, 1], 3), 10),
+            vec![(1, 0..4), (3, 7..10)]
+        );
+        // Fewer bytes than parts leaves the last parts empty
+        assert_eq!(
+            parts(SplitWriteFault::from_parts(1, vec![1, 2, 3], 3), 2),
+            vec![(1, 0..1), (2, 1..2)]
+        );
+        // Bytes past the listed parts are never persisted, parts past the write are cut
+        let by_bytes = |persist| SplitWriteFault::from_parts_bytes(1, persist, vec![4, 4]);
+        assert_eq!(parts(by_bytes(vec![2, 1]), 12), vec![(1, 0..4), (2, 4..8)]);
+        assert_eq!(parts(by_bytes(vec![2]), 6), vec![(2, 4..6)]);
+        assert_eq!(parts(by_bytes(vec![2, 5]), 3), vec![]);
+    }
+
+    #[test]
+    fn scheduled_fault_window_transitions() {
+        let schedule = FaultSchedule {
+            active_after_ops: Some(10),
+            active_for_ops: Some(5),
+            ..Default::default()
+        };
+        let at = |ops| schedule.window_at(ops, Duration::ZERO);
+        assert_eq!(
+            at(9),
+            FaultWindow::Pending {
+                ops_left: Some(1),
+                time_left: None
+            }
+        );
+        assert_eq!(
+            at(10),
+            FaultWindow::Active {
+                ops_left: Some(5),
+                time_left: None
+            }
+        );
+        assert_eq!(
+            at(14),
+            FaultWindow::Active {
+                ops_left: Some(1),
+                time_left: None
+            }
+        );
+        assert_eq!(at(15), FaultWindow::Expired);
+
+        let schedule = FaultSchedule {
+            active_after: Some(Duration::from_secs(30)),
+            active_for: Some(Duration::from_secs(10)),
+            ..Default::default()
+        };
+        let at = |secs| schedule.window_at(0, Duration::from_secs(secs));
+        assert_eq!(
+            at(29),
+            FaultWindow::Pending {
+                ops_left: None,
+                time_left: Some(Duration::from_secs(1))
+            }
+        );
+        assert_eq!(
+            at(30),
+            FaultWindow::Active {
+                ops_left: None,
+                time_left: Some(Duration::from_secs(10))
+            }
+        );
+        assert_eq!(at(40), FaultWindow::Expired);
+    }
+
+    #[test]
+    fn faults_from_injection_tables() {
+        let path =
+            std::env::temp_dir().join(format!("lazyfs-rs-{}-faults.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+            cache_nr_pages = 5
+
+            [[injection]]
+            type = "split_write"
+            file = "/data/wal"
+            occurrence = 2
+            parts = 3
+            persist = [1, 3]
+
+            [[injection]]
+            type = "reorder"
+            file = "/data/wal"
+            op = "write"
+            occurrence = 1
+            persist = [2]
+
+            [[injection]]
+            type = "split_write"
+            file = "/data/sst"
+            occurrence = 1
+            parts_bytes = [4096, 100]
+            persist = [2]
+
+            [[injection]]
+            type = "split_write"
+            file = "/data/log"
+            occurrence = 3
+            sector_torn = { fixed = 2 }
+            "#,
+        )
+        .unwrap();
+        let faults = Config::load_faults(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let specs = |file: &str| -> Vec<String> { faults[file].iter().map(|f| f.spec()).collect() };
+        assert_eq!(faults.len(), 3);
+        assert_eq!(
+            specs("/data/wal"),
+            vec![
+                "split-write occurence=2 persist=[1, 3] parts=3 parts_bytes=[] sector_torn=None",
+                "reorder op=write occurence=1 persist=[2]",
+            ]
+        );
+        assert_eq!(
+            specs("/data/sst"),
+            vec!["split-write occurence=1 persist=[2] parts=0 parts_bytes=[4096, 100] sector_torn=None"]
+        );
+        assert_eq!(
+            specs("/data/log"),
+            vec!["split-write occurence=3 persist=[] parts=0 parts_bytes=[] sector_torn=Some(Fixed(2))"]
+        );
+        assert!(Config::faults_from_str("cache_nr_pages = 5")
+            .unwrap()
+            .is_empty());
+
+        let err = |injection: &str| {
+            let toml = format!("[[injection]]\nfile = \"/f\"\n{}", injection);
+            Config::faults_from_str(&toml).err().unwrap().to_string()
+        };
+        assert_eq!(
+            err("type = \"split_write\"\noccurrence = 1\nparts = 2\nparts_bytes = [1]\npersist = [1]"),
+            "Invalid injection #1 on /f: give only one of parts, parts_bytes or sector_torn"
+        );
+        assert_eq!(
+            err("type = \"split_write\"\noccurrence = 1\nsector_torn = { seeded = 7 }\npersist = [1]"),
+            "Invalid injection #1 on /f: sector_torn writes take no persist"
+        );
+        assert_eq!(
+            err("type = \"split_write\"\noccurrence = 1\nparts = 2\npersist = [3]"),
+            "Invalid injection #1 on /f: persist index 3 is out of range, there are 2 parts"
+        );
+        assert_eq!(
+            err("type = \"reorder\"\nop = \"write\"\noccurrence = 0\npersist = [1]"),
+            "Invalid injection #1 on /f: occurrence must be at least 1, got 0"
+        );
+        assert!(err("type = \"tear\"\noccurrence = 1").contains("tear"));
+    }
+
+    #[test]
+    fn latency_config_from_toml() {
+        let config: Config = toml::from_str(
+            r#"
+            log_all_operations = false
+            is_default_config = false
+            cache_nr_pages = 5
+            cache_page_size = 4096
+            io_block_size = 4096
+            disk_sector_size = 512
+            apply_lru_eviction = false
+            eviction_policy = "clock"
+            clock_skew = "-90s"
+            fifo_path = "faults.fifo"
+            fifo_path_completed = ""
+            log_file = ""
+
+            [latency]
+            miss_latency_us = 2000
+            seed = 7
+
+            [[latency.overrides]]
+            path_regex = "\\.sst$"
+            hit_latency_us = 5
+
+            [[latency.overrides]]
+            path_regex = "wal"
+            case_insensitive = true
+            normalize = "nfc"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.eviction_policy, "clock");
+        assert_eq!(config.clock_skew.to_string(), "-90s");
+        assert_eq!(config.latency.hit_latency, Duration::ZERO);
+        assert_eq!(config.latency.miss_latency, Duration::from_millis(2));
+        assert_eq!(config.latency.seed, 7);
+        assert_eq!(config.latency.overrides[0].path_regex, "\\.sst$");
+        assert_eq!(
+            config.latency.overrides[0].match_options,
+            MatchOptions::default()
+        );
+        assert_eq!(
+            config.latency.overrides[1].match_options,
+            MatchOptions {
+                case_insensitive: true,
+                normalize: Normalization::Nfc,
+            }
+        );
+        assert_eq!(
+            config.latency.overrides[0].hit_latency,
+            Duration::from_micros(5)
+        );
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::HashSet;
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+
+use crate::pagecache::config::DirentDurability;
+
+/// A name that appeared in the backing file system, or a directory that left it
+#[derive(Clone, Debug, PartialEq)]
+pub enum DirentChange {
+    /// A created file or a new hard link
+    Create {
+        path: PathBuf,
+    },
+    Mkdir {
+        path: PathBuf,
+    },
+    /// A removed directory, recreated empty with `mode` if rolled back
+    Rmdir {
+        path: PathBuf,
+        mode: u32,
+    },
+    Rename {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    /// Two names swapped by a `RENAME_EXCHANGE` rename
+    Exchange {
+        first: PathBuf,
+        second: PathBuf,
+    },
+}
+
+impl DirentChange {
+    /// Directories whose fsync makes the change durable
+    fn dirs(&self) -> HashSet<PathBuf> {
+        let paths = match self {
+            DirentChange::Create { path }
+            | DirentChange::Mkdir { path }
+            | DirentChange::Rmdir { path, .. } => vec![path],
+            DirentChange::Rename { from, to } => vec![from, to],
+            DirentChange::Exchange { first, second } => vec![first, second],
+        };
+        paths
+            .into_iter()
+            .filter_map(|path| path.parent())
+            .map(Path::to_path_buf)
+            .collect()
+    }
+
+    /// Takes the change back in the backing file system. A name already gone is left alone,
+    /// and a file a rename replaced stays lost. A directory goes with everything in it, none of
+    /// which could outlive it.
+    pub fn undo(&self) -> io::Result<()> {
+        let res = match self {
+            DirentChange::Create { path } => fs::remove_file(path),
+            DirentChange::Mkdir { path } => fs::remove_dir_all(path),
+            DirentChange::Rmdir { path, mode } => {
+                match fs::DirBuilder::new().mode(*mode).create(path) {
+                    Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+                    res => res,
+                }
+            }
+            DirentChange::Rename { from, to } => fs::rename(to, from),
+            DirentChange::Exchange { first, second } => exchange(first, second),
+        };
+        match res {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            res => res,
+        }
+    }
+}
+
+/// Swaps the files at `first` and `second` in one step, both of which must exist
+pub fn exchange(first: &Path, second: &Path) -> io::Result<()> {
+    let c_path = |path: &Path| {
+        CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    };
+    let (first, second) = (c_path(first)?, c_path(second)?);
+    let res = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            first.as_ptr(),
+            libc::AT_FDCWD,
+            second.as_ptr(),
+            libc::RENAME_EXCHANGE,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+struct PendingDirent {
+    change: DirentChange,
+    /// Owner of the file the name points to, whose fsync commits the name under `Ext4Like`
+    owner: String,
+    /// Directories still to be fsynced
+    dirs: HashSet<PathBuf>,
+}
+
+/// Names created, renamed or removed since their directories were last fsynced, oldest first
+#[derive(Debug, Default)]
+pub struct DirentJournal {
+    pending: Vec<PendingDirent>,
+}
+
+impl DirentJournal {
+    pub fn record(&mut self, change: DirentChange, owner: &str) {
+        let dirs = change.dirs();
+        self.pending.push(PendingDirent {
+            change,
+            owner: owner.to_string(),
+            dirs,
+        });
+    }
+
+    pub fn dir_synced(&mut self, dir: &Path) {
+        for pending in &mut self.pending {
+            pending.dirs.remove(dir);
+        }
+        self.pending.retain(|pending| !pending.dirs.is_empty());
+    }
+
+    pub fn owner_synced(&mut self, owner: &str, durability: DirentDurability) {
+        if durability == DirentDurability::Ext4Like {
+            self.pending.retain(|pending| pending.owner != owner);
+        }
+    }
+
+    pub fn owner_renamed(&mut self, old_owner: &str, new_owner: &str) {
+        for pending in &mut self.pending {
+            if pending.owner == old_owner {
+                pending.owner = new_owner.to_string();
+            }
+        }
+    }
+
+    /// Everything was synced, directories included
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+
+    pub fn pending(&self) -> Vec<DirentChange> {
+        self.pending.iter().map(|p| p.change.clone()).collect()
+    }
+
+    /// Empties the journal, newest change first so they can be undone in order
+    pub fn take_newest_first(&mut self) -> Vec<DirentChange> {
+        self.pending.drain(..).rev().map(|p| p.change).collect()
+    }
+}
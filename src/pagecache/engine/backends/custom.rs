@@ -1,234 +1,719 @@
+use crate::clock::{Clock, SystemClock};
+use crate::lock_diag::{RwLock, RwLockAt};
 use crate::pagecache::config::Config;
+use crate::pagecache::engine::eviction::{
+    eviction_policy, EvictionHistory, EvictionPolicy, EvictionReason, EvictionRecord,
+};
 use crate::pagecache::engine::page::Page;
+use crate::pagecache::engine::page_storage::allocate_pages;
 use crate::pagecache::engine::{AllocateOperationType, PageCacheEngine};
-use crate::pagecache::{BlockId, Offsets, PageId};
+use crate::pagecache::stats::CacheStats;
+use crate::pagecache::{BlockId, Offsets, OwnerId, PageId};
+use crate::TRACING_TARGET;
 use anyhow::{anyhow, Result};
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
 use std::fs::OpenOptions;
-use std::io::{Cursor, Seek, SeekFrom, Write};
-use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::hash::{Hash, Hasher};
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use tracing::warn;
 
 pub type PageSynced = bool;
 
-#[derive(Debug)]
+/// Pages free for any owner to take
+type FreePages = Vec<(PageId, Box<Page>)>;
+
+/// What became of a block given to `CustomCacheEngine::allocate_blocks_with_outcomes`. Only an
+/// allocated block left anything behind in the engine.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AllocateOutcome {
+    /// Cached in the page
+    Allocated(PageId),
+    /// Its data doesn't fit inside an IO block from the offset it starts at
+    TooLarge,
+    /// No page could take it, even by evicting one
+    NoFreePage,
+    /// Its page refused the data
+    WriteFailed,
+}
+
+impl AllocateOutcome {
+    /// The page the block went to, -1 if none, as `PageCacheEngine::allocate_blocks` reports it
+    pub fn page_id(&self) -> PageId {
+        match self {
+            AllocateOutcome::Allocated(page_id) => *page_id,
+            _ => -1,
+        }
+    }
+}
+
+/// Owners are hashed to one of `engine_shards` shards, each behind a lock of its own, so owners
+/// of different shards cache and read in parallel. A page is either free, or held by the shard
+/// of its owner.
 pub struct CustomCacheEngine {
     config: Box<Config>,
-    data: RwLock<CustomCacheEngineInner>,
+    shards: Vec<RwLock<CustomCacheEngineInner>>,
+    /// Only ever locked last, after the lock of a shard if any
+    free_pages: Mutex<FreePages>,
+    evictions: EvictionHistory,
+    /// Evictions `drain_evictions` hasn't handed out yet
+    undrained_evictions: Mutex<Vec<EvictionRecord>>,
+    /// Counters of `stats`
+    evicted_pages: AtomicU64,
+    bytes_written_back: AtomicU64,
+    /// Stamps evictions, `SystemClock` unless `set_clock` gave another
+    clock: Mutex<Arc<dyn Clock>>,
+}
+
+impl fmt::Debug for CustomCacheEngine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CustomCacheEngine")
+            .field("config", &self.config)
+            .field("shards", &self.shards)
+            .field("free_pages", &self.free_pages)
+            .field("evictions", &self.evictions)
+            .field("undrained_evictions", &self.undrained_evictions)
+            .field("evicted_pages", &self.evicted_pages)
+            .field("bytes_written_back", &self.bytes_written_back)
+            .finish_non_exhaustive()
+    }
 }
 
+/// A shard. The pages its owners hold only live in `search_index` and are changed in place under
+/// the write lock, every other map refers to them by id.
 #[derive(Debug)]
 pub(crate) struct CustomCacheEngineInner {
     search_index: HashMap<i32, Box<Page>>,
-    free_pages: Vec<i32>,
-    owner_pages_mapping: HashMap<String, HashSet<i32>>,
-    owner_ordered_pages_mapping:
-        HashMap<String, HashMap<BlockId, (PageId, Box<Page>, Offsets, PageSynced)>>,
-    owner_free_pages_mapping: HashMap<String, Vec<i32>>,
-
-    lru_main_vector: VecDeque<i32>,
-    page_order_mapping: HashMap<i32, i32>,
+    owner_pages_mapping: HashMap<OwnerId, HashSet<i32>>,
+    owner_ordered_pages_mapping: HashMap<OwnerId, HashMap<BlockId, (PageId, Offsets, PageSynced)>>,
+    owner_free_pages_mapping: HashMap<OwnerId, Vec<i32>>,
+
+    /// Picks the page of the shard to evict once there are no free ones left
+    eviction: Box<dyn EvictionPolicy>,
+    /// Owners whose pages are evicted last
+    retained_owners: HashSet<OwnerId>,
+    /// Pages evicted from other shards for an owner of this one, owned by no one until it takes
+    /// them
+    reclaimed: Vec<PageId>,
 }
 
 impl CustomCacheEngineInner {
-    pub fn new() -> Self {
+    pub fn new(eviction: Box<dyn EvictionPolicy>) -> Self {
         CustomCacheEngineInner {
             search_index: HashMap::new(),
-            free_pages: Vec::new(),
             owner_pages_mapping: HashMap::new(),
             owner_ordered_pages_mapping: HashMap::new(),
             owner_free_pages_mapping: HashMap::new(),
 
-            lru_main_vector: VecDeque::new(),
-            page_order_mapping: HashMap::new(),
+            eviction,
+            retained_owners: HashSet::new(),
+            reclaimed: Vec::new(),
         }
     }
+
+    /// Pages holding blocks that haven't been synced yet, whatever their own flag says
+    fn unsynced_pages(&self) -> HashSet<PageId> {
+        self.owner_ordered_pages_mapping
+            .values()
+            .flat_map(|blocks| blocks.values())
+            .filter(|&&(_, _, synced)| !synced)
+            .map(|&(page_id, ..)| page_id)
+            .collect()
+    }
 }
 
 impl CustomCacheEngine {
-    pub fn new(config: Box<Config>) -> Self {
-        CustomCacheEngine {
+    /// Engine with all of `cache_nr_pages` allocated up front and free, where `page_storage`
+    /// says. Fails if the page size isn't a multiple of the IO block size, or the eviction policy
+    /// or page storage is unknown.
+    pub fn new(config: Box<Config>) -> Result<Self> {
+        let shards = (0..config.engine_shards.max(1))
+            .map(|_| {
+                let eviction = eviction_policy(&config.eviction_policy)?;
+                Ok(RwLock::new(CustomCacheEngineInner::new(eviction)))
+            })
+            .collect::<Result<_>>()?;
+        let mut free_pages = Vec::with_capacity(config.cache_nr_pages);
+        for (page_id, data) in allocate_pages(&config)?.into_iter().enumerate() {
+            let page = Page::with_storage(config.clone(), data)?;
+            free_pages.push((page_id as PageId, Box::new(page)));
+        }
+
+        Ok(CustomCacheEngine {
+            evictions: EvictionHistory::new(config.eviction_history_size),
+            undrained_evictions: Mutex::new(Vec::new()),
+            evicted_pages: AtomicU64::new(0),
+            bytes_written_back: AtomicU64::new(0),
             config,
-            data: RwLock::new(CustomCacheEngineInner::new()),
+            shards,
+            free_pages: Mutex::new(free_pages),
+            clock: Mutex::new(Arc::new(SystemClock)),
+        })
+    }
+
+    fn shard_index(&self, owner: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        owner.hash(&mut hasher);
+        (hasher.finish() % self.shards.len() as u64) as usize
+    }
+
+    fn shard(&self, owner: &str) -> &RwLock<CustomCacheEngineInner> {
+        &self.shards[self.shard_index(owner)]
+    }
+
+    fn free_pages(&self) -> Result<MutexGuard<'_, FreePages>> {
+        self.free_pages
+            .lock()
+            .map_err(|e| anyhow!("Failed to acquire free pages lock: {:?}", e))
+    }
+
+    /// Takes `page_id` out of the shard, emptied, and makes it free for any owner
+    fn release_page(&self, inner: &mut CustomCacheEngineInner, page_id: PageId) -> Result<()> {
+        inner.eviction.remove(page_id);
+        if let Some(mut page) = inner.search_index.remove(&page_id) {
+            page.reset();
+            page.change_owner("none".into());
+            self.free_pages()?.push((page_id, page));
         }
+        Ok(())
     }
 
-    fn get_page_ptr_read(
+    /// `PageCacheEngine::allocate_blocks`, telling why a block wasn't cached. A block that
+    /// fails does so on its own, the rest of the batch still goes through.
+    pub fn allocate_blocks_with_outcomes(
         &self,
-        data: &RwLockReadGuard<CustomCacheEngineInner>,
-        page_id: PageId,
-    ) -> Option<Box<Page>> {
-        data.search_index.get(&page_id).cloned()
+        content_owner_id: OwnerId,
+        block_data_mapping: HashMap<BlockId, (PageId, &Vec<u8>, i32)>,
+        operation_type: AllocateOperationType,
+    ) -> Result<HashMap<BlockId, AllocateOutcome>> {
+        self.allocate_blocks_sharded(content_owner_id, block_data_mapping, operation_type)
     }
 
-    fn get_page_ptr_write(
+    /// Caches blocks of `content_owner_id` in its shard. Blocks that find no page there, even by
+    /// evicting one of the shard's own, get another go once pages of the other shards were
+    /// evicted for them.
+    fn allocate_blocks_sharded(
         &self,
-        data: &RwLockWriteGuard<CustomCacheEngineInner>,
-        page_id: PageId,
-    ) -> Option<Box<Page>> {
-        data.search_index.get(&page_id).cloned()
+        content_owner_id: OwnerId,
+        block_data_mapping: HashMap<BlockId, (PageId, &Vec<u8>, i32)>,
+        operation_type: AllocateOperationType,
+    ) -> Result<HashMap<BlockId, AllocateOutcome>> {
+        let shard = self.shard(&content_owner_id);
+        let mut allocated = {
+            let mut lock = shard
+                .write_at("engine::allocate_blocks/shard")
+                .map_err(|e| anyhow!("Failed to acquire write lock on shard: {:?}", e))?;
+            self.allocate_blocks_locked(
+                &mut lock,
+                content_owner_id.clone(),
+                &block_data_mapping,
+                operation_type,
+                false,
+            )?
+        };
+        let starved: Vec<_> = allocated
+            .iter()
+            .filter(|(_, &outcome)| outcome == AllocateOutcome::NoFreePage)
+            .map(|(&block_id, _)| block_id)
+            .collect();
+        if starved.is_empty() {
+            return Ok(allocated);
+        }
+
+        let retry = starved
+            .iter()
+            .map(|block_id| (*block_id, block_data_mapping[block_id]))
+            .collect();
+        let retried =
+            self.retry_with_reclaimed(&content_owner_id, starved.len(), true, |inner| {
+                self.allocate_blocks_locked(
+                    inner,
+                    content_owner_id.clone(),
+                    &retry,
+                    operation_type,
+                    true,
+                )
+            })?;
+        allocated.extend(retried);
+        Ok(allocated)
     }
 
-    fn get_next_free_page(
+    /// Returns what became of each block
+    fn allocate_blocks_locked(
         &self,
-        lock: &mut RwLockWriteGuard<CustomCacheEngineInner>,
-        owner_id: String,
-    ) -> Result<(PageId, Option<Box<Page>>)> {
-        // Check if this owner has space left in their pages
-        if let Some(free_pages) = lock.owner_free_pages_mapping.get_mut(&owner_id) {
-            if let Some(&free_page) = free_pages.last() {
-                free_pages.pop();
-                let page = self.get_page_ptr_write(&lock, free_page);
-                return Ok((free_page, page));
+        inner: &mut CustomCacheEngineInner,
+        content_owner_id: OwnerId,
+        block_data_mapping: &HashMap<BlockId, (PageId, &Vec<u8>, i32)>,
+        operation_type: AllocateOperationType,
+        evict_retained: bool,
+    ) -> Result<HashMap<BlockId, AllocateOutcome>> {
+        let mut res_block_allocated_pages = HashMap::new();
+
+        for (&block_id, &(page_id, blk_data, offset_start)) in block_data_mapping {
+            // Reject entries that can't fit in a block before any page is touched, so a single
+            // bad entry fails on its own instead of taking the rest of the batch down with it
+            if offset_start < 0
+                || offset_start as usize + blk_data.len() > self.config.io_block_size
+            {
+                warn!(
+                    target: TRACING_TARGET,
+                    owner = %content_owner_id,
+                    block_id,
+                    offset_start,
+                    len = blk_data.len(),
+                    "rejecting block data that does not fit in an IO block"
+                );
+                res_block_allocated_pages.insert(block_id, AllocateOutcome::TooLarge);
+                continue;
             }
-        }
 
-        // Otherwise, get an empty page
-        if let Some(&last_index) = lock.free_pages.last() {
-            lock.free_pages.pop();
-            let page = self.get_page_ptr_write(&lock, last_index);
-            return Ok((last_index, page));
+            if page_id >= 0 {
+                if let Some(page) = inner.search_index.get_mut(&page_id) {
+                    if page.is_page_owner(&content_owner_id) && page.contains_block(block_id) {
+                        // The cached copy is at least as new as the backing file, and may hold
+                        // writes that haven't been synced yet
+                        if operation_type == AllocateOperationType::OpPassthrough {
+                            res_block_allocated_pages
+                                .insert(block_id, AllocateOutcome::Allocated(page_id));
+                            continue;
+                        }
+                        if let Err(e) =
+                            page.update_block_data(block_id, blk_data, offset_start as usize)
+                        {
+                            warn!(
+                                target: TRACING_TARGET,
+                                owner = %content_owner_id,
+                                block_id,
+                                "failed to update cached block: {:?}",
+                                e
+                            );
+                            res_block_allocated_pages
+                                .insert(block_id, AllocateOutcome::WriteFailed);
+                            continue;
+                        }
+                        res_block_allocated_pages
+                            .insert(block_id, AllocateOutcome::Allocated(page_id));
+
+                        let offs = page.allocated_block_ids.get_block_offsets(block_id);
+                        self.update_owner_pages(
+                            inner,
+                            content_owner_id.clone(),
+                            page_id,
+                            block_id,
+                            offs,
+                            false,
+                        )?;
+
+                        continue;
+                    }
+                }
+            }
+
+            let free_page_id =
+                self.get_next_free_page(inner, &content_owner_id, true, evict_retained)?;
+            if free_page_id >= 0 {
+                if let Some(page) = inner.search_index.get_mut(&free_page_id) {
+                    let offs = page.get_allocate_free_offset(block_id)?;
+                    if let Err(e) =
+                        page.update_block_data(block_id, blk_data, offset_start as usize)
+                    {
+                        // Undo the offset reservation so the page doesn't keep a half-written block
+                        page.remove_block(block_id);
+                        warn!(
+                            target: TRACING_TARGET,
+                            owner = %content_owner_id,
+                            block_id,
+                            "failed to write block into free page: {:?}",
+                            e
+                        );
+                        res_block_allocated_pages.insert(block_id, AllocateOutcome::WriteFailed);
+                        continue;
+                    }
+
+                    let passthrough = operation_type == AllocateOperationType::OpPassthrough;
+                    match operation_type {
+                        AllocateOperationType::OpWrite => page.set_page_as_dirty(true),
+                        // update_block_data marks the page dirty, undo that for data that
+                        // mirrors the backing file
+                        AllocateOperationType::OpPassthrough | AllocateOperationType::OpRead => {
+                            page.set_page_as_dirty(false)
+                        }
+                    }
+
+                    res_block_allocated_pages
+                        .insert(block_id, AllocateOutcome::Allocated(free_page_id));
+                    // Passthrough pages are not hot, so they are the first to go
+                    if passthrough {
+                        inner.eviction.on_insert_cold(free_page_id);
+                    } else {
+                        inner.eviction.on_insert(free_page_id);
+                    }
+
+                    self.update_owner_pages(
+                        inner,
+                        content_owner_id.clone(),
+                        free_page_id,
+                        block_id,
+                        offs,
+                        // Nothing for a sync to write back
+                        operation_type != AllocateOperationType::OpWrite,
+                    )?;
+                } else {
+                    res_block_allocated_pages.insert(block_id, AllocateOutcome::NoFreePage);
+                }
+            } else {
+                res_block_allocated_pages.insert(block_id, AllocateOutcome::NoFreePage);
+            }
         }
 
-        // No empty pages, then
-        if self.config.apply_lru_eviction {
-            let replace_place_id = match lock.lru_main_vector.back() {
-                Some(r) => *r,
-                None => return Ok((-1, None)),
-            };
+        Ok(res_block_allocated_pages)
+    }
+
+    /// Reads blocks of `content_owner_id` into its shard. Returns the page of every block, -1 for
+    /// those that weren't placed, along with those of them that found no page to go to.
+    fn insert_read_blocks_locked(
+        &self,
+        inner: &mut CustomCacheEngineInner,
+        content_owner_id: &OwnerId,
+        blocks: &[(BlockId, &[u8], usize)],
+        evict_retained: bool,
+    ) -> Result<(HashMap<BlockId, PageId>, Vec<BlockId>)> {
+        let mut placed = HashMap::new();
+        let mut starved = Vec::new();
+        for &(block_id, data, valid_len) in blocks {
+            if valid_len == 0 || valid_len > data.len() || data.len() > self.config.io_block_size {
+                warn!(
+                    target: TRACING_TARGET,
+                    owner = %content_owner_id,
+                    block_id,
+                    len = data.len(),
+                    valid_len,
+                    "rejecting read block that does not fit in an IO block"
+                );
+                placed.insert(block_id, -1);
+                continue;
+            }
+            // Whatever is cached already is at least as new as the disk
+            if let Some(&(page_id, ..)) = inner
+                .owner_ordered_pages_mapping
+                .get(content_owner_id)
+                .and_then(|blocks| blocks.get(&block_id))
+            {
+                placed.insert(block_id, page_id);
+                continue;
+            }
 
-            let mut page_to_reset = match self.get_page_ptr_write(&lock, replace_place_id) {
-                Some(p) => p,
-                None => return Ok((-1, None)),
+            let page_id =
+                self.get_next_free_page(inner, content_owner_id, false, evict_retained)?;
+            let page = match inner.search_index.get_mut(&page_id) {
+                Some(page) => page,
+                None => {
+                    placed.insert(block_id, -1);
+                    starved.push(block_id);
+                    continue;
+                }
             };
-            let old_owner = page_to_reset.get_page_owner();
-            let blocks = page_to_reset
-                .allocated_block_ids
-                .get_block_readable_offsets();
+            // A full page can only be an evicted one
+            if !page.is_page_owner(content_owner_id) || !page.has_free_space() {
+                page.reset();
+                page.change_owner(content_owner_id.clone());
+            }
 
-            for block_id in blocks.keys() {
-                lock.owner_ordered_pages_mapping
-                    .get_mut(&old_owner)
-                    .and_then(|pages| pages.remove(&block_id));
+            // Writing the block marks the page dirty, which is only right for the other blocks
+            let was_dirty = page.is_page_dirty();
+            let offsets = page.get_allocate_free_offset(block_id)?;
+            if let Err(e) = page.update_block_data(block_id, &data.to_vec(), 0) {
+                page.remove_block(block_id);
+                page.set_page_as_dirty(was_dirty);
+                warn!(
+                    target: TRACING_TARGET,
+                    owner = %content_owner_id,
+                    block_id,
+                    "failed to write read block into page: {:?}",
+                    e
+                );
+                placed.insert(block_id, -1);
+                continue;
             }
-            lock.owner_free_pages_mapping
-                .get_mut(&old_owner)
-                .map(|pages| pages.remove(replace_place_id as usize));
+            page.make_block_readable_to(block_id, valid_len as i32 - 1);
+            page.set_page_as_dirty(was_dirty);
+
+            inner.eviction.on_insert_cold(page_id);
+            self.update_owner_pages(
+                inner,
+                content_owner_id.clone(),
+                page_id,
+                block_id,
+                offsets,
+                true,
+            )?;
+            placed.insert(block_id, page_id);
+        }
 
-            if page_to_reset.is_page_dirty() {
-                page_to_reset.sync_data()?;
+        Ok((placed, starved))
+    }
+
+    /// Picks a page of its shard for `owner_id`: one it still has room in, a free one or, with
+    /// LRU eviction, the page the eviction policy of the shard picks. Unless `evict_dirty` is
+    /// set, only clean pages are evicted, and unless `evict_retained` is, only pages of owners
+    /// that aren't retained. Returns -1 if there is none.
+    fn get_next_free_page(
+        &self,
+        inner: &mut CustomCacheEngineInner,
+        owner_id: &OwnerId,
+        evict_dirty: bool,
+        evict_retained: bool,
+    ) -> Result<PageId> {
+        // Check if this owner has space left in their pages
+        if let Some(free_pages) = inner.owner_free_pages_mapping.get_mut(owner_id) {
+            if let Some(free_page) = free_pages.pop() {
+                return Ok(free_page);
             }
-            page_to_reset.reset();
+        }
+
+        // Otherwise, get an empty page
+        if let Some(free_page) = inner.reclaimed.pop() {
+            return Ok(free_page);
+        }
+        if let Some((free_page, page)) = self.free_pages()?.pop() {
+            inner.search_index.insert(free_page, page);
+            return Ok(free_page);
+        }
 
-            return Ok((replace_place_id, Some(page_to_reset)));
+        // No empty pages, then
+        if self.config.apply_lru_eviction {
+            if let Some(page_id) = self.evict(inner, owner_id, evict_dirty, evict_retained)? {
+                return Ok(page_id);
+            }
         }
-        Ok((-1, None))
+        Ok(-1)
     }
 
-    fn apply_lru_after_page_visitation_on_write(
+    /// Evicts the page of the shard its eviction policy picks for `evicted_for`, syncing it first
+    /// if dirty, and leaves it empty and owned by no one. Pages of retained owners only go once
+    /// no other page can, and only if `evict_retained` is set.
+    fn evict(
         &self,
-        lock: &mut RwLockWriteGuard<CustomCacheEngineInner>,
-        visited_page_id: PageId,
-    ) -> Result<()> {
-        if let Some(&position) = lock.page_order_mapping.get(&visited_page_id) {
-            lock.lru_main_vector.remove(position as usize);
+        inner: &mut CustomCacheEngineInner,
+        evicted_for: &OwnerId,
+        evict_dirty: bool,
+        evict_retained: bool,
+    ) -> Result<Option<PageId>> {
+        let unsynced = inner.unsynced_pages();
+        let (search_index, retained_owners) = (&inner.search_index, &inner.retained_owners);
+        let evictable = |page_id: PageId| {
+            search_index.get(&page_id).is_some_and(|page| {
+                evict_dirty || (!page.is_page_dirty() && !unsynced.contains(&page_id))
+            })
+        };
+        let is_retained = |page_id: PageId| {
+            search_index
+                .get(&page_id)
+                .is_some_and(|page| retained_owners.contains(&page.get_page_owner()))
+        };
+        let victim = match inner
+            .eviction
+            .victim(&|page_id| evictable(page_id) && !is_retained(page_id))
+        {
+            Some(page_id) => Some((page_id, EvictionReason::Policy)),
+            None if evict_retained => inner
+                .eviction
+                .victim(&evictable)
+                .map(|page_id| (page_id, EvictionReason::RetainedOnly)),
+            None => None,
+        };
+        let (replace_place_id, reason) = match victim {
+            Some(victim) => victim,
+            None => return Ok(None),
+        };
+        let dirty = unsynced.contains(&replace_place_id);
+
+        let page_to_reset = match inner.search_index.get_mut(&replace_place_id) {
+            Some(p) => p,
+            None => return Ok(None),
+        };
+        let dirty = dirty || page_to_reset.is_page_dirty();
+        if page_to_reset.is_page_dirty() {
+            let written = page_to_reset.sync_data()?;
+            self.bytes_written_back
+                .fetch_add(written as u64, Ordering::SeqCst);
+        }
+        page_to_reset.reset();
+        let old_owner = page_to_reset.get_page_owner();
+        page_to_reset.change_owner("none".into());
+
+        let mut blocks: Vec<_> = inner
+            .owner_ordered_pages_mapping
+            .get(&old_owner)
+            .into_iter()
+            .flatten()
+            .filter(|(_, &(page_id, ..))| page_id == replace_place_id)
+            .map(|(&block_id, _)| block_id)
+            .collect();
+        blocks.sort();
+        let mut record = EvictionRecord {
+            seq: 0,
+            at: self
+                .clock
+                .lock()
+                .map_err(|e| anyhow!("Failed to acquire clock lock: {:?}", e))?
+                .now(),
+            page: replace_place_id,
+            owner: old_owner.clone(),
+            blocks,
+            dirty,
+            reason,
+            evicted_for: evicted_for.clone(),
+        };
+        record.seq = self.evictions.record(record.clone())?;
+        self.evicted_pages.fetch_add(1, Ordering::SeqCst);
+        self.undrained_evictions
+            .lock()
+            .map_err(|e| anyhow!("Failed to acquire undrained evictions lock: {:?}", e))?
+            .push(record);
+
+        // The page is no longer the old owner's, nor tracked for eviction until its next
+        // owner caches something in it
+        if let Some(blocks) = inner.owner_ordered_pages_mapping.get_mut(&old_owner) {
+            blocks.retain(|_, &mut (page_id, ..)| page_id != replace_place_id);
+        }
+        if let Some(pages) = inner.owner_free_pages_mapping.get_mut(&old_owner) {
+            pages.retain(|&page_id| page_id != replace_place_id);
         }
+        if let Some(pages) = inner.owner_pages_mapping.get_mut(&old_owner) {
+            pages.remove(&replace_place_id);
+            if pages.is_empty() {
+                inner.owner_pages_mapping.remove(&old_owner);
+                inner.owner_free_pages_mapping.remove(&old_owner);
+                inner.owner_ordered_pages_mapping.remove(&old_owner);
+            }
+        }
+        inner.eviction.remove(replace_place_id);
 
-        lock.lru_main_vector.push_front(visited_page_id);
-        let front_position = *lock.lru_main_vector.front().unwrap();
-        lock.page_order_mapping
-            .insert(visited_page_id, front_position);
+        Ok(Some(replace_place_id))
+    }
 
-        // If the LRU list is larger than the cache size, remove the least recently used page
-        if lock.page_order_mapping.len() > self.config.cache_nr_pages as usize {
-            if let Some(&back_page_id) = lock.lru_main_vector.back() {
-                lock.page_order_mapping.remove(&back_page_id);
-                lock.lru_main_vector.pop_back();
+    /// Evicts up to `count` pages of the shards other than the one of `evicted_for` and takes
+    /// them out of their shards, for when its own shard had none to give. Pages of retained
+    /// owners go last. Each shard is locked on its own, so no shard lock may be held when calling
+    /// this.
+    fn reclaim(&self, evicted_for: &OwnerId, count: usize, evict_dirty: bool) -> Result<FreePages> {
+        let mut reclaimed = Vec::new();
+        if !self.config.apply_lru_eviction {
+            return Ok(reclaimed);
+        }
+        let own = self.shard_index(evicted_for);
+        for evict_retained in [false, true] {
+            for offset in 1..self.shards.len() {
+                if reclaimed.len() == count {
+                    return Ok(reclaimed);
+                }
+                let mut lock = self.shards[(own + offset) % self.shards.len()]
+                    .write_at("engine::reclaim/shard")
+                    .map_err(|e| anyhow!("Failed to acquire write lock on shard: {:?}", e))?;
+                while reclaimed.len() < count {
+                    let page_id =
+                        match self.evict(&mut lock, evicted_for, evict_dirty, evict_retained)? {
+                            Some(page_id) => page_id,
+                            None => break,
+                        };
+                    if let Some(page) = lock.search_index.remove(&page_id) {
+                        reclaimed.push((page_id, page));
+                    }
+                }
             }
         }
-        Ok(())
+        Ok(reclaimed)
     }
 
-    fn apply_lru_after_page_visitation_on_read(
+    /// Runs `retry` under the write lock of the shard of `owner`, which takes the pages evicted
+    /// for it from the other shards before any other. Those it leaves are freed.
+    fn retry_with_reclaimed<T>(
         &self,
-        lock: &mut RwLockWriteGuard<CustomCacheEngineInner>,
-        visited_page_id: PageId,
-    ) {
-        if let Some(&position) = lock.page_order_mapping.get(&visited_page_id) {
-            lock.lru_main_vector.remove(position as usize);
+        owner: &OwnerId,
+        count: usize,
+        evict_dirty: bool,
+        retry: impl FnOnce(&mut CustomCacheEngineInner) -> Result<T>,
+    ) -> Result<T> {
+        let pages = self.reclaim(owner, count, evict_dirty)?;
+        let mut lock = self
+            .shard(owner)
+            .write_at("engine::retry_with_reclaimed/shard")
+            .map_err(|e| anyhow!("Failed to acquire write lock on shard: {:?}", e))?;
+        for (page_id, page) in pages {
+            lock.search_index.insert(page_id, page);
+            lock.reclaimed.push(page_id);
         }
-
-        // Add the visited page to the front of the LRU list
-        lock.lru_main_vector.push_front(visited_page_id);
-        let new_position = *lock.lru_main_vector.front().unwrap();
-        lock.page_order_mapping
-            .insert(visited_page_id, new_position);
+        let res = retry(&mut lock);
+        for page_id in std::mem::take(&mut lock.reclaimed) {
+            self.release_page(&mut lock, page_id)?;
+        }
+        res
     }
 
     fn update_owner_pages(
         &self,
-        lock: &mut RwLockWriteGuard<CustomCacheEngineInner>,
-        new_owner: String,
+        inner: &mut CustomCacheEngineInner,
+        new_owner: OwnerId,
         page_id: PageId,
         block_id: BlockId,
         block_offsets_inside_page: Offsets,
+        synced: PageSynced,
     ) -> Result<()> {
-        let mut page = match self.get_page_ptr_write(&lock, page_id) {
+        let page = match inner.search_index.get_mut(&page_id) {
             Some(p) => p,
             None => return Ok(()),
         };
 
         let real_owner = page.get_page_owner();
-        if real_owner == "none" || real_owner != new_owner {
+        if &*real_owner == "none" || real_owner != new_owner {
             page.change_owner(new_owner.clone());
 
             // Erase old owner page mapping
-            if lock.owner_pages_mapping.contains_key(&real_owner) {
-                lock.owner_pages_mapping
+            if inner.owner_pages_mapping.contains_key(&real_owner) {
+                inner
+                    .owner_pages_mapping
                     .get_mut(&real_owner)
                     .unwrap()
                     .remove(&page_id);
 
-                if lock.owner_ordered_pages_mapping.contains_key(&real_owner) {
-                    lock.owner_ordered_pages_mapping
-                        .get_mut(&real_owner)
-                        .unwrap()
-                        .remove(&block_id);
+                if let Some(blocks) = inner.owner_ordered_pages_mapping.get_mut(&real_owner) {
+                    blocks.retain(|_, &mut (id, ..)| id != page_id);
                 }
 
                 // Check if the owner's pages are now empty and remove the owner if so
-                if lock
+                if inner
                     .owner_pages_mapping
                     .get(&real_owner)
                     .unwrap()
                     .is_empty()
                 {
-                    lock.owner_pages_mapping.remove(&real_owner);
-                    lock.owner_free_pages_mapping.remove(&real_owner);
-                    lock.owner_ordered_pages_mapping.remove(&real_owner);
+                    inner.owner_pages_mapping.remove(&real_owner);
+                    inner.owner_free_pages_mapping.remove(&real_owner);
+                    inner.owner_ordered_pages_mapping.remove(&real_owner);
                 }
             }
         }
+        let has_free_space = page.has_free_space();
 
-        lock.owner_pages_mapping
+        inner
+            .owner_pages_mapping
             .entry(new_owner.clone())
             .or_insert_with(HashSet::new)
             .insert(page_id);
 
-        lock.owner_ordered_pages_mapping
+        inner
+            .owner_ordered_pages_mapping
             .entry(new_owner.clone())
             .or_insert_with(HashMap::new)
-            .insert(
-                block_id,
-                (
-                    page_id,
-                    Box::new(*page.clone()),
-                    block_offsets_inside_page,
-                    false,
-                ),
-            );
+            .insert(block_id, (page_id, block_offsets_inside_page, synced));
 
-        if page.has_free_space() {
-            lock.owner_free_pages_mapping
+        if has_free_space {
+            inner
+                .owner_free_pages_mapping
                 .entry(new_owner)
                 .or_insert_with(Vec::new)
                 .push(page_id);
@@ -241,90 +726,178 @@ impl CustomCacheEngine {
 impl PageCacheEngine for CustomCacheEngine {
     fn allocate_blocks(
         &self,
-        content_owner_id: String,
+        content_owner_id: OwnerId,
         block_data_mapping: HashMap<BlockId, (PageId, &Vec<u8>, i32)>,
         operation_type: AllocateOperationType,
     ) -> Result<HashMap<BlockId, PageId>> {
-        let mut lock = self
-            .data
-            .write()
-            .map_err(|e| anyhow!("Failed to acquire write lock on data: {:?}", e))?;
-
-        let mut res_block_allocated_pages = HashMap::new();
+        let outcomes =
+            self.allocate_blocks_sharded(content_owner_id, block_data_mapping, operation_type)?;
+        Ok(outcomes
+            .into_iter()
+            .map(|(block_id, outcome)| (block_id, outcome.page_id()))
+            .collect())
+    }
 
-        for (&block_id, &(page_id, ref blk_data, offset_start)) in &block_data_mapping {
-            if page_id >= 0 {
-                if let Some(mut page) = self.get_page_ptr_write(&lock, page_id) {
-                    if page.is_page_owner(&content_owner_id.clone()) && page.contains_block(block_id)
-                    {
-                        page.update_block_data(block_id, blk_data, offset_start as usize)?;
-                        res_block_allocated_pages.insert(block_id, page_id);
+    fn insert_read_blocks(
+        &self,
+        content_owner_id: OwnerId,
+        blocks: Vec<(BlockId, &[u8], usize)>,
+    ) -> Result<HashMap<BlockId, PageId>> {
+        let shard = self.shard(&content_owner_id);
+        let (mut placed, starved) = {
+            let mut lock = shard
+                .write_at("engine::insert_read_blocks/shard")
+                .map_err(|e| anyhow!("Failed to acquire write lock on shard: {:?}", e))?;
+            self.insert_read_blocks_locked(&mut lock, &content_owner_id, &blocks, false)?
+        };
+        if starved.is_empty() {
+            return Ok(placed);
+        }
 
-                        self.update_owner_pages(
-                            &mut lock,
-                            content_owner_id.clone(),
-                            page_id,
-                            block_id,
-                            (0, 0),
-                        )?;
+        let retry: Vec<_> = blocks
+            .iter()
+            .filter(|(block_id, ..)| starved.contains(block_id))
+            .copied()
+            .collect();
+        let (retried, _) =
+            self.retry_with_reclaimed(&content_owner_id, starved.len(), false, |inner| {
+                self.insert_read_blocks_locked(inner, &content_owner_id, &retry, true)
+            })?;
+        placed.extend(retried);
+        Ok(placed)
+    }
 
-                        continue;
+    fn copy_blocks(
+        &self,
+        src_owner: OwnerId,
+        dst_owner: OwnerId,
+        pairs: Vec<(BlockId, BlockId)>,
+    ) -> Result<HashMap<BlockId, PageId>> {
+        // (destination block, readable part of the source block)
+        let mut copies = Vec::with_capacity(pairs.len());
+        {
+            let lock = self
+                .shard(&src_owner)
+                .read_at("engine::copy_blocks/src_shard")
+                .map_err(|e| anyhow!("Failed to acquire read lock on shard: {:?}", e))?;
+            for (src_block, dst_block) in pairs {
+                let src_page_id = match lock
+                    .owner_ordered_pages_mapping
+                    .get(&src_owner)
+                    .and_then(|blocks| blocks.get(&src_block))
+                {
+                    Some(&(page_id, ..)) => page_id,
+                    None => {
+                        return Err(anyhow!(
+                            "Block {} of {} is not cached",
+                            src_block,
+                            src_owner
+                        ))
                     }
-                }
-            }
-
-            let (free_page_id, free_page_ptr) =
-                self.get_next_free_page(&mut lock, content_owner_id.clone())?;
-            if free_page_id >= 0 {
-                if let Some(mut page) = free_page_ptr {
-                    let offs = page.get_allocate_free_offset(block_id)?;
-                    page.update_block_data(block_id, blk_data, offset_start as usize)?;
-
-                    if operation_type == AllocateOperationType::OpWrite {
-                        page.set_page_as_dirty(true);
+                };
+                let page = match lock.search_index.get(&src_page_id) {
+                    Some(page)
+                        if page.is_page_owner(&src_owner) && page.contains_block(src_block) =>
+                    {
+                        page
                     }
+                    _ => {
+                        return Err(anyhow!(
+                            "Block {} of {} is not cached",
+                            src_block,
+                            src_owner
+                        ))
+                    }
+                };
+                let (start, _) = page.allocated_block_ids.get_block_offsets(src_block);
+                let readable_to = page.allocated_block_ids.get_readable_to(src_block);
+                let data = page.data()[start as usize..=(start + readable_to) as usize].to_vec();
+                copies.push((dst_block, data));
+            }
+        }
 
-                    res_block_allocated_pages.insert(block_id, free_page_id);
-                    self.apply_lru_after_page_visitation_on_write(&mut lock, free_page_id)?;
-
-                    self.update_owner_pages(
-                        &mut lock,
-                        content_owner_id.clone(),
-                        free_page_id,
-                        block_id,
-                        offs,
-                    )?;
-                } else {
-                    res_block_allocated_pages.insert(block_id, -1);
-                }
-            } else {
-                res_block_allocated_pages.insert(block_id, -1);
+        let dst_shard = self.shard(&dst_owner);
+        let block_data_mapping = {
+            let lock = dst_shard
+                .read_at("engine::copy_blocks/dst_shard")
+                .map_err(|e| anyhow!("Failed to acquire read lock on shard: {:?}", e))?;
+            copies
+                .iter()
+                .map(|(dst_block, data)| {
+                    let dst_page_id = lock
+                        .owner_ordered_pages_mapping
+                        .get(&dst_owner)
+                        .and_then(|blocks| blocks.get(dst_block))
+                        .map_or(-1, |&(page_id, ..)| page_id);
+                    (*dst_block, (dst_page_id, data, 0))
+                })
+                .collect()
+        };
+        let allocated = self.allocate_blocks(
+            dst_owner.clone(),
+            block_data_mapping,
+            AllocateOperationType::OpWrite,
+        )?;
+
+        let mut lock = dst_shard
+            .write_at("engine::copy_blocks/dst_shard")
+            .map_err(|e| anyhow!("Failed to acquire write lock on shard: {:?}", e))?;
+        for (dst_block, data) in &copies {
+            let page_id = allocated[dst_block];
+            if let Some(page) = lock.search_index.get_mut(&page_id) {
+                page.make_block_readable_to(*dst_block, data.len() as i32 - 1);
             }
         }
+        Ok(allocated)
+    }
 
-        Ok(res_block_allocated_pages)
+    fn read_block(
+        &self,
+        content_owner_id: OwnerId,
+        page_id: PageId,
+        block_id: BlockId,
+        buffer: &mut [u8],
+    ) -> Result<Option<usize>> {
+        let lock = self
+            .shard(&content_owner_id)
+            .read_at("engine::read_block/shard")
+            .map_err(|e| anyhow!("Failed to acquire read lock on shard: {:?}", e))?;
+        let page = match lock.search_index.get(&page_id) {
+            Some(page)
+                if page.is_page_owner(&content_owner_id) && page.contains_block(block_id) =>
+            {
+                page
+            }
+            _ => return Ok(None),
+        };
+
+        let (start, _) = page.allocated_block_ids.get_block_offsets(block_id);
+        let readable = page.allocated_block_ids.get_readable_to(block_id) + 1;
+        let len = (readable.max(0) as usize).min(buffer.len());
+        buffer[..len].copy_from_slice(&page.data()[start as usize..start as usize + len]);
+        Ok(Some(len))
     }
 
     fn get_blocks(
         &self,
-        content_owner_id: String,
-        block_pages: HashMap<BlockId, (PageId, Vec<u8>, i32)>,
+        content_owner_id: OwnerId,
+        block_pages: HashMap<BlockId, (PageId, &mut [u8], i32)>,
     ) -> Result<HashMap<BlockId, bool>> {
         let mut lock = self
-            .data
-            .write()
-            .map_err(|e| anyhow!("Failed to acquire write lock on data: {:?}", e))?;
+            .shard(&content_owner_id)
+            .write_at("engine::get_blocks/shard")
+            .map_err(|e| anyhow!("Failed to acquire write lock on shard: {:?}", e))?;
 
         let mut res_block_data = HashMap::new();
 
-        for (block_id, (page_id, ref mut data, read_to_max_index)) in block_pages {
-            if let Some(page) = self.get_page_ptr_write(&lock, page_id) {
+        for (block_id, (page_id, data, read_to_max_index)) in block_pages {
+            if let Some(page) = lock.search_index.get(&page_id) {
                 if page.is_page_owner(&content_owner_id) && page.contains_block(block_id) {
-                    page.get_block_data(block_id, data.as_mut_slice(), read_to_max_index as usize)?;
+                    page.get_block_data(block_id, data, read_to_max_index as usize)?;
                     res_block_data.insert(block_id, true);
 
                     if self.config.apply_lru_eviction {
-                        self.apply_lru_after_page_visitation_on_read(&mut lock, page_id);
+                        lock.eviction.on_access(page_id);
                     }
                 } else {
                     res_block_data.insert(block_id, false);
@@ -339,15 +912,15 @@ impl PageCacheEngine for CustomCacheEngine {
 
     fn is_block_cached(
         &self,
-        content_owner_id: String,
+        content_owner_id: OwnerId,
         page_id: PageId,
         block_id: BlockId,
     ) -> Result<bool> {
         let lock = self
-            .data
-            .read()
-            .map_err(|e| anyhow!("Failed to acquire read lock on data: {:?}", e))?;
-        if let Some(page) = self.get_page_ptr_read(&lock, page_id) {
+            .shard(&content_owner_id)
+            .read_at("engine::is_block_cached/shard")
+            .map_err(|e| anyhow!("Failed to acquire read lock on shard: {:?}", e))?;
+        if let Some(page) = lock.search_index.get(&page_id) {
             return Ok(page.is_page_owner(&content_owner_id) && page.contains_block(block_id));
         }
         Ok(false)
@@ -355,16 +928,16 @@ impl PageCacheEngine for CustomCacheEngine {
 
     fn make_block_readable_to_offset(
         &self,
-        cid: String,
+        cid: OwnerId,
         page_id: PageId,
         block_id: BlockId,
         offset: i32,
     ) -> Result<()> {
         let mut lock = self
-            .data
-            .write()
-            .map_err(|e| anyhow!("Failed to acquire write lock on data: {:?}", e))?;
-        let mut page = match self.get_page_ptr_write(&mut lock, page_id) {
+            .shard(&cid)
+            .write_at("engine::make_block_readable_to_offset/shard")
+            .map_err(|e| anyhow!("Failed to acquire write lock on shard: {:?}", e))?;
+        let page = match lock.search_index.get_mut(&page_id) {
             Some(p) => p,
             None => return Ok(()),
         };
@@ -376,40 +949,23 @@ impl PageCacheEngine for CustomCacheEngine {
     }
 
     fn get_engine_usage(&self) -> Result<f64> {
-        let lock = self
-            .data
-            .read()
-            .map_err(|e| anyhow!("Failed to acquire read lock on data: {:?}", e))?;
-        let used_pages = self.config.cache_nr_pages - lock.free_pages.len();
+        let used_pages = self.config.cache_nr_pages - self.free_pages()?.len();
         Ok((used_pages as f64 / self.config.cache_nr_pages as f64) * 100.0)
     }
 
-    fn remove_cached_blocks(&self, owner: String) -> Result<bool> {
+    fn remove_cached_blocks(&self, owner: OwnerId) -> Result<bool> {
         let mut lock = self
-            .data
-            .write()
-            .map_err(|e| anyhow!("Failed to acquire write lock on data: {:?}", e))?;
+            .shard(&owner)
+            .write_at("engine::remove_cached_blocks/shard")
+            .map_err(|e| anyhow!("Failed to acquire write lock on shard: {:?}", e))?;
 
         lock.owner_free_pages_mapping.remove(&owner);
+        lock.retained_owners.remove(&owner);
 
-        // Process each page owned by the owner
+        // Every page of the owner goes back to the free pages
         if let Some(owner_pgs) = lock.owner_pages_mapping.remove(&owner) {
             for page_id in owner_pgs {
-                // Add the page back to the list of free pages
-                lock.free_pages.push(page_id);
-
-                // Apply LRU eviction logic if enabled
-                if self.config.apply_lru_eviction {
-                    if let Some(position) = lock.page_order_mapping.remove(&page_id) {
-                        lock.lru_main_vector.remove(position as usize);
-                    }
-                }
-
-                // Reset the page and change its owner to "none"
-                if let Some(mut page_ptr) = self.get_page_ptr_write(&mut lock, page_id) {
-                    page_ptr.reset();
-                    page_ptr.change_owner("none".to_string());
-                }
+                self.release_page(&mut lock, page_id)?;
             }
             lock.owner_ordered_pages_mapping.remove(&owner);
         }
@@ -417,191 +973,328 @@ impl PageCacheEngine for CustomCacheEngine {
         Ok(true)
     }
 
-    fn sync_pages(&self, owner: String, size: u32, orig_path: String) -> Result<()> {
+    fn remove_clean_blocks(&self, owner: OwnerId) -> Result<Vec<BlockId>> {
         let mut lock = self
-            .data
-            .write()
-            .map_err(|e| anyhow!("Failed to acquire write lock on data: {:?}", e))?;
-
-        let mut fd = OpenOptions::new().write(true).open(orig_path)?;
-
-        if let Some(iterate_blocks) = lock.owner_ordered_pages_mapping.get_mut(&owner) {
-            // let mut wrote_bytes = 0;
-            let mut page_streak = 0;
+            .shard(&owner)
+            .write_at("engine::remove_clean_blocks/shard")
+            .map_err(|e| anyhow!("Failed to acquire write lock on shard: {:?}", e))?;
+        let inner = &mut *lock;
+
+        let ordered_pages = match inner.owner_ordered_pages_mapping.get_mut(&owner) {
+            Some(ordered_pages) => ordered_pages,
+            None => return Ok(Vec::new()),
+        };
+        let owner_blocks: Vec<(BlockId, PageId)> = ordered_pages
+            .iter()
+            .map(|(&block_id, &(page_id, ..))| (block_id, page_id))
+            .collect();
+
+        let mut removed = Vec::new();
+        let mut emptied = Vec::new();
+        for (block_id, page_id) in owner_blocks {
+            let page = match inner.search_index.get_mut(&page_id) {
+                Some(page) => page,
+                None => continue,
+            };
+            if !page.is_page_owner(&owner) || page.is_page_dirty() {
+                continue;
+            }
 
-            let mut new_iterate_blocks: HashMap<i32, (i32, Page, Offsets, bool)> = HashMap::new();
+            page.remove_block(block_id);
+            ordered_pages.remove(&block_id);
+            removed.push(block_id);
+            if page.allocated_block_ids.empty() {
+                emptied.push(page_id);
+            }
+        }
+        let owner_gone = ordered_pages.is_empty();
 
-            for (index, (_, (page_id, page, offsets, flag))) in
-                iterate_blocks.iter_mut().enumerate()
-            {
-                if page.is_page_dirty() {
-                    new_iterate_blocks
-                        .insert(index as i32, (*page_id, *page.clone(), *offsets, *flag));
-                    page.set_page_as_dirty(false);
-                }
+        // Give pages back once the owner has nothing left in them
+        for page_id in emptied {
+            if let Some(owner_pages) = inner.owner_pages_mapping.get_mut(&owner) {
+                owner_pages.remove(&page_id);
             }
+            if let Some(free_pages) = inner.owner_free_pages_mapping.get_mut(&owner) {
+                free_pages.retain(|&free_page| free_page != page_id);
+            }
+            self.release_page(inner, page_id)?;
+        }
 
-            let mut page_streak_last_offset =
-                new_iterate_blocks.keys().next().unwrap() * (self.config.io_block_size as i32);
+        if owner_gone {
+            inner.owner_ordered_pages_mapping.remove(&owner);
+            inner.owner_pages_mapping.remove(&owner);
+            inner.owner_free_pages_mapping.remove(&owner);
+        }
 
-            let mut page_chunk: Vec<(i32, Page, Offsets, bool)> =
-                Vec::with_capacity(new_iterate_blocks.len());
-            page_chunk.extend(new_iterate_blocks.values().cloned());
+        Ok(removed)
+    }
 
-            for (current_block_id, page_data) in new_iterate_blocks.iter() {
-                let next_block_id = match new_iterate_blocks.get(&(current_block_id + 1)) {
-                    Some(data) => data.0,
-                    None => continue,
+    fn sync_pages(
+        &self,
+        owner: OwnerId,
+        size: u64,
+        orig_path: String,
+        dirty_extents: &HashMap<BlockId, Vec<Offsets>>,
+        only_sync_data: bool,
+    ) -> Result<()> {
+        let mut lock = self
+            .shard(&owner)
+            .write_at("engine::sync_pages/shard")
+            .map_err(|e| anyhow!("Failed to acquire write lock on shard: {:?}", e))?;
+
+        // The file may only ever have existed in the cache
+        let fd = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(orig_path)?;
+
+        let inner = &mut *lock;
+        // Sorted by block, so consecutive blocks can be told apart from a gap
+        let dirty_blocks: BTreeMap<BlockId, PageId> = inner
+            .owner_ordered_pages_mapping
+            .get(&owner)
+            .map(|ordered_blocks| {
+                ordered_blocks
+                    .iter()
+                    .filter(|(_, &(_, _, synced))| !synced)
+                    .map(|(&block_id, &(page_id, _, _))| (block_id, page_id))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        if !dirty_blocks.is_empty() {
+            // Consecutive blocks go out in a single write for as long as they are full, from
+            // `streak_start` in the file. Returns how many bytes that was.
+            let flush = |streak: &mut Vec<u8>, streak_start: u64| -> Result<u64> {
+                let len = streak.len() as u64;
+                if !streak.is_empty() {
+                    fd.write_all_at(streak, streak_start)?;
+                    streak.clear();
+                }
+                Ok(len)
+            };
+            let mut written = 0;
+            let mut streak_start = 0;
+            let mut streak: Vec<u8> = Vec::new();
+            let mut synced_blocks = Vec::with_capacity(dirty_blocks.len());
+            for (&block_id, page_id) in &dirty_blocks {
+                let page = match inner.search_index.get(page_id) {
+                    Some(page) if page.is_page_owner(&owner) && page.contains_block(block_id) => {
+                        page
+                    }
+                    _ => continue,
                 };
-
-                if (*current_block_id as usize) != (new_iterate_blocks.len() - 1)
-                    && *current_block_id == (next_block_id - 1)
-                {
-                    page_streak += 1;
-                    page_chunk.push((page_data.0, page_data.1.clone(), page_data.2, page_data.3));
+                let in_page = page.allocated_block_ids.get_block_offsets(block_id).0 as usize;
+                let in_file = block_id as u64 * self.config.io_block_size as u64;
+
+                // Partially written blocks don't join a streak, only their written ranges go out
+                if let Some(extents) = dirty_extents.get(&block_id) {
+                    for &(from, to) in extents {
+                        let to = to.min(self.config.io_block_size as i32 - 1);
+                        let data = &page.data()[in_page + from as usize..=in_page + to as usize];
+                        fd.write_all_at(data, in_file + from as u64)?;
+                        written += data.len() as u64;
+                    }
                 } else {
-                    page_streak += 1;
-                    page_chunk.push((page_data.0, page_data.1.clone(), page_data.2, page_data.3));
-
-                    let mut buffer = Vec::new();
-                    let mut cursor = Cursor::new(&mut buffer);
-
-                    page_streak_last_offset =
-                        (current_block_id - page_streak + 1) * self.config.io_block_size as i32;
-
-                    for p_id in 0..page_streak {
-                        let streak_block = current_block_id - page_streak + p_id + 1;
-
-                        let streak_pair = &mut page_chunk[p_id as usize];
-                        let page_ptr = &streak_pair.1;
-                        let block_data_offs = &streak_pair.2;
-
-                        let data = &page_ptr.data[block_data_offs.0 as usize..];
-
-                        if p_id == page_streak - 1 {
-                            let readable_to =
-                                page_ptr.allocated_block_ids.get_readable_to(streak_block) + 1;
-                            cursor.write_all(&data[..readable_to as usize])?;
-                        } else {
-                            cursor.write_all(&data[..self.config.io_block_size])?;
-                        }
-
-                        streak_pair.3 = true;
+                    if streak_start + streak.len() as u64 != in_file {
+                        written += flush(&mut streak, streak_start)?;
+                        streak_start = in_file;
+                    }
+                    let readable = page.allocated_block_ids.get_readable_to(block_id) + 1;
+                    let len = (readable.max(0) as usize).min(self.config.io_block_size);
+                    streak.extend_from_slice(&page.data()[in_page..in_page + len]);
+                    if len < self.config.io_block_size {
+                        written += flush(&mut streak, streak_start)?;
+                        streak_start = in_file + self.config.io_block_size as u64;
+                    }
+                }
+                synced_blocks.push((block_id, *page_id));
+            }
+            written += flush(&mut streak, streak_start)?;
+            self.bytes_written_back.fetch_add(written, Ordering::SeqCst);
+
+            // Pages hold a single owner, whose blocks are all synced now
+            if let Some(ordered_blocks) = inner.owner_ordered_pages_mapping.get_mut(&owner) {
+                for (block_id, _) in &synced_blocks {
+                    if let Some((_, _, synced)) = ordered_blocks.get_mut(block_id) {
+                        *synced = true;
                     }
-
-                    fd.seek(SeekFrom::Start(page_streak_last_offset as u64))?;
-                    fd.write(&buffer)?;
-                    // wrote_bytes += fd.write(&buffer)?;
-
-                    page_streak = 0;
-                    page_chunk.clear();
-                    page_streak_last_offset =
-                        (current_block_id + 1) * self.config.io_block_size as i32;
+                }
+            }
+            for (_, page_id) in synced_blocks {
+                if let Some(page) = inner.search_index.get_mut(&page_id) {
+                    page.set_page_as_dirty(false);
                 }
             }
         }
 
-        // Truncate the file to the specified size
-        fd.set_len(size as u64)?;
+        // Truncate the file to the specified size, before flushing so the size is durable too
+        fd.set_len(size)?;
+        if only_sync_data {
+            fd.sync_data()?;
+        } else {
+            fd.sync_all()?;
+        }
 
         Ok(())
     }
 
-    fn rename_owner_pages(&self, old_owner: String, new_owner: String) -> Result<bool> {
-        let mut lock = self
-            .data
-            .write()
-            .map_err(|e| anyhow!("Failed to acquire write lock on data: {:?}", e))?;
+    fn rename_owner_pages(&self, old_owner: OwnerId, new_owner: OwnerId) -> Result<bool> {
+        let old_index = self.shard_index(&old_owner);
+        let new_index = self.shard_index(&new_owner);
+        // Shards are locked in order, so two renames never wait on each other
+        let mut low = self.shards[old_index.min(new_index)]
+            .write_at("engine::rename_owner_pages/shard")
+            .map_err(|e| anyhow!("Failed to acquire write lock on shard: {:?}", e))?;
+        let mut high = if old_index == new_index {
+            None
+        } else {
+            Some(
+                self.shards[old_index.max(new_index)]
+                    .write_at("engine::rename_owner_pages/shard")
+                    .map_err(|e| anyhow!("Failed to acquire write lock on shard: {:?}", e))?,
+            )
+        };
+        let (old_shard, new_shard) = match high.as_deref_mut() {
+            None => (&mut *low, None),
+            Some(high) if old_index < new_index => (&mut *low, Some(high)),
+            Some(high) => (high, Some(&mut *low)),
+        };
 
         // Check if the old owner exists in the mapping
-        if !lock.owner_pages_mapping.contains_key(&old_owner) {
+        if !old_shard.owner_pages_mapping.contains_key(&old_owner) {
             return Ok(false);
         }
 
         // Retrieve the old owner's data
-        let old_page_mapping = lock.owner_pages_mapping.remove(&old_owner).unwrap();
-        let old_free_mapping = lock.owner_free_pages_mapping.remove(&old_owner).unwrap();
-        let old_ordered_pages = lock.owner_ordered_pages_mapping.remove(&old_owner).unwrap();
+        let old_page_mapping = old_shard.owner_pages_mapping.remove(&old_owner).unwrap();
+        let old_free_mapping = old_shard
+            .owner_free_pages_mapping
+            .remove(&old_owner)
+            .unwrap_or_default();
+        let old_ordered_pages = old_shard
+            .owner_ordered_pages_mapping
+            .remove(&old_owner)
+            .unwrap_or_default();
+        let retained = old_shard.retained_owners.remove(&old_owner);
+
+        // The pages follow their owner to its new shard
+        let shard = match new_shard {
+            Some(new_shard) => {
+                for &page_id in &old_page_mapping {
+                    old_shard.eviction.remove(page_id);
+                    if let Some(page) = old_shard.search_index.remove(&page_id) {
+                        new_shard.search_index.insert(page_id, page);
+                        new_shard.eviction.on_insert(page_id);
+                    }
+                }
+                new_shard
+            }
+            None => old_shard,
+        };
 
         // Change the owner of each page
         for &page_id in &old_page_mapping {
-            if let Some(mut page) = self.get_page_ptr_write(&mut lock, page_id) {
+            if let Some(page) = shard.search_index.get_mut(&page_id) {
                 page.change_owner(new_owner.clone());
             }
         }
 
         // Update the mappings for the new owner
-        lock.owner_pages_mapping
+        shard
+            .owner_pages_mapping
             .insert(new_owner.clone(), old_page_mapping);
-        lock.owner_free_pages_mapping
+        shard
+            .owner_free_pages_mapping
             .insert(new_owner.clone(), old_free_mapping);
-        lock.owner_ordered_pages_mapping
+        if retained {
+            shard.retained_owners.insert(new_owner.clone());
+        }
+        shard
+            .owner_ordered_pages_mapping
             .insert(new_owner, old_ordered_pages);
 
         Ok(true)
     }
 
+    fn set_owner_retained(&self, owner: OwnerId, retained: bool) -> Result<()> {
+        let mut lock = self
+            .shard(&owner)
+            .write_at("engine::set_owner_retained/shard")
+            .map_err(|e| anyhow!("Failed to acquire write lock on shard: {:?}", e))?;
+        if retained {
+            lock.retained_owners.insert(owner);
+        } else {
+            lock.retained_owners.remove(&owner);
+        }
+        Ok(())
+    }
+
     fn truncate_cached_blocks(
         &self,
-        content_owner_id: String,
+        content_owner_id: OwnerId,
         blocks_to_remove: HashMap<BlockId, PageId>,
         from_block_id: BlockId,
         index_inside_block: i32,
     ) -> Result<bool> {
         let mut lock = self
-            .data
-            .write()
-            .map_err(|e| anyhow!("Failed to acquire write lock on data: {:?}", e))?;
+            .shard(&content_owner_id)
+            .write_at("engine::truncate_cached_blocks/shard")
+            .map_err(|e| anyhow!("Failed to acquire write lock on shard: {:?}", e))?;
 
+        let inner = &mut *lock;
+        let mut emptied = Vec::new();
         for (&block_id, &page_id) in &blocks_to_remove {
-            if let Some(mut page) = self.get_page_ptr_write(&mut lock, page_id) {
-                if page.is_page_owner(&content_owner_id) {
-                    if block_id == from_block_id && index_inside_block > 0 {
-                        if page.contains_block(from_block_id) {
-                            page.make_block_readable_to(from_block_id, index_inside_block - 1);
-                            page.write_null_from(from_block_id, index_inside_block);
-                        }
-                    } else {
-                        page.remove_block(block_id);
+            let page = match inner.search_index.get_mut(&page_id) {
+                Some(page) if page.is_page_owner(&content_owner_id) => page,
+                _ => continue,
+            };
+            if block_id == from_block_id && index_inside_block > 0 {
+                if page.contains_block(from_block_id) {
+                    page.make_block_readable_to(from_block_id, index_inside_block - 1);
+                    page.write_null_from(from_block_id, index_inside_block);
+                }
+                continue;
+            }
 
-                        if let Some(owner_pages) =
-                            lock.owner_pages_mapping.get_mut(&content_owner_id)
-                        {
-                            owner_pages.remove(&page_id);
-                        }
-                        if let Some(ordered_pages) =
-                            lock.owner_ordered_pages_mapping.get_mut(&content_owner_id)
-                        {
-                            ordered_pages.remove(&block_id);
-                        }
+            page.remove_block(block_id);
+            if page.allocated_block_ids.empty() {
+                emptied.push(page_id);
+            }
+            if let Some(ordered_pages) =
+                inner.owner_ordered_pages_mapping.get_mut(&content_owner_id)
+            {
+                ordered_pages.remove(&block_id);
+            }
+        }
 
-                        if !page.is_page_dirty() {
-                            lock.free_pages.push(page_id);
-                            if self.config.apply_lru_eviction {
-                                if let Some(position) = lock.page_order_mapping.remove(&page_id) {
-                                    lock.lru_main_vector.remove(position as usize);
-                                }
-                            }
-                            page.reset();
-                            page.change_owner("none".to_string());
-                        }
-                    }
-                }
+        // Give pages back once the owner has nothing left in them
+        for page_id in emptied {
+            if let Some(owner_pages) = inner.owner_pages_mapping.get_mut(&content_owner_id) {
+                owner_pages.remove(&page_id);
+            }
+            if let Some(free_pages) = inner.owner_free_pages_mapping.get_mut(&content_owner_id) {
+                free_pages.retain(|&free_page| free_page != page_id);
             }
+            self.release_page(inner, page_id)?;
         }
 
         Ok(true)
     }
 
-    fn get_dirty_blocks_info(&self, owner: String) -> Result<Vec<(BlockId, Offsets, PageId)>> {
+    fn get_dirty_blocks_info(&self, owner: OwnerId) -> Result<Vec<(BlockId, Offsets, PageId)>> {
         let lock = self
-            .data
-            .read()
-            .map_err(|e| anyhow!("Failed to acquire read lock on data: {:?}", e))?;
+            .shard(&owner)
+            .read_at("engine::get_dirty_blocks_info/shard")
+            .map_err(|e| anyhow!("Failed to acquire read lock on shard: {:?}", e))?;
         let mut res = Vec::new();
         if let Some(ordered_pages) = lock.owner_ordered_pages_mapping.get(&owner) {
-            for (&block_id, &(page_id, ref page, _, is_synced)) in ordered_pages {
-                if !is_synced {
+            for (&block_id, &(page_id, _, is_synced)) in ordered_pages {
+                if is_synced {
+                    continue;
+                }
+                if let Some(page) = lock.search_index.get(&page_id) {
                     let offs = (0, page.allocated_block_ids.get_readable_to(block_id));
                     res.push((block_id, offs, page_id));
                 }
@@ -609,4 +1302,877 @@ impl PageCacheEngine for CustomCacheEngine {
         }
         Ok(res)
     }
+
+    fn eviction_history(&self) -> Result<Vec<EvictionRecord>> {
+        self.evictions.records()
+    }
+
+    fn stats(&self) -> Result<CacheStats> {
+        let mut dirty_pages = 0;
+        for shard in &self.shards {
+            let lock = shard
+                .read_at("engine::stats/shard")
+                .map_err(|e| anyhow!("Failed to acquire read lock on shard: {:?}", e))?;
+            let unsynced = lock.unsynced_pages();
+            dirty_pages += lock
+                .search_index
+                .iter()
+                .filter(|(page_id, page)| page.is_page_dirty() || unsynced.contains(page_id))
+                .count();
+        }
+        Ok(CacheStats {
+            evictions: self.evicted_pages.load(Ordering::SeqCst),
+            dirty_pages: dirty_pages as u64,
+            used_pages: (self.config.cache_nr_pages - self.free_pages()?.len()) as u64,
+            total_pages: self.config.cache_nr_pages as u64,
+            bytes_written_back: self.bytes_written_back.load(Ordering::SeqCst),
+            ..Default::default()
+        })
+    }
+
+    fn reset_stats(&self) -> Result<()> {
+        self.evicted_pages.store(0, Ordering::SeqCst);
+        self.bytes_written_back.store(0, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn drain_evictions(&self) -> Result<Vec<EvictionRecord>> {
+        let mut undrained = self
+            .undrained_evictions
+            .lock()
+            .map_err(|e| anyhow!("Failed to acquire undrained evictions lock: {:?}", e))?;
+        Ok(std::mem::take(&mut *undrained))
+    }
+
+    fn set_clock(&self, clock: Arc<dyn Clock>) -> Result<()> {
+        *self
+            .clock
+            .lock()
+            .map_err(|e| anyhow!("Failed to acquire clock lock: {:?}", e))? = clock;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use std::time::{Duration, SystemTime};
+
+    /// With a single shard, so the eviction order holds across owners
+    fn engine_with_pages(nr_pages: usize) -> CustomCacheEngine {
+        let config = Config {
+            cache_nr_pages: nr_pages,
+            apply_lru_eviction: true,
+            engine_shards: 1,
+            ..Default::default()
+        };
+        CustomCacheEngine::new(Box::new(config)).unwrap()
+    }
+
+    fn allocate(
+        engine: &CustomCacheEngine,
+        owner: &str,
+        block_id: BlockId,
+        operation_type: AllocateOperationType,
+    ) -> PageId {
+        let data = vec![7u8; 16];
+        let blocks = HashMap::from([(block_id, (-1, &data, 0))]);
+        engine
+            .allocate_blocks(owner.into(), blocks, operation_type)
+            .unwrap()[&block_id]
+    }
+
+    #[test]
+    fn usage_climbs_from_empty_to_full() {
+        let config = Config {
+            cache_nr_pages: 4,
+            ..Default::default()
+        };
+        let blocks_per_page = config.cache_page_size / config.io_block_size;
+        let engine = CustomCacheEngine::new(Box::new(config)).unwrap();
+        assert_eq!(engine.get_engine_usage().unwrap(), 0.0);
+
+        for i in 0..4 * blocks_per_page {
+            let owner: OwnerId = format!("owner-{}", i).into();
+            assert!(allocate(&engine, &owner, 0, AllocateOperationType::OpWrite) >= 0);
+            let usage = (i + 1) as f64 * 100.0 / (4 * blocks_per_page) as f64;
+            assert_eq!(engine.get_engine_usage().unwrap(), usage);
+        }
+        assert!(allocate(&engine, "late", 0, AllocateOperationType::OpWrite) < 0);
+
+        let bad_geometry = Config {
+            cache_page_size: 4096 + 1,
+            ..Default::default()
+        };
+        assert!(CustomCacheEngine::new(Box::new(bad_geometry)).is_err());
+    }
+
+    #[test]
+    fn passthrough_blocks_are_clean_and_cold() {
+        let engine = engine_with_pages(4);
+        let written = allocate(&engine, "written", 0, AllocateOperationType::OpWrite);
+        let merged = allocate(&engine, "merged", 0, AllocateOperationType::OpPassthrough);
+        assert!(written >= 0 && merged >= 0);
+
+        let dirty = |owner: &str| engine.get_dirty_blocks_info(owner.into()).unwrap();
+        assert_eq!(dirty("written").len(), 1);
+        assert!(dirty("merged").is_empty());
+
+        let mut lock = engine
+            .shard("merged")
+            .write_at("engine::passthrough_blocks_are_clean_and_cold/shard")
+            .unwrap();
+        assert_eq!(lock.eviction.victim(&|_| true), Some(merged));
+        assert_eq!(
+            lock.eviction.victim(&|page_id| page_id != merged),
+            Some(written)
+        );
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_page() {
+        let engine = engine_with_pages(4);
+        // Passthrough pages are clean, so evicting them needs no backing file
+        let cache = |owner: &str| allocate(&engine, owner, 0, AllocateOperationType::OpPassthrough);
+        let touch = |owner: &str, page_id| {
+            let mut data = [0u8; 16];
+            let blocks = HashMap::from([(0, (page_id, &mut data[..], 15))]);
+            engine.get_blocks(owner.into(), blocks).unwrap()[&0]
+        };
+        let owners = ["a", "b", "c", "d"];
+        let pages: Vec<_> = owners.iter().map(|owner| cache(owner)).collect();
+        for i in [2, 0, 3, 1, 0, 2] {
+            assert!(touch(owners[i], pages[i]));
+        }
+
+        // From least to most recently used: d, b, a, c
+        let page_of_e = cache("e");
+        assert_eq!(page_of_e, pages[3]);
+        assert!(!touch("d", pages[3]));
+        assert!(touch("e", page_of_e));
+        assert_eq!(cache("f"), pages[1]);
+        assert!(touch("f", pages[1]));
+        assert!(touch("a", pages[0]));
+        assert_eq!(cache("g"), pages[2]);
+    }
+
+    #[test]
+    fn configured_policy_picks_the_victim() {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-policies", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let owners: Vec<_> = ["a", "b", "c", "d", "e"]
+            .iter()
+            .map(|name| {
+                let path = dir.join(name);
+                std::fs::write(&path, b"").unwrap();
+                OwnerId::from(path.to_string_lossy())
+            })
+            .collect();
+
+        for (policy, victim) in [("lru", 1), ("fifo", 0), ("clock", 1)] {
+            let config = Config {
+                cache_nr_pages: 4,
+                apply_lru_eviction: true,
+                eviction_policy: policy.to_string(),
+                engine_shards: 1,
+                ..Default::default()
+            };
+            let engine = CustomCacheEngine::new(Box::new(config)).unwrap();
+            let pages: Vec<_> = owners[..4]
+                .iter()
+                .map(|owner| allocate(&engine, owner, 0, AllocateOperationType::OpWrite))
+                .collect();
+            for i in [2, 0] {
+                let mut data = [0u8; 16];
+                let blocks = HashMap::from([(0, (pages[i], &mut data[..], 15))]);
+                assert!(engine.get_blocks(owners[i].clone(), blocks).unwrap()[&0]);
+            }
+            let evicted = allocate(&engine, &owners[4], 0, AllocateOperationType::OpWrite);
+            assert_eq!(evicted, pages[victim], "{}", policy);
+        }
+
+        let unknown = Config {
+            eviction_policy: "mru".to_string(),
+            ..Default::default()
+        };
+        assert!(CustomCacheEngine::new(Box::new(unknown)).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn evictions_are_kept_in_order() {
+        let config = Config {
+            cache_nr_pages: 2,
+            apply_lru_eviction: true,
+            eviction_history_size: 2,
+            engine_shards: 1,
+            ..Default::default()
+        };
+        let engine = CustomCacheEngine::new(Box::new(config)).unwrap();
+        let clock = Arc::new(ManualClock::new(SystemTime::UNIX_EPOCH));
+        engine.set_clock(clock.clone()).unwrap();
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-history", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let owner = |name: &str| {
+            let path = dir.join(name);
+            std::fs::write(&path, b"").unwrap();
+            OwnerId::from(path.to_string_lossy())
+        };
+        let (a, b, c, d, e) = (owner("a"), owner("b"), owner("c"), owner("d"), owner("e"));
+        let summary = |record: &EvictionRecord| {
+            (
+                record.seq,
+                record.owner.clone(),
+                record.blocks.clone(),
+                record.dirty,
+                record.reason,
+                record.evicted_for.clone(),
+            )
+        };
+
+        let page_of_a = allocate(&engine, &a, 3, AllocateOperationType::OpWrite);
+        let page_of_b = allocate(&engine, &b, 0, AllocateOperationType::OpPassthrough);
+        assert!(engine.eviction_history().unwrap().is_empty());
+        engine.set_owner_retained(a.clone(), true).unwrap();
+        assert_eq!(
+            allocate(&engine, &c, 0, AllocateOperationType::OpWrite),
+            page_of_b
+        );
+        engine.set_owner_retained(c.clone(), true).unwrap();
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(
+            allocate(&engine, &d, 0, AllocateOperationType::OpWrite),
+            page_of_a
+        );
+
+        let history = engine.eviction_history().unwrap();
+        assert_eq!(
+            history.iter().map(summary).collect::<Vec<_>>(),
+            vec![
+                (
+                    1,
+                    b.clone(),
+                    vec![0],
+                    false,
+                    EvictionReason::Policy,
+                    c.clone()
+                ),
+                (
+                    2,
+                    a.clone(),
+                    vec![3],
+                    true,
+                    EvictionReason::RetainedOnly,
+                    d.clone()
+                ),
+            ]
+        );
+        assert_eq!(history[1].page, page_of_a);
+        assert_eq!(
+            history.iter().map(|record| record.at).collect::<Vec<_>>(),
+            vec![
+                SystemTime::UNIX_EPOCH,
+                SystemTime::UNIX_EPOCH + Duration::from_secs(5)
+            ]
+        );
+
+        // Only the latest two are kept
+        allocate(&engine, &e, 0, AllocateOperationType::OpWrite);
+        let history = engine.eviction_history().unwrap();
+        assert_eq!(
+            history.iter().map(|record| record.seq).collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+        assert_eq!(summary(&history[1]).1, d);
+        assert!(history[1].to_string().starts_with("#3 page="));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn sync_writes_only_dirty_extents() {
+        let config = Config::default();
+        let engine = CustomCacheEngine::new(Box::new(config.clone())).unwrap();
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-extents", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path: OwnerId = dir.join("file").to_string_lossy().into();
+        std::fs::write(&*path, vec![b'a'; 8192]).unwrap();
+
+        // Block 1 cached with new contents, of which only a few scattered ranges were written
+        let data = vec![b'b'; 4096];
+        let page_id = engine
+            .allocate_blocks(
+                path.clone(),
+                HashMap::from([(1, (-1, &data, 0))]),
+                AllocateOperationType::OpWrite,
+            )
+            .unwrap()[&1];
+        assert!(page_id >= 0);
+
+        let extents = HashMap::from([(1, vec![(10, 19), (100, 149), (4090, 4095)])]);
+        engine
+            .sync_pages(path.clone(), 8192, path.to_string(), &extents, true)
+            .unwrap();
+
+        let synced: Vec<usize> = std::fs::read(&*path)
+            .unwrap()
+            .iter()
+            .enumerate()
+            .filter(|(_, &byte)| byte == b'b')
+            .map(|(i, _)| i - 4096)
+            .collect();
+        let expected: Vec<usize> = (10..20).chain(100..150).chain(4090..4096).collect();
+        assert_eq!(synced, expected);
+        assert!(engine.get_dirty_blocks_info(path).unwrap().is_empty());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Syncs full blocks `block_ids`, each filled with its own id, into a fresh file and returns
+    /// what the file ends up holding
+    fn sync_full_blocks(name: &str, block_ids: &[BlockId]) -> Vec<u8> {
+        let engine = engine_with_pages(8);
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path: OwnerId = dir.join("file").to_string_lossy().into();
+        std::fs::write(&*path, b"").unwrap();
+
+        // Sync the empty owner first, which has nothing to write
+        engine
+            .sync_pages(path.clone(), 0, path.to_string(), &HashMap::new(), true)
+            .unwrap();
+
+        for &block_id in block_ids {
+            let data = vec![block_id as u8 + 1; 4096];
+            let blocks = HashMap::from([(block_id, (-1, &data, 0))]);
+            let page_id = engine
+                .allocate_blocks(path.clone(), blocks, AllocateOperationType::OpWrite)
+                .unwrap()[&block_id];
+            engine
+                .make_block_readable_to_offset(path.clone(), page_id, block_id, 4095)
+                .unwrap();
+        }
+        let size = (*block_ids.iter().max().unwrap() as u64 + 1) * 4096;
+        engine
+            .sync_pages(path.clone(), size, path.to_string(), &HashMap::new(), false)
+            .unwrap();
+        assert!(engine
+            .get_dirty_blocks_info(path.clone())
+            .unwrap()
+            .is_empty());
+
+        let contents = std::fs::read(&*path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        contents
+    }
+
+    #[test]
+    fn sync_lays_out_consecutive_blocks() {
+        let contents = sync_full_blocks("sync-consecutive", &[2, 0, 1]);
+        let expected: Vec<u8> = (1..=3).flat_map(|fill| vec![fill; 4096]).collect();
+        assert_eq!(contents, expected);
+    }
+
+    #[test]
+    fn sync_leaves_gaps_between_blocks() {
+        let contents = sync_full_blocks("sync-gap", &[5, 0, 1]);
+        assert_eq!(contents.len(), 6 * 4096);
+        for (block_id, fill) in [(0, 1), (1, 2), (2, 0), (3, 0), (4, 0), (5, 6)] {
+            let block = &contents[block_id * 4096..(block_id + 1) * 4096];
+            assert!(block.iter().all(|&byte| byte == fill), "block {}", block_id);
+        }
+    }
+
+    #[test]
+    fn copy_blocks_between_owners() {
+        let engine = engine_with_pages(4);
+        let pattern: Vec<u8> = (0..100).collect();
+        {
+            let mut free_pages = engine.free_pages().unwrap();
+            let at = free_pages
+                .iter()
+                .position(|(page_id, _)| *page_id == 3)
+                .unwrap();
+            let (_, page) = free_pages.remove(at);
+            drop(free_pages);
+            let mut lock = engine
+                .shard("src")
+                .write_at("engine::copy_blocks_between_owners/shard")
+                .unwrap();
+            lock.search_index.insert(3, page);
+            let page = lock.search_index.get_mut(&3).unwrap();
+            page.change_owner("src".into());
+            let offsets = page.get_allocate_free_offset(5).unwrap();
+            page.update_block_data(5, &pattern, 0).unwrap();
+            page.make_block_readable_to(5, 99);
+            lock.owner_pages_mapping
+                .insert("src".into(), HashSet::from([3]));
+            lock.owner_ordered_pages_mapping
+                .insert("src".into(), HashMap::from([(5, (3, offsets, true))]));
+        }
+
+        let copied = engine
+            .copy_blocks("src".into(), "dst".into(), vec![(5, 0)])
+            .unwrap();
+        assert!(copied[&0] >= 0 && copied[&0] != 3);
+
+        let dirty = engine.get_dirty_blocks_info("dst".into()).unwrap();
+        assert_eq!(dirty.iter().map(|d| d.0).collect::<Vec<_>>(), vec![0]);
+        assert!(engine
+            .get_dirty_blocks_info("src".into())
+            .unwrap()
+            .is_empty());
+        let lock = engine
+            .shard("src")
+            .read_at("engine::copy_blocks_between_owners/shard")
+            .unwrap();
+        let src_page = &lock.search_index[&3];
+        assert!(src_page.is_page_owner("src"));
+        assert_eq!(&src_page.data()[..100], &pattern[..]);
+
+        drop(lock);
+        assert!(engine
+            .copy_blocks("src".into(), "dst".into(), vec![(6, 1)])
+            .is_err());
+
+        let mut buf = vec![0xff; 4096];
+        assert_eq!(
+            engine.read_block("src".into(), 3, 5, &mut buf).unwrap(),
+            Some(100)
+        );
+        assert_eq!(&buf[..100], &pattern[..]);
+        assert_eq!(
+            engine.read_block("dst".into(), 3, 5, &mut buf).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn oversized_block_fails_alone() {
+        let engine = engine_with_pages(3);
+        let (whole, oversized, tail) = (vec![1u8; 4096], vec![2u8; 5000], vec![3u8; 200]);
+        let blocks = HashMap::from([
+            (0, (-1, &whole, 0)),
+            (1, (-1, &oversized, 0)),
+            // Fits in a block, but not from where it starts
+            (2, (-1, &tail, 4000)),
+            (3, (-1, &tail, 100)),
+        ]);
+        let outcomes = engine
+            .allocate_blocks_with_outcomes("owner".into(), blocks, AllocateOperationType::OpWrite)
+            .unwrap();
+        assert_eq!(outcomes[&1], AllocateOutcome::TooLarge);
+        assert_eq!(outcomes[&2], AllocateOutcome::TooLarge);
+        let (page_0, page_3) = (outcomes[&0].page_id(), outcomes[&3].page_id());
+        assert!(page_0 >= 0 && page_3 >= 0);
+
+        // Only the blocks that landed are mapped, to the pages they landed in
+        let shard = engine
+            .shard("owner")
+            .read_at("engine::oversized_block_fails_alone/shard")
+            .unwrap();
+        let mapped: HashMap<_, _> = shard.owner_ordered_pages_mapping["owner"]
+            .iter()
+            .map(|(&block_id, &(page_id, ..))| (block_id, page_id))
+            .collect();
+        assert_eq!(mapped, HashMap::from([(0, page_0), (3, page_3)]));
+        assert_eq!(
+            shard.owner_pages_mapping["owner"],
+            HashSet::from([page_0, page_3])
+        );
+        for (block_id, page_id) in [(1, page_0), (2, page_0), (1, page_3), (2, page_3)] {
+            assert!(!shard.search_index[&page_id].contains_block(block_id));
+        }
+        drop(shard);
+        let mut dirty: Vec<_> = engine
+            .get_dirty_blocks_info("owner".into())
+            .unwrap()
+            .into_iter()
+            .map(|(block_id, ..)| block_id)
+            .collect();
+        dirty.sort();
+        assert_eq!(dirty, [0, 3]);
+    }
+
+    #[test]
+    fn read_blocks_are_clean_readable_and_evictable() {
+        let engine = engine_with_pages(2);
+        let pattern: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        let placed = engine
+            .insert_read_blocks("reader".into(), vec![(4, &pattern[..], 100)])
+            .unwrap();
+        assert!(engine
+            .get_dirty_blocks_info("reader".into())
+            .unwrap()
+            .is_empty());
+
+        let page_id = placed[&4];
+        assert!(page_id >= 0);
+        assert_eq!(
+            engine
+                .shard("reader")
+                .read_at("engine::read_blocks_are_clean_readable_and_evictable/shard")
+                .unwrap()
+                .owner_ordered_pages_mapping["reader"][&4]
+                .0,
+            page_id
+        );
+        let mut buf = vec![0xff; 4096];
+        let read = |buf: &mut [u8]| engine.read_block("reader".into(), page_id, 4, buf).unwrap();
+        assert_eq!(read(&mut buf), Some(100));
+        assert_eq!(&buf[..100], &pattern[..100]);
+
+        // A block already cached is newer than what's on disk
+        let zeros = [0; 200];
+        let placed = engine
+            .insert_read_blocks("reader".into(), vec![(4, &zeros[..], 200)])
+            .unwrap();
+        assert_eq!(placed, HashMap::from([(4, page_id)]));
+        assert_eq!(read(&mut buf), Some(100));
+        assert_eq!(&buf[..100], &pattern[..100]);
+
+        // With no free page left, a write makes room by evicting the read block
+        let engine = engine_with_pages(1);
+        engine
+            .insert_read_blocks("reader".into(), vec![(4, &pattern[..], 100)])
+            .unwrap();
+        assert!(allocate(&engine, "writer", 0, AllocateOperationType::OpWrite) >= 0);
+        assert!(!engine
+            .shard("reader")
+            .read_at("engine::read_blocks_are_clean_readable_and_evictable/shard")
+            .unwrap()
+            .owner_ordered_pages_mapping
+            .get("reader")
+            .is_some_and(|blocks| blocks.contains_key(&4)));
+    }
+
+    #[test]
+    fn retained_owners_are_evicted_last() {
+        let engine = engine_with_pages(2);
+        let cached = |owner: &str| {
+            engine
+                .shard(owner)
+                .read_at("engine::retained_owners_are_evicted_last/shard")
+                .unwrap()
+                .owner_ordered_pages_mapping
+                .get(owner)
+                .is_some_and(|blocks| blocks.contains_key(&0))
+        };
+        let data = vec![1u8; 4096];
+        let read = |owner: &str| {
+            engine
+                .insert_read_blocks(owner.into(), vec![(0, &data[..], 4096)])
+                .unwrap()[&0]
+                >= 0
+        };
+
+        engine.set_owner_retained("sst".into(), true).unwrap();
+        assert!(read("log"));
+        assert!(read("sst"));
+        // The retained page is the colder one, the other goes first
+        assert!(read("other"));
+        assert!(cached("sst"));
+        assert!(!cached("log"));
+
+        // Once the other page is dirty, only the retained one can make room for a read
+        assert!(allocate(&engine, "writer", 0, AllocateOperationType::OpWrite) >= 0);
+        assert!(cached("sst"));
+        assert!(read("late"));
+        assert!(!cached("sst"));
+    }
+
+    #[test]
+    fn eviction_hands_pages_over_cleanly() {
+        let config = Config {
+            cache_nr_pages: 4,
+            cache_page_size: 8192,
+            apply_lru_eviction: true,
+            engine_shards: 1,
+            ..Default::default()
+        };
+        let engine = CustomCacheEngine::new(Box::new(config)).unwrap();
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-evict", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let a: OwnerId = dir.join("a").to_string_lossy().into();
+        std::fs::write(&*a, b"").unwrap();
+
+        // Written like the cache does, readable up to what was written
+        let write = |owner: &str, block_id| {
+            let page_id = allocate(&engine, owner, block_id, AllocateOperationType::OpWrite);
+            engine
+                .make_block_readable_to_offset(owner.into(), page_id, block_id, 15)
+                .unwrap();
+            page_id
+        };
+
+        // Two blocks per page, so owner a fills the cache with eight
+        let pages_of_a: Vec<_> = (0..8).map(|block_id| write(&a, block_id)).collect();
+        assert!(pages_of_a.iter().all(|&page_id| page_id >= 0));
+        let state = |check: &dyn Fn(&CustomCacheEngineInner)| {
+            check(
+                &engine.shards[0]
+                    .read_at("engine::eviction_test/shard")
+                    .unwrap(),
+            )
+        };
+        // Only pages that belong to someone are up for eviction
+        let check_tracked = |inner: &CustomCacheEngineInner| {
+            let owned: usize = inner.owner_pages_mapping.values().map(HashSet::len).sum();
+            assert_eq!(inner.eviction.len(), owned);
+        };
+
+        // The coldest page, holding blocks 0 and 1 of a, goes to b and is synced on the way
+        let evicted = write("b", 0);
+        assert_eq!(evicted, pages_of_a[0]);
+        assert_eq!(std::fs::read(&*a).unwrap().len(), 4096 + 4096);
+        state(&|inner| {
+            let blocks_of_a = &inner.owner_ordered_pages_mapping[&a];
+            assert!(!blocks_of_a.contains_key(&0) && !blocks_of_a.contains_key(&1));
+            assert_eq!(blocks_of_a.len(), 6);
+            assert!(!inner.owner_pages_mapping[&a].contains(&evicted));
+            assert!(!inner.owner_free_pages_mapping[&a].contains(&evicted));
+            assert_eq!(inner.owner_pages_mapping["b"], HashSet::from([evicted]));
+            assert_eq!(inner.owner_free_pages_mapping["b"], vec![evicted]);
+            check_tracked(inner);
+        });
+
+        // b fills its own page before evicting anything else
+        assert_eq!(write("b", 1), evicted);
+        state(&|inner| {
+            assert_eq!(inner.owner_ordered_pages_mapping[&a].len(), 6);
+            assert!(inner.owner_free_pages_mapping["b"].is_empty());
+        });
+
+        // Taking every page of a leaves nothing of it behind
+        for block_id in 2..8 {
+            assert!(write("b", block_id) >= 0);
+        }
+        state(&|inner| {
+            assert!(!inner.owner_pages_mapping.contains_key(&a));
+            assert!(!inner.owner_ordered_pages_mapping.contains_key(&a));
+            assert!(!inner.owner_free_pages_mapping.contains_key(&a));
+            assert_eq!(inner.owner_pages_mapping["b"].len(), 4);
+            assert_eq!(inner.owner_ordered_pages_mapping["b"].len(), 8);
+            check_tracked(inner);
+        });
+        assert!(engine.remove_cached_blocks("b".into()).unwrap());
+        assert_eq!(engine.free_pages().unwrap().len(), 4);
+        state(&|inner| {
+            check_tracked(inner);
+            assert_eq!(inner.eviction.len(), 0);
+        });
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn read_blocks_never_evict_dirty_pages() {
+        let engine = engine_with_pages(1);
+        assert!(allocate(&engine, "writer", 0, AllocateOperationType::OpWrite) >= 0);
+
+        let data = vec![1u8; 4096];
+        let placed = engine
+            .insert_read_blocks(
+                "reader".into(),
+                vec![(0, &data[..], 4096), (1, &data[..], 0)],
+            )
+            .unwrap();
+        assert_eq!(placed, HashMap::from([(0, -1), (1, -1)]));
+        assert_eq!(
+            engine.get_dirty_blocks_info("writer".into()).unwrap().len(),
+            1
+        );
+    }
+
+    #[test]
+    fn writes_land_in_the_cached_page() {
+        let engine = engine_with_pages(2);
+        let pattern: Vec<u8> = (0..4096).map(|i| (i % 251) as u8).collect();
+        let write = |page_id, data: &Vec<u8>, start| {
+            let blocks = HashMap::from([(3, (page_id, data, start))]);
+            engine
+                .allocate_blocks("owner".into(), blocks, AllocateOperationType::OpWrite)
+                .unwrap()[&3]
+        };
+        let page_id = write(-1, &pattern, 0);
+        assert!(page_id >= 0);
+        engine
+            .make_block_readable_to_offset("owner".into(), page_id, 3, 4095)
+            .unwrap();
+
+        let read = || {
+            let mut buf = vec![0u8; 4096];
+            let res = engine
+                .get_blocks(
+                    "owner".into(),
+                    HashMap::from([(3, (page_id, &mut buf[..], 4095))]),
+                )
+                .unwrap();
+            assert_eq!(res, HashMap::from([(3, true)]));
+            buf
+        };
+        assert_eq!(read(), pattern);
+
+        // Overwriting in place changes the same page
+        let patch = vec![0xaa; 10];
+        assert_eq!(write(page_id, &patch, 100), page_id);
+        let mut expected = pattern.clone();
+        expected[100..110].copy_from_slice(&patch);
+        assert_eq!(read(), expected);
+        let dirty = engine.get_dirty_blocks_info("owner".into()).unwrap();
+        assert_eq!(dirty, vec![(3, (0, 4095), page_id)]);
+    }
+
+    #[test]
+    fn get_blocks_fills_caller_buffers() {
+        let config = Config {
+            cache_nr_pages: 1,
+            cache_page_size: 8192,
+            ..Default::default()
+        };
+        let engine = CustomCacheEngine::new(Box::new(config)).unwrap();
+        let first = vec![1u8; 4096];
+        let second: Vec<u8> = (0..4096).map(|i| (i % 251) as u8).collect();
+        let placed = engine
+            .insert_read_blocks(
+                "reader".into(),
+                vec![(0, &first[..], 4096), (1, &second[..], 4096)],
+            )
+            .unwrap();
+        let (first_page, second_page) = (placed[&0], placed[&1]);
+        assert!(first_page >= 0);
+        assert_eq!(first_page, second_page);
+
+        // The second block sits halfway into the page, a short buffer gets what fits
+        let mut whole = vec![0u8; 4096];
+        let mut short = vec![0u8; 100];
+        let res = engine
+            .get_blocks(
+                "reader".into(),
+                HashMap::from([
+                    (0, (first_page, &mut whole[..], 4095)),
+                    (1, (second_page, &mut short[..], 4095)),
+                ]),
+            )
+            .unwrap();
+        assert_eq!(res, HashMap::from([(0, true), (1, true)]));
+        assert_eq!(whole, first);
+        assert_eq!(short, second[..100]);
+
+        let mut other = vec![0u8; 4096];
+        let res = engine
+            .get_blocks(
+                "other".into(),
+                HashMap::from([(0, (first_page, &mut other[..], 4095))]),
+            )
+            .unwrap();
+        assert_eq!(res, HashMap::from([(0, false)]));
+        assert!(other.iter().all(|&b| b == 0));
+    }
+
+    /// `count` owners, each in a shard none of the others is in
+    fn owners_of_distinct_shards(engine: &CustomCacheEngine, count: usize) -> Vec<OwnerId> {
+        let mut shards = HashSet::new();
+        (0..)
+            .map(|i| OwnerId::from(format!("owner-{}", i)))
+            .filter(|owner| shards.insert(engine.shard_index(owner)))
+            .take(count)
+            .collect()
+    }
+
+    #[test]
+    fn owners_of_other_shards_make_room() {
+        let config = Config {
+            cache_nr_pages: 2,
+            apply_lru_eviction: true,
+            engine_shards: 4,
+            ..Default::default()
+        };
+        let engine = CustomCacheEngine::new(Box::new(config)).unwrap();
+        let owners = owners_of_distinct_shards(&engine, 4);
+        let (a, b, c, d) = (&owners[0], &owners[1], &owners[2], &owners[3]);
+        let pages = [
+            allocate(&engine, a, 0, AllocateOperationType::OpPassthrough),
+            allocate(&engine, b, 0, AllocateOperationType::OpPassthrough),
+        ];
+
+        // The shard of c holds nothing to evict, so one of the others gives up a page
+        let page_of_c = allocate(&engine, c, 0, AllocateOperationType::OpPassthrough);
+        assert!(pages.contains(&page_of_c));
+        let history = engine.eviction_history().unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(&history[0].evicted_for, c);
+        assert_eq!(history[0].page, page_of_c);
+        assert_eq!(engine.get_engine_usage().unwrap(), 100.0);
+
+        // Renamed into another shard, the page moves along
+        assert!(engine.rename_owner_pages(c.clone(), d.clone()).unwrap());
+        assert!(engine.is_block_cached(d.clone(), page_of_c, 0).unwrap());
+        assert!(!engine.is_block_cached(c.clone(), page_of_c, 0).unwrap());
+        assert!(engine.remove_cached_blocks(d.clone()).unwrap());
+        assert_eq!(engine.get_engine_usage().unwrap(), 50.0);
+        // and once freed goes to whoever needs one, without evicting anything
+        assert_eq!(
+            allocate(&engine, c, 1, AllocateOperationType::OpPassthrough),
+            page_of_c
+        );
+        assert_eq!(engine.stats().unwrap().evictions, 1);
+    }
+
+    #[test]
+    fn owners_cache_in_parallel() {
+        const THREADS: usize = 8;
+        const BLOCKS: BlockId = 64;
+        let run = |nr_pages: usize, operation_type: AllocateOperationType| {
+            let config = Config {
+                cache_nr_pages: nr_pages,
+                apply_lru_eviction: true,
+                ..Default::default()
+            };
+            let engine = CustomCacheEngine::new(Box::new(config)).unwrap();
+            std::thread::scope(|scope| {
+                for thread in 0..THREADS {
+                    let engine = &engine;
+                    scope.spawn(move || {
+                        let owner: OwnerId = format!("owner-{}", thread).into();
+                        for block_id in 0..BLOCKS {
+                            let data = vec![thread as u8; 4096];
+                            let blocks = HashMap::from([(block_id, (-1, &data, 0))]);
+                            let page_id = engine
+                                .allocate_blocks(owner.clone(), blocks, operation_type)
+                                .unwrap()[&block_id];
+                            assert!(page_id >= 0);
+
+                            let mut buf = vec![0xff; 4096];
+                            let blocks = HashMap::from([(block_id, (page_id, &mut buf[..], 4095))]);
+                            // Another owner may have taken the page back already
+                            if engine.get_blocks(owner.clone(), blocks).unwrap()[&block_id] {
+                                assert!(buf.iter().all(|&b| b == thread as u8));
+                            }
+                        }
+                    });
+                }
+            });
+            engine
+        };
+
+        // With room for everything, nothing is evicted and every owner has all of its blocks
+        let engine = run(THREADS * BLOCKS as usize, AllocateOperationType::OpWrite);
+        let stats = engine.stats().unwrap();
+        assert_eq!((stats.used_pages, stats.evictions), (stats.total_pages, 0));
+        for thread in 0..THREADS {
+            let owner: OwnerId = format!("owner-{}", thread).into();
+            assert_eq!(
+                engine.get_dirty_blocks_info(owner).unwrap().len(),
+                BLOCKS as usize
+            );
+        }
+
+        // Short of pages, owners keep taking them from each other
+        let engine = run(THREADS, AllocateOperationType::OpPassthrough);
+        let stats = engine.stats().unwrap();
+        assert_eq!(stats.used_pages, stats.total_pages);
+        assert_eq!(
+            stats.evictions,
+            (THREADS * BLOCKS as usize - THREADS) as u64
+        );
+    }
 }
@@ -0,0 +1,532 @@
+use crate::pagecache::engine::{AllocateOperationType, PageCacheEngine};
+use crate::pagecache::stats::CacheStats;
+use crate::pagecache::{BlockId, Offsets, OwnerId, PageId};
+use anyhow::{anyhow, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Engine keeping every block in memory on a page of its own, for tests that want a `Cache`
+/// without touching the disk. Page ids are handed out in order from 0 and never reused, nothing
+/// is evicted, and `sync_pages` writes to a map of paths standing in for the disk, read back
+/// with `disk_contents`. Clones share their state, so one can be kept for assertions while the
+/// other is handed to the cache.
+#[derive(Clone, Debug)]
+pub struct MemCacheEngine {
+    io_block_size: usize,
+    /// Most blocks held at once, no limit if `None`
+    capacity: Option<usize>,
+    state: Arc<Mutex<MemState>>,
+}
+
+#[derive(Debug, Default)]
+struct MemState {
+    blocks: HashMap<(OwnerId, BlockId), MemBlock>,
+    next_page_id: PageId,
+    /// What `sync_pages` wrote, by path
+    disk: HashMap<String, Vec<u8>>,
+    bytes_written_back: u64,
+}
+
+#[derive(Debug)]
+struct MemBlock {
+    page_id: PageId,
+    /// A whole IO block, zeroed past what was written
+    data: Vec<u8>,
+    /// Last readable byte of `data`
+    readable_to: i32,
+    synced: bool,
+}
+
+impl MemState {
+    fn block(&self, owner: &OwnerId, page_id: PageId, block_id: BlockId) -> Option<&MemBlock> {
+        self.blocks
+            .get(&(owner.clone(), block_id))
+            .filter(|block| block.page_id == page_id)
+    }
+
+    /// Blocks of `owner` by id
+    fn owner_blocks(&self, owner: &str) -> BTreeMap<BlockId, &MemBlock> {
+        self.blocks
+            .iter()
+            .filter(|((block_owner, _), _)| &**block_owner == owner)
+            .map(|((_, block_id), block)| (*block_id, block))
+            .collect()
+    }
+}
+
+impl MemCacheEngine {
+    /// Engine with no limit on the blocks it holds. `io_block_size` has to match the one of the
+    /// config the cache is given.
+    pub fn new(io_block_size: usize) -> Self {
+        MemCacheEngine {
+            io_block_size,
+            capacity: None,
+            state: Arc::new(Mutex::new(MemState::default())),
+        }
+    }
+
+    /// Holds at most `blocks` blocks, refusing new ones past that
+    pub fn with_capacity(mut self, blocks: usize) -> Self {
+        self.capacity = Some(blocks);
+        self
+    }
+
+    /// What the syncs so far left at `path`, `None` if nothing was ever synced there
+    pub fn disk_contents(&self, path: &str) -> Option<Vec<u8>> {
+        self.state().ok()?.disk.get(path).cloned()
+    }
+
+    fn state(&self) -> Result<MutexGuard<'_, MemState>> {
+        self.state
+            .lock()
+            .map_err(|e| anyhow!("Failed to acquire memory engine lock: {:?}", e))
+    }
+
+    /// Gives `block_id` of `owner` a new page holding `data` from `offset`, unless the engine
+    /// is full. Returns the page.
+    fn insert_block(
+        &self,
+        state: &mut MemState,
+        owner: &OwnerId,
+        block_id: BlockId,
+        data: &[u8],
+        offset: usize,
+        synced: bool,
+    ) -> Option<PageId> {
+        if self
+            .capacity
+            .is_some_and(|capacity| state.blocks.len() >= capacity)
+        {
+            return None;
+        }
+        let page_id = state.next_page_id;
+        state.next_page_id += 1;
+        let mut block = vec![0; self.io_block_size];
+        block[offset..offset + data.len()].copy_from_slice(data);
+        state.blocks.insert(
+            (owner.clone(), block_id),
+            MemBlock {
+                page_id,
+                data: block,
+                readable_to: 0,
+                synced,
+            },
+        );
+        Some(page_id)
+    }
+}
+
+impl PageCacheEngine for MemCacheEngine {
+    fn allocate_blocks(
+        &self,
+        content_owner_id: OwnerId,
+        block_data_mapping: HashMap<BlockId, (PageId, &Vec<u8>, i32)>,
+        operation_type: AllocateOperationType,
+    ) -> Result<HashMap<BlockId, PageId>> {
+        let mut state = self.state()?;
+        let mut allocated = HashMap::new();
+        // In block order, for the page ids to be the same from one run to the next
+        let block_data_mapping: BTreeMap<_, _> = block_data_mapping.into_iter().collect();
+        for (block_id, (_, data, offset)) in block_data_mapping {
+            if offset < 0 || offset as usize + data.len() > self.io_block_size {
+                allocated.insert(block_id, -1);
+                continue;
+            }
+            let offset = offset as usize;
+            let key = (content_owner_id.clone(), block_id);
+            if let Some(block) = state.blocks.get_mut(&key) {
+                // The cached copy is at least as new as the backing file
+                if operation_type != AllocateOperationType::OpPassthrough {
+                    block.data[offset..offset + data.len()].copy_from_slice(data);
+                    block.synced = false;
+                }
+                allocated.insert(block_id, block.page_id);
+                continue;
+            }
+            let synced = operation_type != AllocateOperationType::OpWrite;
+            let page_id = self
+                .insert_block(
+                    &mut state,
+                    &content_owner_id,
+                    block_id,
+                    data,
+                    offset,
+                    synced,
+                )
+                .unwrap_or(-1);
+            allocated.insert(block_id, page_id);
+        }
+        Ok(allocated)
+    }
+
+    fn insert_read_blocks(
+        &self,
+        content_owner_id: OwnerId,
+        blocks: Vec<(BlockId, &[u8], usize)>,
+    ) -> Result<HashMap<BlockId, PageId>> {
+        let mut state = self.state()?;
+        let mut placed = HashMap::new();
+        for (block_id, data, valid_len) in blocks {
+            if valid_len == 0 || valid_len > data.len() || data.len() > self.io_block_size {
+                placed.insert(block_id, -1);
+                continue;
+            }
+            let key = (content_owner_id.clone(), block_id);
+            if let Some(block) = state.blocks.get(&key) {
+                placed.insert(block_id, block.page_id);
+                continue;
+            }
+            let page_id =
+                match self.insert_block(&mut state, &content_owner_id, block_id, data, 0, true) {
+                    Some(page_id) => {
+                        state.blocks.get_mut(&key).unwrap().readable_to = valid_len as i32 - 1;
+                        page_id
+                    }
+                    None => -1,
+                };
+            placed.insert(block_id, page_id);
+        }
+        Ok(placed)
+    }
+
+    fn copy_blocks(
+        &self,
+        src_owner: OwnerId,
+        dst_owner: OwnerId,
+        pairs: Vec<(BlockId, BlockId)>,
+    ) -> Result<HashMap<BlockId, PageId>> {
+        let copies = {
+            let state = self.state()?;
+            let mut copies = Vec::with_capacity(pairs.len());
+            for (src_block, dst_block) in pairs {
+                let block = state
+                    .blocks
+                    .get(&(src_owner.clone(), src_block))
+                    .ok_or_else(|| anyhow!("Block {} of {} is not cached", src_block, src_owner))?;
+                copies.push((
+                    dst_block,
+                    block.data[..=block.readable_to as usize].to_vec(),
+                ));
+            }
+            copies
+        };
+
+        let block_data_mapping = copies
+            .iter()
+            .map(|(dst_block, data)| (*dst_block, (-1, data, 0)))
+            .collect();
+        let allocated = self.allocate_blocks(
+            dst_owner.clone(),
+            block_data_mapping,
+            AllocateOperationType::OpWrite,
+        )?;
+
+        let mut state = self.state()?;
+        for (dst_block, data) in &copies {
+            if let Some(block) = state.blocks.get_mut(&(dst_owner.clone(), *dst_block)) {
+                block.readable_to = data.len() as i32 - 1;
+            }
+        }
+        Ok(allocated)
+    }
+
+    fn read_block(
+        &self,
+        content_owner_id: OwnerId,
+        page_id: PageId,
+        block_id: BlockId,
+        buffer: &mut [u8],
+    ) -> Result<Option<usize>> {
+        let state = self.state()?;
+        let block = match state.block(&content_owner_id, page_id, block_id) {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+        let len = ((block.readable_to + 1).max(0) as usize).min(buffer.len());
+        buffer[..len].copy_from_slice(&block.data[..len]);
+        Ok(Some(len))
+    }
+
+    fn get_blocks(
+        &self,
+        content_owner_id: OwnerId,
+        block_pages: HashMap<BlockId, (PageId, &mut [u8], i32)>,
+    ) -> Result<HashMap<BlockId, bool>> {
+        let state = self.state()?;
+        let mut found = HashMap::new();
+        for (block_id, (page_id, buffer, read_to_max_index)) in block_pages {
+            let block = match state.block(&content_owner_id, page_id, block_id) {
+                Some(block) => block,
+                None => {
+                    found.insert(block_id, false);
+                    continue;
+                }
+            };
+            if read_to_max_index < 0 || read_to_max_index as usize >= self.io_block_size {
+                return Err(anyhow!("Invalid offset or buffer size"));
+            }
+            let len = (read_to_max_index as usize + 1).min(buffer.len());
+            buffer[..len].copy_from_slice(&block.data[..len]);
+            found.insert(block_id, true);
+        }
+        Ok(found)
+    }
+
+    fn is_block_cached(
+        &self,
+        content_owner_id: OwnerId,
+        page_id: PageId,
+        block_id: BlockId,
+    ) -> Result<bool> {
+        Ok(self
+            .state()?
+            .block(&content_owner_id, page_id, block_id)
+            .is_some())
+    }
+
+    fn make_block_readable_to_offset(
+        &self,
+        cid: OwnerId,
+        page_id: PageId,
+        block_id: BlockId,
+        offset: i32,
+    ) -> Result<()> {
+        let mut state = self.state()?;
+        if let Some(block) = state.blocks.get_mut(&(cid, block_id)) {
+            if block.page_id == page_id {
+                block.readable_to = offset;
+            }
+        }
+        Ok(())
+    }
+
+    fn get_engine_usage(&self) -> Result<f64> {
+        Ok(self.stats()?.usage())
+    }
+
+    fn remove_cached_blocks(&self, content_owner_id: OwnerId) -> Result<bool> {
+        self.state()?
+            .blocks
+            .retain(|(owner, _), _| *owner != content_owner_id);
+        Ok(true)
+    }
+
+    fn remove_clean_blocks(&self, content_owner_id: OwnerId) -> Result<Vec<BlockId>> {
+        let mut state = self.state()?;
+        let mut removed = Vec::new();
+        state.blocks.retain(|(owner, block_id), block| {
+            let clean = *owner == content_owner_id && block.synced;
+            if clean {
+                removed.push(*block_id);
+            }
+            !clean
+        });
+        removed.sort();
+        Ok(removed)
+    }
+
+    fn sync_pages(
+        &self,
+        owner: OwnerId,
+        size: u64,
+        orig_path: String,
+        dirty_extents: &HashMap<BlockId, Vec<Offsets>>,
+        _only_sync_data: bool,
+    ) -> Result<()> {
+        let mut state = self.state()?;
+        let block_size = self.io_block_size;
+        // (file offset, bytes) to write and the blocks they come from
+        let mut writes: Vec<(usize, Vec<u8>)> = Vec::new();
+        let mut synced = Vec::new();
+        for (block_id, block) in state.owner_blocks(&owner) {
+            if block.synced {
+                continue;
+            }
+            let in_file = block_id as usize * block_size;
+            match dirty_extents.get(&block_id) {
+                Some(extents) => {
+                    for &(from, to) in extents {
+                        let to = to.min(block_size as i32 - 1);
+                        let data = block.data[from as usize..=to as usize].to_vec();
+                        writes.push((in_file + from as usize, data));
+                    }
+                }
+                None => {
+                    let len = ((block.readable_to + 1).max(0) as usize).min(block_size);
+                    writes.push((in_file, block.data[..len].to_vec()));
+                }
+            }
+            synced.push(block_id);
+        }
+
+        let mut written = 0;
+        let disk = state.disk.entry(orig_path).or_default();
+        for (offset, data) in writes {
+            if disk.len() < offset + data.len() {
+                disk.resize(offset + data.len(), 0);
+            }
+            disk[offset..offset + data.len()].copy_from_slice(&data);
+            written += data.len() as u64;
+        }
+        disk.resize(size as usize, 0);
+        state.bytes_written_back += written;
+        for block_id in synced {
+            if let Some(block) = state.blocks.get_mut(&(owner.clone(), block_id)) {
+                block.synced = true;
+            }
+        }
+        Ok(())
+    }
+
+    fn rename_owner_pages(&self, old_owner: OwnerId, new_owner: OwnerId) -> Result<bool> {
+        let mut state = self.state()?;
+        let moved: Vec<_> = state
+            .blocks
+            .keys()
+            .filter(|(owner, _)| *owner == old_owner)
+            .cloned()
+            .collect();
+        if moved.is_empty() {
+            return Ok(false);
+        }
+        state.blocks.retain(|(owner, _), _| *owner != new_owner);
+        for key in moved {
+            let block = state.blocks.remove(&key).unwrap();
+            state.blocks.insert((new_owner.clone(), key.1), block);
+        }
+        Ok(true)
+    }
+
+    fn truncate_cached_blocks(
+        &self,
+        content_owner_id: OwnerId,
+        blocks_to_remove: HashMap<BlockId, PageId>,
+        from_block_id: BlockId,
+        index_inside_block: i32,
+    ) -> Result<bool> {
+        let mut state = self.state()?;
+        for (block_id, page_id) in blocks_to_remove {
+            let key = (content_owner_id.clone(), block_id);
+            if state.block(&content_owner_id, page_id, block_id).is_none() {
+                continue;
+            }
+            if block_id == from_block_id && index_inside_block > 0 {
+                let block = state.blocks.get_mut(&key).unwrap();
+                block.readable_to = index_inside_block - 1;
+                block.data[index_inside_block as usize..].fill(0);
+                continue;
+            }
+            state.blocks.remove(&key);
+        }
+        Ok(true)
+    }
+
+    fn get_dirty_blocks_info(&self, owner: OwnerId) -> Result<Vec<(BlockId, Offsets, PageId)>> {
+        let state = self.state()?;
+        Ok(state
+            .owner_blocks(&owner)
+            .into_iter()
+            .filter(|(_, block)| !block.synced)
+            .map(|(block_id, block)| (block_id, (0, block.readable_to), block.page_id))
+            .collect())
+    }
+
+    fn stats(&self) -> Result<CacheStats> {
+        let state = self.state()?;
+        Ok(CacheStats {
+            dirty_pages: state.blocks.values().filter(|block| !block.synced).count() as u64,
+            used_pages: state.blocks.len() as u64,
+            total_pages: self.capacity.unwrap_or(0) as u64,
+            bytes_written_back: state.bytes_written_back,
+            ..Default::default()
+        })
+    }
+
+    fn reset_stats(&self) -> Result<()> {
+        self.state()?.bytes_written_back = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pagecache::cache::Cache;
+    use crate::pagecache::config::Config;
+    use std::path::PathBuf;
+
+    /// A cache over a `MemCacheEngine`, along with a handle on the engine
+    fn mem_cache(capacity: Option<usize>) -> (Cache, MemCacheEngine) {
+        let config = Config::default();
+        let mut engine = MemCacheEngine::new(config.io_block_size);
+        if let Some(capacity) = capacity {
+            engine = engine.with_capacity(capacity);
+        }
+        (Cache::new(config, engine.clone()), engine)
+    }
+
+    #[test]
+    fn syncs_and_truncates_on_the_virtual_disk() {
+        let (cache, engine) = mem_cache(None);
+        let path = "/lazyfs-mem/wal";
+        cache.insert_item("wal".to_string()).unwrap();
+        cache.write_at("wal".to_string(), 0, &[1; 5000]).unwrap();
+        cache.write_at("wal".to_string(), 8192, b"tail").unwrap();
+        assert_eq!(engine.disk_contents(path), None);
+        // One page per block, numbered in block order
+        assert_eq!(
+            engine.get_dirty_blocks_info("wal".into()).unwrap(),
+            vec![(0, (0, 4095), 0), (1, (0, 903), 1), (2, (0, 3), 2)]
+        );
+
+        cache
+            .sync_owner("wal".to_string(), true, PathBuf::from(path))
+            .unwrap();
+        let mut expected = vec![1; 5000];
+        expected.resize(8192, 0);
+        expected.extend_from_slice(b"tail");
+        assert_eq!(engine.disk_contents(path).unwrap(), expected);
+        assert!(engine
+            .get_dirty_blocks_info("wal".into())
+            .unwrap()
+            .is_empty());
+
+        cache.truncate_item("wal".to_string(), 100).unwrap();
+        cache.write_at("wal".to_string(), 50, &[2; 10]).unwrap();
+        cache
+            .sync_owner("wal".to_string(), true, PathBuf::from(path))
+            .unwrap();
+        let mut expected = vec![1; 100];
+        expected[50..60].fill(2);
+        assert_eq!(engine.disk_contents(path).unwrap(), expected);
+        assert_eq!(cache.stats().unwrap().used_pages, 1);
+    }
+
+    #[test]
+    fn full_engine_refuses_new_blocks() {
+        let (cache, engine) = mem_cache(Some(2));
+        cache.insert_item("sst".to_string()).unwrap();
+        assert_eq!(
+            cache
+                .write_at("sst".to_string(), 0, &[3; 3 * 4096])
+                .unwrap(),
+            2 * 4096
+        );
+        assert_eq!(cache.get_cache_usage().unwrap(), 100.0);
+
+        // Blocks move along with their owner
+        assert!(engine
+            .rename_owner_pages("sst".into(), "sst.old".into())
+            .unwrap());
+        let mut buf = vec![0; 4096];
+        assert_eq!(
+            engine.read_block("sst.old".into(), 1, 1, &mut buf).unwrap(),
+            Some(4096)
+        );
+        assert_eq!(buf, vec![3; 4096]);
+        assert_eq!(
+            engine.read_block("sst".into(), 1, 1, &mut buf).unwrap(),
+            None
+        );
+    }
+}
@@ -1 +1,31 @@
-pub mod custom;
\ No newline at end of file
+use anyhow::{anyhow, Result};
+
+use crate::pagecache::config::Config;
+use crate::pagecache::engine::PageCacheEngine;
+
+pub mod custom;
+pub mod memory;
+pub mod passthrough;
+pub mod spill;
+
+/// The engine named in `Config::engine`
+pub fn from_config(config: &Config) -> Result<Box<dyn PageCacheEngine>> {
+    match config.engine.as_str() {
+        "custom" => Ok(Box::new(custom::CustomCacheEngine::new(Box::new(
+            config.clone(),
+        ))?)),
+        "memory" => {
+            let blocks = config.cache_nr_pages * config.cache_page_size / config.io_block_size;
+            Ok(Box::new(
+                memory::MemCacheEngine::new(config.io_block_size).with_capacity(blocks),
+            ))
+        }
+        "passthrough" => Ok(Box::new(passthrough::PassthroughEngine::new(
+            config.io_block_size,
+        ))),
+        "spill" => Ok(Box::new(spill::SpillCacheEngine::new(Box::new(
+            config.clone(),
+        ))?)),
+        _ => Err(anyhow!("Unknown engine '{}'", config.engine)),
+    }
+}
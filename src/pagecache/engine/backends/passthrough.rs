@@ -0,0 +1,279 @@
+use crate::pagecache::engine::{AllocateOperationType, PageCacheEngine};
+use crate::pagecache::{BlockId, Offsets, OwnerId, PageId};
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{RwLock, RwLockReadGuard};
+
+/// Page id given to blocks written straight to disk. Nothing is ever found under it.
+const WRITTEN_THROUGH: PageId = 0;
+
+/// Engine caching nothing, as a baseline to measure what the cache costs. Writes go straight to
+/// the backing file of their owner, as learned from `set_owner_path`, every lookup misses so
+/// reads go to the backing file too, and `sync_pages` is a plain fsync. Since nothing is held
+/// back, clearing the cache loses nothing.
+#[derive(Debug)]
+pub struct PassthroughEngine {
+    io_block_size: usize,
+    /// Backing file of each owner
+    paths: RwLock<HashMap<OwnerId, PathBuf>>,
+}
+
+impl PassthroughEngine {
+    /// `io_block_size` has to match the one of the config the cache is given
+    pub fn new(io_block_size: usize) -> Self {
+        PassthroughEngine {
+            io_block_size,
+            paths: RwLock::new(HashMap::new()),
+        }
+    }
+
+    fn paths(&self) -> Result<RwLockReadGuard<'_, HashMap<OwnerId, PathBuf>>> {
+        self.paths
+            .read()
+            .map_err(|e| anyhow!("Failed to acquire passthrough paths lock: {:?}", e))
+    }
+
+    /// Backing file of `owner` opened for writing, `None` if its path isn't known
+    fn open(&self, owner: &str) -> Result<Option<File>> {
+        let paths = self.paths()?;
+        let path = match paths.get(owner) {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let file = OpenOptions::new()
+            .write(true)
+            .open(path)
+            .with_context(|| format!("Unable to open {} for {}", path.display(), owner))?;
+        Ok(Some(file))
+    }
+
+    fn block_start(&self, block_id: BlockId) -> u64 {
+        block_id as u64 * self.io_block_size as u64
+    }
+}
+
+impl PageCacheEngine for PassthroughEngine {
+    fn allocate_blocks(
+        &self,
+        content_owner_id: OwnerId,
+        block_data_mapping: HashMap<BlockId, (PageId, &Vec<u8>, i32)>,
+        operation_type: AllocateOperationType,
+    ) -> Result<HashMap<BlockId, PageId>> {
+        // Reads and read-merges only mirror the backing file, which already has them
+        let file = match operation_type {
+            AllocateOperationType::OpWrite => self.open(&content_owner_id)?,
+            _ => None,
+        };
+        let mut allocated = HashMap::with_capacity(block_data_mapping.len());
+        for (block_id, (_, data, start)) in block_data_mapping {
+            let page_id = match &file {
+                Some(file) => {
+                    file.write_all_at(data, self.block_start(block_id) + start as u64)?;
+                    WRITTEN_THROUGH
+                }
+                None => -1,
+            };
+            allocated.insert(block_id, page_id);
+        }
+        Ok(allocated)
+    }
+
+    fn insert_read_blocks(
+        &self,
+        _content_owner_id: OwnerId,
+        blocks: Vec<(BlockId, &[u8], usize)>,
+    ) -> Result<HashMap<BlockId, PageId>> {
+        Ok(blocks
+            .into_iter()
+            .map(|(block_id, ..)| (block_id, -1))
+            .collect())
+    }
+
+    fn copy_blocks(
+        &self,
+        src_owner: OwnerId,
+        dst_owner: OwnerId,
+        pairs: Vec<(BlockId, BlockId)>,
+    ) -> Result<HashMap<BlockId, PageId>> {
+        let src = match self.paths()?.get(&src_owner) {
+            Some(path) => File::open(path)
+                .with_context(|| format!("Unable to open {} for {}", path.display(), src_owner))?,
+            None => return Ok(pairs.into_iter().map(|(_, dst)| (dst, -1)).collect()),
+        };
+        let dst = match self.open(&dst_owner)? {
+            Some(file) => file,
+            None => return Ok(pairs.into_iter().map(|(_, dst)| (dst, -1)).collect()),
+        };
+        let mut copied = HashMap::with_capacity(pairs.len());
+        let mut buffer = vec![0; self.io_block_size];
+        for (src_block, dst_block) in pairs {
+            let len = src.read_at(&mut buffer, self.block_start(src_block))?;
+            dst.write_all_at(&buffer[..len], self.block_start(dst_block))?;
+            copied.insert(dst_block, WRITTEN_THROUGH);
+        }
+        Ok(copied)
+    }
+
+    fn read_block(
+        &self,
+        _content_owner_id: OwnerId,
+        _page_id: PageId,
+        _block_id: BlockId,
+        _buffer: &mut [u8],
+    ) -> Result<Option<usize>> {
+        Ok(None)
+    }
+
+    fn get_blocks(
+        &self,
+        _content_owner_id: OwnerId,
+        block_pages: HashMap<BlockId, (PageId, &mut [u8], i32)>,
+    ) -> Result<HashMap<BlockId, bool>> {
+        Ok(block_pages
+            .into_keys()
+            .map(|block_id| (block_id, false))
+            .collect())
+    }
+
+    fn is_block_cached(
+        &self,
+        _content_owner_id: OwnerId,
+        _page_id: PageId,
+        _block_id: BlockId,
+    ) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn make_block_readable_to_offset(
+        &self,
+        _cid: OwnerId,
+        _page_id: PageId,
+        _block_id: BlockId,
+        _offset: i32,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn get_engine_usage(&self) -> Result<f64> {
+        Ok(0.0)
+    }
+
+    fn remove_cached_blocks(&self, _content_owner_id: OwnerId) -> Result<bool> {
+        Ok(true)
+    }
+
+    fn remove_clean_blocks(&self, _content_owner_id: OwnerId) -> Result<Vec<BlockId>> {
+        Ok(Vec::new())
+    }
+
+    fn sync_pages(
+        &self,
+        _owner: OwnerId,
+        size: u64,
+        orig_path: String,
+        _dirty_extents: &HashMap<BlockId, Vec<Offsets>>,
+        only_sync_data: bool,
+    ) -> Result<()> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&orig_path)
+            .with_context(|| format!("Unable to open {} to sync it", orig_path))?;
+        if file.metadata()?.len() != size {
+            file.set_len(size)?;
+        }
+        if only_sync_data {
+            file.sync_data()?;
+        } else {
+            file.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn rename_owner_pages(&self, old_owner: OwnerId, new_owner: OwnerId) -> Result<bool> {
+        let mut paths = self
+            .paths
+            .write()
+            .map_err(|e| anyhow!("Failed to acquire passthrough paths lock: {:?}", e))?;
+        if let Some(path) = paths.remove(&old_owner) {
+            paths.insert(new_owner, path);
+        }
+        Ok(false)
+    }
+
+    fn set_owner_path(&self, content_owner_id: OwnerId, path: &Path) -> Result<()> {
+        self.paths
+            .write()
+            .map_err(|e| anyhow!("Failed to acquire passthrough paths lock: {:?}", e))?
+            .insert(content_owner_id, path.to_path_buf());
+        Ok(())
+    }
+
+    /// Cuts the backing file at once, the truncate is as much written through as the writes
+    fn truncate_cached_blocks(
+        &self,
+        content_owner_id: OwnerId,
+        _blocks_to_remove: HashMap<BlockId, PageId>,
+        from_block_id: BlockId,
+        index_inside_block: i32,
+    ) -> Result<bool> {
+        let file = match self.open(&content_owner_id)? {
+            Some(file) => file,
+            None => return Ok(true),
+        };
+        let size = self.block_start(from_block_id) + index_inside_block.max(0) as u64;
+        if file.metadata()?.len() > size {
+            file.set_len(size)?;
+        }
+        Ok(true)
+    }
+
+    fn get_dirty_blocks_info(&self, _owner: OwnerId) -> Result<Vec<(BlockId, Offsets, PageId)>> {
+        Ok(Vec::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pagecache::cache::Cache;
+    use crate::pagecache::config::Config;
+    use std::fs;
+
+    #[test]
+    fn writes_land_on_disk_and_reads_miss() {
+        let dir =
+            std::env::temp_dir().join(format!("lazyfs-rs-{}-passthrough", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file");
+        fs::write(&path, b"").unwrap();
+
+        let config = Config::default();
+        let block_size = config.io_block_size;
+        let cache = Cache::new(config, PassthroughEngine::new(block_size));
+        let owner = cache
+            .insert_inode_mapping(path.clone(), "file".to_string(), false)
+            .unwrap();
+        let data = vec![7u8; block_size + 10];
+        assert_eq!(cache.write_at(owner.clone(), 0, &data).unwrap(), data.len());
+        assert_eq!(fs::read(&path).unwrap(), data);
+        // Partial writes go to their place without merging anything
+        cache.write_at(owner.clone(), 2, b"ab").unwrap();
+        assert_eq!(&fs::read(&path).unwrap()[..5], &[7, 7, b'a', b'b', 7]);
+
+        let mut buf = vec![0; 5];
+        assert_eq!(cache.read_at(owner.clone(), 0, &mut buf).unwrap(), 5);
+        assert_eq!(buf, [7, 7, b'a', b'b', 7]);
+        assert_eq!(cache.stats().unwrap().hits, 0);
+
+        cache.truncate_item(owner.clone(), 3).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), [7, 7, b'a']);
+        cache.sync_owner(owner, false, path.clone()).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), [7, 7, b'a']);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,694 @@
+use crate::pagecache::config::Config;
+use crate::pagecache::engine::lru::LruList;
+use crate::pagecache::engine::{AllocateOperationType, PageCacheEngine};
+use crate::pagecache::stats::CacheStats;
+use crate::pagecache::{BlockId, Offsets, OwnerId, PageId};
+use anyhow::{anyhow, Context, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::sync::{Mutex, MutexGuard};
+
+/// Engine for caches larger than memory. Each page holds one block. `cache_nr_pages` of them
+/// stay in memory, and once those are taken the least recently used one is written to a slot of
+/// `spill_path` instead of being dropped, at `slot * cache_page_size`. A spilled page is read
+/// back into memory the next time its block is looked up, spilling another in its place. The
+/// engine only refuses blocks once `spill_max_pages` slots are taken too. Nothing is lost by
+/// spilling, so the cache is never told about it and dirty pages stay dirty until synced.
+#[derive(Debug)]
+pub struct SpillCacheEngine {
+    io_block_size: usize,
+    /// Size of a spill slot
+    page_size: usize,
+    /// Pages kept in memory
+    hot_pages: usize,
+    spill_max_pages: usize,
+    spill: File,
+    state: Mutex<SpillState>,
+}
+
+#[derive(Debug, Default)]
+struct SpillState {
+    blocks: HashMap<(OwnerId, BlockId), SpillBlock>,
+    /// Owner and block of each page
+    pages: HashMap<PageId, (OwnerId, BlockId)>,
+    /// Pages in memory, from most to least recently used
+    hot: LruList,
+    next_page_id: PageId,
+    /// Slots freed by pages read back or dropped, reused before new ones
+    free_slots: Vec<usize>,
+    /// Slots taken at least once so far
+    used_slots: usize,
+    spilled_pages: u64,
+    bytes_written_back: u64,
+}
+
+#[derive(Debug)]
+struct SpillBlock {
+    page_id: PageId,
+    residence: Residence,
+    /// Last readable byte
+    readable_to: i32,
+    synced: bool,
+}
+
+#[derive(Debug)]
+enum Residence {
+    /// A whole IO block, zeroed past what was written
+    Hot(Vec<u8>),
+    /// Spilled to this slot
+    Spilled(usize),
+}
+
+impl SpillState {
+    fn block(&self, owner: &OwnerId, page_id: PageId, block_id: BlockId) -> Option<&SpillBlock> {
+        self.blocks
+            .get(&(owner.clone(), block_id))
+            .filter(|block| block.page_id == page_id)
+    }
+
+    /// Blocks of `owner` by id
+    fn owner_blocks(&self, owner: &str) -> BTreeMap<BlockId, &SpillBlock> {
+        self.blocks
+            .iter()
+            .filter(|((block_owner, _), _)| &**block_owner == owner)
+            .map(|((_, block_id), block)| (*block_id, block))
+            .collect()
+    }
+
+    /// Drops `key`, giving back its slot if it was spilled
+    fn free(&mut self, key: &(OwnerId, BlockId)) {
+        if let Some(block) = self.blocks.remove(key) {
+            self.pages.remove(&block.page_id);
+            self.hot.remove(block.page_id);
+            if let Residence::Spilled(slot) = block.residence {
+                self.free_slots.push(slot);
+            }
+        }
+    }
+}
+
+impl SpillCacheEngine {
+    /// Engine keeping `cache_nr_pages` pages in memory and spilling up to `spill_max_pages` to
+    /// `spill_path`, which is created or emptied. Fails if there is no spill path, no page to
+    /// keep in memory or the page size can't hold a block.
+    pub fn new(config: Box<Config>) -> Result<Self> {
+        if config.spill_path.as_os_str().is_empty() {
+            return Err(anyhow!("No spill_path to spill pages to"));
+        }
+        if config.cache_nr_pages == 0 {
+            return Err(anyhow!(
+                "The spill engine needs at least one page in memory"
+            ));
+        }
+        if config.cache_page_size < config.io_block_size {
+            return Err(anyhow!(
+                "Page size {} can't hold a block of {} bytes",
+                config.cache_page_size,
+                config.io_block_size
+            ));
+        }
+        let spill = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&config.spill_path)
+            .with_context(|| {
+                format!("Unable to open spill file {}", config.spill_path.display())
+            })?;
+        Ok(SpillCacheEngine {
+            io_block_size: config.io_block_size,
+            page_size: config.cache_page_size,
+            hot_pages: config.cache_nr_pages,
+            spill_max_pages: config.spill_max_pages,
+            spill,
+            state: Mutex::new(SpillState::default()),
+        })
+    }
+
+    fn state(&self) -> Result<MutexGuard<'_, SpillState>> {
+        self.state
+            .lock()
+            .map_err(|e| anyhow!("Failed to acquire spill engine lock: {:?}", e))
+    }
+
+    fn slot_offset(&self, slot: usize) -> u64 {
+        slot as u64 * self.page_size as u64
+    }
+
+    /// Spills the least recently used page in memory. Returns whether there was one and a slot
+    /// to put it in.
+    fn spill_one(&self, state: &mut SpillState) -> Result<bool> {
+        let victim = match state.hot.back() {
+            Some(victim) => victim,
+            None => return Ok(false),
+        };
+        let slot = match state.free_slots.pop() {
+            Some(slot) => slot,
+            None if state.used_slots < self.spill_max_pages => {
+                state.used_slots += 1;
+                state.used_slots - 1
+            }
+            None => return Ok(false),
+        };
+        let key = state.pages[&victim].clone();
+        let block = state.blocks.get_mut(&key).unwrap();
+        if let Residence::Hot(data) = &block.residence {
+            if let Err(e) = self.spill.write_all_at(data, self.slot_offset(slot)) {
+                state.free_slots.push(slot);
+                return Err(
+                    anyhow::Error::from(e).context(format!("Unable to spill page {}", victim))
+                );
+            }
+        }
+        block.residence = Residence::Spilled(slot);
+        state.hot.remove(victim);
+        state.spilled_pages += 1;
+        Ok(true)
+    }
+
+    /// Spills pages until one more fits in memory. Returns whether it does.
+    fn make_room(&self, state: &mut SpillState) -> Result<bool> {
+        while state.hot.len() >= self.hot_pages {
+            if !self.spill_one(state)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Brings `key` back into memory if it was spilled and makes it the most recently used page
+    fn fault_in(&self, state: &mut SpillState, key: &(OwnerId, BlockId)) -> Result<()> {
+        let (page_id, slot) = match state.blocks.get(key) {
+            Some(block) => match block.residence {
+                Residence::Spilled(slot) => (block.page_id, slot),
+                Residence::Hot(_) => {
+                    state.hot.push_front(block.page_id);
+                    return Ok(());
+                }
+            },
+            None => return Ok(()),
+        };
+        let mut data = vec![0; self.io_block_size];
+        self.spill
+            .read_exact_at(&mut data, self.slot_offset(slot))
+            .with_context(|| format!("Unable to read page {} back", page_id))?;
+        // The slot given back is there for the page making room
+        state.free_slots.push(slot);
+        let room = self.make_room(state);
+        if !matches!(room, Ok(true)) {
+            // Still spilled there
+            state.free_slots.retain(|&free| free != slot);
+            room?;
+            return Err(anyhow!("No room to read page {} back", page_id));
+        }
+        state.blocks.get_mut(key).unwrap().residence = Residence::Hot(data);
+        state.hot.push_front(page_id);
+        Ok(())
+    }
+
+    /// The block's data, wherever it is, leaving it there
+    fn data_of(&self, block: &SpillBlock) -> Result<Vec<u8>> {
+        match &block.residence {
+            Residence::Hot(data) => Ok(data.clone()),
+            Residence::Spilled(slot) => {
+                let mut data = vec![0; self.io_block_size];
+                self.spill
+                    .read_exact_at(&mut data, self.slot_offset(*slot))
+                    .with_context(|| format!("Unable to read page {} back", block.page_id))?;
+                Ok(data)
+            }
+        }
+    }
+
+    /// The in-memory data of `key`, which must have been faulted in
+    fn hot_data<'a>(state: &'a mut SpillState, key: &(OwnerId, BlockId)) -> &'a mut Vec<u8> {
+        match &mut state.blocks.get_mut(key).unwrap().residence {
+            Residence::Hot(data) => data,
+            Residence::Spilled(_) => unreachable!("block was faulted in"),
+        }
+    }
+
+    /// Gives `block_id` of `owner` a new page in memory holding `data` from `offset`, unless
+    /// nothing can be spilled to make room. Returns the page.
+    fn insert_block(
+        &self,
+        state: &mut SpillState,
+        owner: &OwnerId,
+        block_id: BlockId,
+        data: &[u8],
+        offset: usize,
+        synced: bool,
+    ) -> Result<Option<PageId>> {
+        if !self.make_room(state)? {
+            return Ok(None);
+        }
+        let page_id = state.next_page_id;
+        state.next_page_id += 1;
+        let mut block = vec![0; self.io_block_size];
+        block[offset..offset + data.len()].copy_from_slice(data);
+        let key = (owner.clone(), block_id);
+        state.blocks.insert(
+            key.clone(),
+            SpillBlock {
+                page_id,
+                residence: Residence::Hot(block),
+                readable_to: 0,
+                synced,
+            },
+        );
+        state.pages.insert(page_id, key);
+        state.hot.push_front(page_id);
+        Ok(Some(page_id))
+    }
+}
+
+impl PageCacheEngine for SpillCacheEngine {
+    fn allocate_blocks(
+        &self,
+        content_owner_id: OwnerId,
+        block_data_mapping: HashMap<BlockId, (PageId, &Vec<u8>, i32)>,
+        operation_type: AllocateOperationType,
+    ) -> Result<HashMap<BlockId, PageId>> {
+        let mut state = self.state()?;
+        let mut allocated = HashMap::new();
+        // In block order, for the page ids to be the same from one run to the next
+        let block_data_mapping: BTreeMap<_, _> = block_data_mapping.into_iter().collect();
+        for (block_id, (_, data, offset)) in block_data_mapping {
+            if offset < 0 || offset as usize + data.len() > self.io_block_size {
+                allocated.insert(block_id, -1);
+                continue;
+            }
+            let offset = offset as usize;
+            let key = (content_owner_id.clone(), block_id);
+            if let Some(page_id) = state.blocks.get(&key).map(|block| block.page_id) {
+                // The cached copy is at least as new as the backing file
+                if operation_type != AllocateOperationType::OpPassthrough {
+                    self.fault_in(&mut state, &key)?;
+                    Self::hot_data(&mut state, &key)[offset..offset + data.len()]
+                        .copy_from_slice(data);
+                    if operation_type == AllocateOperationType::OpWrite {
+                        state.blocks.get_mut(&key).unwrap().synced = false;
+                    }
+                }
+                allocated.insert(block_id, page_id);
+                continue;
+            }
+            let synced = operation_type != AllocateOperationType::OpWrite;
+            let page_id = self
+                .insert_block(
+                    &mut state,
+                    &content_owner_id,
+                    block_id,
+                    data,
+                    offset,
+                    synced,
+                )?
+                .unwrap_or(-1);
+            allocated.insert(block_id, page_id);
+        }
+        Ok(allocated)
+    }
+
+    fn insert_read_blocks(
+        &self,
+        content_owner_id: OwnerId,
+        blocks: Vec<(BlockId, &[u8], usize)>,
+    ) -> Result<HashMap<BlockId, PageId>> {
+        let mut state = self.state()?;
+        let mut placed = HashMap::new();
+        for (block_id, data, valid_len) in blocks {
+            if valid_len == 0 || valid_len > data.len() || data.len() > self.io_block_size {
+                placed.insert(block_id, -1);
+                continue;
+            }
+            let key = (content_owner_id.clone(), block_id);
+            if let Some(block) = state.blocks.get(&key) {
+                placed.insert(block_id, block.page_id);
+                continue;
+            }
+            let page_id =
+                match self.insert_block(&mut state, &content_owner_id, block_id, data, 0, true)? {
+                    Some(page_id) => {
+                        state.blocks.get_mut(&key).unwrap().readable_to = valid_len as i32 - 1;
+                        page_id
+                    }
+                    None => -1,
+                };
+            placed.insert(block_id, page_id);
+        }
+        Ok(placed)
+    }
+
+    fn copy_blocks(
+        &self,
+        src_owner: OwnerId,
+        dst_owner: OwnerId,
+        pairs: Vec<(BlockId, BlockId)>,
+    ) -> Result<HashMap<BlockId, PageId>> {
+        let copies = {
+            let state = self.state()?;
+            let mut copies = Vec::with_capacity(pairs.len());
+            for (src_block, dst_block) in pairs {
+                let block = state
+                    .blocks
+                    .get(&(src_owner.clone(), src_block))
+                    .ok_or_else(|| anyhow!("Block {} of {} is not cached", src_block, src_owner))?;
+                let mut data = self.data_of(block)?;
+                data.truncate(block.readable_to as usize + 1);
+                copies.push((dst_block, data));
+            }
+            copies
+        };
+
+        let block_data_mapping = copies
+            .iter()
+            .map(|(dst_block, data)| (*dst_block, (-1, data, 0)))
+            .collect();
+        let allocated = self.allocate_blocks(
+            dst_owner.clone(),
+            block_data_mapping,
+            AllocateOperationType::OpWrite,
+        )?;
+
+        let mut state = self.state()?;
+        for (dst_block, data) in &copies {
+            if let Some(block) = state.blocks.get_mut(&(dst_owner.clone(), *dst_block)) {
+                block.readable_to = data.len() as i32 - 1;
+            }
+        }
+        Ok(allocated)
+    }
+
+    fn read_block(
+        &self,
+        content_owner_id: OwnerId,
+        page_id: PageId,
+        block_id: BlockId,
+        buffer: &mut [u8],
+    ) -> Result<Option<usize>> {
+        let state = self.state()?;
+        let block = match state.block(&content_owner_id, page_id, block_id) {
+            Some(block) => block,
+            None => return Ok(None),
+        };
+        let data = self.data_of(block)?;
+        let len = ((block.readable_to + 1).max(0) as usize).min(buffer.len());
+        buffer[..len].copy_from_slice(&data[..len]);
+        Ok(Some(len))
+    }
+
+    fn get_blocks(
+        &self,
+        content_owner_id: OwnerId,
+        block_pages: HashMap<BlockId, (PageId, &mut [u8], i32)>,
+    ) -> Result<HashMap<BlockId, bool>> {
+        let mut state = self.state()?;
+        let mut found = HashMap::new();
+        for (block_id, (page_id, buffer, read_to_max_index)) in block_pages {
+            if state.block(&content_owner_id, page_id, block_id).is_none() {
+                found.insert(block_id, false);
+                continue;
+            }
+            if read_to_max_index < 0 || read_to_max_index as usize >= self.io_block_size {
+                return Err(anyhow!("Invalid offset or buffer size"));
+            }
+            let key = (content_owner_id.clone(), block_id);
+            self.fault_in(&mut state, &key)?;
+            let data = Self::hot_data(&mut state, &key);
+            let len = (read_to_max_index as usize + 1).min(buffer.len());
+            buffer[..len].copy_from_slice(&data[..len]);
+            found.insert(block_id, true);
+        }
+        Ok(found)
+    }
+
+    fn is_block_cached(
+        &self,
+        content_owner_id: OwnerId,
+        page_id: PageId,
+        block_id: BlockId,
+    ) -> Result<bool> {
+        Ok(self
+            .state()?
+            .block(&content_owner_id, page_id, block_id)
+            .is_some())
+    }
+
+    fn make_block_readable_to_offset(
+        &self,
+        cid: OwnerId,
+        page_id: PageId,
+        block_id: BlockId,
+        offset: i32,
+    ) -> Result<()> {
+        let mut state = self.state()?;
+        if let Some(block) = state.blocks.get_mut(&(cid, block_id)) {
+            if block.page_id == page_id {
+                block.readable_to = offset;
+            }
+        }
+        Ok(())
+    }
+
+    fn get_engine_usage(&self) -> Result<f64> {
+        Ok(self.stats()?.usage())
+    }
+
+    fn remove_cached_blocks(&self, content_owner_id: OwnerId) -> Result<bool> {
+        let mut state = self.state()?;
+        let keys: Vec<_> = state
+            .blocks
+            .keys()
+            .filter(|(owner, _)| *owner == content_owner_id)
+            .cloned()
+            .collect();
+        for key in keys {
+            state.free(&key);
+        }
+        Ok(true)
+    }
+
+    fn remove_clean_blocks(&self, content_owner_id: OwnerId) -> Result<Vec<BlockId>> {
+        let mut state = self.state()?;
+        let mut removed: Vec<_> = state
+            .owner_blocks(&content_owner_id)
+            .into_iter()
+            .filter(|(_, block)| block.synced)
+            .map(|(block_id, _)| block_id)
+            .collect();
+        for &block_id in &removed {
+            state.free(&(content_owner_id.clone(), block_id));
+        }
+        removed.sort();
+        Ok(removed)
+    }
+
+    fn sync_pages(
+        &self,
+        owner: OwnerId,
+        size: u64,
+        orig_path: String,
+        dirty_extents: &HashMap<BlockId, Vec<Offsets>>,
+        only_sync_data: bool,
+    ) -> Result<()> {
+        let mut state = self.state()?;
+        // The file may only ever have existed in the cache
+        let fd = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(orig_path)?;
+
+        let block_size = self.io_block_size;
+        let mut written = 0;
+        let mut synced = Vec::new();
+        for (block_id, block) in state.owner_blocks(&owner) {
+            if block.synced {
+                continue;
+            }
+            // Spilled pages are read back for the write only, they stay spilled
+            let data = self.data_of(block)?;
+            let in_file = block_id as u64 * block_size as u64;
+            match dirty_extents.get(&block_id) {
+                Some(extents) => {
+                    for &(from, to) in extents {
+                        let to = to.min(block_size as i32 - 1);
+                        let range = &data[from as usize..=to as usize];
+                        fd.write_all_at(range, in_file + from as u64)?;
+                        written += range.len() as u64;
+                    }
+                }
+                None => {
+                    let len = ((block.readable_to + 1).max(0) as usize).min(block_size);
+                    fd.write_all_at(&data[..len], in_file)?;
+                    written += len as u64;
+                }
+            }
+            synced.push(block_id);
+        }
+        state.bytes_written_back += written;
+        for block_id in synced {
+            if let Some(block) = state.blocks.get_mut(&(owner.clone(), block_id)) {
+                block.synced = true;
+            }
+        }
+
+        // Truncate the file to the specified size, before flushing so the size is durable too
+        fd.set_len(size)?;
+        if only_sync_data {
+            fd.sync_data()?;
+        } else {
+            fd.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn rename_owner_pages(&self, old_owner: OwnerId, new_owner: OwnerId) -> Result<bool> {
+        let mut state = self.state()?;
+        let moved: Vec<_> = state
+            .blocks
+            .keys()
+            .filter(|(owner, _)| *owner == old_owner)
+            .cloned()
+            .collect();
+        if moved.is_empty() {
+            return Ok(false);
+        }
+        let replaced: Vec<_> = state
+            .blocks
+            .keys()
+            .filter(|(owner, _)| *owner == new_owner)
+            .cloned()
+            .collect();
+        for key in replaced {
+            state.free(&key);
+        }
+        for key in moved {
+            let block = state.blocks.remove(&key).unwrap();
+            let new_key = (new_owner.clone(), key.1);
+            state.pages.insert(block.page_id, new_key.clone());
+            state.blocks.insert(new_key, block);
+        }
+        Ok(true)
+    }
+
+    fn truncate_cached_blocks(
+        &self,
+        content_owner_id: OwnerId,
+        blocks_to_remove: HashMap<BlockId, PageId>,
+        from_block_id: BlockId,
+        index_inside_block: i32,
+    ) -> Result<bool> {
+        let mut state = self.state()?;
+        for (block_id, page_id) in blocks_to_remove {
+            let key = (content_owner_id.clone(), block_id);
+            if state.block(&content_owner_id, page_id, block_id).is_none() {
+                continue;
+            }
+            if block_id == from_block_id && index_inside_block > 0 {
+                self.fault_in(&mut state, &key)?;
+                Self::hot_data(&mut state, &key)[index_inside_block as usize..].fill(0);
+                state.blocks.get_mut(&key).unwrap().readable_to = index_inside_block - 1;
+                continue;
+            }
+            state.free(&key);
+        }
+        Ok(true)
+    }
+
+    fn get_dirty_blocks_info(&self, owner: OwnerId) -> Result<Vec<(BlockId, Offsets, PageId)>> {
+        let state = self.state()?;
+        Ok(state
+            .owner_blocks(&owner)
+            .into_iter()
+            .filter(|(_, block)| !block.synced)
+            .map(|(block_id, block)| (block_id, (0, block.readable_to), block.page_id))
+            .collect())
+    }
+
+    fn stats(&self) -> Result<CacheStats> {
+        let state = self.state()?;
+        Ok(CacheStats {
+            evictions: state.spilled_pages,
+            dirty_pages: state.blocks.values().filter(|block| !block.synced).count() as u64,
+            used_pages: state.blocks.len() as u64,
+            total_pages: (self.hot_pages + self.spill_max_pages) as u64,
+            bytes_written_back: state.bytes_written_back,
+            ..Default::default()
+        })
+    }
+
+    fn reset_stats(&self) -> Result<()> {
+        let mut state = self.state()?;
+        state.spilled_pages = 0;
+        state.bytes_written_back = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pagecache::cache::Cache;
+    use std::fs;
+
+    #[test]
+    fn four_times_the_memory_reads_back() {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-spill", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = Config {
+            cache_nr_pages: 4,
+            spill_path: dir.join("spill"),
+            spill_max_pages: 12,
+            ..Config::default()
+        };
+        let block_size = config.io_block_size;
+        let cache = Cache::new(
+            config.clone(),
+            SpillCacheEngine::new(Box::new(config)).unwrap(),
+        );
+        let path = dir.join("file");
+        let owner = "file".to_string();
+        let block = |i: usize| vec![i as u8 + 1; block_size];
+        for i in 0..16 {
+            let written = cache
+                .write_at(owner.clone(), (i * block_size) as u64, &block(i))
+                .unwrap();
+            assert_eq!(written, block_size);
+        }
+        // Every slot and page is taken
+        let extra = cache.write_at(owner.clone(), (16 * block_size) as u64, &block(16));
+        assert_eq!(extra.unwrap(), 0);
+        assert_eq!(cache.stats().unwrap().evictions, 12);
+
+        // Backwards, so every read faults a page back in and spills another
+        for i in (0..16).rev() {
+            let mut buf = vec![0; block_size];
+            cache
+                .read_at(owner.clone(), (i * block_size) as u64, &mut buf)
+                .unwrap();
+            assert_eq!(buf, block(i), "block {}", i);
+        }
+
+        cache
+            .sync_owner(owner.clone(), false, path.clone())
+            .unwrap();
+        let synced = fs::read(&path).unwrap();
+        assert_eq!(synced.len(), 16 * block_size);
+        for (i, synced) in synced.chunks(block_size).enumerate() {
+            assert_eq!(synced, block(i), "block {}", i);
+        }
+
+        // Removing the owner frees its slots for the next one
+        cache.truncate_item(owner, 0).unwrap();
+        for i in 0..16 {
+            let written = cache
+                .write_at("other".to_string(), (i * block_size) as u64, &block(i))
+                .unwrap();
+            assert_eq!(written, block_size);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
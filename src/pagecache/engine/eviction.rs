@@ -0,0 +1,308 @@
+use anyhow::{anyhow, Result};
+use std::collections::{HashSet, VecDeque};
+use std::fmt::{self, Debug};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::pagecache::engine::lru::LruList;
+use crate::pagecache::{BlockId, OwnerId, PageId};
+
+/// Decides which page makes room once every page of the engine is taken. The engine tells it
+/// about every page that gets data, is read or is freed, and asks it for a victim among the
+/// pages it can evict at that moment.
+pub trait EvictionPolicy: Debug + Send + Sync {
+    /// Data was cached in `page_id`. A page already tracked gets more blocks this way, which
+    /// counts as an access.
+    fn on_insert(&mut self, page_id: PageId);
+
+    /// Data nobody asked for yet was cached in `page_id`, such as the rest of a block pulled in
+    /// to merge a write. Evicted before what was inserted normally, where the policy can tell.
+    /// A page already tracked keeps its place.
+    fn on_insert_cold(&mut self, page_id: PageId);
+
+    /// A block of `page_id` was read
+    fn on_access(&mut self, page_id: PageId);
+
+    /// `page_id` was freed or handed to another owner
+    fn remove(&mut self, page_id: PageId);
+
+    /// The page to evict among those `eligible` accepts, if any. It stays tracked until
+    /// `remove`d.
+    fn victim(&mut self, eligible: &dyn Fn(PageId) -> bool) -> Option<PageId>;
+
+    /// Pages tracked
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// The policy named `name` in `Config::eviction_policy`
+pub fn eviction_policy(name: &str) -> Result<Box<dyn EvictionPolicy>> {
+    match name {
+        "lru" => Ok(Box::<LruPolicy>::default()),
+        "fifo" => Ok(Box::<FifoPolicy>::default()),
+        "clock" => Ok(Box::<ClockPolicy>::default()),
+        _ => Err(anyhow!("Unknown eviction policy '{}'", name)),
+    }
+}
+
+/// Evicts the page used longest ago
+#[derive(Debug, Default)]
+pub struct LruPolicy {
+    pages: LruList,
+}
+
+impl EvictionPolicy for LruPolicy {
+    fn on_insert(&mut self, page_id: PageId) {
+        self.pages.push_front(page_id);
+    }
+
+    fn on_insert_cold(&mut self, page_id: PageId) {
+        self.pages.push_back_if_absent(page_id);
+    }
+
+    fn on_access(&mut self, page_id: PageId) {
+        self.pages.push_front(page_id);
+    }
+
+    fn remove(&mut self, page_id: PageId) {
+        self.pages.remove(page_id);
+    }
+
+    fn victim(&mut self, eligible: &dyn Fn(PageId) -> bool) -> Option<PageId> {
+        self.pages.iter().rev().find(|&page_id| eligible(page_id))
+    }
+
+    fn len(&self) -> usize {
+        self.pages.len()
+    }
+}
+
+/// Evicts the page that got its data first, however often it was used since
+#[derive(Debug, Default)]
+pub struct FifoPolicy {
+    pages: LruList,
+}
+
+impl EvictionPolicy for FifoPolicy {
+    fn on_insert(&mut self, page_id: PageId) {
+        if !self.pages.contains(page_id) {
+            self.pages.push_front(page_id);
+        }
+    }
+
+    fn on_insert_cold(&mut self, page_id: PageId) {
+        self.pages.push_back_if_absent(page_id);
+    }
+
+    fn on_access(&mut self, _page_id: PageId) {}
+
+    fn remove(&mut self, page_id: PageId) {
+        self.pages.remove(page_id);
+    }
+
+    fn victim(&mut self, eligible: &dyn Fn(PageId) -> bool) -> Option<PageId> {
+        self.pages.iter().rev().find(|&page_id| eligible(page_id))
+    }
+
+    fn len(&self) -> usize {
+        self.pages.len()
+    }
+}
+
+/// Second chance: pages are swept in insertion order and one used since the hand last passed
+/// it is spared once, going back to the end of the sweep
+#[derive(Debug, Default)]
+pub struct ClockPolicy {
+    /// Oldest at the back, where the hand is
+    pages: LruList,
+    referenced: HashSet<PageId>,
+}
+
+impl EvictionPolicy for ClockPolicy {
+    fn on_insert(&mut self, page_id: PageId) {
+        if self.pages.contains(page_id) {
+            self.referenced.insert(page_id);
+        } else {
+            self.pages.push_front(page_id);
+        }
+    }
+
+    fn on_insert_cold(&mut self, page_id: PageId) {
+        self.pages.push_back_if_absent(page_id);
+    }
+
+    fn on_access(&mut self, page_id: PageId) {
+        if self.pages.contains(page_id) {
+            self.referenced.insert(page_id);
+        }
+    }
+
+    fn remove(&mut self, page_id: PageId) {
+        self.pages.remove(page_id);
+        self.referenced.remove(&page_id);
+    }
+
+    fn victim(&mut self, eligible: &dyn Fn(PageId) -> bool) -> Option<PageId> {
+        let swept: Vec<_> = self
+            .pages
+            .iter()
+            .rev()
+            .filter(|&page_id| eligible(page_id))
+            .collect();
+        for &page_id in &swept {
+            if !self.referenced.remove(&page_id) {
+                return Some(page_id);
+            }
+            self.pages.push_front(page_id);
+        }
+        // Every page had its second chance, the first one swept is the oldest again
+        swept.first().copied()
+    }
+
+    fn len(&self) -> usize {
+        self.pages.len()
+    }
+}
+
+/// Why a page was picked for eviction
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EvictionReason {
+    /// The policy's pick among the pages of owners that aren't retained
+    Policy,
+    /// Only pages of retained owners could go
+    RetainedOnly,
+}
+
+impl EvictionReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EvictionReason::Policy => "policy",
+            EvictionReason::RetainedOnly => "retained-only",
+        }
+    }
+}
+
+/// A page taken from its owner to make room for another
+#[derive(Clone, Debug, PartialEq)]
+pub struct EvictionRecord {
+    /// Evictions since the engine started, this one included
+    pub seq: u64,
+    pub at: SystemTime,
+    pub page: PageId,
+    pub owner: OwnerId,
+    /// Blocks of `owner` the page held, sorted
+    pub blocks: Vec<BlockId>,
+    /// Whether the page held unsynced data, which was synced before the page was reused
+    pub dirty: bool,
+    pub reason: EvictionReason,
+    /// Who the page was evicted for
+    pub evicted_for: OwnerId,
+}
+
+impl fmt::Display for EvictionRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let blocks: Vec<_> = self.blocks.iter().map(|b| b.to_string()).collect();
+        write!(
+            f,
+            "#{} page={} owner={} blocks={} dirty={} reason={} for={}",
+            self.seq,
+            self.page,
+            self.owner,
+            blocks.join(","),
+            self.dirty,
+            self.reason.as_str(),
+            self.evicted_for
+        )
+    }
+}
+
+/// The last `capacity` evictions, oldest first. Kept behind its own lock, which is only held
+/// to push or copy a record.
+#[derive(Debug)]
+pub struct EvictionHistory {
+    capacity: usize,
+    state: Mutex<(u64, VecDeque<EvictionRecord>)>,
+}
+
+impl EvictionHistory {
+    pub fn new(capacity: usize) -> Self {
+        EvictionHistory {
+            capacity,
+            state: Mutex::new((0, VecDeque::with_capacity(capacity))),
+        }
+    }
+
+    /// Numbers `record` and keeps it, dropping the oldest one if full. Returns its number.
+    pub fn record(&self, mut record: EvictionRecord) -> Result<u64> {
+        let mut state = self
+            .state
+            .lock()
+            .map_err(|e| anyhow!("Failed to acquire eviction history lock: {:?}", e))?;
+        let (evictions, records) = &mut *state;
+        *evictions += 1;
+        if self.capacity == 0 {
+            return Ok(*evictions);
+        }
+        if records.len() == self.capacity {
+            records.pop_front();
+        }
+        record.seq = *evictions;
+        records.push_back(record);
+        Ok(*evictions)
+    }
+
+    pub fn records(&self) -> Result<Vec<EvictionRecord>> {
+        let state = self
+            .state
+            .lock()
+            .map_err(|e| anyhow!("Failed to acquire eviction history lock: {:?}", e))?;
+        Ok(state.1.iter().cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Fills a policy with pages 0 to 3 in order, reads 0 and 2, then takes victims until
+    /// every page is evicted
+    fn evictions(name: &str) -> Vec<PageId> {
+        let mut policy = eviction_policy(name).unwrap();
+        for page_id in 0..4 {
+            policy.on_insert(page_id);
+        }
+        policy.on_access(0);
+        policy.on_access(2);
+        let mut victims = Vec::new();
+        while let Some(victim) = policy.victim(&|_| true) {
+            policy.remove(victim);
+            victims.push(victim);
+        }
+        assert_eq!(policy.len(), 0);
+        victims
+    }
+
+    #[test]
+    fn policies_pick_their_victims() {
+        assert_eq!(evictions("lru"), vec![1, 3, 0, 2]);
+        assert_eq!(evictions("fifo"), vec![0, 1, 2, 3]);
+        // 0 and 2 are spared once and go behind 3
+        assert_eq!(evictions("clock"), vec![1, 3, 0, 2]);
+        assert!(eviction_policy("random").is_err());
+
+        // Only eligible pages are picked, those skipped keep their place
+        let mut clock = eviction_policy("clock").unwrap();
+        for page_id in 0..3 {
+            clock.on_insert(page_id);
+        }
+        clock.on_access(1);
+        assert_eq!(clock.victim(&|page_id| page_id != 0), Some(2));
+        clock.on_insert_cold(7);
+        assert_eq!(clock.victim(&|_| true), Some(7));
+        clock.remove(7);
+        assert_eq!(clock.victim(&|_| true), Some(0));
+    }
+}
@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+
+use crate::pagecache::PageId;
+
+#[derive(Clone, Copy, Debug, Default)]
+struct Link {
+    /// Next page towards the most recently used end
+    newer: Option<PageId>,
+    /// Next page towards the least recently used end
+    older: Option<PageId>,
+}
+
+/// Pages from most to least recently used, as a doubly linked list keyed by page id so that
+/// touching or dropping a page doesn't have to look for it
+#[derive(Debug, Default)]
+pub struct LruList {
+    links: HashMap<PageId, Link>,
+    newest: Option<PageId>,
+    oldest: Option<PageId>,
+}
+
+impl LruList {
+    pub fn len(&self) -> usize {
+        self.links.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.links.is_empty()
+    }
+
+    pub fn contains(&self, page_id: PageId) -> bool {
+        self.links.contains_key(&page_id)
+    }
+
+    /// Most recently used page
+    pub fn front(&self) -> Option<PageId> {
+        self.newest
+    }
+
+    /// Least recently used page
+    pub fn back(&self) -> Option<PageId> {
+        self.oldest
+    }
+
+    /// Makes `page_id` the most recently used page, wherever it was
+    pub fn push_front(&mut self, page_id: PageId) {
+        self.remove(page_id);
+        let link = Link {
+            newer: None,
+            older: self.newest,
+        };
+        match self.newest {
+            Some(newest) => self.links.get_mut(&newest).unwrap().newer = Some(page_id),
+            None => self.oldest = Some(page_id),
+        }
+        self.newest = Some(page_id);
+        self.links.insert(page_id, link);
+    }
+
+    /// Makes `page_id` the least recently used page unless it is already listed. Returns
+    /// whether it was added.
+    pub fn push_back_if_absent(&mut self, page_id: PageId) -> bool {
+        if self.contains(page_id) {
+            return false;
+        }
+        let link = Link {
+            newer: self.oldest,
+            older: None,
+        };
+        match self.oldest {
+            Some(oldest) => self.links.get_mut(&oldest).unwrap().older = Some(page_id),
+            None => self.newest = Some(page_id),
+        }
+        self.oldest = Some(page_id);
+        self.links.insert(page_id, link);
+        true
+    }
+
+    /// Takes `page_id` out of the list, returning whether it was in it
+    pub fn remove(&mut self, page_id: PageId) -> bool {
+        let link = match self.links.remove(&page_id) {
+            Some(link) => link,
+            None => return false,
+        };
+        match link.newer {
+            Some(newer) => self.links.get_mut(&newer).unwrap().older = link.older,
+            None => self.newest = link.older,
+        }
+        match link.older {
+            Some(older) => self.links.get_mut(&older).unwrap().newer = link.newer,
+            None => self.oldest = link.newer,
+        }
+        true
+    }
+
+    pub fn pop_back(&mut self) -> Option<PageId> {
+        let oldest = self.oldest?;
+        self.remove(oldest);
+        Some(oldest)
+    }
+
+    /// Pages from most to least recently used, `rev` for the other way around
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            list: self,
+            newest: self.newest,
+            oldest: self.oldest,
+            remaining: self.len(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Iter<'a> {
+    list: &'a LruList,
+    newest: Option<PageId>,
+    oldest: Option<PageId>,
+    remaining: usize,
+}
+
+impl Iterator for Iter<'_> {
+    type Item = PageId;
+
+    fn next(&mut self) -> Option<PageId> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let page_id = self.newest?;
+        self.newest = self.list.links[&page_id].older;
+        self.remaining -= 1;
+        Some(page_id)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl DoubleEndedIterator for Iter<'_> {
+    fn next_back(&mut self) -> Option<PageId> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let page_id = self.oldest?;
+        self.oldest = self.list.links[&page_id].newer;
+        self.remaining -= 1;
+        Some(page_id)
+    }
+}
+
+impl ExactSizeIterator for Iter<'_> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_pages_in_use_order() {
+        let mut lru = LruList::default();
+        for page_id in 0..4 {
+            lru.push_front(page_id);
+        }
+        assert_eq!(lru.iter().collect::<Vec<_>>(), vec![3, 2, 1, 0]);
+
+        lru.push_front(1);
+        assert!(lru.push_back_if_absent(7));
+        assert!(!lru.push_back_if_absent(3));
+        assert!(lru.remove(2));
+        assert!(!lru.remove(2));
+        assert_eq!(lru.iter().collect::<Vec<_>>(), vec![1, 3, 0, 7]);
+        assert_eq!(lru.iter().rev().collect::<Vec<_>>(), vec![7, 0, 3, 1]);
+
+        // Both ends meet in the middle without handing out a page twice
+        let mut iter = lru.iter();
+        assert_eq!((iter.next(), iter.next_back()), (Some(1), Some(7)));
+        assert_eq!(iter.collect::<Vec<_>>(), vec![3, 0]);
+
+        assert_eq!(
+            (lru.front(), lru.pop_back(), lru.back()),
+            (Some(1), Some(7), Some(0))
+        );
+        while lru.pop_back().is_some() {}
+        assert!(lru.is_empty());
+        assert_eq!((lru.front(), lru.back()), (None, None));
+    }
+}
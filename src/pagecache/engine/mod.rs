@@ -1,41 +1,86 @@
 use anyhow::Result;
 use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::clock::Clock;
+use crate::pagecache::engine::eviction::EvictionRecord;
+use crate::pagecache::stats::CacheStats;
+use crate::pagecache::{DirtyBlockInfo, OwnerId};
 
 pub mod backends;
 pub mod block_offsets;
+pub mod eviction;
+pub mod lru;
 pub mod page;
+pub mod page_storage;
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum AllocateOperationType {
     OpRead,       // Specifies that the operation comes from a read operation
     OpWrite,      // Specifies that the operation comes from a write operation
-    OpPassthrough, // Specifies the otherwise case (equal to OpRead for now)
+    // Blocks LazyFS pulls in itself (read-merge, verification): cached for later reads but
+    // never dirty and not promoted by the eviction policy
+    OpPassthrough,
 }
 
-pub trait PageCacheEngine {
+/// Page storage behind `Cache`. Methods take `&self` and `Cache` calls them without holding
+/// any engine lock of its own, from whichever threads serve requests, so an implementation
+/// synchronizes its own mutable state. A backend with no mutable state needs no locking.
+pub trait PageCacheEngine: Send + Sync {
     fn allocate_blocks(
         &self,
-        content_owner_id: String,
+        content_owner_id: OwnerId,
         block_data_mapping: HashMap<i32, (i32, &Vec<u8>, i32)>,
         operation_type: AllocateOperationType,
     ) -> Result<HashMap<i32, i32>>;
 
+    /// Caches blocks read from the backing file, as `(block, data, valid_len)` with the first
+    /// `valid_len` bytes of `data` readable. The pages stay clean, start out cold and are never
+    /// made room for by evicting dirty pages. Blocks the owner already has cached are left alone.
+    /// Returns the page each block is cached in, the one it already was in if it was left
+    /// alone, or -1 if it couldn't be placed.
+    fn insert_read_blocks(
+        &self,
+        content_owner_id: OwnerId,
+        blocks: Vec<(i32, &[u8], usize)>,
+    ) -> Result<HashMap<i32, i32>>;
+
+    /// Copies each `(src, dst)` block of `src_owner` into `dst_owner`, allocating destination
+    /// pages the same way a write would. The copies are dirty and readable as far as the sources.
+    fn copy_blocks(
+        &self,
+        src_owner: OwnerId,
+        dst_owner: OwnerId,
+        pairs: Vec<(i32, i32)>,
+    ) -> Result<HashMap<i32, i32>>;
+
+    /// Copies the readable part of `block_id` into the start of `buffer`. Returns how many bytes
+    /// were copied, or `None` if `page_id` doesn't hold the block for `content_owner_id`.
+    fn read_block(
+        &self,
+        content_owner_id: OwnerId,
+        page_id: i32,
+        block_id: i32,
+        buffer: &mut [u8],
+    ) -> Result<Option<usize>>;
+
     fn get_blocks(
         &self,
-        content_owner_id: String,
-        block_pages: HashMap<i32, (i32, Vec<u8>, i32)>,
+        content_owner_id: OwnerId,
+        block_pages: HashMap<i32, (i32, &mut [u8], i32)>,
     ) -> Result<HashMap<i32, bool>>;
 
     fn is_block_cached(
         &self,
-        content_owner_id: String,
+        content_owner_id: OwnerId,
         page_id: i32,
         block_id: i32,
     ) -> Result<bool>;
 
     fn make_block_readable_to_offset(
         &self,
-        cid: String,
+        cid: OwnerId,
         page_id: i32,
         block_id: i32,
         offset: i32,
@@ -43,19 +88,339 @@ pub trait PageCacheEngine {
 
     fn get_engine_usage(&self) -> Result<f64>;
 
-    fn remove_cached_blocks(&self, content_owner_id: String) -> Result<bool>;
+    fn remove_cached_blocks(&self, content_owner_id: OwnerId) -> Result<bool>;
+
+    /// Drops every block of the owner that lives in a clean page, returning the removed block ids.
+    fn remove_clean_blocks(&self, content_owner_id: OwnerId) -> Result<Vec<i32>>;
+
+    /// Writes the owner's dirty blocks to `orig_path`, creating it if needed, truncates it to
+    /// `size` and flushes it to the device, with fdatasync if `only_sync_data` and fsync
+    /// otherwise. Blocks listed in `dirty_extents` only have those byte ranges written.
+    fn sync_pages(
+        &self,
+        owner: OwnerId,
+        size: u64,
+        orig_path: String,
+        dirty_extents: &HashMap<i32, Vec<(i32, i32)>>,
+        only_sync_data: bool,
+    ) -> Result<()>;
+
+    fn rename_owner_pages(&self, old_owner: OwnerId, new_owner: OwnerId) -> Result<bool>;
+
+    /// Asks eviction to keep the owner's pages over everyone else's, giving them up only when
+    /// nothing else can go. Backends without eviction can ignore it.
+    fn set_owner_retained(&self, _content_owner_id: OwnerId, _retained: bool) -> Result<()> {
+        Ok(())
+    }
 
-    fn sync_pages(&self, owner: String, size: u32, orig_path: String) -> Result<()>;
+    /// Whenever the cache maps `path` to the owner, after a lookup, a rename or an exchange.
+    /// Backends that only touch the disk in `sync_pages` can ignore it.
+    fn set_owner_path(&self, _content_owner_id: OwnerId, _path: &Path) -> Result<()> {
+        Ok(())
+    }
 
-    fn rename_owner_pages(&self, old_owner: String, new_owner: String) -> Result<bool>;
+    /// The clock evictions are stamped with, `Cache::with_clock`'s. Backends that stamp nothing
+    /// can ignore it.
+    fn set_clock(&self, _clock: Arc<dyn Clock>) -> Result<()> {
+        Ok(())
+    }
 
     fn truncate_cached_blocks(
         &self,
-        content_owner_id: String,
+        content_owner_id: OwnerId,
         blocks_to_remove: HashMap<i32, i32>,
         from_block_id: i32,
         index_inside_block: i32,
     ) -> Result<bool>;
 
-    fn get_dirty_blocks_info(&self, owner: String) -> Result<Vec<(i32, (i32, i32), i32)>>;
+    fn get_dirty_blocks_info(&self, owner: OwnerId) -> Result<Vec<DirtyBlockInfo>>;
+
+    /// Recent evictions, oldest first, as many as `eviction_history_size` keeps. Backends
+    /// without eviction have none.
+    fn eviction_history(&self) -> Result<Vec<EvictionRecord>> {
+        Ok(Vec::new())
+    }
+
+    /// Evictions since the last call, oldest first, so the cache can forget the blocks it still
+    /// maps to those pages. Unlike `eviction_history` nothing is left out. Backends without
+    /// eviction have none.
+    fn drain_evictions(&self) -> Result<Vec<EvictionRecord>> {
+        Ok(Vec::new())
+    }
+
+    /// Page counts, evictions and bytes written back. Hits and misses are left to the cache.
+    fn stats(&self) -> Result<CacheStats> {
+        Ok(CacheStats::default())
+    }
+
+    /// Zeroes the counters of `stats`, the page counts aren't counters
+    fn reset_stats(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pagecache::cache::Cache;
+    use crate::pagecache::config::Config;
+    use anyhow::anyhow;
+    use std::path::PathBuf;
+
+    /// Smallest possible backend: holds nothing, so every block is a miss and every write is
+    /// dropped
+    struct NullEngine;
+
+    impl PageCacheEngine for NullEngine {
+        fn allocate_blocks(
+            &self,
+            _: OwnerId,
+            block_data_mapping: HashMap<i32, (i32, &Vec<u8>, i32)>,
+            _: AllocateOperationType,
+        ) -> Result<HashMap<i32, i32>> {
+            Ok(block_data_mapping.keys().map(|&id| (id, -1)).collect())
+        }
+
+        fn insert_read_blocks(
+            &self,
+            _: OwnerId,
+            blocks: Vec<(i32, &[u8], usize)>,
+        ) -> Result<HashMap<i32, i32>> {
+            Ok(blocks.iter().map(|&(id, _, _)| (id, -1)).collect())
+        }
+
+        fn copy_blocks(
+            &self,
+            _: OwnerId,
+            _: OwnerId,
+            pairs: Vec<(i32, i32)>,
+        ) -> Result<HashMap<i32, i32>> {
+            Ok(pairs.iter().map(|&(_, dst)| (dst, -1)).collect())
+        }
+
+        fn read_block(&self, _: OwnerId, _: i32, _: i32, _: &mut [u8]) -> Result<Option<usize>> {
+            Ok(None)
+        }
+
+        fn get_blocks(
+            &self,
+            _: OwnerId,
+            block_pages: HashMap<i32, (i32, &mut [u8], i32)>,
+        ) -> Result<HashMap<i32, bool>> {
+            Ok(block_pages.keys().map(|&id| (id, false)).collect())
+        }
+
+        fn is_block_cached(&self, _: OwnerId, _: i32, _: i32) -> Result<bool> {
+            Ok(false)
+        }
+
+        fn make_block_readable_to_offset(&self, _: OwnerId, _: i32, _: i32, _: i32) -> Result<()> {
+            Ok(())
+        }
+
+        fn get_engine_usage(&self) -> Result<f64> {
+            Ok(0.0)
+        }
+
+        fn remove_cached_blocks(&self, _: OwnerId) -> Result<bool> {
+            Ok(false)
+        }
+
+        fn remove_clean_blocks(&self, _: OwnerId) -> Result<Vec<i32>> {
+            Ok(Vec::new())
+        }
+
+        fn sync_pages(
+            &self,
+            _: OwnerId,
+            _: u64,
+            _: String,
+            _: &HashMap<i32, Vec<(i32, i32)>>,
+            _: bool,
+        ) -> Result<()> {
+            Ok(())
+        }
+
+        fn rename_owner_pages(&self, _: OwnerId, _: OwnerId) -> Result<bool> {
+            Ok(false)
+        }
+
+        fn truncate_cached_blocks(
+            &self,
+            _: OwnerId,
+            _: HashMap<i32, i32>,
+            _: i32,
+            _: i32,
+        ) -> Result<bool> {
+            Ok(false)
+        }
+
+        fn get_dirty_blocks_info(&self, _: OwnerId) -> Result<Vec<DirtyBlockInfo>> {
+            Ok(Vec::new())
+        }
+    }
+
+    /// Places every block in page 0 and fails everything else. `readable` decides whether
+    /// making a block readable succeeds, so a write can get far enough to be cached.
+    struct FailingEngine {
+        readable: bool,
+    }
+
+    impl PageCacheEngine for FailingEngine {
+        fn allocate_blocks(
+            &self,
+            _: OwnerId,
+            block_data_mapping: HashMap<i32, (i32, &Vec<u8>, i32)>,
+            _: AllocateOperationType,
+        ) -> Result<HashMap<i32, i32>> {
+            Ok(block_data_mapping.keys().map(|&id| (id, 0)).collect())
+        }
+
+        fn insert_read_blocks(
+            &self,
+            _: OwnerId,
+            _: Vec<(i32, &[u8], usize)>,
+        ) -> Result<HashMap<i32, i32>> {
+            Err(anyhow!("insert_read_blocks failed"))
+        }
+
+        fn copy_blocks(
+            &self,
+            _: OwnerId,
+            _: OwnerId,
+            _: Vec<(i32, i32)>,
+        ) -> Result<HashMap<i32, i32>> {
+            Err(anyhow!("copy_blocks failed"))
+        }
+
+        fn read_block(&self, _: OwnerId, _: i32, _: i32, _: &mut [u8]) -> Result<Option<usize>> {
+            Err(anyhow!("read_block failed"))
+        }
+
+        fn get_blocks(
+            &self,
+            _: OwnerId,
+            _: HashMap<i32, (i32, &mut [u8], i32)>,
+        ) -> Result<HashMap<i32, bool>> {
+            Err(anyhow!("get_blocks failed"))
+        }
+
+        fn is_block_cached(&self, _: OwnerId, _: i32, _: i32) -> Result<bool> {
+            Err(anyhow!("is_block_cached failed"))
+        }
+
+        fn make_block_readable_to_offset(&self, _: OwnerId, _: i32, _: i32, _: i32) -> Result<()> {
+            if self.readable {
+                Ok(())
+            } else {
+                Err(anyhow!("make_block_readable_to_offset failed"))
+            }
+        }
+
+        fn get_engine_usage(&self) -> Result<f64> {
+            Err(anyhow!("get_engine_usage failed"))
+        }
+
+        fn remove_cached_blocks(&self, _: OwnerId) -> Result<bool> {
+            Err(anyhow!("remove_cached_blocks failed"))
+        }
+
+        fn remove_clean_blocks(&self, _: OwnerId) -> Result<Vec<i32>> {
+            Err(anyhow!("remove_clean_blocks failed"))
+        }
+
+        fn sync_pages(
+            &self,
+            _: OwnerId,
+            _: u64,
+            _: String,
+            _: &HashMap<i32, Vec<(i32, i32)>>,
+            _: bool,
+        ) -> Result<()> {
+            Err(anyhow!("sync_pages failed"))
+        }
+
+        fn rename_owner_pages(&self, _: OwnerId, _: OwnerId) -> Result<bool> {
+            Err(anyhow!("rename_owner_pages failed"))
+        }
+
+        fn truncate_cached_blocks(
+            &self,
+            _: OwnerId,
+            _: HashMap<i32, i32>,
+            _: i32,
+            _: i32,
+        ) -> Result<bool> {
+            Err(anyhow!("truncate_cached_blocks failed"))
+        }
+
+        fn get_dirty_blocks_info(&self, _: OwnerId) -> Result<Vec<DirtyBlockInfo>> {
+            Err(anyhow!("get_dirty_blocks_info failed"))
+        }
+    }
+
+    fn assert_shareable<T: Send + Sync>() {}
+
+    #[test]
+    fn stateless_backend_plugs_into_a_shareable_cache() {
+        assert_shareable::<Cache>();
+
+        let cache = Cache::new(Config::default(), NullEngine);
+        let data = vec![1; 16];
+        let put = cache
+            .put_data_blocks(
+                "owner".to_string(),
+                HashMap::from([(0, (&data, 0, 15))]),
+                AllocateOperationType::OpWrite,
+                None,
+            )
+            .unwrap();
+        assert_eq!(put, HashMap::from([(0, false)]));
+        assert!(!cache.is_block_cached("owner".to_string(), 0).unwrap());
+    }
+
+    #[test]
+    fn engine_failures_reach_the_caller() {
+        let data = vec![1; 16];
+        let put = |cache: &Cache| {
+            cache.put_data_blocks(
+                "owner".to_string(),
+                HashMap::from([(0, (&data, 0, 15))]),
+                AllocateOperationType::OpWrite,
+                None,
+            )
+        };
+        fn failed<T>(res: Result<T>, call: &str) {
+            let err = res.err().expect("engine failure was swallowed");
+            assert_eq!(err.to_string(), format!("{} failed", call));
+        }
+
+        let cache = Cache::new(Config::default(), FailingEngine { readable: false });
+        failed(put(&cache), "make_block_readable_to_offset");
+
+        let cache = Cache::new(Config::default(), FailingEngine { readable: true });
+        assert_eq!(put(&cache).unwrap(), HashMap::from([(0, true)]));
+        failed(cache.get_cache_usage(), "get_engine_usage");
+        failed(
+            cache.is_block_cached("owner".to_string(), 0),
+            "is_block_cached",
+        );
+        let mut buf = [0; 16];
+        failed(
+            cache.get_data_blocks("owner".to_string(), HashMap::from([(0, &mut buf[..])])),
+            "get_blocks",
+        );
+        failed(cache.report_unsynced_data(), "get_dirty_blocks_info");
+        failed(cache.clear_cache(), "get_dirty_blocks_info");
+
+        // Nothing unsynced to report, so the removals are what fails
+        let cache = Cache::new(Config::default(), FailingEngine { readable: true });
+        cache.insert_item("owner".to_string()).unwrap();
+        failed(cache.clear_cache(), "remove_cached_blocks");
+        cache.insert_item("owner".to_string()).unwrap();
+        failed(
+            cache.remove_cached_item("owner".to_string(), PathBuf::from("/owner"), true),
+            "remove_cached_blocks",
+        );
+    }
 }
@@ -1,6 +1,7 @@
 use crate::pagecache::config::Config;
 use crate::pagecache::engine::block_offsets::BlockOffsets;
-use crate::pagecache::{BlockId, Offsets};
+use crate::pagecache::engine::page_storage::PageData;
+use crate::pagecache::{BlockId, Offsets, OwnerId};
 use anyhow::{anyhow, Result};
 use std::fs::OpenOptions;
 use std::io::{Seek, SeekFrom, Write};
@@ -9,30 +10,38 @@ use std::vec::Vec;
 #[derive(Clone, Debug)]
 pub struct Page {
     is_dirty: bool,
-    page_owner_id: String,
+    page_owner_id: OwnerId,
     free_block_indexes: Vec<i32>,
     config: Box<Config>,
-    pub data: Vec<u8>,
+    data: PageData,
     pub allocated_block_ids: BlockOffsets,
 }
 
 impl Page {
-    fn new(config: Box<Config>) -> Result<Self> {
+    /// Page keeping its bytes in `data`, which must be `cache_page_size` long and zeroed
+    pub(crate) fn with_storage(config: Box<Config>, data: PageData) -> Result<Self> {
         if config.cache_page_size % config.io_block_size != 0 {
             return Err(anyhow!(
                 "Cache page size must be divisible by IO block size"
             ));
         }
+        if data.as_slice().len() != config.cache_page_size {
+            return Err(anyhow!(
+                "Page storage of {} bytes for pages of {}",
+                data.as_slice().len(),
+                config.cache_page_size
+            ));
+        }
 
         let cache_page_size = config.cache_page_size;
         let io_block_size = config.io_block_size;
 
         let mut page = Page {
             is_dirty: false,
-            page_owner_id: "none".to_string(),
+            page_owner_id: "none".into(),
             free_block_indexes: Vec::with_capacity(config.cache_page_size / config.io_block_size),
             config,
-            data: vec![0; cache_page_size],
+            data,
             allocated_block_ids: BlockOffsets::default(),
         };
 
@@ -44,15 +53,23 @@ impl Page {
         Ok(page)
     }
 
+    pub fn data(&self) -> &[u8] {
+        self.data.as_slice()
+    }
+
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        self.data.as_mut_slice()
+    }
+
     pub fn is_page_owner(&self, query: &str) -> bool {
-        self.page_owner_id == query
+        &*self.page_owner_id == query
     }
 
-    pub fn change_owner(&mut self, new_owner: String) {
+    pub fn change_owner(&mut self, new_owner: OwnerId) {
         self.page_owner_id = new_owner;
     }
 
-    pub fn get_page_owner(&self) -> String {
+    pub fn get_page_owner(&self) -> OwnerId {
         self.page_owner_id.clone()
     }
 
@@ -64,7 +81,7 @@ impl Page {
         self.free_block_indexes.clear();
         self.allocated_block_ids.reset();
         self.is_dirty = false;
-        self.data.fill(0);
+        self.data_mut().fill(0);
         for i in (0..self.config.cache_page_size).step_by(self.config.io_block_size) {
             self.free_block_indexes.push(i as i32);
         }
@@ -79,8 +96,8 @@ impl Page {
         let block_offsets = self.get_block_offsets(block_id);
         let off_min = block_offsets.0;
         if block_offsets.0 >= 0 && block_offsets.1 > 0 {
-            if new_data.len() > self.config.io_block_size {
-                return Err(anyhow!("Data length must be less than IO block size"));
+            if off_start + new_data.len() > self.config.io_block_size {
+                return Err(anyhow!("Data must fit inside the IO block"));
             }
             self.rewrite_offset_data(
                 new_data,
@@ -96,7 +113,7 @@ impl Page {
     fn rewrite_offset_data(&mut self, new_data: &[u8], start: usize, end: usize) {
         self.set_page_as_dirty(true);
         for (i, &byte) in new_data.iter().enumerate() {
-            self.data[start + i] = byte;
+            self.data_mut()[start + i] = byte;
         }
     }
 
@@ -117,30 +134,30 @@ impl Page {
         }
     }
 
+    /// Copies the block's bytes up to `read_to_max_index`, counted from the start of the block,
+    /// into `buffer`. Stops early if `buffer` is shorter.
     pub fn get_block_data(
         &self,
         block_id: BlockId,
         buffer: &mut [u8],
         read_to_max_index: usize,
     ) -> Result<()> {
-        let (off_min, _) = self.get_block_offsets(block_id);
-        let off_max = read_to_max_index + 1;
-
-        if off_max <= self.data.len()
-            && off_min < off_max as i32
-            && (off_max - off_min as usize) <= buffer.len()
-        {
-            buffer[..off_max - off_min as usize]
-                .copy_from_slice(&self.data[off_min as usize..off_max]);
-            Ok(())
-        } else {
-            Err(anyhow!("Invalid offset or buffer size"))
+        let (off_min, off_max) = self.get_block_offsets(block_id);
+        if off_min < 0 || read_to_max_index as i32 > off_max - off_min {
+            return Err(anyhow!("Invalid offset or buffer size"));
         }
+
+        let start = off_min as usize;
+        let len = (read_to_max_index + 1).min(buffer.len());
+        buffer[..len].copy_from_slice(&self.data()[start..start + len]);
+        Ok(())
     }
 
     // TODO: i dont know if this is correct, need to check if this is how i can use fuse
-    pub fn sync_data(&mut self) -> Result<bool> {
-        let path = &self.page_owner_id;
+    /// Writes the page's blocks to its owner, returning how many bytes went out. The page stays
+    /// dirty unless all of them did.
+    pub fn sync_data(&mut self) -> Result<usize> {
+        let path: &str = &self.page_owner_id;
         let mut file = OpenOptions::new().write(true).open(path)?;
 
         let block_readable_offsets = self.allocated_block_ids.get_block_readable_offsets();
@@ -156,18 +173,17 @@ impl Page {
                 should_write += total_bytes;
 
                 file.seek(SeekFrom::Start(offset))?;
-                let bytes_to_write =
-                    &self.data[offset_start as usize..(offset_start + total_bytes as i32) as usize];
+                let bytes_to_write = &self.data()
+                    [offset_start as usize..(offset_start + total_bytes as i32) as usize];
                 actually_wrote += file.write(bytes_to_write)?;
             }
         }
 
-        let res = should_write == actually_wrote;
-        if res {
+        if should_write == actually_wrote {
             self.is_dirty = false;
         }
 
-        Ok(res)
+        Ok(actually_wrote)
     }
 
     pub fn is_page_dirty(&self) -> bool {
@@ -187,7 +203,7 @@ impl Page {
         let (off_first, _) = self.get_block_offsets(block_id);
         let range = off_first + (from_offset as i32)..(self.config.io_block_size as i32);
         for i in range {
-            self.data[i as usize] = 0;
+            self.data_mut()[i as usize] = 0;
         }
     }
 
@@ -197,7 +213,7 @@ impl Page {
             self.free_block_indexes.push(off_first as i32);
             self.allocated_block_ids.remove_block(block_id);
             for i in off_first..off_first + (self.config.io_block_size as i32) {
-                self.data[i as usize] = 0;
+                self.data_mut()[i as usize] = 0;
             }
         }
 
@@ -0,0 +1,277 @@
+use crate::pagecache::config::Config;
+use anyhow::{anyhow, Result};
+use std::fmt;
+
+/// The bytes of a page of the custom engine, on the heap or in a view of a region mapped for
+/// all of them
+pub enum PageData {
+    Heap(Vec<u8>),
+    #[cfg(feature = "mmap")]
+    Mapped(mapped::MappedPage),
+}
+
+impl PageData {
+    pub fn heap(len: usize) -> Self {
+        PageData::Heap(vec![0; len])
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        match self {
+            PageData::Heap(data) => data,
+            #[cfg(feature = "mmap")]
+            PageData::Mapped(page) => page.as_slice(),
+        }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        match self {
+            PageData::Heap(data) => data,
+            #[cfg(feature = "mmap")]
+            PageData::Mapped(page) => page.as_mut_slice(),
+        }
+    }
+}
+
+/// A copy of a mapped page lives on the heap, two pages never share bytes
+impl Clone for PageData {
+    fn clone(&self) -> Self {
+        PageData::Heap(self.as_slice().to_vec())
+    }
+}
+
+impl fmt::Debug for PageData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            PageData::Heap(_) => "heap",
+            #[cfg(feature = "mmap")]
+            PageData::Mapped(_) => "mapped",
+        };
+        write!(f, "PageData({}, {} bytes)", kind, self.as_slice().len())
+    }
+}
+
+/// Zeroed storage for the `cache_nr_pages` pages of `cache_page_size` bytes, as
+/// `Config::page_storage` asks: a buffer of its own for each with `heap`, or views into a single
+/// region mapped up front with `mmap`, which needs the `mmap` feature
+pub fn allocate_pages(config: &Config) -> Result<Vec<PageData>> {
+    match config.page_storage.as_str() {
+        "heap" => Ok((0..config.cache_nr_pages)
+            .map(|_| PageData::heap(config.cache_page_size))
+            .collect()),
+        #[cfg(feature = "mmap")]
+        "mmap" => mapped::allocate_pages(config),
+        #[cfg(not(feature = "mmap"))]
+        "mmap" => Err(anyhow!("Page storage 'mmap' needs the mmap feature")),
+        _ => Err(anyhow!("Unknown page storage '{}'", config.page_storage)),
+    }
+}
+
+#[cfg(feature = "mmap")]
+mod mapped {
+    use super::PageData;
+    use crate::pagecache::config::Config;
+    use anyhow::{anyhow, Context, Result};
+    use std::fs::OpenOptions;
+    use std::io;
+    use std::os::fd::AsRawFd;
+    use std::path::Path;
+    use std::ptr::{self, NonNull};
+    use std::slice;
+    use std::sync::Arc;
+
+    /// A region mapped read-write, unmapped once the last page viewing it is dropped
+    struct MmapRegion {
+        ptr: NonNull<u8>,
+        len: usize,
+    }
+
+    // The region is plain memory, which of it each page may touch is up to `MappedPage`
+    unsafe impl Send for MmapRegion {}
+    unsafe impl Sync for MmapRegion {}
+
+    impl MmapRegion {
+        /// Anonymous if `path` is `None`, otherwise shared with the file at `path`, which is
+        /// created or emptied. Either way it starts out zeroed.
+        fn new(len: usize, path: Option<&Path>) -> Result<Self> {
+            if len == 0 {
+                return Ok(MmapRegion {
+                    ptr: NonNull::dangling(),
+                    len,
+                });
+            }
+            let file = match path {
+                Some(path) => {
+                    let file = OpenOptions::new()
+                        .read(true)
+                        .write(true)
+                        .create(true)
+                        .truncate(true)
+                        .open(path)
+                        .with_context(|| format!("Unable to open {}", path.display()))?;
+                    file.set_len(len as u64)?;
+                    Some(file)
+                }
+                None => None,
+            };
+            let (flags, fd) = match &file {
+                Some(file) => (libc::MAP_SHARED, file.as_raw_fd()),
+                None => (
+                    libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+                    -1,
+                ),
+            };
+            // The mapping outlives `file`, closing the descriptor doesn't unmap it
+            let ptr = unsafe {
+                libc::mmap(
+                    ptr::null_mut(),
+                    len,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    flags,
+                    fd,
+                    0,
+                )
+            };
+            if ptr == libc::MAP_FAILED {
+                return Err(anyhow::Error::from(io::Error::last_os_error())
+                    .context(format!("Unable to map {} bytes of pages", len)));
+            }
+            let ptr = NonNull::new(ptr as *mut u8).ok_or_else(|| anyhow!("mmap returned null"))?;
+            Ok(MmapRegion { ptr, len })
+        }
+    }
+
+    impl Drop for MmapRegion {
+        fn drop(&mut self) {
+            if self.len > 0 {
+                unsafe { libc::munmap(self.ptr.as_ptr() as *mut libc::c_void, self.len) };
+            }
+        }
+    }
+
+    /// `len` bytes of a region from `offset`. Each is handed out once by `allocate_pages` over
+    /// a range no other one covers, and isn't `Clone`, so holding it is holding those bytes.
+    pub struct MappedPage {
+        region: Arc<MmapRegion>,
+        offset: usize,
+        len: usize,
+    }
+
+    impl MappedPage {
+        pub fn as_slice(&self) -> &[u8] {
+            unsafe { slice::from_raw_parts(self.region.ptr.as_ptr().add(self.offset), self.len) }
+        }
+
+        pub fn as_mut_slice(&mut self) -> &mut [u8] {
+            unsafe {
+                slice::from_raw_parts_mut(self.region.ptr.as_ptr().add(self.offset), self.len)
+            }
+        }
+    }
+
+    pub fn allocate_pages(config: &Config) -> Result<Vec<PageData>> {
+        let page_size = config.cache_page_size;
+        let len = config
+            .cache_nr_pages
+            .checked_mul(page_size)
+            .ok_or_else(|| {
+                anyhow!(
+                    "{} pages of {} bytes overflow",
+                    config.cache_nr_pages,
+                    page_size
+                )
+            })?;
+        let path = Some(config.page_storage_path.as_path()).filter(|p| !p.as_os_str().is_empty());
+        let region = Arc::new(MmapRegion::new(len, path)?);
+        Ok((0..config.cache_nr_pages)
+            .map(|page| {
+                PageData::Mapped(MappedPage {
+                    region: region.clone(),
+                    offset: page * page_size,
+                    len: page_size,
+                })
+            })
+            .collect())
+    }
+}
+
+#[cfg(all(test, feature = "mmap"))]
+mod tests {
+    use super::*;
+    use crate::pagecache::engine::page::Page;
+    use std::fs;
+
+    fn mmap_config(nr_pages: usize) -> Config {
+        Config {
+            cache_nr_pages: nr_pages,
+            page_storage: "mmap".to_string(),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn mapped_pages_never_alias() {
+        let config = mmap_config(4);
+        let mut pages = allocate_pages(&config).unwrap();
+        assert_eq!(pages.len(), 4);
+        for (i, page) in pages.iter_mut().enumerate() {
+            assert!(page.as_slice().iter().all(|&b| b == 0));
+            page.as_mut_slice().fill(i as u8 + 1);
+        }
+        for (i, page) in pages.iter().enumerate() {
+            assert_eq!(page.as_slice().len(), config.cache_page_size);
+            assert!(
+                page.as_slice().iter().all(|&b| b == i as u8 + 1),
+                "page {}",
+                i
+            );
+        }
+        let mut ranges: Vec<_> = pages
+            .iter()
+            .map(|page| page.as_slice().as_ptr_range())
+            .collect();
+        ranges.sort_by_key(|range| range.start);
+        for pair in ranges.windows(2) {
+            assert!(pair[0].end <= pair[1].start);
+        }
+
+        // A copy has bytes of its own
+        let mut copy = pages[0].clone();
+        copy.as_mut_slice().fill(9);
+        assert!(pages[0].as_slice().iter().all(|&b| b == 1));
+    }
+
+    #[test]
+    fn reset_and_removal_zero_mapped_pages() {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-mmap", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = Config {
+            page_storage_path: dir.join("pages"),
+            ..mmap_config(2)
+        };
+        let block_size = config.io_block_size;
+        let mut data = allocate_pages(&config).unwrap().into_iter();
+        let mut page = Page::with_storage(Box::new(config.clone()), data.next().unwrap()).unwrap();
+        let mut other = Page::with_storage(Box::new(config.clone()), data.next().unwrap()).unwrap();
+
+        page.get_allocate_free_offset(0).unwrap();
+        other.get_allocate_free_offset(0).unwrap();
+        page.update_block_data(0, &vec![7; block_size], 0).unwrap();
+        other.update_block_data(0, &vec![8; block_size], 0).unwrap();
+        // Shared with the file behind the region
+        let file = fs::read(dir.join("pages")).unwrap();
+        assert_eq!(file.len(), 2 * config.cache_page_size);
+        assert_eq!(file.iter().filter(|&&b| b == 7).count(), block_size);
+
+        page.write_null_from(0, 10);
+        let mut buffer = vec![0xff; block_size];
+        page.get_block_data(0, &mut buffer, block_size - 1).unwrap();
+        assert!(buffer[..10].iter().all(|&b| b == 7));
+        assert!(buffer[10..].iter().all(|&b| b == 0));
+
+        page.reset();
+        assert!(page.data().iter().all(|&b| b == 0));
+        other.remove_block(0);
+        assert!(other.data().iter().all(|&b| b == 0));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
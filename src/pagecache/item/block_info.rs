@@ -1,7 +1,18 @@
+use crate::pagecache::Offsets;
+
+/// Past this many disjoint dirty ranges a block is simply treated as dirty as a whole
+pub const MAX_DIRTY_EXTENTS: usize = 8;
+
 #[derive(Clone, Debug)]
 pub struct BlockInfo {
     pub readable_offset: (i32, i32),
     pub page_index_number: i32,
+    /// Op id of the last application write that touched this block
+    pub last_write_op: Option<u64>,
+    /// Byte ranges written since the last sync, sorted and disjoint
+    pub dirty_extents: Vec<Offsets>,
+    /// Set once the extents overflowed, the whole block gets synced
+    pub whole_block_dirty: bool,
 }
 
 impl BlockInfo {
@@ -12,13 +23,68 @@ impl BlockInfo {
         self.readable_offset.1
     }
 
+    /// Cuts the block after byte `to`, along with whatever was dirty past it
     pub fn truncate_readable_to(&mut self, to: i32) {
         self.readable_offset.1 = to;
+        self.dirty_extents.retain(|&(from, _)| from <= to);
+        if let Some(last) = self.dirty_extents.last_mut() {
+            last.1 = last.1.min(to);
+        }
     }
 
     pub fn clone_readable_offsets(&self) -> (i32, i32) {
         self.readable_offset
     }
+
+    /// Records bytes `from..=to` as written, merging with overlapping or adjacent extents
+    pub fn mark_dirty(&mut self, from: i32, to: i32) {
+        if self.whole_block_dirty {
+            return;
+        }
+
+        let (mut from, mut to) = (from, to);
+        self.dirty_extents.retain(|&(start, end)| {
+            if start > to + 1 || end + 1 < from {
+                return true;
+            }
+            from = from.min(start);
+            to = to.max(end);
+            false
+        });
+        let index = self
+            .dirty_extents
+            .partition_point(|&(start, _)| start < from);
+        self.dirty_extents.insert(index, (from, to));
+
+        if self.dirty_extents.len() > MAX_DIRTY_EXTENTS {
+            self.dirty_extents.clear();
+            self.whole_block_dirty = true;
+        }
+    }
+
+    /// Dirty ranges to sync, `None` if the whole block has to be written
+    pub fn dirty_extents(&self) -> Option<&[Offsets]> {
+        if self.whole_block_dirty || self.dirty_extents.is_empty() {
+            return None;
+        }
+        Some(&self.dirty_extents)
+    }
+
+    /// Bytes waiting to be synced, all of the readable ones once the whole block is dirty
+    pub fn dirty_bytes(&self) -> u64 {
+        if self.whole_block_dirty {
+            return (self.readable_offset.1 + 1).max(0) as u64;
+        }
+        self.dirty_extents
+            .iter()
+            .map(|&(from, to)| (to - from + 1).max(0) as u64)
+            .sum()
+    }
+
+    pub fn clear_dirty(&mut self) {
+        self.dirty_extents.clear();
+        self.whole_block_dirty = false;
+    }
 }
 
 impl Default for BlockInfo {
@@ -26,6 +92,9 @@ impl Default for BlockInfo {
         Self {
             readable_offset: (0, 0),
             page_index_number: -1,
+            last_write_op: None,
+            dirty_extents: Vec::new(),
+            whole_block_dirty: false,
         }
     }
 }
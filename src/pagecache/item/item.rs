@@ -1,27 +1,100 @@
 use crate::pagecache::item::block_info::BlockInfo;
-use crate::pagecache::item::metadata::Metadata;
+use crate::pagecache::item::metadata::{Metadata, MetadataField};
+use crate::pagecache::item::stats::OwnerStats;
 use crate::pagecache::{BlockId, PageId, Offsets};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::time::SystemTime;
 
 #[derive(Clone, Debug)]
 pub struct Item {
     pub data: ItemData,
     pub metadata: Metadata,
     pub is_synced: bool,
+    /// Modification time of the backing file as observed right after the last sync, or when
+    /// the owner was first mapped if it was never synced since
+    pub last_sync_time: Option<SystemTime>,
+    /// Size of the backing file as observed along with `last_sync_time`
+    pub last_synced_size: u64,
+    /// How much of the backing file still belongs to the file: the last synced size, lowered by
+    /// every truncate since. `None` until the first sync or truncate, when all of it does.
+    pub backing_limit: Option<u64>,
+    pub stats: OwnerStats,
+    /// Mapped into memory, so writes may reach the backing file without going through the cache
+    pub externally_modified: bool,
+    /// Rank in the order owners got dirty in, `None` while everything is synced
+    pub dirty_since: Option<u64>,
+    /// Set while syncs keep failing, cleared by the next one that goes through
+    pub sync_failure: Option<SyncFailure>,
+    /// Under an immutable path policy
+    pub immutable: bool,
+    /// Hash of each cached block of an immutable item, taken when it was cached
+    pub block_hashes: HashMap<BlockId, u64>,
+    /// Extended attributes by name. They follow the item through renames and go with its last
+    /// link.
+    pub xattrs: HashMap<String, Vec<u8>>,
+    /// Names of the extended attributes set or removed since the last metadata sync
+    pub xattrs_changed: HashSet<String>,
+}
+
+/// Why an owner is quarantined. Checkpoints leave it out until `retry_at`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SyncFailure {
+    /// Failed syncs in a row
+    pub attempts: u32,
+    pub last_error: String,
+    pub retry_at: SystemTime,
 }
 
 impl Item {
-    pub fn update_metadata(&mut self, new_meta: Metadata, values_to_update: Vec<String>) {
+    /// Fresh item whose metadata timestamps are `now`
+    pub fn new(now: SystemTime) -> Self {
+        Self {
+            metadata: Metadata::with_time(now),
+            ..Default::default()
+        }
+    }
+
+    /// Whether a failed sync is still backing off at `now`
+    pub fn is_quarantined(&self, now: SystemTime) -> bool {
+        self.sync_failure
+            .as_ref()
+            .is_some_and(|failure| now < failure.retry_at)
+    }
+
+    /// Records what the backing file looked like right after it was brought up to date
+    pub fn record_backing_file(&mut self, modified: SystemTime, size: u64) {
+        self.observe_backing_file(modified, size);
+        self.backing_limit = Some(size);
+    }
+
+    /// Takes the backing file as it is now for what the last sync left, without limiting how
+    /// much of it belongs to the file
+    pub fn observe_backing_file(&mut self, modified: SystemTime, size: u64) {
+        self.last_sync_time = Some(modified);
+        self.last_synced_size = size;
+    }
+
+    pub fn update_metadata(&mut self, new_meta: Metadata, fields: &[MetadataField]) {
         let old_meta = &mut self.metadata;
 
-        for value in values_to_update.iter() {
-            match value.as_str() {
-                "size" => old_meta.size = new_meta.size,
-                "atime" => old_meta.atim = new_meta.atim,
-                "ctime" => old_meta.ctim = new_meta.ctim,
-                "mtime" => old_meta.mtim = new_meta.mtim,
-                "nlinks" => old_meta.nlinks = new_meta.nlinks,
-                _ => (),
+        for field in fields {
+            match field {
+                MetadataField::Size => {
+                    if new_meta.size < old_meta.size {
+                        let limit = self.backing_limit.get_or_insert(new_meta.size);
+                        *limit = (*limit).min(new_meta.size);
+                    }
+                    old_meta.size = new_meta.size;
+                }
+                MetadataField::Atime => old_meta.atim = new_meta.atim,
+                MetadataField::Ctime => old_meta.ctim = new_meta.ctim,
+                MetadataField::Mtime => old_meta.mtim = new_meta.mtim,
+                MetadataField::Nlinks => old_meta.nlinks = new_meta.nlinks,
+                MetadataField::Mode => old_meta.mode = new_meta.mode,
+                MetadataField::Uid => old_meta.uid = new_meta.uid,
+                MetadataField::Gid => old_meta.gid = new_meta.gid,
+                MetadataField::Blocks => old_meta.blocks = new_meta.blocks,
+                MetadataField::Rdev => old_meta.rdev = new_meta.rdev,
             }
         }
     }
@@ -33,6 +106,17 @@ impl Default for Item {
             data: ItemData::default(),
             metadata: Metadata::default(),
             is_synced: true,
+            last_sync_time: None,
+            last_synced_size: 0,
+            backing_limit: None,
+            stats: OwnerStats::default(),
+            externally_modified: false,
+            dirty_since: None,
+            sync_failure: None,
+            immutable: false,
+            block_hashes: HashMap::new(),
+            xattrs: HashMap::new(),
+            xattrs_changed: HashSet::new(),
         }
     }
 }
@@ -40,6 +124,8 @@ impl Default for Item {
 #[derive(Clone, Debug)]
 pub struct ItemData {
     blocks: HashMap<BlockId, Box<BlockInfo>>,
+    /// Sum of the dirty bytes of every block, kept in step by every change to them
+    dirty_bytes: u64,
 }
 
 impl ItemData {
@@ -64,24 +150,31 @@ impl ItemData {
             .collect()
     }
 
-    pub fn truncate_blocks_after(&mut self, block_id: BlockId, blk_byte_index: i32) -> HashMap<i32, i32> {
+    pub fn truncate_blocks_after(
+        &mut self,
+        block_id: BlockId,
+        blk_byte_index: i32,
+    ) -> HashMap<i32, i32> {
         let mut res = HashMap::new();
         let mut ids_to_remove = Vec::new();
 
-        for (&id, block_info) in self.blocks.iter_mut() {
+        for (&id, block_info) in self.blocks.iter() {
             if id >= block_id {
                 res.insert(id, block_info.page_index_number);
 
                 if id > block_id || blk_byte_index == 0 {
                     ids_to_remove.push(id);
-                } else if id == block_id {
-                    block_info.truncate_readable_to(blk_byte_index - 1);
                 }
             }
         }
 
         for id in ids_to_remove {
-            self.blocks.remove(&id);
+            self.remove_block(id);
+        }
+        if blk_byte_index > 0 {
+            self.update_block(block_id, |block| {
+                block.truncate_readable_to(blk_byte_index - 1)
+            });
         }
 
         res
@@ -94,21 +187,136 @@ impl ItemData {
         _readable_from: i32,
         readable_to: i32,
     ) -> i32 {
-        let block = self
-            .blocks
+        self.blocks
             .entry(block_id)
             .or_insert_with(|| Box::new(BlockInfo::default()));
 
-        block.page_index_number = allocated_page;
-        block.make_readable_to(readable_to)
+        self.update_block(block_id, |block| {
+            block.page_index_number = allocated_page;
+            block.make_readable_to(readable_to)
+        })
+        .unwrap()
+    }
+
+    pub fn set_block_write_op(&mut self, block_id: BlockId, op_id: u64) {
+        if let Some(block) = self.blocks.get_mut(&block_id) {
+            block.last_write_op = Some(op_id);
+        }
+    }
+
+    pub fn mark_block_dirty(&mut self, block_id: BlockId, from: i32, to: i32) {
+        self.update_block(block_id, |block| block.mark_dirty(from, to));
+    }
+
+    /// Runs `f` on a cached block, keeping `dirty_bytes` in step with what it changed
+    fn update_block<T>(
+        &mut self,
+        block_id: BlockId,
+        f: impl FnOnce(&mut BlockInfo) -> T,
+    ) -> Option<T> {
+        let block = self.blocks.get_mut(&block_id)?;
+        let before = block.dirty_bytes();
+        let res = f(block);
+        self.dirty_bytes = self.dirty_bytes - before + block.dirty_bytes();
+        Some(res)
+    }
+
+    /// Dirty byte ranges of a block, all of its readable bytes once it is dirty as a whole
+    pub fn block_dirty_ranges(&self, block_id: BlockId) -> Vec<Offsets> {
+        match self.blocks.get(&block_id) {
+            Some(block) if block.whole_block_dirty => vec![(0, block.readable_offset.1)],
+            Some(block) => block.dirty_extents.clone(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Offset right past the last dirty byte, 0 if nothing is dirty
+    pub fn dirty_end(&self, block_size: usize) -> u64 {
+        self.blocks
+            .iter()
+            .filter_map(|(&id, block)| {
+                let last = if block.whole_block_dirty {
+                    block.readable_offset.1
+                } else {
+                    block.dirty_extents.last()?.1
+                };
+                Some(id as u64 * block_size as u64 + last as u64 + 1)
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Bytes of every block waiting to be synced
+    pub fn dirty_bytes(&self) -> u64 {
+        self.dirty_bytes
+    }
+
+    /// How many more dirty bytes there would be after writing each `(block, written range,
+    /// readable to)` of `writes`
+    pub fn dirty_growth(&self, writes: &[(BlockId, Offsets, i32)]) -> u64 {
+        writes
+            .iter()
+            .map(|&(block_id, (from, to), readable_to)| {
+                let mut block = match self.blocks.get(&block_id) {
+                    Some(block) => (**block).clone(),
+                    None => BlockInfo::default(),
+                };
+                let before = block.dirty_bytes();
+                block.make_readable_to(readable_to);
+                block.mark_dirty(from, to);
+                block.dirty_bytes().saturating_sub(before)
+            })
+            .sum()
+    }
+
+    /// Dirty extents of the blocks that only need part of their bytes synced
+    pub fn dirty_extents(&self) -> HashMap<BlockId, Vec<Offsets>> {
+        self.blocks
+            .iter()
+            .filter_map(|(&id, block)| Some((id, block.dirty_extents()?.to_vec())))
+            .collect()
+    }
+
+    pub fn clear_dirty_extents(&mut self) {
+        for block in self.blocks.values_mut() {
+            block.clear_dirty();
+        }
+        self.dirty_bytes = 0;
+    }
+
+    pub fn get_block_write_op(&self, block_id: BlockId) -> Option<u64> {
+        self.blocks
+            .get(&block_id)
+            .and_then(|block| block.last_write_op)
+    }
+
+    /// (block, page, readable offsets, last write op) for every cached block, ordered by block
+    pub fn block_map(&self) -> Vec<(BlockId, PageId, Offsets, Option<u64>)> {
+        let mut map: Vec<_> = self
+            .blocks
+            .iter()
+            .map(|(&id, block)| {
+                (
+                    id,
+                    block.page_index_number,
+                    block.readable_offset,
+                    block.last_write_op,
+                )
+            })
+            .collect();
+        map.sort_by_key(|&(id, _, _, _)| id);
+        map
     }
 
     pub fn remove_block(&mut self, block_id: BlockId) {
-        self.blocks.remove(&block_id);
+        if let Some(block) = self.blocks.remove(&block_id) {
+            self.dirty_bytes -= block.dirty_bytes();
+        }
     }
 
     pub fn remove_all(&mut self) {
         self.blocks.clear();
+        self.dirty_bytes = 0;
     }
 
     pub fn has_block(&self, block_id: BlockId) -> bool {
@@ -124,6 +332,120 @@ impl Default for ItemData {
     fn default() -> Self {
         Self {
             blocks: HashMap::with_capacity(30000),
+            dirty_bytes: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pagecache::item::block_info::MAX_DIRTY_EXTENTS;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn block_provenance_follows_last_write() {
+        let mut data = ItemData::default();
+        // Two clients whose op ids come from different ranges, interleaving on blocks 1 and 2
+        let writes = [(0, 100), (1, 1000), (1, 101), (2, 1001), (2, 1002)];
+        for (block_id, op_id) in writes {
+            data.set_block_page_id(block_id, block_id, 0, 4095);
+            data.set_block_write_op(block_id, op_id);
+        }
+        data.set_block_page_id(3, 3, 0, 4095);
+
+        let provenance: Vec<_> = data
+            .block_map()
+            .into_iter()
+            .map(|(block_id, _, _, op_id)| (block_id, op_id))
+            .collect();
+        assert_eq!(
+            provenance,
+            vec![(0, Some(100)), (1, Some(101)), (2, Some(1002)), (3, None)]
+        );
+    }
+
+    #[test]
+    fn dirty_extents_merge_and_overflow() {
+        let mut data = ItemData::default();
+        data.set_block_page_id(0, 0, 0, 4095);
+        data.set_block_page_id(1, 1, 0, 4095);
+        for (from, to) in [(100, 199), (300, 309), (200, 249), (50, 120)] {
+            data.mark_block_dirty(0, from, to);
+        }
+        for i in 0..=MAX_DIRTY_EXTENTS as i32 {
+            data.mark_block_dirty(1, i * 100, i * 100 + 9);
+        }
+
+        assert_eq!(
+            data.dirty_extents(),
+            HashMap::from([(0, vec![(50, 249), (300, 309)])])
+        );
+        data.clear_dirty_extents();
+        assert!(data.dirty_extents().is_empty());
+    }
+
+    #[test]
+    fn dirty_bytes_follow_writes_and_truncates() {
+        let mut data = ItemData::default();
+        data.set_block_page_id(0, 0, 0, 4095);
+        data.set_block_page_id(1, 1, 0, 4095);
+        data.mark_block_dirty(0, 100, 199);
+        data.mark_block_dirty(0, 300, 309);
+        for i in 0..=MAX_DIRTY_EXTENTS as i32 {
+            data.mark_block_dirty(1, i * 100, i * 100 + 9);
+        }
+        assert_eq!(data.dirty_bytes(), 100 + 10 + 4096);
+
+        // Only the gap between the two extents is new
+        assert_eq!(data.dirty_growth(&[(0, (150, 349), 4095)]), 140);
+        assert_eq!(data.dirty_growth(&[(2, (0, 9), 9)]), 10);
+
+        data.truncate_blocks_after(0, 305);
+        assert_eq!(data.dirty_bytes(), 100 + 5);
+        data.remove_block(0);
+        assert_eq!(data.dirty_bytes(), 0);
+    }
+
+    #[test]
+    fn metadata_updates_only_the_fields_named() {
+        let fields = |m: &Metadata| {
+            [
+                format!("{}", m.size),
+                format!("{:?}", m.atim),
+                format!("{:?}", m.mtim),
+                format!("{:?}", m.ctim),
+                format!("{}", m.nlinks),
+                format!("{}", m.mode),
+                format!("{}", m.uid),
+                format!("{}", m.gid),
+                format!("{}", m.blocks),
+                format!("{}", m.rdev),
+            ]
+        };
+        let before = Metadata::with_time(UNIX_EPOCH);
+        let after = Metadata {
+            size: 7,
+            nlinks: 2,
+            mode: 0o100600,
+            uid: 1000,
+            gid: 100,
+            blocks: 8,
+            rdev: 3,
+            ..Metadata::with_time(UNIX_EPOCH + Duration::from_secs(60))
+        };
+
+        for (i, &field) in MetadataField::ALL.iter().enumerate() {
+            let mut item = Item {
+                metadata: before.clone(),
+                ..Default::default()
+            };
+            item.update_metadata(after.clone(), &[field]);
+            let mut expected = fields(&before);
+            expected[i] = fields(&after)[i].clone();
+            assert_eq!(fields(&item.metadata), expected, "{}", field);
+            assert_eq!(field.to_string().parse::<MetadataField>().unwrap(), field);
         }
+        assert!("atim".parse::<MetadataField>().is_err());
     }
 }
@@ -1,34 +1,178 @@
-use std::time::SystemTime;
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::clock::Clock;
 
 #[derive(Clone, Debug)]
 pub struct Metadata {
     pub nlinks: u32,
-    pub size: u32,
+    pub size: u64,
     pub atim: SystemTime,
     pub mtim: SystemTime,
     pub ctim: SystemTime,
+    /// File type and permission bits, as in `st_mode`
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// 512-byte blocks allocated, as in `st_blocks`
+    pub blocks: u64,
+    /// Device the file stands for if it is a special file
+    pub rdev: u64,
 }
 
 impl Metadata {
-    pub fn new() -> Self {
+    /// Metadata of a file with no link yet, stamped with the time of `clock`
+    pub fn new(clock: &dyn Clock) -> Self {
         Self {
             nlinks: 0,
-            size: 0,
-            atim: SystemTime::now(),
-            mtim: SystemTime::now(),
-            ctim: SystemTime::now(),
+            ..Self::with_time(clock.now())
         }
     }
-}
 
-impl Default for Metadata {
-    fn default() -> Self {
+    /// Default metadata with every timestamp set to `now`
+    pub fn with_time(now: SystemTime) -> Self {
         Self {
             nlinks: 1,
             size: 0,
-            atim: SystemTime::now(),
-            mtim: SystemTime::now(),
-            ctim: SystemTime::now(),
+            atim: now,
+            mtim: now,
+            ctim: now,
+            mode: 0,
+            uid: 0,
+            gid: 0,
+            blocks: 0,
+            rdev: 0,
+        }
+    }
+
+    /// The metadata of a file as `stat` reported it
+    pub fn from_fs_metadata(metadata: &fs::Metadata) -> Self {
+        Self {
+            nlinks: metadata.nlink() as u32,
+            size: metadata.size(),
+            atim: unix_time(metadata.atime(), metadata.atime_nsec()),
+            mtim: unix_time(metadata.mtime(), metadata.mtime_nsec()),
+            ctim: unix_time(metadata.ctime(), metadata.ctime_nsec()),
+            mode: metadata.mode(),
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            blocks: metadata.blocks(),
+            rdev: metadata.rdev(),
         }
     }
 }
+
+/// `secs` and `nanos` since the epoch, before it if `secs` is negative
+fn unix_time(secs: i64, nanos: i64) -> SystemTime {
+    let since_epoch = |secs: i64| Duration::new(secs.unsigned_abs(), 0);
+    let time = if secs < 0 {
+        UNIX_EPOCH - since_epoch(secs)
+    } else {
+        UNIX_EPOCH + since_epoch(secs)
+    };
+    time + Duration::from_nanos(nanos as u64)
+}
+
+/// Stamped at the epoch, as no clock is at hand. Metadata meant to carry the current time is
+/// made with `with_time` from the clock of the cache.
+impl Default for Metadata {
+    fn default() -> Self {
+        Self::with_time(UNIX_EPOCH)
+    }
+}
+
+/// A field of `Metadata` that `Item::update_metadata` can update
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MetadataField {
+    Size,
+    Atime,
+    Mtime,
+    Ctime,
+    Nlinks,
+    Mode,
+    Uid,
+    Gid,
+    Blocks,
+    Rdev,
+}
+
+const FIELD_NAMES: [(&str, MetadataField); 10] = [
+    ("size", MetadataField::Size),
+    ("atime", MetadataField::Atime),
+    ("mtime", MetadataField::Mtime),
+    ("ctime", MetadataField::Ctime),
+    ("nlinks", MetadataField::Nlinks),
+    ("mode", MetadataField::Mode),
+    ("uid", MetadataField::Uid),
+    ("gid", MetadataField::Gid),
+    ("blocks", MetadataField::Blocks),
+    ("rdev", MetadataField::Rdev),
+];
+
+impl MetadataField {
+    pub const ALL: [MetadataField; 10] = [
+        MetadataField::Size,
+        MetadataField::Atime,
+        MetadataField::Mtime,
+        MetadataField::Ctime,
+        MetadataField::Nlinks,
+        MetadataField::Mode,
+        MetadataField::Uid,
+        MetadataField::Gid,
+        MetadataField::Blocks,
+        MetadataField::Rdev,
+    ];
+}
+
+impl FromStr for MetadataField {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        FIELD_NAMES
+            .iter()
+            .find(|(name, _)| *name == s)
+            .map(|(_, field)| *field)
+            .ok_or_else(|| anyhow!("Unknown metadata field '{}'", s))
+    }
+}
+
+impl fmt::Display for MetadataField {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (name, _) = FIELD_NAMES.iter().find(|(_, field)| field == self).unwrap();
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn converts_what_stat_reports() {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-stat", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file");
+        fs::write(&path, vec![1; 5000]).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+
+        let stat = fs::metadata(&path).unwrap();
+        let metadata = Metadata::from_fs_metadata(&stat);
+        assert_eq!((metadata.nlinks, metadata.size), (1, 5000));
+        assert_eq!(metadata.mode, libc::S_IFREG | 0o640);
+        assert_eq!((metadata.uid, metadata.gid), (stat.uid(), stat.gid()));
+        assert_eq!((metadata.blocks, metadata.rdev), (stat.blocks(), 0));
+        assert_eq!(metadata.mtim, stat.modified().unwrap());
+        assert_eq!(metadata.atim, stat.accessed().unwrap());
+
+        assert_eq!(
+            unix_time(-2, 500),
+            UNIX_EPOCH - Duration::new(1, 999_999_500)
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
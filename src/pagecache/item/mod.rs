@@ -1,6 +1,7 @@
 pub mod metadata;
+pub mod stats;
 
 mod item;
-pub use item::Item;
+pub use item::{Item, SyncFailure};
 
 mod block_info;
\ No newline at end of file
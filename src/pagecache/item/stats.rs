@@ -0,0 +1,104 @@
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::str::FromStr;
+
+/// Activity counters kept per owner. They live next to the item so updating them only needs
+/// the item lock the read and write paths already hold.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OwnerStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub bytes_written: u64,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub syncs: u64,
+    /// Bytes dirtied since the last sync, each counted once however often it is rewritten
+    pub dirty_bytes: u64,
+    pub dirty_bytes_hwm: u64,
+    /// Written blocks the cache had no room for
+    pub dropped_blocks: u64,
+}
+
+impl OwnerStats {
+    /// Counts a write of `requested` bytes, `dirtied` of which were clean before it
+    pub fn record_write(&mut self, requested: u64, dirtied: u64) {
+        self.writes += 1;
+        self.bytes_written += requested;
+        self.dirty_bytes += dirtied;
+        self.dirty_bytes_hwm = self.dirty_bytes_hwm.max(self.dirty_bytes);
+    }
+
+    pub fn record_dropped(&mut self, blocks: u64) {
+        self.dropped_blocks += blocks;
+    }
+
+    pub fn record_read(&mut self, hits: u64, misses: u64) {
+        self.reads += 1;
+        self.cache_hits += hits;
+        self.cache_misses += misses;
+    }
+
+    pub fn record_sync(&mut self) {
+        self.syncs += 1;
+        self.dirty_bytes = 0;
+    }
+
+    pub fn get(&self, metric: StatMetric) -> u64 {
+        match metric {
+            StatMetric::Reads => self.reads,
+            StatMetric::Writes => self.writes,
+            StatMetric::BytesWritten => self.bytes_written,
+            StatMetric::CacheHits => self.cache_hits,
+            StatMetric::CacheMisses => self.cache_misses,
+            StatMetric::Syncs => self.syncs,
+            StatMetric::DirtyBytesHwm => self.dirty_bytes_hwm,
+            StatMetric::DroppedBlocks => self.dropped_blocks,
+        }
+    }
+}
+
+/// Counter to rank owners by
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StatMetric {
+    Reads,
+    Writes,
+    BytesWritten,
+    CacheHits,
+    CacheMisses,
+    Syncs,
+    DirtyBytesHwm,
+    DroppedBlocks,
+}
+
+const METRIC_NAMES: [(&str, StatMetric); 8] = [
+    ("reads", StatMetric::Reads),
+    ("writes", StatMetric::Writes),
+    ("bytes-written", StatMetric::BytesWritten),
+    ("cache-hits", StatMetric::CacheHits),
+    ("cache-misses", StatMetric::CacheMisses),
+    ("syncs", StatMetric::Syncs),
+    ("dirty-bytes-hwm", StatMetric::DirtyBytesHwm),
+    ("dropped-blocks", StatMetric::DroppedBlocks),
+];
+
+impl FromStr for StatMetric {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        METRIC_NAMES
+            .iter()
+            .find(|(name, _)| *name == s)
+            .map(|(_, metric)| *metric)
+            .ok_or_else(|| anyhow!("Unknown metric '{}'", s))
+    }
+}
+
+impl fmt::Display for StatMetric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (name, _) = METRIC_NAMES
+            .iter()
+            .find(|(_, metric)| metric == self)
+            .unwrap();
+        write!(f, "{}", name)
+    }
+}
@@ -1,8 +1,19 @@
 pub mod cache;
 pub mod config;
+pub mod dirents;
 pub mod engine;
 pub mod item;
+pub mod owner;
+pub mod stats;
+pub mod xattr;
 
+use std::sync::Arc;
+
+/// Id of the owner of cached content, cheap to clone since every call and page carries one.
+/// `String`s and `&str`s convert into it with `into()`.
+pub type OwnerId = Arc<str>;
 pub type Offsets = (i32, i32);
 pub type BlockId = i32;
-pub type PageId = i32;
\ No newline at end of file
+pub type PageId = i32;
+/// A dirty block of an owner: its id, the dirty range inside it and the page holding it.
+pub type DirtyBlockInfo = (BlockId, Offsets, PageId);
\ No newline at end of file
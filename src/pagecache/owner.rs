@@ -0,0 +1,51 @@
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Identity of a backing file as the file system it lives on sees it. Every hard link of a
+/// file has the same one, and a rename keeps it.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct OwnerKey {
+    pub dev: u64,
+    pub ino: u64,
+}
+
+impl OwnerKey {
+    /// The file at `path` itself, which for a symlink isn't the file it points to
+    pub fn of(path: &Path) -> Result<Self> {
+        let metadata = path
+            .symlink_metadata()
+            .map_err(|e| anyhow!("Unable to stat {}: {}", path.display(), e))?;
+        Ok(OwnerKey {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+        })
+    }
+}
+
+/// The form owners are keyed by in the cache and the engine
+impl fmt::Display for OwnerKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.dev, self.ino)
+    }
+}
+
+impl FromStr for OwnerKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (dev, ino) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Owner '{}' is not a device and inode pair", s))?;
+        let parse = |n: &str| {
+            n.parse()
+                .map_err(|_| anyhow!("Owner '{}' is not a device and inode pair", s))
+        };
+        Ok(OwnerKey {
+            dev: parse(dev)?,
+            ino: parse(ino)?,
+        })
+    }
+}
@@ -0,0 +1,25 @@
+/// Counters over the whole cache, next to the per owner `OwnerStats`. Hits and misses are
+/// counted in blocks looked up, the page counts are taken when the stats are.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Pages taken from their owner to make room for another
+    pub evictions: u64,
+    /// Pages holding data their backing file hasn't seen yet
+    pub dirty_pages: u64,
+    pub used_pages: u64,
+    pub total_pages: u64,
+    /// Bytes written to backing files by syncs and by evicting dirty pages
+    pub bytes_written_back: u64,
+}
+
+impl CacheStats {
+    /// Share of the pages in use, in percent, as `Cache::get_cache_usage` reports it
+    pub fn usage(&self) -> f64 {
+        if self.total_pages == 0 {
+            return 0.0;
+        }
+        (self.used_pages as f64 / self.total_pages as f64) * 100.0
+    }
+}
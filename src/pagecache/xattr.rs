@@ -0,0 +1,93 @@
+use anyhow::Result;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// Extended attributes of the backing file at `path` itself, not of what a symlink points to.
+/// A file system without them has none.
+pub fn read_all(path: &Path) -> Result<HashMap<String, Vec<u8>>> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let names = match read_sized(|buf, len| unsafe {
+        libc::llistxattr(c_path.as_ptr(), buf as *mut libc::c_char, len)
+    }) {
+        Ok(names) => names,
+        Err(e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(HashMap::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut xattrs = HashMap::new();
+    for name in names.split(|&b| b == 0).filter(|name| !name.is_empty()) {
+        let c_name = CString::new(name)?;
+        let value = match read_sized(|buf, len| unsafe {
+            libc::lgetxattr(
+                c_path.as_ptr(),
+                c_name.as_ptr(),
+                buf as *mut libc::c_void,
+                len,
+            )
+        }) {
+            Ok(value) => value,
+            // Removed since it was listed
+            Err(e) if e.raw_os_error() == Some(libc::ENODATA) => continue,
+            Err(e) => return Err(e.into()),
+        };
+        xattrs.insert(String::from_utf8_lossy(name).into_owned(), value);
+    }
+    Ok(xattrs)
+}
+
+/// Sets the extended attribute `name` of the backing file at `path`
+pub fn set(path: &Path, name: &str, value: &[u8]) -> Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let c_name = CString::new(name)?;
+    let res = unsafe {
+        libc::lsetxattr(
+            c_path.as_ptr(),
+            c_name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            0,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Removes the extended attribute `name` of the backing file at `path`. One that isn't set
+/// there is already gone.
+pub fn remove(path: &Path, name: &str) -> Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let c_name = CString::new(name)?;
+    if unsafe { libc::lremovexattr(c_path.as_ptr(), c_name.as_ptr()) } != 0 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::ENODATA) {
+            return Err(e.into());
+        }
+    }
+    Ok(())
+}
+
+/// Calls `f`, one of the xattr calls that take a buffer and its size, first for the size and
+/// then for the bytes. Retried if the value grew in between.
+fn read_sized(f: impl Fn(*mut u8, usize) -> isize) -> io::Result<Vec<u8>> {
+    loop {
+        let len = f(std::ptr::null_mut(), 0);
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buf = vec![0u8; len as usize];
+        let read = f(buf.as_mut_ptr(), buf.len());
+        if read >= 0 {
+            buf.truncate(read as usize);
+            return Ok(buf);
+        }
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(libc::ERANGE) {
+            return Err(e);
+        }
+    }
+}
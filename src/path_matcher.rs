@@ -0,0 +1,156 @@
+use anyhow::{anyhow, Result};
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+use unicode_normalization::UnicodeNormalization;
+
+/// Unicode normal form applied to patterns and paths before matching. macOS hands out
+/// decomposed (NFD) names while most other sources produce composed (NFC) ones.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Normalization {
+    #[default]
+    None,
+    Nfc,
+    Nfd,
+}
+
+impl Normalization {
+    pub fn apply(&self, s: &str) -> String {
+        match self {
+            Normalization::None => s.to_string(),
+            Normalization::Nfc => s.nfc().collect(),
+            Normalization::Nfd => s.nfd().collect(),
+        }
+    }
+}
+
+impl FromStr for Normalization {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Normalization::None),
+            "nfc" => Ok(Normalization::Nfc),
+            "nfd" => Ok(Normalization::Nfd),
+            _ => Err(anyhow!("Unknown normalization '{}'", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+pub struct MatchOptions {
+    #[serde(default)]
+    pub case_insensitive: bool,
+    #[serde(default)]
+    pub normalize: Normalization,
+}
+
+/// Path regex shared by faults and per-path settings, so they all treat case and Unicode
+/// normalization the same way
+#[derive(Clone, Debug)]
+pub struct PathMatcher {
+    /// The pattern as given, before normalization
+    source: String,
+    regex: Regex,
+    options: MatchOptions,
+}
+
+impl PathMatcher {
+    pub fn new(pattern: &str, options: MatchOptions) -> Result<Self> {
+        let regex = RegexBuilder::new(&options.normalize.apply(pattern))
+            .case_insensitive(options.case_insensitive)
+            .build()?;
+        Ok(PathMatcher {
+            source: pattern.to_string(),
+            regex,
+            options,
+        })
+    }
+
+    /// The same pattern compiled with other options
+    pub fn with_options(&self, options: MatchOptions) -> Result<Self> {
+        PathMatcher::new(&self.source, options)
+    }
+
+    /// `path` the way it is compared against the pattern
+    pub fn normalize(&self, path: &Path) -> String {
+        self.options.normalize.apply(&path.to_string_lossy())
+    }
+
+    pub fn is_match(&self, path: &Path) -> bool {
+        self.regex.is_match(&self.normalize(path))
+    }
+
+    /// The pattern after normalization
+    pub fn pattern(&self) -> &str {
+        self.regex.as_str()
+    }
+
+    pub fn options(&self) -> MatchOptions {
+        self.options
+    }
+}
+
+/// Same pattern compiled with the same options
+impl PartialEq for PathMatcher {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source && self.options == other.options
+    }
+}
+
+impl fmt::Display for PathMatcher {
+    /// Just the pattern unless options are set, so fault specs (and their saved state) stay the
+    /// same for faults that don't use them
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.pattern())?;
+        if self.options.case_insensitive {
+            write!(f, " case-insensitive")?;
+        }
+        if self.options.normalize != Normalization::None {
+            write!(f, " normalize={:?}", self.options.normalize)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn case_insensitive_matching() {
+        let path = Path::new("/data/DB/000001.SST");
+        let exact = PathMatcher::new("db/.*\\.sst$", MatchOptions::default()).unwrap();
+        assert!(!exact.is_match(path));
+
+        let options = MatchOptions {
+            case_insensitive: true,
+            ..Default::default()
+        };
+        let folded = PathMatcher::new("db/.*\\.sst$", options).unwrap();
+        assert!(folded.is_match(path));
+        assert_eq!(folded.to_string(), "db/.*\\.sst$ case-insensitive");
+    }
+
+    #[test]
+    fn composed_and_decomposed_names_match_once_normalized() {
+        let composed = "caf\u{e9}\\.log";
+        let decomposed = Path::new("/data/cafe\u{301}.log");
+        assert!(!PathMatcher::new(composed, MatchOptions::default())
+            .unwrap()
+            .is_match(decomposed));
+
+        for normalize in [Normalization::Nfc, Normalization::Nfd] {
+            let options = MatchOptions {
+                normalize,
+                ..Default::default()
+            };
+            let matcher = PathMatcher::new(composed, options).unwrap();
+            assert!(matcher.is_match(decomposed));
+            assert!(matcher.is_match(Path::new("/data/caf\u{e9}.log")));
+        }
+    }
+}
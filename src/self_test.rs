@@ -0,0 +1,229 @@
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::pagecache::cache::Cache;
+use crate::pagecache::config::Config;
+use crate::pagecache::engine::AllocateOperationType;
+use crate::pagecache::item::metadata::{Metadata, MetadataField};
+
+/// Step of the self-test round trip, in the order they run
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SelfTestStage {
+    Geometry,
+    CreateFile,
+    Put,
+    Sync,
+    Drop,
+    ReadBack,
+    Compare,
+    Cleanup,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SelfTestReport {
+    /// First stage that failed and why, `None` if the round trip went through
+    pub failure: Option<(SelfTestStage, String)>,
+}
+
+impl SelfTestReport {
+    pub fn passed(&self) -> bool {
+        self.failure.is_none()
+    }
+}
+
+impl fmt::Display for SelfTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.failure {
+            None => write!(f, "self-test passed"),
+            Some((stage, reason)) => write!(f, "self-test failed at {:?}: {}", stage, reason),
+        }
+    }
+}
+
+fn check_geometry(config: &Config) -> Result<()> {
+    if config.io_block_size == 0 || config.cache_page_size == 0 || config.cache_nr_pages == 0 {
+        return Err(anyhow!(
+            "block size, page size and number of pages must all be non zero"
+        ));
+    }
+    if !config.cache_page_size.is_multiple_of(config.io_block_size) {
+        return Err(anyhow!(
+            "page size {} is not a multiple of the IO block size {}",
+            config.cache_page_size,
+            config.io_block_size
+        ));
+    }
+    if config.disk_sector_size != 0 && !config.io_block_size.is_multiple_of(config.disk_sector_size)
+    {
+        return Err(anyhow!(
+            "IO block size {} is not a multiple of the sector size {}",
+            config.io_block_size,
+            config.disk_sector_size
+        ));
+    }
+    Ok(())
+}
+
+/// Pushes a known pattern spanning a few blocks (the last one partial) through the cache,
+/// syncs it to a temp file under `root`, drops it from the cache and reads it back from the
+/// backing file. The temp file is removed whatever the outcome.
+pub fn run(cache: &Cache, config: &Config, root: &Path) -> SelfTestReport {
+    let path = root.join(format!(".lazyfs-self-test-{}", std::process::id()));
+    let owner = format!("lazyfs-self-test-{}", std::process::id());
+
+    let mut failure = round_trip(cache, config, &path, &owner).err();
+    if path.exists() {
+        if let Err(e) = fs::remove_file(&path) {
+            failure.get_or_insert((SelfTestStage::Cleanup, e.to_string()));
+        }
+    }
+
+    SelfTestReport { failure }
+}
+
+fn round_trip(
+    cache: &Cache,
+    config: &Config,
+    path: &Path,
+    owner: &str,
+) -> std::result::Result<(), (SelfTestStage, String)> {
+    let at = |stage| move |e: anyhow::Error| (stage, e.to_string());
+
+    check_geometry(config).map_err(at(SelfTestStage::Geometry))?;
+
+    let block_size = config.io_block_size;
+    let pattern: Vec<u8> = (0..block_size * 3 + block_size / 2)
+        .map(|i| (i % 251) as u8)
+        .collect();
+    fs::File::create(path).map_err(|e| at(SelfTestStage::CreateFile)(e.into()))?;
+    let owner = &cache
+        .insert_inode_mapping(path.to_path_buf(), owner.to_string(), false)
+        .map_err(at(SelfTestStage::CreateFile))?;
+
+    let chunks: Vec<Vec<u8>> = pattern.chunks(block_size).map(|c| c.to_vec()).collect();
+    let blocks = chunks
+        .iter()
+        .enumerate()
+        .map(|(i, chunk)| (i as i32, (chunk, 0, chunk.len() as i32 - 1)))
+        .collect();
+    let put = cache
+        .put_data_blocks(
+            owner.to_string(),
+            blocks,
+            AllocateOperationType::OpWrite,
+            None,
+        )
+        .map_err(at(SelfTestStage::Put))?;
+    let mut dropped: Vec<_> = put
+        .iter()
+        .filter(|(_, ok)| !**ok)
+        .map(|(id, _)| *id)
+        .collect();
+    if !dropped.is_empty() {
+        dropped.sort();
+        return Err((
+            SelfTestStage::Put,
+            format!("blocks {:?} were not cached", dropped),
+        ));
+    }
+    let metadata = Metadata {
+        size: pattern.len() as u64,
+        ..Default::default()
+    };
+    cache
+        .update_content_metadata(owner.to_string(), metadata, &[MetadataField::Size])
+        .map_err(at(SelfTestStage::Put))?;
+
+    cache
+        .sync_file(path.to_path_buf())
+        .map_err(at(SelfTestStage::Sync))?;
+
+    cache
+        .remove_cached_item(owner.to_string(), path.to_path_buf(), true)
+        .map_err(at(SelfTestStage::Drop))?;
+    if cache
+        .has_content_cached(owner.to_string())
+        .map_err(at(SelfTestStage::Drop))?
+    {
+        return Err((SelfTestStage::Drop, "entry still cached".to_string()));
+    }
+
+    let read_back = fs::read(path).map_err(|e| at(SelfTestStage::ReadBack)(e.into()))?;
+    if read_back != pattern {
+        let first_diff = read_back
+            .iter()
+            .zip(&pattern)
+            .position(|(a, b)| a != b)
+            .unwrap_or(read_back.len().min(pattern.len()));
+        return Err((
+            SelfTestStage::Compare,
+            format!(
+                "read {} bytes, expected {}, first difference at byte {}",
+                read_back.len(),
+                pattern.len(),
+                first_diff
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pagecache::engine::backends::custom::CustomCacheEngine;
+
+    fn run_with(config: Config, name: &str) -> SelfTestReport {
+        let cache = Cache::new(
+            config.clone(),
+            CustomCacheEngine::new(Box::new(config.clone())).unwrap(),
+        );
+        run_on(&cache, config, name)
+    }
+
+    fn run_on(cache: &Cache, config: Config, name: &str) -> SelfTestReport {
+        let root = std::env::temp_dir().join(format!("lazyfs-rs-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&root).unwrap();
+        let report = run(cache, &config, &root);
+        assert_eq!(fs::read_dir(&root).unwrap().count(), 0);
+        fs::remove_dir_all(&root).unwrap();
+        report
+    }
+
+    #[test]
+    fn broken_geometry_is_reported() {
+        let config = Config {
+            cache_page_size: 4096 * 2 + 1,
+            ..Default::default()
+        };
+        assert!(CustomCacheEngine::new(Box::new(config.clone())).is_err());
+
+        // Caught before anything reaches the engine
+        let cache = Cache::new(
+            Config::default(),
+            CustomCacheEngine::new(Box::default()).unwrap(),
+        );
+        let report = run_on(&cache, config, "self-test-geometry");
+        assert_eq!(report.failure.unwrap().0, SelfTestStage::Geometry);
+    }
+
+    #[test]
+    fn missing_root_is_reported() {
+        let config = Config::default();
+        let cache = Cache::new(
+            config.clone(),
+            CustomCacheEngine::new(Box::new(config.clone())).unwrap(),
+        );
+        let report = run(&cache, &config, Path::new("/nonexistent/lazyfs-rs"));
+        assert_eq!(report.failure.unwrap().0, SelfTestStage::CreateFile);
+    }
+
+    #[test]
+    fn healthy_config_passes() {
+        let report = run_with(Config::default(), "self-test-healthy");
+        assert!(report.passed(), "{}", report);
+    }
+}
@@ -0,0 +1,605 @@
+use anyhow::{anyhow, Result};
+use std::ffi::CString;
+use std::fmt;
+use std::fs;
+use std::io::ErrorKind;
+use std::mem::MaybeUninit;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::sync::Weak;
+use std::thread::{self, JoinHandle};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+use crate::crash_report::{CrashReport, UnsyncedSummary};
+use crate::formats::{self, geometry_hash, Artifact, Header};
+use crate::lazyfs::LazyFS;
+use crate::pagecache::config::Config;
+use crate::TRACING_TARGET;
+
+/// What recovery had to do to get a control FIFO back into a usable state
+#[derive(Clone, Debug, PartialEq)]
+pub enum FifoAction {
+    /// Nothing was there, a new FIFO was created
+    Created,
+    /// A FIFO left behind by a previous run was removed and recreated, dropping unread commands
+    Recreated,
+    /// Something that is not a FIFO was in the way and got replaced
+    Replaced,
+}
+
+/// Summary of the leftover state found (and cleaned up) before mounting
+#[derive(Clone, Debug, Default)]
+pub struct RecoveryReport {
+    pub fifos: Vec<(PathBuf, FifoAction)>,
+    /// Previous artifacts that were moved aside, as (original, archive) pairs
+    pub rotated: Vec<(PathBuf, PathBuf)>,
+    /// Spec of the crash fault that ended the previous run, from the crash report it left
+    pub fired_fault: Option<String>,
+    /// Data the previous run had not synced when that fault fired, heaviest owners first
+    pub unsynced: Vec<UnsyncedSummary>,
+    /// Owners with unsynced data the crash report left out
+    pub unsynced_omitted: usize,
+    /// Previous artifacts written under another cache geometry than the config's
+    pub mismatched: Vec<PathBuf>,
+}
+
+impl fmt::Display for RecoveryReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "startup recovery report:")?;
+        for (path, action) in &self.fifos {
+            writeln!(f, "  fifo {}: {:?}", path.display(), action)?;
+        }
+        for (from, to) in &self.rotated {
+            writeln!(f, "  rotated {} -> {}", from.display(), to.display())?;
+        }
+        if let Some(spec) = &self.fired_fault {
+            writeln!(f, "  previous run crashed on {}", spec)?;
+        }
+        for unsynced in &self.unsynced {
+            writeln!(
+                f,
+                "  unsynced {}: {} bytes",
+                unsynced.owner, unsynced.dirty_bytes
+            )?;
+        }
+        if self.unsynced_omitted > 0 {
+            writeln!(f, "  unsynced: {} more owners", self.unsynced_omitted)?;
+        }
+        for path in &self.mismatched {
+            writeln!(f, "  {} was written under another geometry", path.display())?;
+        }
+        if self.fifos.is_empty()
+            && self.rotated.is_empty()
+            && self.fired_fault.is_none()
+            && self.unsynced.is_empty()
+            && self.unsynced_omitted == 0
+            && self.mismatched.is_empty()
+        {
+            writeln!(f, "  nothing to recover")?;
+        }
+        Ok(())
+    }
+}
+
+/// Reconciles whatever a previous (possibly crashed) run left on disk with the given config.
+/// Meant to be called once before mounting. The crash report of the previous run is summed up
+/// in the report and archived, as is its fault state unless `resume_faults` is going to restore
+/// it, in which case it has to have been written under the same geometry.
+pub fn recover(config: &Config) -> Result<RecoveryReport> {
+    let mut report = RecoveryReport::default();
+
+    for fifo in [&config.fifo_path, &config.fifo_path_completed] {
+        if fifo.as_os_str().is_empty() {
+            continue;
+        }
+        let action = reinit_fifo(fifo)?;
+        info!(target: TRACING_TARGET, path = %fifo.display(), ?action, "reinitialized fifo");
+        report.fifos.push((fifo.clone(), action));
+    }
+
+    if !config.log_file.as_os_str().is_empty() {
+        rotate_into(&mut report, &config.log_file)?;
+    }
+
+    if !config.crash_report_path.as_os_str().is_empty() {
+        recover_crash_report(config, &mut report)?;
+    }
+
+    if !config.fault_state_path.as_os_str().is_empty() {
+        recover_fault_state(config, &mut report)?;
+    }
+
+    Ok(report)
+}
+
+fn recover_crash_report(config: &Config, report: &mut RecoveryReport) -> Result<()> {
+    let path = &config.crash_report_path;
+    match CrashReport::load(path) {
+        Ok((header, crash)) => {
+            check_geometry(config, path, &header, report);
+            info!(
+                target: TRACING_TARGET,
+                spec = %crash.spec,
+                op_count = crash.op_count,
+                "previous run ended on a crash fault"
+            );
+            report.fired_fault = Some(crash.spec);
+            report.unsynced = crash.unsynced;
+            report.unsynced_omitted = crash.unsynced_omitted;
+        }
+        Err(e) if is_not_found(&e) => return Ok(()),
+        Err(e) => warn!(
+            target: TRACING_TARGET,
+            "Unreadable crash report {}: {:?}",
+            path.display(),
+            e
+        ),
+    }
+    // Even if unreadable, so it is never taken for one written by this run
+    rotate_into(report, path)
+}
+
+fn recover_fault_state(config: &Config, report: &mut RecoveryReport) -> Result<()> {
+    let path = &config.fault_state_path;
+    let contents = match fs::read(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    // Version 1 state has no header, and so no geometry to check
+    if formats::has_magic(&contents, Artifact::FaultState) {
+        match formats::read_header(&mut &contents[..], Artifact::FaultState) {
+            Ok(header) => {
+                if !check_geometry(config, path, &header, report) && config.resume_faults {
+                    return Err(anyhow!(
+                        "Fault state file {} was written under another cache geometry",
+                        path.display()
+                    ));
+                }
+            }
+            Err(e) if config.resume_faults => {
+                return Err(anyhow!("Fault state file {}: {}", path.display(), e))
+            }
+            Err(e) => warn!(
+                target: TRACING_TARGET,
+                "Unreadable fault state {}: {:?}",
+                path.display(),
+                e
+            ),
+        }
+    }
+    if config.resume_faults {
+        return Ok(());
+    }
+    rotate_into(report, path)
+}
+
+/// Whether `header` was written under the geometry of `config`, noting `path` in the report if
+/// it wasn't
+fn check_geometry(
+    config: &Config,
+    path: &Path,
+    header: &Header,
+    report: &mut RecoveryReport,
+) -> bool {
+    if header.geometry_hash == geometry_hash(config) {
+        return true;
+    }
+    warn!(
+        target: TRACING_TARGET,
+        path = %path.display(),
+        "previous artifact was written under another cache geometry"
+    );
+    report.mismatched.push(path.to_path_buf());
+    false
+}
+
+fn is_not_found(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == ErrorKind::NotFound)
+}
+
+fn rotate_into(report: &mut RecoveryReport, path: &Path) -> Result<()> {
+    if let Some(archive) = rotate(path)? {
+        info!(
+            target: TRACING_TARGET,
+            from = %path.display(),
+            to = %archive.display(),
+            "rotated previous artifact"
+        );
+        report.rotated.push((path.to_path_buf(), archive));
+    }
+    Ok(())
+}
+
+/// Waits for SIGTERM or SIGINT on a thread of its own, then shuts `lazyfs` down, syncing the
+/// cache if `sync_on_shutdown` is set, and calls `then` with the signal to let the caller
+/// unmount and exit. The signals are blocked on the calling thread and every thread it spawns
+/// afterwards so that only this one sees them: meant to be called from main before anything
+/// else is started.
+pub fn shutdown_on_signals(
+    lazyfs: Weak<LazyFS>,
+    then: impl FnOnce(i32) + Send + 'static,
+) -> Result<JoinHandle<()>> {
+    let mut signals = MaybeUninit::<libc::sigset_t>::uninit();
+    let signals = unsafe {
+        libc::sigemptyset(signals.as_mut_ptr());
+        libc::sigaddset(signals.as_mut_ptr(), libc::SIGTERM);
+        libc::sigaddset(signals.as_mut_ptr(), libc::SIGINT);
+        signals.assume_init()
+    };
+    let res = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &signals, std::ptr::null_mut()) };
+    if res != 0 {
+        return Err(anyhow!(
+            "Unable to block shutdown signals: {}",
+            std::io::Error::from_raw_os_error(res)
+        ));
+    }
+
+    let thread = thread::Builder::new()
+        .name("lazyfs-signals".to_string())
+        .spawn(move || {
+            let mut signal = 0;
+            let res = unsafe { libc::sigwait(&signals, &mut signal) };
+            if res != 0 {
+                let e = std::io::Error::from_raw_os_error(res);
+                warn!(target: TRACING_TARGET, "Failed to wait for shutdown signals: {:?}", e);
+                return;
+            }
+            info!(target: TRACING_TARGET, signal, "received shutdown signal");
+            if let Some(lazyfs) = lazyfs.upgrade() {
+                if let Err(e) = lazyfs.shutdown(lazyfs.config().sync_on_shutdown) {
+                    warn!(target: TRACING_TARGET, "Failed to shut down cleanly: {:?}", e);
+                }
+            }
+            then(signal);
+        })?;
+    Ok(thread)
+}
+
+fn reinit_fifo(path: &Path) -> Result<FifoAction> {
+    let action = match fs::symlink_metadata(path) {
+        Ok(meta) => {
+            fs::remove_file(path)?;
+            if meta.file_type().is_fifo() {
+                FifoAction::Recreated
+            } else {
+                FifoAction::Replaced
+            }
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => FifoAction::Created,
+        Err(e) => return Err(e.into()),
+    };
+    mkfifo(path)?;
+    Ok(action)
+}
+
+pub(crate) fn mkfifo(path: &Path) -> Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::mkfifo(c_path.as_ptr(), 0o644) } != 0 {
+        return Err(anyhow!(
+            "Unable to create fifo {}: {}",
+            path.display(),
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+/// Moves a non-empty file aside to `<name>.<unix secs>`, or `<name>.<unix secs>.<n>` with the
+/// first `n` free if that is taken, returning the archive path. An earlier archive is never
+/// overwritten.
+fn rotate(path: &Path) -> Result<Option<PathBuf>> {
+    match fs::metadata(path) {
+        Ok(meta) if meta.len() > 0 => {}
+        Ok(_) => return Ok(None),
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    for n in 0u32.. {
+        let mut archive = path.as_os_str().to_owned();
+        match n {
+            0 => archive.push(format!(".{}", secs)),
+            n => archive.push(format!(".{}.{}", secs, n)),
+        }
+        let archive = PathBuf::from(archive);
+        // Unlike a rename, a link fails rather than replace what is already there
+        match fs::hard_link(path, &archive) {
+            Ok(()) => {
+                fs::remove_file(path)?;
+                return Ok(Some(archive));
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    unreachable!("ran out of archive names for {}", path.display())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fault_state::FaultStateFile;
+    use std::os::unix::thread::JoinHandleExt;
+    use std::sync::{mpsc, Arc};
+
+    #[test]
+    fn recovers_leftover_state() {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-recover", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let config = Config {
+            fifo_path: dir.join("faults.fifo"),
+            fifo_path_completed: dir.join("completed.fifo"),
+            log_file: dir.join("lazyfs.log"),
+            ..Default::default()
+        };
+
+        // A crashed run left a plain file where the fifo should be and a non-empty log behind
+        fs::write(&config.fifo_path, b"lazyfs::clear-cache\n").unwrap();
+        fs::write(&config.log_file, b"previous run\n").unwrap();
+
+        let report = recover(&config).unwrap();
+        assert_eq!(
+            report.fifos,
+            vec![
+                (config.fifo_path.clone(), FifoAction::Replaced),
+                (config.fifo_path_completed.clone(), FifoAction::Created),
+            ]
+        );
+        assert!(fs::metadata(&config.fifo_path)
+            .unwrap()
+            .file_type()
+            .is_fifo());
+        assert!(!config.log_file.exists());
+        assert_eq!(report.rotated.len(), 1);
+        assert_eq!(fs::read(&report.rotated[0].1).unwrap(), b"previous run\n");
+
+        // Second start finds the fifos from the first one and nothing to rotate
+        let report = recover(&config).unwrap();
+        assert!(report
+            .fifos
+            .iter()
+            .all(|(_, a)| *a == FifoAction::Recreated));
+        assert!(report.rotated.is_empty());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reports_and_archives_the_previous_crash() {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-prev-crash", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = Config {
+            fifo_path: PathBuf::new(),
+            crash_report_path: dir.join("crash.report"),
+            fault_state_path: dir.join("faults.state"),
+            ..Default::default()
+        };
+
+        let crash = CrashReport {
+            fault_id: 3,
+            spec: "crash fsync before /wal".to_string(),
+            op: "fsync".to_string(),
+            timing: "before".to_string(),
+            path: "/wal".to_string(),
+            offset: None,
+            size: None,
+            op_count: 42,
+            unsynced_omitted: 1,
+            unsynced: vec![UnsyncedSummary {
+                owner: "/wal".to_string(),
+                dirty_bytes: 4096,
+            }],
+        };
+        let header = Header::new(
+            Artifact::CrashReport,
+            crate::crash_report::CRASH_REPORT_VERSION,
+            geometry_hash(&config),
+            SystemTime::now(),
+        );
+        crash.write(&config.crash_report_path, &header).unwrap();
+        // Saved under a smaller cache than the one about to be mounted
+        let state = FaultStateFile {
+            op_count: 42,
+            faults: Vec::new(),
+        };
+        let other = Config {
+            cache_nr_pages: config.cache_nr_pages / 2,
+            ..config.clone()
+        };
+        state
+            .save(
+                &config.fault_state_path,
+                geometry_hash(&other),
+                SystemTime::now(),
+            )
+            .unwrap();
+
+        let report = recover(&config).unwrap();
+        assert_eq!(
+            report.fired_fault.as_deref(),
+            Some("crash fsync before /wal")
+        );
+        assert_eq!(report.unsynced, crash.unsynced);
+        assert_eq!(report.unsynced_omitted, 1);
+        assert_eq!(report.mismatched, vec![config.fault_state_path.clone()]);
+        assert_eq!(
+            report
+                .rotated
+                .iter()
+                .map(|(from, _)| from.clone())
+                .collect::<Vec<_>>(),
+            vec![
+                config.crash_report_path.clone(),
+                config.fault_state_path.clone()
+            ]
+        );
+        assert!(!config.crash_report_path.exists());
+        assert!(!config.fault_state_path.exists());
+        assert_eq!(CrashReport::load(&report.rotated[0].1).unwrap().1, crash);
+        assert!(report.to_string().contains("crash fsync before /wal"));
+
+        // Nothing left for the next start to report
+        let report = recover(&config).unwrap();
+        assert!(report.fired_fault.is_none());
+        assert!(report.rotated.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fault_state_to_resume_must_match_the_geometry() {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-resume-geo", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = Config {
+            fifo_path: PathBuf::new(),
+            fault_state_path: dir.join("faults.state"),
+            resume_faults: true,
+            ..Default::default()
+        };
+        let state = FaultStateFile {
+            op_count: 7,
+            faults: Vec::new(),
+        };
+
+        // Left in place for the resume that follows
+        state
+            .save(
+                &config.fault_state_path,
+                geometry_hash(&config),
+                SystemTime::now(),
+            )
+            .unwrap();
+        let report = recover(&config).unwrap();
+        assert!(report.rotated.is_empty());
+        assert!(report.mismatched.is_empty());
+        assert!(config.fault_state_path.exists());
+
+        let other = Config {
+            cache_page_size: config.cache_page_size * 2,
+            ..config.clone()
+        };
+        state
+            .save(
+                &config.fault_state_path,
+                geometry_hash(&other),
+                SystemTime::now(),
+            )
+            .unwrap();
+        assert!(recover(&config).is_err());
+        assert!(config.fault_state_path.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn report_is_empty_only_without_findings() {
+        assert!(RecoveryReport::default()
+            .to_string()
+            .contains("nothing to recover"));
+
+        let mismatched = RecoveryReport {
+            mismatched: vec![PathBuf::from("faults.state")],
+            ..Default::default()
+        };
+        let crashed = RecoveryReport {
+            fired_fault: Some("crash fsync before /wal".to_string()),
+            ..Default::default()
+        };
+        let unsynced = RecoveryReport {
+            unsynced_omitted: 3,
+            ..Default::default()
+        };
+        for report in [mismatched, crashed, unsynced] {
+            assert!(!report.to_string().contains("nothing to recover"));
+        }
+    }
+
+    #[test]
+    fn rotations_in_the_same_second_keep_every_archive() {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-rotate", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let log = dir.join("lazyfs.log");
+
+        fs::write(&log, b"first run\n").unwrap();
+        let first = rotate(&log).unwrap().unwrap();
+        fs::write(&log, b"second run\n").unwrap();
+        let second = rotate(&log).unwrap().unwrap();
+        fs::write(&log, b"third run\n").unwrap();
+        let third = rotate(&log).unwrap().unwrap();
+
+        assert!(!log.exists());
+        assert_ne!(first, second);
+        assert_ne!(second, third);
+        assert_eq!(fs::read(&first).unwrap(), b"first run\n");
+        assert_eq!(fs::read(&second).unwrap(), b"second run\n");
+        assert_eq!(fs::read(&third).unwrap(), b"third run\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn builder_recovers_leftover_state() {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-startup", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = Config {
+            fifo_path: dir.join("faults.fifo"),
+            fifo_path_completed: dir.join("completed.fifo"),
+            log_file: dir.join("lazyfs.log"),
+            ..Default::default()
+        };
+        // A crashed run left a plain file where the fifo goes and its log behind
+        fs::write(&config.fifo_path, b"lazyfs::clear-cache\n").unwrap();
+        fs::write(&config.log_file, b"previous run\n").unwrap();
+
+        let lazyfs = LazyFS::builder()
+            .config(config.clone())
+            .with_startup_recovery(true)
+            .with_fifo_listener(true)
+            .build()
+            .unwrap();
+        let report = lazyfs.recovery_report().unwrap();
+        assert_eq!(
+            report.fifos,
+            vec![
+                (config.fifo_path.clone(), FifoAction::Replaced),
+                (config.fifo_path_completed.clone(), FifoAction::Created),
+            ]
+        );
+        assert_eq!(report.rotated.len(), 1);
+        assert!(!config.log_file.exists());
+        lazyfs.shutdown(false).unwrap();
+
+        // Off unless asked for
+        let lazyfs = LazyFS::builder().config(config).build().unwrap();
+        assert!(lazyfs.recovery_report().is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sigterm_shuts_down_cleanly() {
+        let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-sigterm", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let lazyfs = LazyFS::builder().build().unwrap();
+        let wal = dir.join("wal");
+        fs::write(&wal, b"").unwrap();
+        lazyfs.do_write(&wal, 7, 0, b"not fsynced").unwrap();
+
+        let (tx, rx) = mpsc::channel();
+        let waiter = shutdown_on_signals(Arc::downgrade(&lazyfs), move |signal| {
+            tx.send(signal).unwrap()
+        })
+        .unwrap();
+        // Sent to the waiting thread alone, the rest of the test binary never sees it
+        assert_eq!(
+            unsafe { libc::pthread_kill(waiter.as_pthread_t(), libc::SIGTERM) },
+            0
+        );
+        waiter.join().unwrap();
+        assert_eq!(rx.recv().unwrap(), libc::SIGTERM);
+        assert_eq!(fs::read(&wal).unwrap(), b"not fsynced");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
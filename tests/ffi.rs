@@ -0,0 +1,35 @@
+//! Runs the C program in `tests/ffi/`, which the build script compiles against the generated
+//! header, over the C ABI of the `ffi` feature, and checks the header in `include/` matches it.
+#![cfg(feature = "ffi")]
+
+use std::ffi::{c_char, c_int, CString};
+
+use lazyfs_rs::ffi;
+
+extern "C" {
+    fn lazyfs_ffi_test_main(dir: *const c_char) -> c_int;
+}
+
+#[test]
+fn c_program_drives_the_cache() {
+    // Pulls the ABI in ahead of the C program, which only refers to it by name
+    let _ = ffi::lazyfs_cache_new as unsafe extern "C" fn(_) -> _;
+
+    let dir = std::env::temp_dir().join(format!("lazyfs-rs-{}-ffi", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let dir_c = CString::new(dir.to_str().unwrap()).unwrap();
+    let failed_at = unsafe { lazyfs_ffi_test_main(dir_c.as_ptr()) };
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(failed_at, 0, "check at ffi_test.c:{} failed", failed_at);
+}
+
+#[test]
+fn checked_in_header_is_up_to_date() {
+    let generated = include_str!(concat!(env!("OUT_DIR"), "/include/lazyfs.h"));
+    let checked_in = include_str!("../include/lazyfs.h");
+    assert!(
+        generated == checked_in,
+        "include/lazyfs.h is out of date, copy the one generated under {}",
+        env!("OUT_DIR")
+    );
+}
@@ -0,0 +1,104 @@
+/*
+ * Drives the cache through the C ABI the way a harness would: writes, reads them back before
+ * and after a sync, then drops what was never synced, and checks how failures come back. Run
+ * by tests/ffi.rs, which hands it a scratch directory. Returns 0, or the line of the first
+ * check that failed.
+ */
+
+#include <errno.h>
+#include <stdio.h>
+#include <string.h>
+
+#include "lazyfs.h"
+
+#define CHECK(cond)                                                                 \
+    do {                                                                            \
+        if (!(cond)) {                                                              \
+            const char *error = lazyfs_last_error_message();                        \
+            fprintf(stderr, "ffi_test.c:%d: %s (%s)\n", __LINE__, #cond,            \
+                    error ? error : "no error");                                    \
+            return __LINE__;                                                        \
+        }                                                                           \
+    } while (0)
+
+static long on_disk(const char *path, char *buf, size_t len) {
+    FILE *file = fopen(path, "rb");
+    if (!file) {
+        return -1;
+    }
+    size_t read = fread(buf, 1, len, file);
+    fclose(file);
+    return (long)read;
+}
+
+int lazyfs_ffi_test_main(const char *dir) {
+    char path[4096], missing[4096], config[4096], buf[64];
+    snprintf(path, sizeof(path), "%s/wal", dir);
+    snprintf(missing, sizeof(missing), "%s/missing", dir);
+    snprintf(config, sizeof(config), "%s/missing.toml", dir);
+
+    FILE *created = fopen(path, "wb");
+    CHECK(created != NULL);
+    fclose(created);
+
+    CHECK(lazyfs_cache_new(config) == NULL);
+    CHECK(lazyfs_last_error_message() != NULL);
+
+    LazyFsCache *cache = lazyfs_cache_new(NULL);
+    CHECK(cache != NULL);
+
+    char *report = lazyfs_unsynced_report_json(cache);
+    CHECK(report != NULL);
+    CHECK(strcmp(report, "{\"omitted\":0,\"unsynced\":[]}") == 0);
+    lazyfs_string_free(report);
+
+    CHECK(lazyfs_write(cache, path, (const uint8_t *)"hello", 5, 0) == 5);
+    CHECK(lazyfs_read(cache, path, (uint8_t *)buf, sizeof(buf), 0) == 5);
+    CHECK(memcmp(buf, "hello", 5) == 0);
+    CHECK(lazyfs_read(cache, path, (uint8_t *)buf, sizeof(buf), 3) == 2);
+    CHECK(memcmp(buf, "lo", 2) == 0);
+    CHECK(on_disk(path, buf, sizeof(buf)) == 0);
+
+    report = lazyfs_unsynced_report_json(cache);
+    CHECK(report != NULL);
+    CHECK(strstr(report, "\"dirty_bytes\":5") != NULL);
+    lazyfs_string_free(report);
+
+    CHECK(lazyfs_fsync(cache, path) == 0);
+    CHECK(on_disk(path, buf, sizeof(buf)) == 5);
+    CHECK(memcmp(buf, "hello", 5) == 0);
+
+    report = lazyfs_unsynced_report_json(cache);
+    CHECK(report != NULL);
+    CHECK(strcmp(report, "{\"omitted\":0,\"unsynced\":[]}") == 0);
+    lazyfs_string_free(report);
+
+    /* Lost with the cache, as in a crash */
+    CHECK(lazyfs_write(cache, path, (const uint8_t *)" world", 6, 5) == 6);
+    CHECK(lazyfs_read(cache, path, (uint8_t *)buf, sizeof(buf), 0) == 11);
+    CHECK(memcmp(buf, "hello world", 11) == 0);
+    report = lazyfs_unsynced_report_json(cache);
+    CHECK(report != NULL);
+    /* Dirty as a whole, the block the write landed in */
+    CHECK(strstr(report, "\"dirty_bytes\":11") != NULL);
+    lazyfs_string_free(report);
+
+    CHECK(lazyfs_drop_unsynced(cache) == 0);
+    report = lazyfs_unsynced_report_json(cache);
+    CHECK(report != NULL);
+    CHECK(strcmp(report, "{\"omitted\":0,\"unsynced\":[]}") == 0);
+    lazyfs_string_free(report);
+    CHECK(lazyfs_read(cache, path, (uint8_t *)buf, sizeof(buf), 0) == 5);
+    CHECK(memcmp(buf, "hello", 5) == 0);
+    CHECK(on_disk(path, buf, sizeof(buf)) == 5);
+
+    CHECK(lazyfs_read(cache, missing, (uint8_t *)buf, sizeof(buf), 0) == -ENOENT);
+    CHECK(lazyfs_last_error_message() != NULL);
+    CHECK(lazyfs_write(cache, missing, (const uint8_t *)"x", 1, 0) == -ENOENT);
+    CHECK(lazyfs_fsync(NULL, path) == -EINVAL);
+    CHECK(lazyfs_read(cache, NULL, (uint8_t *)buf, sizeof(buf), 0) == -EINVAL);
+    CHECK(lazyfs_write(cache, path, NULL, 1, 0) == -EINVAL);
+
+    lazyfs_cache_free(cache);
+    return 0;
+}